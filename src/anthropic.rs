@@ -0,0 +1,429 @@
+//! The translation between the OpenAI chat completions API and the Anthropic Messages API, for the
+//! chat servers registered with `"provider": "anthropic"`
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{Value, json};
+
+use crate::{
+    dual_error, dual_warn,
+    error::{ServerError, ServerResult},
+    sse::SseParser,
+};
+
+/// The version of the Anthropic API the requests are sent for, in the `anthropic-version` header
+pub(crate) const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// The `max_tokens` of the requests without a limit, which the Anthropic API requires
+const DEFAULT_MAX_TOKENS: i64 = 4096;
+
+/// Translate the body of an OpenAI chat request into the body of an Anthropic Messages request
+///
+/// The system and developer messages are joined into the `system` prompt, and the content parts
+/// are translated into content blocks. The tools are dropped, and the tool messages are rejected,
+/// since the tool use is not translated.
+pub(crate) fn to_messages_request(body: &Value) -> ServerResult<Value> {
+    let mut system = Vec::new();
+    let mut messages = Vec::new();
+    for message in body["messages"].as_array().into_iter().flatten() {
+        match message["role"].as_str().unwrap_or_default() {
+            "system" | "developer" => system.push(text_of(&message["content"])),
+            role @ ("user" | "assistant") => {
+                if message["tool_calls"]
+                    .as_array()
+                    .is_some_and(|tool_calls| !tool_calls.is_empty())
+                {
+                    return Err(unsupported("The tool calls are"));
+                }
+                // an assistant message without content has nothing to send
+                if message["content"].is_null() {
+                    continue;
+                }
+
+                messages.push(json!({
+                    "role": role,
+                    "content": content_blocks(&message["content"]),
+                }));
+            }
+            role => return Err(unsupported(&format!("The `{role}` messages are"))),
+        }
+    }
+
+    let max_tokens = body["max_completion_tokens"]
+        .as_i64()
+        .filter(|max_tokens| (1..i32::MAX as i64).contains(max_tokens))
+        .unwrap_or(DEFAULT_MAX_TOKENS);
+    let mut request = json!({
+        "model": body["model"],
+        "messages": messages,
+        "max_tokens": max_tokens,
+    });
+    if !system.is_empty() {
+        request["system"] = system.join("\n\n").into();
+    }
+    // the temperature ranges from 0 to 1, not to 2
+    if let Some(temperature) = body["temperature"].as_f64() {
+        request["temperature"] = temperature.min(1.0).into();
+    }
+    if let Some(top_p) = body["top_p"].as_f64() {
+        request["top_p"] = top_p.into();
+    }
+    match &body["stop"] {
+        Value::String(stop) => request["stop_sequences"] = json!([stop]),
+        Value::Array(stop) if !stop.is_empty() => request["stop_sequences"] = stop.clone().into(),
+        _ => {}
+    }
+    if body["stream"] == true {
+        request["stream"] = true.into();
+    }
+    if let Some(user) = body["user"].as_str() {
+        request["metadata"] = json!({ "user_id": user });
+    }
+
+    if body["tools"]
+        .as_array()
+        .is_some_and(|tools| !tools.is_empty())
+    {
+        dual_warn!("Dropped the tools of the request to the anthropic server");
+    }
+
+    Ok(request)
+}
+
+fn unsupported(what: &str) -> ServerError {
+    let err_msg = format!("{what} not supported by the anthropic servers yet");
+    dual_error!("{}", err_msg);
+    ServerError::BadRequest(err_msg)
+}
+
+/// The text of a message content, which is a string or an array of content parts
+fn text_of(content: &Value) -> String {
+    match content {
+        Value::String(text) => text.clone(),
+        Value::Array(parts) => parts
+            .iter()
+            .filter_map(|part| part["text"].as_str())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => String::new(),
+    }
+}
+
+/// The content blocks of a message content. A string content is sent as it is.
+fn content_blocks(content: &Value) -> Value {
+    let Value::Array(parts) = content else {
+        return content.clone();
+    };
+
+    parts
+        .iter()
+        .filter_map(|part| match part["type"].as_str() {
+            Some("text") => Some(json!({"type": "text", "text": part["text"]})),
+            Some("image_url") => {
+                let url = part["image_url"]["url"].as_str()?;
+                // the data urls are sent as base64 images
+                let source = match url
+                    .strip_prefix("data:")
+                    .and_then(|data| data.split_once(";base64,"))
+                {
+                    Some((media_type, data)) => {
+                        json!({"type": "base64", "media_type": media_type, "data": data})
+                    }
+                    None => json!({"type": "url", "url": url}),
+                };
+                Some(json!({"type": "image", "source": source}))
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .into()
+}
+
+/// Translate an Anthropic message into an OpenAI chat completion
+pub(crate) fn to_chat_completion(message: &Value) -> Value {
+    let content = message["content"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|block| block["text"].as_str())
+        .collect::<String>();
+    let prompt_tokens = message["usage"]["input_tokens"].as_u64().unwrap_or(0);
+    let completion_tokens = message["usage"]["output_tokens"].as_u64().unwrap_or(0);
+
+    json!({
+        "id": message["id"],
+        "object": "chat.completion",
+        "created": unix_now(),
+        "model": message["model"],
+        "choices": [{
+            "index": 0,
+            "message": {"role": "assistant", "content": content},
+            "finish_reason": finish_reason(&message["stop_reason"]),
+            "logprobs": null,
+        }],
+        "usage": {
+            "prompt_tokens": prompt_tokens,
+            "completion_tokens": completion_tokens,
+            "total_tokens": prompt_tokens + completion_tokens,
+        },
+    })
+}
+
+/// Translate an Anthropic error into an OpenAI error
+pub(crate) fn to_openai_error(error: &Value) -> Value {
+    json!({
+        "error": {
+            "message": error["error"]["message"].as_str().unwrap_or("Unknown error"),
+            "type": error["error"]["type"].as_str().unwrap_or("api_error"),
+            "param": null,
+            "code": null,
+        }
+    })
+}
+
+fn finish_reason(stop_reason: &Value) -> &'static str {
+    match stop_reason.as_str() {
+        Some("max_tokens") => "length",
+        Some("tool_use") => "tool_calls",
+        _ => "stop",
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Translates an Anthropic event stream into an OpenAI chat completion stream, chunk by chunk
+#[derive(Debug)]
+pub(crate) struct StreamTranslator {
+    parser: SseParser,
+    /// Whether to end the stream with a chunk reporting the usage
+    include_usage: bool,
+    id: String,
+    model: String,
+    created: u64,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+}
+impl StreamTranslator {
+    pub(crate) fn new(include_usage: bool) -> Self {
+        Self {
+            parser: SseParser::default(),
+            include_usage,
+            id: String::new(),
+            model: String::new(),
+            created: unix_now(),
+            prompt_tokens: 0,
+            completion_tokens: 0,
+        }
+    }
+
+    /// Feed the next chunk of the Anthropic stream. Returns the OpenAI events it translates to.
+    pub(crate) fn translate(&mut self, chunk: &[u8]) -> String {
+        self.parser
+            .feed(chunk)
+            .iter()
+            .filter_map(|data| self.translate_event(data))
+            .collect()
+    }
+
+    /// End the Anthropic stream. Returns the OpenAI events of its unterminated last event.
+    pub(crate) fn finish(&mut self) -> String {
+        self.parser
+            .finish()
+            .and_then(|data| self.translate_event(&data))
+            .unwrap_or_default()
+    }
+
+    fn translate_event(&mut self, data: &str) -> Option<String> {
+        let event = serde_json::from_str::<Value>(data).ok()?;
+        match event["type"].as_str()? {
+            "message_start" => {
+                let message = &event["message"];
+                self.id = message["id"].as_str().unwrap_or_default().to_string();
+                self.model = message["model"].as_str().unwrap_or_default().to_string();
+                self.prompt_tokens = message["usage"]["input_tokens"].as_u64().unwrap_or(0);
+                Some(self.chunk(json!({"role": "assistant", "content": ""}), None))
+            }
+            "content_block_delta" => {
+                let text = event["delta"]["text"].as_str()?;
+                Some(self.chunk(json!({"role": "assistant", "content": text}), None))
+            }
+            "message_delta" => {
+                if let Some(output_tokens) = event["usage"]["output_tokens"].as_u64() {
+                    self.completion_tokens = output_tokens;
+                }
+                Some(self.chunk(
+                    json!({"role": "assistant", "content": null}),
+                    Some(finish_reason(&event["delta"]["stop_reason"])),
+                ))
+            }
+            "message_stop" => {
+                let mut events = String::new();
+                if self.include_usage {
+                    let usage = json!({
+                        "id": self.id,
+                        "object": "chat.completion.chunk",
+                        "created": self.created,
+                        "model": self.model,
+                        "system_fingerprint": "",
+                        "choices": [],
+                        "usage": {
+                            "prompt_tokens": self.prompt_tokens,
+                            "completion_tokens": self.completion_tokens,
+                            "total_tokens": self.prompt_tokens + self.completion_tokens,
+                        },
+                    });
+                    events.push_str(&format!("data: {usage}\n\n"));
+                }
+                events.push_str("data: [DONE]\n\n");
+                Some(events)
+            }
+            "error" => Some(format!("data: {}\n\n", to_openai_error(&event))),
+            // ping, content_block_start and content_block_stop
+            _ => None,
+        }
+    }
+
+    fn chunk(&self, delta: Value, finish_reason: Option<&str>) -> String {
+        let chunk = json!({
+            "id": self.id,
+            "object": "chat.completion.chunk",
+            "created": self.created,
+            "model": self.model,
+            "system_fingerprint": "",
+            "choices": [{
+                "index": 0,
+                "delta": delta,
+                "logprobs": null,
+                "finish_reason": finish_reason,
+            }],
+        });
+        format!("data: {chunk}\n\n")
+    }
+}
+
+#[test]
+fn test_to_messages_request() {
+    let body = json!({
+        "model": "claude-sonnet-4-5",
+        "messages": [
+            {"role": "system", "content": "Be brief."},
+            {"role": "user", "content": [
+                {"type": "text", "text": "What is in the image?"},
+                {"type": "image_url", "image_url": {"url": "data:image/png;base64,iVBORw0KGgo="}},
+                {"type": "image_url", "image_url": {"url": "https://example.com/cat.png"}},
+            ]},
+            {"role": "assistant", "content": "A cat."},
+            {"role": "user", "content": "Sure?"},
+        ],
+        "max_completion_tokens": i32::MAX,
+        "temperature": 1.5,
+        "stop": "\n\n",
+        "stream": true,
+        "user": "alice",
+    });
+    let request = to_messages_request(&body).unwrap();
+    assert_eq!(
+        request,
+        json!({
+            "model": "claude-sonnet-4-5",
+            "system": "Be brief.",
+            "messages": [
+                {"role": "user", "content": [
+                    {"type": "text", "text": "What is in the image?"},
+                    {"type": "image", "source": {"type": "base64", "media_type": "image/png", "data": "iVBORw0KGgo="}},
+                    {"type": "image", "source": {"type": "url", "url": "https://example.com/cat.png"}},
+                ]},
+                {"role": "assistant", "content": "A cat."},
+                {"role": "user", "content": "Sure?"},
+            ],
+            "max_tokens": DEFAULT_MAX_TOKENS,
+            "temperature": 1.0,
+            "stop_sequences": ["\n\n"],
+            "stream": true,
+            "metadata": {"user_id": "alice"},
+        })
+    );
+
+    // the tool use is not translated
+    let body = json!({
+        "model": "claude-sonnet-4-5",
+        "messages": [{"role": "tool", "content": "{}", "tool_call_id": "call-1"}],
+    });
+    assert!(matches!(
+        to_messages_request(&body),
+        Err(ServerError::BadRequest(_))
+    ));
+}
+
+#[test]
+fn test_to_chat_completion() {
+    let message = json!({
+        "id": "msg_1",
+        "type": "message",
+        "role": "assistant",
+        "model": "claude-sonnet-4-5",
+        "content": [{"type": "text", "text": "Hello"}, {"type": "text", "text": " there"}],
+        "stop_reason": "max_tokens",
+        "usage": {"input_tokens": 10, "output_tokens": 5},
+    });
+    let chat_completion = to_chat_completion(&message);
+    let chat_completion =
+        serde_json::from_value::<endpoints::chat::ChatCompletionObject>(chat_completion).unwrap();
+    assert_eq!(chat_completion.id, "msg_1");
+    assert_eq!(
+        chat_completion.choices[0].message.content.as_deref(),
+        Some("Hello there")
+    );
+    assert_eq!(
+        chat_completion.choices[0].finish_reason,
+        endpoints::common::FinishReason::length
+    );
+    assert_eq!(chat_completion.usage.total_tokens, 15);
+}
+
+#[test]
+fn test_stream_translator() {
+    let stream = [
+        r#"{"type": "message_start", "message": {"id": "msg_1", "model": "claude-sonnet-4-5", "usage": {"input_tokens": 10, "output_tokens": 1}}}"#,
+        r#"{"type": "content_block_start", "index": 0, "content_block": {"type": "text", "text": ""}}"#,
+        r#"{"type": "ping"}"#,
+        r#"{"type": "content_block_delta", "index": 0, "delta": {"type": "text_delta", "text": "Hi"}}"#,
+        r#"{"type": "content_block_stop", "index": 0}"#,
+        r#"{"type": "message_delta", "delta": {"stop_reason": "end_turn"}, "usage": {"output_tokens": 5}}"#,
+        r#"{"type": "message_stop"}"#,
+    ]
+    .iter()
+    .map(|data| {
+        let event = serde_json::from_str::<Value>(data).unwrap();
+        format!("event: {}\ndata: {data}\n\n", event["type"].as_str().unwrap())
+    })
+    .collect::<String>();
+
+    let mut translator = StreamTranslator::new(true);
+    let mut translated = String::new();
+    for chunk in stream.as_bytes().chunks(7) {
+        translated.push_str(&translator.translate(chunk));
+    }
+    translated.push_str(&translator.finish());
+
+    let mut parser = SseParser::default();
+    let events = parser.feed(translated.as_bytes());
+    assert_eq!(events.len(), 5);
+    assert_eq!(events[4], "[DONE]");
+    let chunks = events[..4]
+        .iter()
+        .map(|event| serde_json::from_str::<endpoints::chat::ChatCompletionChunk>(event).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(chunks[0].id, "msg_1");
+    assert_eq!(chunks[1].choices[0].delta.content.as_deref(), Some("Hi"));
+    assert_eq!(
+        chunks[2].choices[0].finish_reason,
+        Some(endpoints::common::FinishReason::stop)
+    );
+    assert!(chunks[3].choices.is_empty());
+    assert_eq!(chunks[3].usage.as_ref().unwrap().total_tokens, 15);
+}
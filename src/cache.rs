@@ -0,0 +1,172 @@
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::http::{HeaderMap, StatusCode};
+use bytes::Bytes;
+use endpoints::chat::ChatCompletionRequest;
+
+use crate::config::CacheConfig;
+
+/// The in-memory LRU cache of the non-streaming chat responses, keyed by the hash of the request
+#[derive(Debug)]
+pub(crate) struct ResponseCache {
+    capacity: usize,
+    ttl: Duration,
+    entries: Mutex<CacheEntries>,
+}
+impl ResponseCache {
+    pub(crate) fn new(cache_config: &CacheConfig) -> Self {
+        Self {
+            capacity: cache_config.capacity,
+            ttl: Duration::from_secs(cache_config.ttl_secs),
+            entries: Mutex::new(CacheEntries::default()),
+        }
+    }
+
+    /// Get the cached response of the request, unless it has expired
+    pub(crate) fn get(&self, key: u64) -> Option<CachedResponse> {
+        let mut entries = self.entries.lock().unwrap();
+        let tick = entries.tick();
+        let entry = entries.map.get_mut(&key)?;
+        if entry.inserted_at.elapsed() > self.ttl {
+            entries.map.remove(&key);
+            return None;
+        }
+
+        entry.last_used = tick;
+        Some(entry.response.clone())
+    }
+
+    /// Cache the response of the request, evicting the least recently used one if full
+    pub(crate) fn insert(&self, key: u64, response: CachedResponse) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.map.len() >= self.capacity
+            && !entries.map.contains_key(&key)
+            && let Some(lru_key) = entries
+                .map
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| *key)
+        {
+            entries.map.remove(&lru_key);
+        }
+
+        let last_used = entries.tick();
+        entries.map.insert(
+            key,
+            CacheEntry {
+                response,
+                inserted_at: Instant::now(),
+                last_used,
+            },
+        );
+    }
+}
+
+/// The key of a chat request, which ignores the fields not affecting the response, e.g. `user`
+pub(crate) fn cache_key(request: &ChatCompletionRequest) -> u64 {
+    let mut request = serde_json::to_value(request).unwrap_or_default();
+    if let Some(request) = request.as_object_mut() {
+        request.remove("user");
+        request.remove("stream");
+    }
+
+    let mut hasher = DefaultHasher::new();
+    request.to_string().hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Whether the response of the chat request can be cached: a deterministic non-streaming request
+/// without tools, or one explicitly marked as cacheable by the `X-Cacheable: true` header
+pub(crate) fn is_cacheable(request: &ChatCompletionRequest, headers: &HeaderMap) -> bool {
+    let explicitly_cacheable = headers
+        .get("x-cacheable")
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("true"));
+
+    request.stream != Some(true)
+        && request.tools.as_ref().is_none_or(|tools| tools.is_empty())
+        && (request.temperature == Some(0.0) || explicitly_cacheable)
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct CachedResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+#[derive(Debug, Default)]
+struct CacheEntries {
+    map: HashMap<u64, CacheEntry>,
+    // incremented on every access to order the entries by recency
+    clock: u64,
+}
+impl CacheEntries {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    response: CachedResponse,
+    inserted_at: Instant,
+    last_used: u64,
+}
+
+#[test]
+fn test_response_cache() {
+    let response = |body: &'static str| CachedResponse {
+        status: StatusCode::OK,
+        headers: HeaderMap::new(),
+        body: Bytes::from(body),
+    };
+
+    let cache = ResponseCache::new(&CacheConfig {
+        capacity: 2,
+        ttl_secs: 60,
+    });
+    cache.insert(1, response("one"));
+    cache.insert(2, response("two"));
+    // the response 2 becomes the least recently used one
+    assert_eq!(cache.get(1).unwrap().body, "one");
+    cache.insert(3, response("three"));
+    assert!(cache.get(2).is_none());
+    assert_eq!(cache.get(1).unwrap().body, "one");
+    assert_eq!(cache.get(3).unwrap().body, "three");
+
+    // the expired responses are not served
+    let cache = ResponseCache::new(&CacheConfig {
+        capacity: 2,
+        ttl_secs: 0,
+    });
+    cache.insert(1, response("one"));
+    std::thread::sleep(Duration::from_millis(5));
+    assert!(cache.get(1).is_none());
+
+    // the requests differing only by user share the key
+    let mut request: ChatCompletionRequest = serde_json::from_value(serde_json::json!({
+        "model": "Llama-3.2-3b",
+        "messages": [{"role": "user", "content": "Hello"}],
+        "temperature": 0.0,
+        "user": "user-1",
+    }))
+    .unwrap();
+    assert!(is_cacheable(&request, &HeaderMap::new()));
+    let key = cache_key(&request);
+    request.user = Some("user-2".to_string());
+    assert_eq!(cache_key(&request), key);
+    request.temperature = Some(0.7);
+    assert_ne!(cache_key(&request), key);
+    assert!(!is_cacheable(&request, &HeaderMap::new()));
+}
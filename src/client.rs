@@ -0,0 +1,142 @@
+use std::{
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::OnceCell;
+
+use crate::{
+    config::{ConnectionConfig, HealthCheckConfig},
+    dual_debug, dual_error,
+    error::{ServerError, ServerResult},
+};
+
+/// Default idle timeout of the pooled connections in seconds, which is the default of reqwest
+const DEFAULT_IDLE_TIMEOUT: u64 = 90;
+
+// Global HTTP client for the requests to downstream servers
+static HTTP_CLIENT: OnceCell<HttpClient> = OnceCell::new();
+
+// Global HTTP client for the health checks of downstream servers
+static HEALTH_CHECK_CLIENT: OnceCell<reqwest::Client> = OnceCell::new();
+
+/// The HTTP client shared by the requests to downstream servers
+///
+/// If `max_lifetime` is set, the client is rebuilt once it gets older than `max_lifetime`. The pool
+/// of the old client is dropped after the in-flight requests holding it finish, so the connections
+/// are recycled periodically.
+struct HttpClient {
+    idle_timeout: Duration,
+    max_lifetime: Option<Duration>,
+    inner: RwLock<(reqwest::Client, Instant)>,
+}
+impl HttpClient {
+    fn new(config: &ConnectionConfig) -> Self {
+        let idle_timeout =
+            Duration::from_secs(config.idle_timeout_secs.unwrap_or(DEFAULT_IDLE_TIMEOUT));
+        let max_lifetime = config.max_lifetime_secs.map(Duration::from_secs);
+
+        Self {
+            idle_timeout,
+            max_lifetime,
+            inner: RwLock::new((Self::build(idle_timeout), Instant::now())),
+        }
+    }
+
+    fn build(idle_timeout: Duration) -> reqwest::Client {
+        reqwest::Client::builder()
+            .pool_idle_timeout(idle_timeout)
+            .build()
+            .unwrap_or_else(|e| {
+                dual_error!("Failed to build the http client: {e}. Use the default one instead.");
+                reqwest::Client::new()
+            })
+    }
+
+    fn get(&self) -> reqwest::Client {
+        if let Some(max_lifetime) = self.max_lifetime
+            && self.inner.read().unwrap().1.elapsed() >= max_lifetime
+        {
+            let mut inner = self.inner.write().unwrap();
+
+            // check again in case another request has rebuilt the client
+            if inner.1.elapsed() >= max_lifetime {
+                dual_debug!("Recycle the connections to downstream servers");
+                *inner = (Self::build(self.idle_timeout), Instant::now());
+            }
+        }
+
+        self.inner.read().unwrap().0.clone()
+    }
+}
+
+/// Initialize the shared HTTP client with the connection config
+pub(crate) fn init_http_client(config: &ConnectionConfig) -> ServerResult<()> {
+    HTTP_CLIENT.set(HttpClient::new(config)).map_err(|_| {
+        let err_msg = "Failed to set HTTP_CLIENT";
+        dual_error!("{}", err_msg);
+        ServerError::Operation(err_msg.to_string())
+    })
+}
+
+/// Get the shared HTTP client for the requests to downstream servers
+pub(crate) fn http_client() -> reqwest::Client {
+    HTTP_CLIENT
+        .get_or_init(|| HttpClient::new(&ConnectionConfig::default()))
+        .get()
+}
+
+/// Initialize the HTTP client of the health checks with their timeouts
+pub(crate) fn init_health_check_client(config: &HealthCheckConfig) -> ServerResult<()> {
+    HEALTH_CHECK_CLIENT
+        .set(build_health_check_client(config))
+        .map_err(|_| {
+            let err_msg = "Failed to set HEALTH_CHECK_CLIENT";
+            dual_error!("{}", err_msg);
+            ServerError::Operation(err_msg.to_string())
+        })
+}
+
+/// Get the HTTP client for the health checks of downstream servers
+pub(crate) fn health_check_client() -> reqwest::Client {
+    HEALTH_CHECK_CLIENT
+        .get_or_init(|| build_health_check_client(&HealthCheckConfig::default()))
+        .clone()
+}
+
+fn build_health_check_client(config: &HealthCheckConfig) -> reqwest::Client {
+    reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(config.connect_timeout_secs))
+        .timeout(Duration::from_secs(config.request_timeout_secs))
+        .build()
+        .unwrap_or_else(|e| {
+            dual_error!(
+                "Failed to build the health check client: {e}. Use the default one instead."
+            );
+            reqwest::Client::new()
+        })
+}
+
+#[test]
+fn test_recycle_http_client() {
+    // the client is rebuilt once it reaches the max lifetime
+    let client = HttpClient::new(&ConnectionConfig {
+        idle_timeout_secs: Some(10),
+        max_lifetime_secs: Some(0),
+    });
+    let created_at = client.inner.read().unwrap().1;
+    std::thread::sleep(Duration::from_millis(5));
+    let _ = client.get();
+    assert!(client.inner.read().unwrap().1 > created_at);
+
+    // the client is never rebuilt without the max lifetime
+    let client = HttpClient::new(&ConnectionConfig::default());
+    let created_at = client.inner.read().unwrap().1;
+    std::thread::sleep(Duration::from_millis(5));
+    let _ = client.get();
+    assert_eq!(client.inner.read().unwrap().1, created_at);
+    assert_eq!(
+        client.idle_timeout,
+        Duration::from_secs(DEFAULT_IDLE_TIMEOUT)
+    );
+}
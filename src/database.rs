@@ -1,5 +1,16 @@
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
 use rusqlite::{Connection, Result};
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{config::DatabaseConfig, dual_error, dual_warn};
+
+// Path of the SQLite database holding the chat history
+const DATABASE_PATH: &str = "chat_history.db";
+// Version of the schema, stored in `PRAGMA user_version`. Bump it when adding a migration.
+const SCHEMA_VERSION: i64 = 1;
 
 // FIX: Add 'pub' to make this struct visible to main.rs
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -8,19 +19,320 @@ pub struct ChatMessage {
     pub content: String,
 }
 
-// FIX: Add 'pub' to make this function visible to main.rs
-pub fn connect() -> Result<Connection> {
-    let conn = Connection::open("chat_history.db")?;
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS chat_history (
+pub type StoreResult<T> = std::result::Result<T, StoreError>;
+
+#[derive(Error, Debug)]
+pub enum StoreError {
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+    #[error(transparent)]
+    Postgres(#[from] tokio_postgres::Error),
+    #[error("The task of the SQLite store failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+    #[error("Unsupported database url: {0}")]
+    UnsupportedUrl(String),
+}
+
+/// The storage of the chat history of the sessions
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    /// The messages of the session, from the oldest to the most recent
+    async fn get_history(&self, session_id: &str) -> StoreResult<Vec<ChatMessage>>;
+
+    /// Append the message to the history of the session
+    async fn save_message(&self, session_id: &str, message: &ChatMessage) -> StoreResult<()>;
+
+    /// Replace the history of the session with the given messages, e.g. after compacting it
+    async fn replace_history(&self, session_id: &str, messages: &[ChatMessage]) -> StoreResult<()>;
+
+    /// The ids of the sessions having a history, in alphabetical order
+    async fn list_sessions(&self) -> StoreResult<Vec<String>>;
+
+    /// Delete the history of the session. Returns whether the session had a history.
+    async fn delete_session(&self, session_id: &str) -> StoreResult<bool>;
+}
+
+/// Open the store of the chat history selected by the `database_url` of the config. The history
+/// is kept in the local SQLite database if it is not set.
+pub async fn open_store(config: &DatabaseConfig) -> StoreResult<Arc<dyn SessionStore>> {
+    match config.database_url.as_deref() {
+        None => Ok(Arc::new(SqliteStore::open(DATABASE_PATH).await?)),
+        Some(url) if url.starts_with("postgres://") || url.starts_with("postgresql://") => {
+            Ok(Arc::new(PostgresStore::connect(url).await?))
+        }
+        Some(url) => match url.strip_prefix("sqlite://") {
+            Some(path) => Ok(Arc::new(SqliteStore::open(path).await?)),
+            None => Err(StoreError::UnsupportedUrl(url.to_string())),
+        },
+    }
+}
+
+/// The chat history kept in a SQLite database file, which is only shared by the gateways on the
+/// same host
+pub struct SqliteStore {
+    // a single connection, used by one blocking task at a time
+    conn: Arc<Mutex<Connection>>,
+}
+impl SqliteStore {
+    /// Open the database, creating the schema and the indices if they do not exist yet
+    pub async fn open(path: impl Into<String>) -> StoreResult<Self> {
+        let path = path.into();
+        let conn = tokio::task::spawn_blocking(move || open(&path)).await??;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Run the queries on the connection in a blocking task, so that the SQLite I/O does not hold
+    /// up the async workers
+    async fn with_conn<T, F>(&self, f: F) -> StoreResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            // a panic while holding the lock leaves the connection usable, so the poison is ignored
+            let conn = conn.lock().unwrap_or_else(|e| e.into_inner());
+            f(&conn)
+        })
+        .await?;
+        Ok(result?)
+    }
+}
+#[async_trait]
+impl SessionStore for SqliteStore {
+    async fn get_history(&self, session_id: &str) -> StoreResult<Vec<ChatMessage>> {
+        let session_id = session_id.to_string();
+        self.with_conn(move |conn| get_history(conn, &session_id))
+            .await
+    }
+
+    async fn save_message(&self, session_id: &str, message: &ChatMessage) -> StoreResult<()> {
+        let (session_id, message) = (session_id.to_string(), message.clone());
+        self.with_conn(move |conn| save_message(conn, &session_id, &message))
+            .await
+    }
+
+    async fn replace_history(&self, session_id: &str, messages: &[ChatMessage]) -> StoreResult<()> {
+        let (session_id, messages) = (session_id.to_string(), messages.to_vec());
+        self.with_conn(move |conn| replace_history(conn, &session_id, &messages))
+            .await
+    }
+
+    async fn list_sessions(&self) -> StoreResult<Vec<String>> {
+        self.with_conn(|conn| {
+            let mut stmt =
+                conn.prepare("SELECT DISTINCT session_id FROM chat_history ORDER BY session_id")?;
+            let session_ids = stmt
+                .query_map([], |row| row.get(0))?
+                .collect::<Result<Vec<String>>>()?;
+            Ok(session_ids)
+        })
+        .await
+    }
+
+    async fn delete_session(&self, session_id: &str) -> StoreResult<bool> {
+        let session_id = session_id.to_string();
+        let deleted = self
+            .with_conn(move |conn| {
+                conn.execute(
+                    "DELETE FROM chat_history WHERE session_id = ?1",
+                    [session_id],
+                )
+            })
+            .await?;
+        Ok(deleted > 0)
+    }
+}
+
+/// The chat history kept in a Postgres database, which is shared by all the gateway replicas
+/// connecting to it
+pub struct PostgresStore {
+    url: String,
+    // replaced by a new connection once the current one is closed, e.g. by a restart of the
+    // database
+    client: tokio::sync::RwLock<Arc<tokio_postgres::Client>>,
+}
+impl PostgresStore {
+    /// Connect to the database, creating the schema and the indices if they do not exist yet
+    pub async fn connect(url: &str) -> StoreResult<Self> {
+        let client = connect_postgres(url).await?;
+
+        // the id keeps the order of the messages saved within the same second
+        client
+            .batch_execute(
+                "CREATE TABLE IF NOT EXISTS chat_history (
+                    id         BIGSERIAL PRIMARY KEY,
+                    session_id TEXT NOT NULL,
+                    role       TEXT NOT NULL,
+                    content    TEXT NOT NULL,
+                    timestamp  BIGINT NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS idx_chat_history_session_id_timestamp
+                    ON chat_history (session_id, timestamp, id);",
+            )
+            .await?;
+
+        Ok(Self {
+            url: url.to_string(),
+            client: tokio::sync::RwLock::new(Arc::new(client)),
+        })
+    }
+
+    /// The client of the connection to the database, reconnecting if the connection is closed
+    async fn client(&self) -> StoreResult<Arc<tokio_postgres::Client>> {
+        let client = self.client.read().await.clone();
+        if !client.is_closed() {
+            return Ok(client);
+        }
+
+        // the first request finding the connection closed reconnects for the others
+        let mut client = self.client.write().await;
+        if client.is_closed() {
+            dual_warn!("The connection to the Postgres database is closed. Reconnecting");
+            *client = Arc::new(connect_postgres(&self.url).await?);
+        }
+        Ok(client.clone())
+    }
+}
+#[async_trait]
+impl SessionStore for PostgresStore {
+    async fn get_history(&self, session_id: &str) -> StoreResult<Vec<ChatMessage>> {
+        let rows = self
+            .client()
+            .await?
+            .query(
+                "SELECT role, content FROM chat_history WHERE session_id = $1 ORDER BY timestamp ASC, id ASC",
+                &[&session_id],
+            )
+            .await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| ChatMessage {
+                role: row.get(0),
+                content: row.get(1),
+            })
+            .collect())
+    }
+
+    async fn save_message(&self, session_id: &str, message: &ChatMessage) -> StoreResult<()> {
+        self.client()
+            .await?
+            .execute(
+                "INSERT INTO chat_history (session_id, role, content, timestamp) VALUES ($1, $2, $3, $4)",
+                &[&session_id, &message.role, &message.content, &unix_timestamp()],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn replace_history(&self, session_id: &str, messages: &[ChatMessage]) -> StoreResult<()> {
+        let roles = messages
+            .iter()
+            .map(|message| message.role.as_str())
+            .collect::<Vec<_>>();
+        let contents = messages
+            .iter()
+            .map(|message| message.content.as_str())
+            .collect::<Vec<_>>();
+
+        // a single statement, so that the other replicas never see the history half replaced
+        self.client()
+            .await?
+            .execute(
+                "WITH deleted AS (DELETE FROM chat_history WHERE session_id = $1)
+                INSERT INTO chat_history (session_id, role, content, timestamp)
+                SELECT $1, role, content, $4
+                FROM UNNEST($2::TEXT[], $3::TEXT[]) WITH ORDINALITY AS messages (role, content, idx)
+                ORDER BY idx",
+                &[&session_id, &roles, &contents, &unix_timestamp()],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn list_sessions(&self) -> StoreResult<Vec<String>> {
+        let rows = self
+            .client()
+            .await?
+            .query(
+                "SELECT DISTINCT session_id FROM chat_history ORDER BY session_id",
+                &[],
+            )
+            .await?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    async fn delete_session(&self, session_id: &str) -> StoreResult<bool> {
+        let deleted = self
+            .client()
+            .await?
+            .execute(
+                "DELETE FROM chat_history WHERE session_id = $1",
+                &[&session_id],
+            )
+            .await?;
+        Ok(deleted > 0)
+    }
+}
+
+// Connect to the Postgres database, driving the connection in the background until it is closed
+async fn connect_postgres(url: &str) -> StoreResult<tokio_postgres::Client> {
+    let (client, connection) = tokio_postgres::connect(url, tokio_postgres::NoTls).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            dual_error!("The connection to the Postgres database failed: {e}");
+        }
+    });
+    Ok(client)
+}
+
+// Seconds since the Unix epoch, which order the messages of a session
+fn unix_timestamp() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+// Open the database, tune the connection and migrate the schema if it is outdated
+fn open(path: &str) -> Result<Connection> {
+    let conn = Connection::open(path)?;
+
+    // WAL lets the readers of the history run alongside the writer, and NORMAL is durable enough
+    // in WAL mode while saving a sync on every commit
+    conn.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))?;
+    conn.pragma_update(None, "synchronous", "NORMAL")?;
+
+    migrate(&conn)?;
+
+    Ok(conn)
+}
+
+// Bring the schema up to `SCHEMA_VERSION`. The schema is up to date after the first connection,
+// so the later connections only read the version.
+fn migrate(conn: &Connection) -> Result<()> {
+    let version: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version >= SCHEMA_VERSION {
+        return Ok(());
+    }
+
+    // version 1: the databases created before the index only have the table
+    conn.execute_batch(&format!(
+        "BEGIN;
+        CREATE TABLE IF NOT EXISTS chat_history (
             session_id TEXT NOT NULL,
             role       TEXT NOT NULL,
             content    TEXT NOT NULL,
             timestamp  INTEGER NOT NULL
-        )",
-        [],
-    )?;
-    Ok(conn)
+        );
+        CREATE INDEX IF NOT EXISTS idx_chat_history_session_id_timestamp
+            ON chat_history (session_id, timestamp);
+        PRAGMA user_version = {SCHEMA_VERSION};
+        COMMIT;"
+    ))
 }
 
 // FIX: Add 'pub'
@@ -44,14 +356,11 @@ pub fn get_history(conn: &Connection, session_id: &str) -> Result<Vec<ChatMessag
 
 // FIX: Add 'pub'
 pub fn save_message(conn: &Connection, session_id: &str, message: &ChatMessage) -> Result<()> {
-    let timestamp = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
+    let timestamp = unix_timestamp();
 
     conn.execute(
         "INSERT INTO chat_history (session_id, role, content, timestamp) VALUES (?1, ?2, ?3, ?4)",
-        &[
+        [
             &session_id.to_string(),
             &message.role,
             &message.content,
@@ -59,4 +368,228 @@ pub fn save_message(conn: &Connection, session_id: &str, message: &ChatMessage)
         ],
     )?;
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Replace the history of the session with the given messages, e.g. after compacting it
+pub fn replace_history(
+    conn: &Connection,
+    session_id: &str,
+    messages: &[ChatMessage],
+) -> Result<()> {
+    let timestamp = unix_timestamp();
+
+    // the messages share the timestamp, and are read back in the order they are inserted
+    let tx = conn.unchecked_transaction()?;
+    tx.execute(
+        "DELETE FROM chat_history WHERE session_id = ?1",
+        [session_id],
+    )?;
+    for message in messages {
+        tx.execute(
+            "INSERT INTO chat_history (session_id, role, content, timestamp) VALUES (?1, ?2, ?3, ?4)",
+            [
+                &session_id.to_string(),
+                &message.role,
+                &message.content,
+                &timestamp.to_string(),
+            ],
+        )?;
+    }
+    tx.commit()
+}
+
+#[test]
+fn test_replace_history() {
+    let dir = std::env::temp_dir().join(format!("llama-nexus-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let conn = open(dir.join("chat_history.db").to_str().unwrap()).unwrap();
+    let message = |role: &str, content: &str| ChatMessage {
+        role: role.to_string(),
+        content: content.to_string(),
+    };
+
+    for content in ["one", "two", "three"] {
+        save_message(&conn, "session-1", &message("user", content)).unwrap();
+    }
+    save_message(&conn, "session-2", &message("user", "other")).unwrap();
+
+    let compacted = [
+        message("system", "summary"),
+        message("user", "three"),
+        message("assistant", "four"),
+    ];
+    replace_history(&conn, "session-1", &compacted).unwrap();
+    let contents = |session_id: &str| {
+        get_history(&conn, session_id)
+            .unwrap()
+            .into_iter()
+            .map(|message| message.content)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(contents("session-1"), ["summary", "three", "four"]);
+    // the other sessions are left alone
+    assert_eq!(contents("session-2"), ["other"]);
+
+    drop(conn);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_open_store() {
+    let dir = std::env::temp_dir().join(format!("llama-nexus-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("chat_history.db");
+    let config = |database_url: String| DatabaseConfig {
+        database_url: Some(database_url),
+        ..Default::default()
+    };
+
+    let store = open_store(&config(format!("sqlite://{}", path.display())))
+        .await
+        .unwrap();
+    assert!(path.exists());
+    let message = ChatMessage {
+        role: "user".to_string(),
+        content: "hello".to_string(),
+    };
+    store.save_message("session-1", &message).await.unwrap();
+    let history = store.get_history("session-1").await.unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].content, "hello");
+    store.save_message("session-0", &message).await.unwrap();
+    assert_eq!(
+        store.list_sessions().await.unwrap(),
+        ["session-0", "session-1"]
+    );
+    assert!(store.delete_session("session-1").await.unwrap());
+    assert!(!store.delete_session("session-1").await.unwrap());
+    assert_eq!(store.list_sessions().await.unwrap(), ["session-0"]);
+
+    assert!(matches!(
+        open_store(&config("mysql://localhost/chat".to_string())).await,
+        Err(StoreError::UnsupportedUrl(_))
+    ));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_get_history_uses_index() {
+    let dir = std::env::temp_dir().join(format!("llama-nexus-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("chat_history.db");
+    let path = path.to_str().unwrap();
+
+    // an existing database created before the index
+    {
+        let conn = Connection::open(path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE chat_history (
+                session_id TEXT NOT NULL,
+                role       TEXT NOT NULL,
+                content    TEXT NOT NULL,
+                timestamp  INTEGER NOT NULL
+            );",
+        )
+        .unwrap();
+        let tx = conn.unchecked_transaction().unwrap();
+        for i in 0..20_000 {
+            tx.execute(
+                "INSERT INTO chat_history (session_id, role, content, timestamp) VALUES (?1, 'user', 'hello', ?2)",
+                rusqlite::params![format!("session-{}", i % 500), i],
+            )
+            .unwrap();
+        }
+        tx.commit().unwrap();
+    }
+
+    let conn = open(path).unwrap();
+    let version: i64 = conn
+        .pragma_query_value(None, "user_version", |row| row.get(0))
+        .unwrap();
+    assert_eq!(version, SCHEMA_VERSION);
+    conn.execute_batch("ANALYZE").unwrap();
+
+    let mut stmt = conn
+        .prepare(
+            "EXPLAIN QUERY PLAN SELECT role, content FROM chat_history WHERE session_id = ?1 ORDER BY timestamp ASC",
+        )
+        .unwrap();
+    let plan = stmt
+        .query_map(["session-42"], |row| row.get::<_, String>(3))
+        .unwrap()
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+    assert!(
+        plan.iter()
+            .any(|detail| detail.contains("USING INDEX idx_chat_history_session_id_timestamp")),
+        "plan: {plan:?}"
+    );
+    // the index also provides the order, so no temp b-tree is needed for sorting
+    assert!(!plan.iter().any(|detail| detail.contains("TEMP B-TREE")));
+
+    assert_eq!(get_history(&conn, "session-42").unwrap().len(), 40);
+
+    drop(stmt);
+    drop(conn);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_postgres_store() {
+    // the test needs a Postgres database, e.g. `postgres://postgres@127.0.0.1:5432/postgres`
+    let Ok(url) = std::env::var("LLAMA_NEXUS_TEST_POSTGRES_URL") else {
+        return;
+    };
+    let store = PostgresStore::connect(&url).await.unwrap();
+    let message = |role: &str, content: &str| ChatMessage {
+        role: role.to_string(),
+        content: content.to_string(),
+    };
+    let session_id = format!("session-{}", uuid::Uuid::new_v4());
+    let contents = |history: Vec<ChatMessage>| {
+        history
+            .into_iter()
+            .map(|message| message.content)
+            .collect::<Vec<_>>()
+    };
+
+    for content in ["one", "two", "three"] {
+        store
+            .save_message(&session_id, &message("user", content))
+            .await
+            .unwrap();
+    }
+    assert_eq!(
+        contents(store.get_history(&session_id).await.unwrap()),
+        ["one", "two", "three"]
+    );
+    store
+        .replace_history(
+            &session_id,
+            &[message("system", "summary"), message("user", "three")],
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        contents(store.get_history(&session_id).await.unwrap()),
+        ["summary", "three"]
+    );
+    assert!(store.list_sessions().await.unwrap().contains(&session_id));
+
+    // the store reconnects once the connection is closed
+    let _ = store
+        .client()
+        .await
+        .unwrap()
+        .batch_execute("SELECT pg_terminate_backend(pg_backend_pid())")
+        .await;
+    while !store.client.read().await.is_closed() {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    assert_eq!(store.get_history(&session_id).await.unwrap().len(), 2);
+
+    assert!(store.delete_session(&session_id).await.unwrap());
+    assert!(!store.delete_session(&session_id).await.unwrap());
+    assert!(!store.list_sessions().await.unwrap().contains(&session_id));
+}
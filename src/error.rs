@@ -1,11 +1,13 @@
 use axum::{
     Json,
-    http::StatusCode,
+    http::{StatusCode, header},
     response::{IntoResponse, Response},
 };
 use serde::Serialize;
 use thiserror::Error;
 
+use crate::server::RoutingCandidate;
+
 pub type ServerResult<T> = std::result::Result<T, ServerError>;
 
 #[derive(Error, Debug, Clone)]
@@ -16,6 +18,65 @@ pub enum ServerError {
         "Not found available server. Please register a(n) {0} server via the `/admin/servers/register` endpoint."
     )]
     NotFoundServer(String),
+    #[error("{message}")]
+    NoAvailableServer {
+        message: String,
+        /// The servers considered for the request, reported only if routing debugging is on
+        candidates: Option<Vec<RoutingCandidate>>,
+    },
+    #[error(
+        "No {kind} server available. Please register a(n) {kind} server via the `/admin/servers/register` endpoint."
+    )]
+    MissingCapability {
+        kind: String,
+        /// The kinds of the registered servers
+        available_kinds: Vec<String>,
+    },
+    #[error("Not found model: {model}")]
+    NotFoundModel {
+        model: String,
+        /// The servers considered for the request, reported only if routing debugging is on
+        candidates: Option<Vec<RoutingCandidate>>,
+    },
+    #[error("Not found session: {0}")]
+    NotFoundSession(String),
+    #[error("Not found server: {0}")]
+    NotFoundServerId(String),
+    #[error("Too many concurrent streaming responses. Please retry later.")]
+    TooManyStreams,
+    #[error("The server {0} takes too many concurrent requests. Please retry later.")]
+    ServerBusy(String),
+    #[error("Too many requests waiting for a server. Please retry later.")]
+    QueueFull {
+        /// Seconds the client should wait before retrying
+        retry_after: u64,
+    },
+    #[error("Timed out waiting for a server. Please retry later.")]
+    QueueTimeout {
+        /// Seconds the client should wait before retrying
+        retry_after: u64,
+    },
+    #[error("{message}")]
+    Maintenance {
+        message: String,
+        /// Seconds the client should wait before retrying
+        retry_after: u64,
+    },
+    #[error("Invalid request: {0}")]
+    BadRequest(String),
+    #[error("The request was flagged by the content moderation: {}", categories.join(", "))]
+    ContentFlagged {
+        /// The categories the request was flagged for
+        categories: Vec<String>,
+    },
+    #[error("Payload too large: {0}")]
+    PayloadTooLarge(String),
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+    #[error("Conflict: {0}")]
+    Conflict(String),
     #[error("Invalid server kind: {0}")]
     InvalidServerKind(String),
     #[error("Failed to load config: {0}")]
@@ -24,8 +85,16 @@ pub enum ServerError {
     McpEmptyContent,
     #[error("Mcp server not found")]
     McpNotFoundClient,
+    #[error("Mcp tool not found: {0}")]
+    McpNotFoundTool(String),
     #[error("Mcp operation failed: {0}")]
     McpOperation(String),
+    #[error("The mcp server {server} is reconnecting. Please retry later.")]
+    McpReconnecting {
+        server: String,
+        /// Seconds the client should wait before retrying
+        retry_after: u64,
+    },
 }
 impl IntoResponse for ServerError {
     fn into_response(self) -> Response {
@@ -46,6 +115,120 @@ impl IntoResponse for ServerError {
                 Some("server_kind".into()),
                 Some("not_found_server".into()),
             ),
+            ServerError::NoAvailableServer { message, .. } => (
+                StatusCode::NOT_FOUND,
+                message.clone(),
+                "not_found".into(),
+                Some("server_kind".into()),
+                Some("not_found_server".into()),
+            ),
+            ServerError::MissingCapability { .. } => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                self.to_string(),
+                "server_error".into(),
+                Some("server_kind".into()),
+                Some("missing_capability".into()),
+            ),
+            ServerError::NotFoundModel { model, .. } => (
+                StatusCode::NOT_FOUND,
+                format!("Not found model: {model}"),
+                "invalid_request_error".into(),
+                Some("model".into()),
+                Some("model_not_found".into()),
+            ),
+            ServerError::NotFoundSession(session_id) => (
+                StatusCode::NOT_FOUND,
+                format!("Not found session: {session_id}"),
+                "invalid_request_error".into(),
+                Some("session_id".into()),
+                Some("session_not_found".into()),
+            ),
+            ServerError::NotFoundServerId(server_id) => (
+                StatusCode::NOT_FOUND,
+                format!("Not found server: {server_id}"),
+                "invalid_request_error".into(),
+                Some("server_id".into()),
+                Some("server_not_found".into()),
+            ),
+            ServerError::TooManyStreams => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Too many concurrent streaming responses. Please retry later.".into(),
+                "server_error".into(),
+                Some("stream".into()),
+                Some("too_many_streams".into()),
+            ),
+            ServerError::ServerBusy(server_id) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!(
+                    "The server {server_id} takes too many concurrent requests. Please retry later."
+                ),
+                "server_error".into(),
+                None,
+                Some("server_busy".into()),
+            ),
+            ServerError::QueueFull { .. } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too many requests waiting for a server. Please retry later.".into(),
+                "server_error".into(),
+                None,
+                Some("queue_full".into()),
+            ),
+            ServerError::QueueTimeout { .. } => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Timed out waiting for a server. Please retry later.".into(),
+                "server_error".into(),
+                None,
+                Some("queue_timeout".into()),
+            ),
+            ServerError::Maintenance { message, .. } => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                message.clone(),
+                "server_error".into(),
+                None,
+                Some("maintenance".into()),
+            ),
+            ServerError::BadRequest(e) => (
+                StatusCode::BAD_REQUEST,
+                format!("Invalid request: {e}"),
+                "invalid_request_error".into(),
+                None,
+                Some("bad_request".into()),
+            ),
+            ServerError::ContentFlagged { .. } => (
+                StatusCode::BAD_REQUEST,
+                self.to_string(),
+                "invalid_request_error".into(),
+                Some("messages".into()),
+                Some("content_flagged".into()),
+            ),
+            ServerError::PayloadTooLarge(e) => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("Payload too large: {e}"),
+                "invalid_request_error".into(),
+                None,
+                Some("payload_too_large".into()),
+            ),
+            ServerError::Unauthorized(e) => (
+                StatusCode::UNAUTHORIZED,
+                format!("Unauthorized: {e}"),
+                "invalid_request_error".into(),
+                None,
+                Some("invalid_api_key".into()),
+            ),
+            ServerError::Forbidden(e) => (
+                StatusCode::FORBIDDEN,
+                format!("Forbidden: {e}"),
+                "invalid_request_error".into(),
+                None,
+                Some("forbidden".into()),
+            ),
+            ServerError::Conflict(e) => (
+                StatusCode::CONFLICT,
+                format!("Conflict: {e}"),
+                "invalid_request_error".into(),
+                None,
+                Some("conflict".into()),
+            ),
             ServerError::InvalidServerKind(kind) => (
                 StatusCode::BAD_REQUEST,
                 format!("Invalid server kind: {kind}"),
@@ -74,6 +257,13 @@ impl IntoResponse for ServerError {
                 None,
                 Some("mcp_not_found".into()),
             ),
+            ServerError::McpNotFoundTool(name) => (
+                StatusCode::NOT_FOUND,
+                format!("Mcp tool not found: {name}"),
+                "not_found".into(),
+                Some("name".into()),
+                Some("mcp_tool_not_found".into()),
+            ),
             ServerError::McpOperation(e) => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 format!("Mcp operation failed: {e}"),
@@ -81,6 +271,39 @@ impl IntoResponse for ServerError {
                 None,
                 Some("mcp_operation_failed".into()),
             ),
+            ServerError::McpReconnecting { .. } => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                self.to_string(),
+                "server_error".into(),
+                None,
+                Some("mcp_reconnecting".into()),
+            ),
+        };
+
+        let retry_after = match &self {
+            ServerError::Maintenance { retry_after, .. }
+            | ServerError::QueueFull { retry_after }
+            | ServerError::QueueTimeout { retry_after }
+            | ServerError::McpReconnecting { retry_after, .. } => Some(*retry_after),
+            _ => None,
+        };
+
+        let flagged_categories = match &self {
+            ServerError::ContentFlagged { categories } => Some(categories.clone()),
+            _ => None,
+        };
+
+        let available_kinds = match &self {
+            ServerError::MissingCapability {
+                available_kinds, ..
+            } => Some(available_kinds.clone()),
+            _ => None,
+        };
+
+        let routing_candidates = match self {
+            ServerError::NoAvailableServer { candidates, .. }
+            | ServerError::NotFoundModel { candidates, .. } => candidates,
+            _ => None,
         };
 
         let body = OpenAIErrorResponse {
@@ -89,10 +312,21 @@ impl IntoResponse for ServerError {
                 error_type,
                 param,
                 code,
+                flagged_categories,
+                available_kinds,
+                routing_candidates,
             },
         };
 
-        (status, Json(body)).into_response()
+        match retry_after {
+            Some(retry_after) => (
+                status,
+                [(header::RETRY_AFTER, retry_after.to_string())],
+                Json(body),
+            )
+                .into_response(),
+            None => (status, Json(body)).into_response(),
+        }
     }
 }
 
@@ -108,4 +342,10 @@ struct OpenAIError {
     error_type: String,
     param: Option<String>,
     code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    flagged_categories: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    available_kinds: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    routing_candidates: Option<Vec<RoutingCandidate>>,
 }
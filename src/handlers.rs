@@ -1,83 +1,315 @@
-use std::{sync::Arc, time::SystemTime};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    ops::ControlFlow,
+    sync::Arc,
+    time::SystemTime,
+};
 
 use axum::{
     Json,
     body::Body,
-    extract::{Extension, State},
-    http::{HeaderMap, Response, StatusCode},
+    extract::{Extension, Path, Query, State},
+    http::{HeaderMap, HeaderValue, Response, StatusCode},
 };
 use bytes::Bytes;
 use endpoints::{
     chat::{
-        ChatCompletionAssistantMessage, ChatCompletionChunk, ChatCompletionObject,
-        ChatCompletionRequest, ChatCompletionRequestMessage, ChatCompletionToolMessage, Tool,
-        ToolCall, ToolChoice, ToolFunction,
+        ChatCompletionAssistantMessage, ChatCompletionObject, ChatCompletionRequest,
+        ChatCompletionRequestMessage, ChatCompletionToolMessage, ChatCompletionUserMessageContent,
+        ContentPart, Function, StreamOptions, TextContentPart, Tool, ToolCall, ToolChoice,
+        ToolFunction,
     },
-    embeddings::EmbeddingRequest,
+    completions::{CompletionChoice, CompletionObject, CompletionPrompt, CompletionRequest},
+    embeddings::{EmbeddingRequest, InputText},
     models::{ListModelsResponse, Model},
 };
 use futures_util::StreamExt;
-use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use once_cell::sync::OnceCell;
+use reqwest::header::{AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE};
 use rmcp::model::{CallToolRequestParam, RawContent};
-use tokio::select;
+use tokio::{select, sync::OwnedSemaphorePermit};
 use tokio_util::sync::CancellationToken;
 
 use crate::{
-    AppState, dual_debug, dual_error, dual_info, dual_warn,
+    AppState, anthropic,
+    cache::{self, CachedResponse},
+    client::http_client,
+    config::McpToolServerConfig,
+    database::ChatMessage,
+    dual_debug, dual_error, dual_info, dual_warn,
     error::{ServerError, ServerResult},
+    idempotency::{
+        IDEMPOTENCY_KEY_HEADER, IDEMPOTENT_REPLAYED_HEADER, IdempotencyCaller, IdempotencyGuard,
+        Reservation,
+    },
     info::ApiServer,
-    mcp::{DEFAULT_SEARCH_FALLBACK_MESSAGE, MCP_SERVICES, MCP_TOOLS, SEARCH_MCP_SERVER_NAMES},
-    server::{RoutingPolicy, Server, ServerIdToRemove, ServerKind, TargetServerInfo},
+    mcp::{self, MCP_SERVICES, MCP_TOOLS},
+    moderation,
+    queue::RequestQueue,
+    server::{
+        ExclusionReason, Provider, RoutingPolicy, Server, ServerGroup, ServerId, ServerIdToRemove,
+        ServerKind, TargetServerInfo,
+    },
+    sse::{SseEventSplitter, SseParser},
+    usage::{ANONYMOUS_USER, UsageLedger},
+    utils::keys_match,
 };
 
+#[cfg(test)]
+use crate::{test_state, test_state_with};
+
+// The header sending a chat request to the given url instead of a registered server
+const OVERRIDE_UPSTREAM_HEADER: &str = "x-override-upstream";
+// The header selecting the chat servers of the given tag, e.g. `vision`
+const MODEL_TAG_HEADER: &str = "x-model-tag";
+// The header asking for the request the gateway would send downstream, without sending it
+const DRY_RUN_HEADER: &str = "x-dry-run";
+// The header suppressing the MCP tool injection for the request
+const DISABLE_TOOLS_HEADER: &str = "x-disable-tools";
+// The header carrying the admin key of the admin-only features
+const ADMIN_KEY_HEADER: &str = "x-admin-key";
+// The header carrying the id the request is logged with
+const REQUEST_ID_HEADER: &str = "x-request-id";
+// The SSE comment keeping an idle streaming response alive
+const SSE_KEEP_ALIVE: &[u8] = b": keep-alive\n\n";
+/// How long a request waits for a permit of a server taking as many requests as it is allowed to
+const SERVER_PERMIT_WAIT: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// The request headers forwarded to the audio and image servers if not set in the config
+const DEFAULT_FORWARD_REQUEST_HEADERS: &[&str] =
+    &["content-type", "accept", "authorization", "user-agent"];
+/// The response headers of the downstream servers returned to the clients if not set in the config
+const DEFAULT_EXPOSE_RESPONSE_HEADERS: &[&str] = &[
+    "access-control-allow-origin",
+    "access-control-allow-headers",
+    "access-control-allow-methods",
+    "content-type",
+    "content-length",
+    "cache-control",
+    "connection",
+    "user",
+    "date",
+    "requires-tool-call",
+];
+/// The headers never crossing the gateway, even if listed in the config
+const SENSITIVE_HEADERS: &[&str] = &[
+    "cookie",
+    "set-cookie",
+    "proxy-authorization",
+    ADMIN_KEY_HEADER,
+];
+/// The headers describing the connection to the gateway or the length of the body of the client,
+/// which the HTTP client sets anew for the downstream request
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "host",
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "content-length",
+];
+
+// The request headers forwarded to the audio and image servers. The defaults are used if not set.
+pub(crate) static FORWARD_REQUEST_HEADERS: OnceCell<Vec<String>> = OnceCell::new();
+// The response headers returned to the clients. The defaults are used if not set.
+pub(crate) static EXPOSE_RESPONSE_HEADERS: OnceCell<Vec<String>> = OnceCell::new();
+// Interval in seconds of the keep-alive comments of the idle streaming responses. Not sent if not set.
+pub(crate) static STREAM_KEEP_ALIVE_INTERVAL: OnceCell<u64> = OnceCell::new();
+
+/// Get the id of the request set by the request-id middleware
+pub(crate) fn request_id(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|request_id| request_id.to_str().ok())
+}
+
+/// Forward the id of the request and the trace context of the current span to the downstream
+/// server, so that the logs and the spans of both sides can be correlated
+pub(crate) fn forward_tracing_headers(
+    ds_request: reqwest::RequestBuilder,
+    headers: &HeaderMap,
+) -> reqwest::RequestBuilder {
+    let mut tracing_headers = HeaderMap::new();
+    if let Some(request_id) = headers.get(REQUEST_ID_HEADER) {
+        tracing_headers.insert(REQUEST_ID_HEADER, request_id.clone());
+    }
+    crate::telemetry::inject_trace_context(&mut tracing_headers);
+
+    ds_request.headers(tracing_headers)
+}
+
+#[test]
+fn test_forward_tracing_headers() {
+    let mut headers = HeaderMap::new();
+    let ds_request =
+        forward_tracing_headers(http_client().post("http://localhost:10086/v1"), &headers)
+            .build()
+            .unwrap();
+    assert!(ds_request.headers().get(REQUEST_ID_HEADER).is_none());
+
+    headers.insert(REQUEST_ID_HEADER, "request-1".parse().unwrap());
+    let ds_request =
+        forward_tracing_headers(http_client().post("http://localhost:10086/v1"), &headers)
+            .build()
+            .unwrap();
+    assert_eq!(ds_request.headers()[REQUEST_ID_HEADER], "request-1");
+}
+
+#[tracing::instrument(name = "chat", skip_all)]
 pub(crate) async fn chat_handler(
     State(state): State<Arc<AppState>>,
     Extension(cancel_token): Extension<CancellationToken>,
+    caller: IdempotencyCaller,
     headers: HeaderMap,
-    Json(mut request): Json<ChatCompletionRequest>,
+    Json(mut body): Json<serde_json::Value>,
 ) -> ServerResult<axum::response::Response> {
-    let request_id = headers
-        .get("x-request-id")
-        .and_then(|h| h.to_str().ok())
-        .unwrap_or("unknown")
-        .to_string();
+    // the body a repeated idempotency key is checked against, as sent by the client
+    let idempotency_body = idempotency_key(&state, &headers)
+        .map(|_| body.to_string())
+        .unwrap_or_default();
+    // take the gateway-specific field out before parsing the chat request
+    let allowed_mcp_tools = take_allowed_mcp_tools(&mut body)?;
+    let disable_mcp_tools = take_disable_mcp_tools(&mut body)? || is_tools_disabled(&headers);
+    // the legacy `max_tokens` is not parsed, so keep it as `max_completion_tokens`
+    if let Some(body) = body.as_object_mut()
+        && !body.contains_key("max_completion_tokens")
+        && let Some(max_tokens) = body.remove("max_tokens")
+    {
+        body.insert("max_completion_tokens".to_string(), max_tokens);
+    }
+    let mut request = serde_json::from_value::<ChatCompletionRequest>(body).map_err(|e| {
+        let err_msg = format!("Failed to parse the chat request: {e}");
+        dual_error!("{}", err_msg);
+        ServerError::BadRequest(err_msg)
+    })?;
+
+    // reject the models hidden from the clients
+    check_model_allowed(&state, request.model.as_deref()).await?;
+
+    // cap the tokens generated for the request
+    if let Some(cap) = state.config.read().await.server.max_completion_tokens {
+        request.max_completion_tokens = Some(clamp_max_completion_tokens(
+            request.max_completion_tokens,
+            cap,
+        ));
+    }
+
+    // the usage is accounted to the user the client tells, not to the generated one
+    let usage_user = request.user.clone();
+    let usage_model = request.model.clone();
+    let is_stream = request.stream == Some(true);
+    // replay the response of a repeated idempotency key instead of forwarding the request again
+    let idempotency_key = match is_stream || headers.contains_key(DRY_RUN_HEADER) {
+        true => None,
+        false => idempotency_key(&state, &headers),
+    };
+    let idempotency_guard = match reserve_idempotency_key(
+        &state,
+        "chat",
+        &caller,
+        idempotency_key.as_deref(),
+        idempotency_body.as_bytes(),
+    )? {
+        ControlFlow::Break(response) => return Ok(response),
+        ControlFlow::Continue(idempotency_guard) => idempotency_guard,
+    };
+
+    // ask for the usage in the last chunk of the stream, which is stripped from the stream unless
+    // the client asked for it too
+    let strip_usage_chunk = state.usage.is_some()
+        && is_stream
+        && !request
+            .stream_options
+            .as_ref()
+            .and_then(|stream_options| stream_options.include_usage)
+            .unwrap_or(false);
+    if strip_usage_chunk {
+        request.stream_options = Some(StreamOptions {
+            include_usage: Some(true),
+        });
+    }
 
     // check if the user id is provided
     if request.user.is_none() {
         request.user = Some(gen_chat_id());
     };
     dual_info!(
-        "Received a new chat request from user: {} - request_id: {}",
-        request.user.as_ref().unwrap(),
-        request_id
+        "Received a new chat request from user: {}",
+        request.user.as_ref().unwrap()
     );
 
+    // reject the requests flagged by the content moderation before routing them
+    let moderation_config = state.config.read().await.moderation.clone();
+    if let Some(moderation_config) = moderation_config {
+        moderation::check_chat_request(&moderation_config, &request, &cancel_token).await?;
+    }
+
+    // merge the consecutive messages of the same role for the backends rejecting them
+    if state.config.read().await.server.collapse_consecutive_roles {
+        request.messages = collapse_consecutive_roles(std::mem::take(&mut request.messages));
+    }
+
+    // reject the requests already sending more tools than allowed
+    let max_tools = state.config.read().await.server.max_tools;
+    let client_tools = request.tools.as_ref().map_or(0, |tools| tools.len());
+    if let Some(max_tools) = max_tools
+        && client_tools > max_tools
+    {
+        let err_msg = format!(
+            "The request has {client_tools} tools, more than the maximum of {max_tools} tools"
+        );
+        dual_error!("{}", err_msg);
+        return Err(ServerError::BadRequest(err_msg));
+    }
+
     // update the request with MCP tools
-    dual_info!("Updating the request with MCP tools");
-    if let Some(mcp_config) = state.config.read().await.mcp.as_ref()
+    let mut injected_mcp_tools = InjectedMcpTools::default();
+    if disable_mcp_tools {
+        dual_info!("Skipped the MCP tools disabled for the request");
+    } else if let Some(mcp_config) = state.config.read().await.mcp.as_ref()
         && !mcp_config.server.tool_servers.is_empty()
     {
-        let mut more_tools = Vec::new();
-        for server_config in mcp_config.server.tool_servers.iter() {
-            if server_config.enable {
-                server_config
-                    .tools
-                    .as_ref()
-                    .unwrap()
-                    .iter()
-                    .for_each(|mcp_tool| {
-                        let tool = Tool::new(ToolFunction {
-                            name: mcp_tool.name.to_string(),
-                            description: mcp_tool.description.as_ref().map(|s| s.to_string()),
-                            parameters: Some((*mcp_tool.input_schema).clone()),
-                        });
-
-                        more_tools.push(tool.clone());
-                    });
+        dual_info!("Updating the request with MCP tools");
+        let mut more_tools = mcp_tools_for_request(
+            &mcp_config.server.tool_servers,
+            allowed_mcp_tools.as_deref(),
+        );
+
+        // the tools of the client take precedence over the MCP tools of the same name
+        let client_tool_names = request
+            .tools
+            .iter()
+            .flatten()
+            .map(|tool| tool.function.name.as_str())
+            .collect::<HashSet<_>>();
+        more_tools.retain(|tool| {
+            let collides = client_tool_names.contains(tool.function.name.as_str());
+            if collides {
+                dual_warn!(
+                    "Skipped the MCP tool {} colliding with a tool of the client",
+                    tool.function.name
+                );
             }
+            !collides
+        });
+
+        // keep the total number of tools within the limit
+        if let Some(max_tools) = max_tools
+            && client_tools + more_tools.len() > max_tools
+        {
+            dual_warn!(
+                "Dropped {} MCP tools exceeding the maximum of {} tools",
+                client_tools + more_tools.len() - max_tools,
+                max_tools
+            );
+            more_tools.truncate(max_tools - client_tools);
         }
 
         if !more_tools.is_empty() {
+            injected_mcp_tools = InjectedMcpTools::new(&more_tools);
             if let Some(tools) = &mut request.tools {
                 tools.extend(more_tools);
             } else {
@@ -118,14 +350,555 @@ pub(crate) async fn chat_handler(
         // }
     }
 
-    chat(
-        State(state),
+    let response = chat(
+        State(state.clone()),
         Extension(cancel_token),
         headers,
         Json(request),
-        &request_id,
+        allowed_mcp_tools.as_deref(),
+        &injected_mcp_tools,
+    )
+    .await?;
+    let response = redact_response(&state, is_stream, response).await?;
+    let response = account_usage(
+        &state,
+        usage_user,
+        usage_model,
+        is_stream,
+        strip_usage_chunk,
+        response,
+    )
+    .await?;
+
+    store_idempotent_response("chat", idempotency_guard, response).await
+}
+
+/// Clamp the maximum number of tokens generated for a chat request down to the cap. The requests
+/// without a limit, i.e. with a negative value or the default `i32::MAX`, are clamped too.
+fn clamp_max_completion_tokens(max_completion_tokens: Option<i32>, cap: u32) -> i32 {
+    let cap = cap.min(i32::MAX as u32) as i32;
+    match max_completion_tokens {
+        Some(max_completion_tokens) if (0..=cap).contains(&max_completion_tokens) => {
+            max_completion_tokens
+        }
+        Some(max_completion_tokens)
+            if max_completion_tokens >= 0 && max_completion_tokens != i32::MAX =>
+        {
+            dual_info!(
+                "Clamped the max completion tokens of the request from {} to {}",
+                max_completion_tokens,
+                cap
+            );
+            cap
+        }
+        _ => cap,
+    }
+}
+
+#[test]
+fn test_clamp_max_completion_tokens() {
+    assert_eq!(clamp_max_completion_tokens(Some(100), 4096), 100);
+    assert_eq!(clamp_max_completion_tokens(Some(4096), 4096), 4096);
+    assert_eq!(clamp_max_completion_tokens(Some(100_000), 4096), 4096);
+    // no limit
+    assert_eq!(clamp_max_completion_tokens(None, 4096), 4096);
+    assert_eq!(clamp_max_completion_tokens(Some(-1), 4096), 4096);
+    assert_eq!(clamp_max_completion_tokens(Some(i32::MAX), 4096), 4096);
+    assert_eq!(clamp_max_completion_tokens(Some(10), u32::MAX), 10);
+}
+
+/// The MCP tools the gateway added to a chat request. Only the calls of these tools are run by the
+/// gateway, the other tools are defined and run by the client.
+#[derive(Debug, Default)]
+pub(crate) struct InjectedMcpTools(HashSet<String>);
+impl InjectedMcpTools {
+    fn new(tools: &[Tool]) -> Self {
+        Self(
+            tools
+                .iter()
+                .map(|tool| tool.function.name.clone())
+                .collect(),
+        )
+    }
+
+    /// Whether any of the tool calls asks for a tool the gateway did not add, e.g. a function
+    /// defined by the client
+    fn has_client_tool(&self, tool_calls: &[ToolCall]) -> bool {
+        tool_calls
+            .iter()
+            .any(|tool_call| !self.0.contains(&tool_call.function.name))
+    }
+}
+
+#[test]
+fn test_injected_mcp_tools() {
+    let tool_call = |name: &str| ToolCall {
+        id: "call-1".to_string(),
+        ty: "function".to_string(),
+        function: endpoints::chat::Function {
+            name: name.to_string(),
+            arguments: "{}".to_string(),
+        },
+    };
+    let injected_mcp_tools = InjectedMcpTools::new(&[Tool::new(ToolFunction {
+        name: "get_weather".to_string(),
+        description: None,
+        parameters: None,
+    })]);
+
+    assert!(!injected_mcp_tools.has_client_tool(&[tool_call("get_weather")]));
+    assert!(injected_mcp_tools.has_client_tool(&[tool_call("get_local_time")]));
+    assert!(
+        injected_mcp_tools
+            .has_client_tool(&[tool_call("get_weather"), tool_call("get_local_time")])
+    );
+    assert!(InjectedMcpTools::default().has_client_tool(&[tool_call("get_weather")]));
+}
+
+/// Take the optional `allowed_mcp_tools` field out of the chat request body
+///
+/// The field lists the names of the MCP tools the client permits for the request. If it is set,
+/// only the listed tools are offered to the model and executed.
+fn take_allowed_mcp_tools(body: &mut serde_json::Value) -> ServerResult<Option<Vec<String>>> {
+    let Some(value) = body
+        .as_object_mut()
+        .and_then(|body| body.remove("allowed_mcp_tools"))
+    else {
+        return Ok(None);
+    };
+
+    serde_json::from_value::<Option<Vec<String>>>(value).map_err(|e| {
+        let err_msg = format!("`allowed_mcp_tools` should be a list of tool names: {e}");
+        dual_error!("{}", err_msg);
+        ServerError::BadRequest(err_msg)
+    })
+}
+
+/// Take the optional `disable_mcp_tools` field out of the chat request body
+///
+/// If the field is `true`, no MCP tools are offered to the model for the request.
+fn take_disable_mcp_tools(body: &mut serde_json::Value) -> ServerResult<bool> {
+    let Some(value) = body
+        .as_object_mut()
+        .and_then(|body| body.remove("disable_mcp_tools"))
+    else {
+        return Ok(false);
+    };
+
+    serde_json::from_value::<Option<bool>>(value)
+        .map(|disable| disable.unwrap_or_default())
+        .map_err(|e| {
+            let err_msg = format!("`disable_mcp_tools` should be a boolean: {e}");
+            dual_error!("{}", err_msg);
+            ServerError::BadRequest(err_msg)
+        })
+}
+
+/// Whether the request suppresses the MCP tool injection by the `X-Disable-Tools: true` header
+fn is_tools_disabled(headers: &HeaderMap) -> bool {
+    headers
+        .get(DISABLE_TOOLS_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("true"))
+}
+
+#[test]
+fn test_disable_mcp_tools() {
+    let mut body = serde_json::json!({
+        "messages": [{"role": "user", "content": "Hello"}],
+        "disable_mcp_tools": true,
+    });
+    assert!(take_disable_mcp_tools(&mut body).unwrap());
+    assert!(body.get("disable_mcp_tools").is_none());
+    assert!(!take_disable_mcp_tools(&mut body).unwrap());
+
+    let mut body = serde_json::json!({"disable_mcp_tools": "yes"});
+    assert!(take_disable_mcp_tools(&mut body).is_err());
+
+    let mut headers = HeaderMap::new();
+    assert!(!is_tools_disabled(&headers));
+    headers.insert(DISABLE_TOOLS_HEADER, "True".parse().unwrap());
+    assert!(is_tools_disabled(&headers));
+    headers.insert(DISABLE_TOOLS_HEADER, "false".parse().unwrap());
+    assert!(!is_tools_disabled(&headers));
+}
+
+/// Merge the consecutive messages of the same role by concatenating their contents
+///
+/// The messages from different participants (`name`), the assistant messages with tool calls and
+/// the tool messages are kept apart.
+fn collapse_consecutive_roles(
+    messages: Vec<ChatCompletionRequestMessage>,
+) -> Vec<ChatCompletionRequestMessage> {
+    let mut collapsed: Vec<ChatCompletionRequestMessage> = Vec::with_capacity(messages.len());
+    for message in messages {
+        let merged = match (collapsed.last(), &message) {
+            (
+                Some(ChatCompletionRequestMessage::System(prev)),
+                ChatCompletionRequestMessage::System(curr),
+            ) if prev.name() == curr.name() => {
+                Some(ChatCompletionRequestMessage::new_system_message(
+                    format!("{}\n\n{}", prev.content(), curr.content()),
+                    prev.name().cloned(),
+                ))
+            }
+            (
+                Some(ChatCompletionRequestMessage::User(prev)),
+                ChatCompletionRequestMessage::User(curr),
+            ) if prev.name() == curr.name() => {
+                let content = match (prev.content(), curr.content()) {
+                    (
+                        ChatCompletionUserMessageContent::Text(prev),
+                        ChatCompletionUserMessageContent::Text(curr),
+                    ) => ChatCompletionUserMessageContent::Text(format!("{prev}\n\n{curr}")),
+                    (prev, curr) => {
+                        let into_parts = |content: &ChatCompletionUserMessageContent| match content
+                        {
+                            ChatCompletionUserMessageContent::Text(text) => {
+                                vec![ContentPart::Text(TextContentPart::new(text))]
+                            }
+                            ChatCompletionUserMessageContent::Parts(parts) => parts.clone(),
+                        };
+
+                        let mut parts = into_parts(prev);
+                        parts.extend(into_parts(curr));
+                        ChatCompletionUserMessageContent::Parts(parts)
+                    }
+                };
+
+                Some(ChatCompletionRequestMessage::new_user_message(
+                    content,
+                    prev.name().cloned(),
+                ))
+            }
+            (
+                Some(ChatCompletionRequestMessage::Assistant(prev)),
+                ChatCompletionRequestMessage::Assistant(curr),
+            ) if prev.name() == curr.name()
+                && prev.tool_calls().is_none_or(|calls| calls.is_empty())
+                && curr.tool_calls().is_none_or(|calls| calls.is_empty()) =>
+            {
+                let content = match (prev.content(), curr.content()) {
+                    (Some(prev), Some(curr)) => Some(format!("{prev}\n\n{curr}")),
+                    (prev, curr) => prev.or(curr).cloned(),
+                };
+
+                Some(ChatCompletionRequestMessage::new_assistant_message(
+                    content,
+                    prev.name().cloned(),
+                    None,
+                ))
+            }
+            _ => None,
+        };
+
+        match merged {
+            Some(merged) => *collapsed.last_mut().unwrap() = merged,
+            None => collapsed.push(message),
+        }
+    }
+
+    collapsed
+}
+
+#[test]
+fn test_collapse_consecutive_roles() {
+    let request = serde_json::from_value::<ChatCompletionRequest>(serde_json::json!({
+        "messages": [
+            {"role": "system", "content": "You are a helpful assistant."},
+            {"role": "user", "content": "Here is the context: Paris is in France."},
+            {"role": "user", "content": "What is the capital of France?"},
+            {"role": "assistant", "content": "Paris"},
+            {"role": "user", "content": "And of Germany?"},
+            {"role": "user", "content": [{"type": "text", "text": "Answer in one word."}]},
+        ],
+    }))
+    .unwrap();
+
+    let messages = collapse_consecutive_roles(request.messages);
+    let messages = serde_json::to_value(&messages).unwrap();
+    assert_eq!(
+        messages,
+        serde_json::json!([
+            {"role": "system", "content": "You are a helpful assistant."},
+            {"role": "user", "content": "Here is the context: Paris is in France.\n\nWhat is the capital of France?"},
+            {"role": "assistant", "content": "Paris"},
+            {"role": "user", "content": [
+                {"type": "text", "text": "And of Germany?"},
+                {"type": "text", "text": "Answer in one word."},
+            ]},
+        ])
+    );
+}
+
+/// Collect the tools of the enabled and healthy MCP servers permitted for the request
+fn mcp_tools_for_request(
+    tool_servers: &[McpToolServerConfig],
+    allowed_mcp_tools: Option<&[String]>,
+) -> Vec<Tool> {
+    mcp_tools_by_server(tool_servers, allowed_mcp_tools)
+        .into_iter()
+        .map(|(_, tool)| tool)
+        .collect()
+}
+
+/// Collect the tools of the enabled and healthy MCP servers permitted for the request, along with
+/// the name of the server of each tool
+fn mcp_tools_by_server<'a>(
+    tool_servers: &'a [McpToolServerConfig],
+    allowed_mcp_tools: Option<&[String]>,
+) -> Vec<(&'a str, Tool)> {
+    let mut more_tools = Vec::new();
+    for server_config in tool_servers.iter() {
+        if server_config.enable && !mcp::is_mcp_service_healthy(&server_config.name) {
+            dual_warn!(
+                "Skipped the tools of the unhealthy MCP server {}",
+                server_config.name
+            );
+            continue;
+        }
+
+        if server_config.enable {
+            server_config
+                .tools
+                .as_ref()
+                .unwrap()
+                .iter()
+                .filter(|mcp_tool| {
+                    is_mcp_tool_allowed(
+                        allowed_mcp_tools,
+                        &server_config.exposed_tool_name(&mcp_tool.name),
+                    )
+                })
+                .filter(
+                    |mcp_tool| match validate_tool_schema(&mcp_tool.input_schema) {
+                        Ok(()) => true,
+                        Err(e) => {
+                            dual_warn!(
+                                "Skipped the MCP tool {} of {} with an invalid input schema: {}",
+                                mcp_tool.name,
+                                server_config.name,
+                                e
+                            );
+                            false
+                        }
+                    },
+                )
+                .for_each(|mcp_tool| {
+                    let tool = Tool::new(ToolFunction {
+                        name: server_config.exposed_tool_name(&mcp_tool.name),
+                        description: mcp_tool.description.as_ref().map(|s| s.to_string()),
+                        parameters: Some((*mcp_tool.input_schema).clone()),
+                    });
+
+                    more_tools.push((server_config.name.as_str(), tool));
+                });
+        }
+    }
+
+    more_tools
+}
+
+/// Check that the input schema of a tool is a JSON Schema of an object, which the backends expect
+/// in the `parameters` of a function
+fn validate_tool_schema(schema: &serde_json::Map<String, serde_json::Value>) -> Result<(), String> {
+    match schema.get("type") {
+        None => {}
+        Some(serde_json::Value::String(ty)) if ty == "object" => {}
+        Some(ty) => return Err(format!("`type` should be \"object\", got {ty}")),
+    }
+
+    if let Some(properties) = schema.get("properties") {
+        let Some(properties) = properties.as_object() else {
+            return Err("`properties` should be an object".to_string());
+        };
+        if let Some((name, _)) = properties
+            .iter()
+            .find(|(_, property)| !property.is_object())
+        {
+            return Err(format!(
+                "the schema of the property `{name}` should be an object"
+            ));
+        }
+    }
+
+    if let Some(required) = schema.get("required") {
+        let is_valid = required
+            .as_array()
+            .is_some_and(|required| required.iter().all(|name| name.is_string()));
+        if !is_valid {
+            return Err("`required` should be an array of property names".to_string());
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_validate_tool_schema() {
+    let schema = |value: serde_json::Value| value.as_object().unwrap().clone();
+
+    assert!(validate_tool_schema(&schema(serde_json::json!({}))).is_ok());
+    assert!(
+        validate_tool_schema(&schema(serde_json::json!({
+            "type": "object",
+            "properties": {"city": {"type": "string"}},
+            "required": ["city"],
+        })))
+        .is_ok()
+    );
+    assert!(validate_tool_schema(&schema(serde_json::json!({"type": "string"}))).is_err());
+    assert!(
+        validate_tool_schema(&schema(serde_json::json!({
+            "type": "object",
+            "properties": ["city"],
+        })))
+        .is_err()
+    );
+    assert!(
+        validate_tool_schema(&schema(serde_json::json!({
+            "type": "object",
+            "properties": {"city": "string"},
+        })))
+        .is_err()
+    );
+    assert!(
+        validate_tool_schema(&schema(serde_json::json!({
+            "type": "object",
+            "required": "city",
+        })))
+        .is_err()
+    );
+}
+
+// All tools are allowed if the request does not restrict them
+fn is_mcp_tool_allowed(allowed_mcp_tools: Option<&[String]>, tool_name: &str) -> bool {
+    allowed_mcp_tools.is_none_or(|allowed| allowed.iter().any(|name| name == tool_name))
+}
+
+#[test]
+fn test_unhealthy_mcp_tools() {
+    let new_tool =
+        |name: &'static str| rmcp::model::Tool::new(name, "", Arc::new(serde_json::Map::new()));
+    let tool_server = |name: &str, tool: &'static str| McpToolServerConfig {
+        name: name.to_string(),
+        tools: Some(vec![new_tool(tool)]),
+        ..Default::default()
+    };
+    let tool_servers = vec![
+        tool_server("cardea-healthy", "get_time"),
+        tool_server("cardea-unhealthy", "get_news"),
+    ];
+
+    // the tools of the unhealthy server are left out until it passes a check
+    mcp::record_mcp_health("cardea-unhealthy", Err("No response".to_string()));
+    let tools = mcp_tools_for_request(&tool_servers, None);
+    assert_eq!(tools.len(), 1);
+    assert_eq!(tools[0].function.name, "get_time");
+
+    mcp::record_mcp_health("cardea-unhealthy", Ok(()));
+    assert_eq!(mcp_tools_for_request(&tool_servers, None).len(), 2);
+}
+
+#[test]
+fn test_mcp_tool_prefix() {
+    let new_tool =
+        |name: &'static str| rmcp::model::Tool::new(name, "", Arc::new(serde_json::Map::new()));
+    let tool_server = |name: &str, tool_prefix: Option<&str>| McpToolServerConfig {
+        name: name.to_string(),
+        tools: Some(vec![new_tool("get")]),
+        tool_prefix: tool_prefix.map(String::from),
+        ..Default::default()
+    };
+    let tool_servers = vec![
+        tool_server("cardea-weather", Some("weather_")),
+        tool_server("cardea-news", Some("news_")),
+    ];
+
+    // the tools of the same name are told apart by their prefix
+    let tools = mcp_tools_for_request(&tool_servers, None);
+    let names = tools
+        .iter()
+        .map(|tool| tool.function.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["weather_get", "news_get"]);
+
+    // each tool is listed with the server it comes from
+    let servers = mcp_tools_by_server(&tool_servers, None)
+        .into_iter()
+        .map(|(server_name, _)| server_name)
+        .collect::<Vec<_>>();
+    assert_eq!(servers, ["cardea-weather", "cardea-news"]);
+
+    // the tools are allowed by the names they are offered under
+    let allowed_mcp_tools = ["news_get".to_string()];
+    let tools = mcp_tools_for_request(&tool_servers, Some(&allowed_mcp_tools));
+    assert_eq!(tools.len(), 1);
+    assert_eq!(tools[0].function.name, "news_get");
+
+    assert_eq!(
+        tool_server("cardea-weather", None).exposed_tool_name("get"),
+        "get"
+    );
+}
+
+#[tokio::test]
+async fn test_allowed_mcp_tools() {
+    let mut body = serde_json::json!({
+        "messages": [{"role": "user", "content": "What is the weather in Paris?"}],
+        "allowed_mcp_tools": ["get_weather"],
+    });
+    let allowed_mcp_tools = take_allowed_mcp_tools(&mut body).unwrap();
+    assert_eq!(allowed_mcp_tools, Some(vec!["get_weather".to_string()]));
+    assert!(body.get("allowed_mcp_tools").is_none());
+    let mut request = serde_json::from_value::<ChatCompletionRequest>(body).unwrap();
+
+    // only the allowed tools are injected
+    let new_tool =
+        |name: &'static str| rmcp::model::Tool::new(name, "", Arc::new(serde_json::Map::new()));
+    let tool_servers = vec![McpToolServerConfig {
+        name: "cardea-tools".to_string(),
+        tools: Some(vec![new_tool("get_weather"), new_tool("run_shell")]),
+        ..Default::default()
+    }];
+    let tools = mcp_tools_for_request(&tool_servers, allowed_mcp_tools.as_deref());
+    assert_eq!(tools.len(), 1);
+    assert_eq!(tools[0].function.name, "get_weather");
+    assert_eq!(mcp_tools_for_request(&tool_servers, None).len(), 2);
+
+    // the call to a non-allowed tool is rejected before reaching any mcp server, and the model is
+    // told about it
+    let tool_call = ToolCall {
+        id: "call-1".to_string(),
+        ty: "function".to_string(),
+        function: endpoints::chat::Function {
+            name: "run_shell".to_string(),
+            arguments: "{}".to_string(),
+        },
+    };
+    let chat_server = TargetServerInfo {
+        id: "chat-server-1".to_string(),
+        url: "http://127.0.0.1:1/v1".to_string(),
+        ..Default::default()
+    };
+    call_mcp_server(
+        &[tool_call],
+        &mut request,
+        &HeaderMap::new(),
+        &chat_server,
+        CancellationToken::new(),
+        allowed_mcp_tools.as_deref(),
     )
     .await
+    .unwrap_err();
+    let Some(ChatCompletionRequestMessage::Tool(message)) = request.messages.last() else {
+        panic!("no tool message: {:?}", request.messages);
+    };
+    assert!(
+        message.content().contains("Tool not allowed"),
+        "{}",
+        message.content()
+    );
 }
 
 pub(crate) async fn chat(
@@ -133,20 +906,88 @@ pub(crate) async fn chat(
     Extension(cancel_token): Extension<CancellationToken>,
     headers: HeaderMap,
     Json(mut request): Json<ChatCompletionRequest>,
-    request_id: impl AsRef<str>,
+    allowed_mcp_tools: Option<&[String]>,
+    injected_mcp_tools: &InjectedMcpTools,
 ) -> ServerResult<axum::response::Response> {
-    let request_id = request_id.as_ref();
+    // Send the requests for several choices as one request per choice if enabled
+    let (fan_out_choices, max_fan_out_choices) = {
+        let server_config = &state.config.read().await.server;
+        (
+            request.n_choice.is_some_and(|n| n > 1) && server_config.fan_out_choices,
+            server_config.max_fan_out_choices,
+        )
+    };
+    if fan_out_choices && request.stream == Some(true) {
+        let err_msg = "The streaming requests with `n` greater than 1 are not supported";
+        dual_error!("{}", err_msg);
+        return Err(ServerError::BadRequest(err_msg.to_string()));
+    }
+    if fan_out_choices && request.n_choice.is_some_and(|n| n > max_fan_out_choices) {
+        let err_msg = format!("The `n` of the request should be at most {max_fan_out_choices}");
+        dual_error!("{}", err_msg);
+        return Err(ServerError::BadRequest(err_msg));
+    }
+
+    // Return the request instead of sending it if asked for a dry run
+    if is_dry_run(&state, &headers).await? {
+        return dry_run_chat(&state, &headers, request, fan_out_choices).await;
+    }
+
+    // Hold the permit until the streaming response is served
+    let stream_permit = match request.stream {
+        Some(true) => acquire_stream_permit(&state)?,
+        _ => None,
+    };
+
+    // Serve the repeated deterministic requests from the cache
+    let cache_key = match &state.response_cache {
+        Some(_)
+            if cache::is_cacheable(&request, &headers)
+                && !headers.contains_key(OVERRIDE_UPSTREAM_HEADER) =>
+        {
+            Some(cache::cache_key(&request))
+        }
+        _ => None,
+    };
+    if let (Some(response_cache), Some(key)) = (&state.response_cache, cache_key)
+        && let Some(cached) = response_cache.get(key)
+    {
+        dual_info!("Serve the chat response from the cache");
+        return Ok(build_cached_response(cached, "HIT"));
+    }
+
+    let (passthrough_unknown_tools, strip_tools_on_retry) = {
+        let config = state.config.read().await;
+        (
+            config.server.passthrough_unknown_tools,
+            config.server.strip_tools_on_retry,
+        )
+    };
+    if fan_out_choices {
+        let response = fan_out_chat(
+            &state,
+            &request,
+            &headers,
+            cancel_token,
+            allowed_mcp_tools,
+            injected_mcp_tools,
+            passthrough_unknown_tools,
+        )
+        .await?;
+        return cache_chat_response(&state, cache_key, response).await;
+    }
 
     // Get target server
-    let chat_server = get_chat_server(&state, request_id).await?;
+    let chat_server = resolve_chat_server(&state, &headers, request.model.as_deref()).await?;
 
     // Send request and handle response
-    let response = send_request_with_retry(
+    let (response, server_permit) = send_request_with_retry(
         &chat_server,
         &mut request,
         &headers,
-        request_id,
         cancel_token.clone(),
+        state.request_queue.as_deref(),
+        strip_tools_on_retry,
     )
     .await?;
 
@@ -159,1182 +1000,3873 @@ pub(crate) async fn chat(
                 &mut request,
                 &headers,
                 &chat_server,
-                request_id,
                 cancel_token,
+                allowed_mcp_tools,
+                injected_mcp_tools,
+                passthrough_unknown_tools,
+                stream_permit.into_iter().chain(server_permit).collect(),
             )
             .await
         }
         Some(false) | None => {
             // Handle non-stream response
-            handle_non_stream_response(
+            let response = handle_non_stream_response(
                 response,
                 &mut request,
                 &headers,
                 &chat_server,
-                request_id,
                 cancel_token,
+                allowed_mcp_tools,
+                injected_mcp_tools,
+                passthrough_unknown_tools,
             )
-            .await
+            .await?;
+
+            cache_chat_response(&state, cache_key, response).await
         }
     }
 }
 
-pub(crate) async fn embeddings_handler(
-    State(state): State<Arc<AppState>>,
-    Extension(cancel_token): Extension<CancellationToken>,
-    headers: HeaderMap,
-    Json(request): Json<EmbeddingRequest>,
+/// Whether the request asks for a dry run by the `X-Dry-Run: true` header. The dry runs are
+/// rejected unless `allow_dry_run` is enabled.
+async fn is_dry_run(state: &AppState, headers: &HeaderMap) -> ServerResult<bool> {
+    let dry_run = headers
+        .get(DRY_RUN_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("true"));
+    if dry_run && !state.config.read().await.server.allow_dry_run {
+        let err_msg = "The dry runs are disabled";
+        dual_error!("{}", err_msg);
+        return Err(ServerError::Forbidden(err_msg.to_string()));
+    }
+
+    Ok(dry_run)
+}
+
+/// Route the chat request and return the request the gateway would send downstream, with the url
+/// of the target server, without sending it
+async fn dry_run_chat(
+    state: &Arc<AppState>,
+    headers: &HeaderMap,
+    request: ChatCompletionRequest,
+    fan_out_choices: bool,
 ) -> ServerResult<axum::response::Response> {
-    // Get request ID from headers
-    let request_id = headers
-        .get("x-request-id")
-        .and_then(|h| h.to_str().ok())
-        .unwrap_or("unknown")
-        .to_string();
+    let chat_server = resolve_chat_server(state, headers, request.model.as_deref()).await?;
 
-    dual_info!(
-        "Received a new embeddings request - request_id: {}",
-        request_id
-    );
+    let mut body = serde_json::to_value(&request).map_err(|e| {
+        let err_msg = format!("Failed to serialize the chat request: {e}");
+        dual_error!("{}", err_msg);
+        ServerError::Operation(err_msg)
+    })?;
+    // each request of a fan-out asks for one choice
+    if fan_out_choices && let Some(body) = body.as_object_mut() {
+        body.remove("n");
+    }
+    strip_unsupported_params(&mut body, &chat_server);
+    dual_info!("Dry run of the chat request to {}", chat_server.id);
+
+    let dry_run = serde_json::json!({
+        "dry_run": true,
+        "server": {
+            "id": chat_server.id,
+            "url": chat_server.chat_completions_url(request.model.as_deref())?,
+        },
+        "requests": match fan_out_choices {
+            true => request.n_choice.unwrap_or(1),
+            false => 1,
+        },
+        "request": body,
+    });
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(dry_run.to_string()))
+        .map_err(|e| {
+            let err_msg = format!("Failed to create response: {e}");
+            dual_error!("{err_msg}");
+            ServerError::Operation(err_msg)
+        })
+}
 
-    // get the embeddings server
-    let servers = state.server_group.read().await;
-    let embeddings_servers = match servers.get(&ServerKind::embeddings) {
-        Some(servers) => servers,
-        None => {
-            let err_msg = "No embedding server available. Please register a embedding server via the `/admin/servers/register` endpoint.";
-            dual_error!("{} - request_id: {}", err_msg, request_id);
-            return Err(ServerError::Operation(err_msg.to_string()));
+/// Store the successful non-stream chat response in the cache if the request is cacheable
+async fn cache_chat_response(
+    state: &AppState,
+    cache_key: Option<u64>,
+    response: axum::response::Response,
+) -> ServerResult<axum::response::Response> {
+    match (&state.response_cache, cache_key) {
+        (Some(response_cache), Some(key)) if response.status() == StatusCode::OK => {
+            let (parts, body) = response.into_parts();
+            let body = axum::body::to_bytes(body, usize::MAX).await.map_err(|e| {
+                let err_msg = format!("Failed to read the chat response: {e}");
+                dual_error!("{err_msg}");
+                ServerError::Operation(err_msg)
+            })?;
+            let cached = CachedResponse {
+                status: parts.status,
+                headers: parts.headers,
+                body,
+            };
+            response_cache.insert(key, cached.clone());
+            Ok(build_cached_response(cached, "MISS"))
         }
-    };
+        _ => Ok(response),
+    }
+}
 
-    let embedding_server = match embeddings_servers.next().await {
-        Ok(target_server_info) => target_server_info,
-        Err(e) => {
-            let err_msg = format!("Failed to get the embeddings server: {e}");
-            dual_error!("{} - request_id: {}", err_msg, request_id);
-            return Err(ServerError::Operation(err_msg));
+/// Send a non-stream chat request asking for `n` choices as `n` concurrent requests asking for
+/// one choice each, for the backends not supporting `n`. Each request is routed on its own, so
+/// the requests are spread across the chat servers. The responses are merged into one chat
+/// completion with `n` choices, or the first failed response is returned as it is.
+async fn fan_out_chat(
+    state: &Arc<AppState>,
+    request: &ChatCompletionRequest,
+    headers: &HeaderMap,
+    cancel_token: CancellationToken,
+    allowed_mcp_tools: Option<&[String]>,
+    injected_mcp_tools: &InjectedMcpTools,
+    passthrough_unknown_tools: bool,
+) -> ServerResult<axum::response::Response> {
+    let n = request.n_choice.unwrap_or(1);
+    dual_info!("Fan out the chat request for {} choices", n);
+    let strip_tools_on_retry = state.config.read().await.server.strip_tools_on_retry;
+
+    // the chat request is not `Clone`, so each request is rebuilt from its json
+    let body = serde_json::to_value(request).map_err(|e| {
+        let err_msg = format!("Failed to serialize the chat request: {e}");
+        dual_error!("{}", err_msg);
+        ServerError::Operation(err_msg)
+    })?;
+
+    let requests = (0..n).map(|_| {
+        let body = body.clone();
+        let cancel_token = cancel_token.clone();
+        async move {
+            let mut request =
+                serde_json::from_value::<ChatCompletionRequest>(body).map_err(|e| {
+                    let err_msg = format!("Failed to parse the chat request: {e}");
+                    dual_error!("{}", err_msg);
+                    ServerError::Operation(err_msg)
+                })?;
+            request.n_choice = None;
+            let chat_server = resolve_chat_server(state, headers, request.model.as_deref()).await?;
+            let (response, _server_permit) = send_request_with_retry(
+                &chat_server,
+                &mut request,
+                headers,
+                cancel_token.clone(),
+                state.request_queue.as_deref(),
+                strip_tools_on_retry,
+            )
+            .await?;
+            handle_non_stream_response(
+                response,
+                &mut request,
+                headers,
+                &chat_server,
+                cancel_token,
+                allowed_mcp_tools,
+                injected_mcp_tools,
+                passthrough_unknown_tools,
+            )
+            .await
         }
-    };
-    let embeddings_service_url =
-        format!("{}/embeddings", embedding_server.url.trim_end_matches('/'));
-    dual_info!(
-        "Forward the embeddings request to {} - request_id: {}",
-        embeddings_service_url,
-        request_id
-    );
+    });
+    let responses = futures_util::future::join_all(requests).await;
 
-    // parse the content-type header
-    let content_type = headers
-        .get("content-type")
-        .and_then(|v| v.to_str().ok())
-        .ok_or_else(|| {
-            let err_msg = "Missing Content-Type header".to_string();
-            dual_error!("{} - request_id: {}", err_msg, request_id);
+    let mut merged: Option<ChatCompletionObject> = None;
+    for response in responses {
+        let response = response?;
+        if response.status() != StatusCode::OK {
+            return Ok(response);
+        }
+
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .map_err(|e| {
+                let err_msg = format!("Failed to read the chat response: {e}");
+                dual_error!("{err_msg}");
+                ServerError::Operation(err_msg)
+            })?;
+        let chat_completion = parse_chat_completion(&bytes)?;
+        match merged.as_mut() {
+            None => merged = Some(chat_completion),
+            Some(merged) => {
+                merged.choices.extend(chat_completion.choices);
+                merged.usage.prompt_tokens += chat_completion.usage.prompt_tokens;
+                merged.usage.completion_tokens += chat_completion.usage.completion_tokens;
+                merged.usage.total_tokens += chat_completion.usage.total_tokens;
+            }
+        }
+    }
+    let mut merged = merged.ok_or_else(|| {
+        let err_msg = "No chat response to merge";
+        dual_error!("{}", err_msg);
+        ServerError::Operation(err_msg.to_string())
+    })?;
+    for (index, choice) in merged.choices.iter_mut().enumerate() {
+        choice.index = index as u32;
+    }
+
+    let json_body = serde_json::to_string(&merged).map_err(|e| {
+        let err_msg = format!("Failed to serialize the chat response: {e}");
+        dual_error!("{err_msg}");
+        ServerError::Operation(err_msg)
+    })?;
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(json_body))
+        .map_err(|e| {
+            let err_msg = format!("Failed to create response: {e}");
+            dual_error!("{err_msg}");
             ServerError::Operation(err_msg)
-        })?;
-    let content_type = content_type.to_string();
-    dual_debug!(
-        "Request content type: {} - request_id: {}",
-        content_type,
-        request_id
-    );
+        })
+}
 
-    // Create request client
-    let ds_request = if let Some(api_key) = &embedding_server.api_key
-        && !api_key.is_empty()
-    {
-        reqwest::Client::new()
-            .post(embeddings_service_url)
-            .header("Content-Type", content_type)
-            .header(AUTHORIZATION, api_key)
-            .json(&request)
-    } else if headers.contains_key("authorization") {
-        let authorization = headers
-            .get("authorization")
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_string();
+/// Build the response of a cacheable chat request, with the `X-Cache: HIT` header if it is served
+/// from the cache, or `X-Cache: MISS` otherwise
+fn build_cached_response(
+    cached: CachedResponse,
+    x_cache: &'static str,
+) -> axum::response::Response {
+    let mut response = Response::new(Body::from(cached.body));
+    *response.status_mut() = cached.status;
+    *response.headers_mut() = cached.headers;
+    response
+        .headers_mut()
+        .insert("x-cache", HeaderValue::from_static(x_cache));
+    response
+}
 
-        reqwest::Client::new()
-            .post(embeddings_service_url)
-            .header("Content-Type", content_type)
-            .header("Authorization", authorization)
-            .json(&request)
-    } else {
-        reqwest::Client::new()
-            .post(embeddings_service_url)
-            .header("Content-Type", content_type)
-            .json(&request)
-    };
+/// The `Idempotency-Key` of the request, if the client set one and the keys are enabled
+fn idempotency_key(state: &AppState, headers: &HeaderMap) -> Option<String> {
+    state.idempotency.as_ref()?;
+    headers
+        .get(IDEMPOTENCY_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .map(str::to_string)
+}
 
-    // Use select! to handle request cancellation
-    let ds_response = select! {
-        response = ds_request.send() => {
-            response.map_err(|e| {
-                let err_msg = format!(
-                    "Failed to forward the request to the downstream server: {e}",
-                );
-                dual_error!("{err_msg} - request_id: {request_id}");
-                ServerError::Operation(err_msg)
-            })?
+/// Reserve the idempotency key of the caller on the endpoint while the request is forwarded. Breaks
+/// with the response kept for the key, with the `Idempotent-Replayed: true` header, if the key has
+/// been seen within the TTL. The keys still being forwarded or sent with another body are rejected.
+fn reserve_idempotency_key(
+    state: &AppState,
+    endpoint: &str,
+    caller: &IdempotencyCaller,
+    idempotency_key: Option<&str>,
+    body: &[u8],
+) -> ServerResult<ControlFlow<axum::response::Response, Option<IdempotencyGuard>>> {
+    let (Some(idempotency), Some(key)) = (&state.idempotency, idempotency_key) else {
+        return Ok(ControlFlow::Continue(None));
+    };
+    let kept = match idempotency.reserve(endpoint, caller, key, body) {
+        Reservation::Reserved(guard) => return Ok(ControlFlow::Continue(Some(guard))),
+        Reservation::Replay(kept) => kept,
+        Reservation::InFlight => {
+            let err_msg = format!("The request of the idempotency key {key} is in progress");
+            dual_warn!("{err_msg}");
+            return Err(ServerError::Conflict(err_msg));
         }
-        _ = cancel_token.cancelled() => {
-            let warn_msg = "Request was cancelled by client";
-            dual_warn!("{} - request_id: {}", warn_msg, request_id);
-            return Err(ServerError::Operation(warn_msg.to_string()));
+        Reservation::Mismatch => {
+            let err_msg =
+                format!("The idempotency key {key} was already used with another request body");
+            dual_warn!("{err_msg}");
+            return Err(ServerError::BadRequest(err_msg));
         }
     };
+    dual_info!(
+        "Replay the {} response of the idempotency key {}",
+        endpoint,
+        key
+    );
 
-    let status = ds_response.status();
+    let mut response = Response::new(Body::from(kept.body));
+    *response.status_mut() = kept.status;
+    *response.headers_mut() = kept.headers;
+    response
+        .headers_mut()
+        .insert(IDEMPOTENT_REPLAYED_HEADER, HeaderValue::from_static("true"));
+    Ok(ControlFlow::Break(response))
+}
 
-    // Handle response body reading with cancellation
-    let bytes = select! {
-        bytes = ds_response.bytes() => {
-            bytes.map_err(|e| {
-                let err_msg = format!("Failed to get the full response as bytes: {e}");
-                dual_error!("{err_msg} - request_id: {request_id}");
-                ServerError::Operation(err_msg)
-            })?
-        }
-        _ = cancel_token.cancelled() => {
-            let warn_msg = "Request was cancelled while reading response";
-            dual_warn!("{} - request_id: {}", warn_msg, request_id);
-            return Err(ServerError::Operation(warn_msg.to_string()));
-        }
+/// Keep the successful response for the reserved idempotency key of the endpoint, if the client
+/// set one. The failed responses are not kept, so that the client may retry them.
+async fn store_idempotent_response(
+    endpoint: &str,
+    idempotency_guard: Option<IdempotencyGuard>,
+    response: axum::response::Response,
+) -> ServerResult<axum::response::Response> {
+    let Some(idempotency_guard) = idempotency_guard else {
+        return Ok(response);
     };
-
-    match Response::builder()
-        .status(status)
-        .header("Content-Type", "application/json")
-        .body(Body::from(bytes))
-    {
-        Ok(response) => {
-            dual_info!(
-                "Embeddings request completed successfully - request_id: {}",
-                request_id
-            );
-            Ok(response)
-        }
-        Err(e) => {
-            let err_msg = format!("Failed to create the response: {e}");
-            dual_error!("{err_msg} - request_id: {request_id}");
-            Err(ServerError::Operation(err_msg))
-        }
+    if response.status() != StatusCode::OK {
+        return Ok(response);
     }
+
+    let (parts, body) = response.into_parts();
+    let body = axum::body::to_bytes(body, usize::MAX).await.map_err(|e| {
+        let err_msg = format!("Failed to read the {endpoint} response: {e}");
+        dual_error!("{err_msg}");
+        ServerError::Operation(err_msg)
+    })?;
+    idempotency_guard.store(CachedResponse {
+        status: parts.status,
+        headers: parts.headers.clone(),
+        body: body.clone(),
+    });
+
+    Ok(Response::from_parts(parts, Body::from(body)))
 }
 
-pub(crate) async fn audio_transcriptions_handler(
-    State(state): State<Arc<AppState>>,
-    Extension(cancel_token): Extension<CancellationToken>,
-    req: axum::extract::Request<Body>,
+/// Redact the configured patterns from the content of a successful non-streaming chat or
+/// completions response. The streaming responses are passed through untouched.
+async fn redact_response(
+    state: &AppState,
+    is_stream: bool,
+    response: axum::response::Response,
 ) -> ServerResult<axum::response::Response> {
-    // Get request ID from headers
-    let request_id = req
-        .headers()
-        .get("x-request-id")
-        .and_then(|h| h.to_str().ok())
-        .unwrap_or("unknown")
-        .to_string();
+    let Some(redactor) = &state.redactor else {
+        return Ok(response);
+    };
+    if is_stream || response.status() != StatusCode::OK {
+        return Ok(response);
+    }
 
-    dual_info!(
-        "Received a new audio transcription request - request_id: {}",
-        request_id
-    );
+    let (mut parts, body) = response.into_parts();
+    let bytes = axum::body::to_bytes(body, usize::MAX).await.map_err(|e| {
+        let err_msg = format!("Failed to read the response: {e}");
+        dual_error!("{err_msg}");
+        ServerError::Operation(err_msg)
+    })?;
+    let Ok(mut json) = serde_json::from_slice::<serde_json::Value>(&bytes) else {
+        return Ok(Response::from_parts(parts, Body::from(bytes)));
+    };
+    if !redactor.redact_response(&mut json) {
+        return Ok(Response::from_parts(parts, Body::from(bytes)));
+    }
 
-    // get the transcribe server
-    let transcription_server = {
-        let servers = state.server_group.read().await;
-        let transcribe_servers = match servers.get(&ServerKind::transcribe) {
-            Some(servers) => servers,
-            None => {
-                let err_msg = "No transcribe server available";
-                dual_error!("{} - request_id: {}", err_msg, request_id);
-                return Err(ServerError::Operation(err_msg.to_string()));
-            }
-        };
+    dual_info!("Redacted the content of the response");
+    // the redaction changes the length of the body
+    parts.headers.remove(CONTENT_LENGTH);
+    Ok(Response::from_parts(parts, Body::from(json.to_string())))
+}
 
-        match transcribe_servers.next().await {
-            Ok(target_server_info) => target_server_info,
-            Err(e) => {
-                let err_msg = format!("Failed to get the transcribe server: {e}");
-                dual_error!("{} - request_id: {}", err_msg, request_id);
-                return Err(ServerError::Operation(err_msg));
-            }
-        }
+/// Tally the tokens used by a served chat or completions request in the usage ledger, from the
+/// `usage` of the response. The usage of a streaming response is taken from the last chunk carrying
+/// it, once the stream is over.
+async fn account_usage(
+    state: &AppState,
+    user: Option<String>,
+    model: Option<String>,
+    is_stream: bool,
+    strip_usage_chunk: bool,
+    response: axum::response::Response,
+) -> ServerResult<axum::response::Response> {
+    let Some(ledger) = state.usage.clone() else {
+        return Ok(response);
     };
+    // the responses served from the cache did not use any tokens
+    if response.status() != StatusCode::OK
+        || response
+            .headers()
+            .get("x-cache")
+            .is_some_and(|x_cache| x_cache == "HIT")
+    {
+        return Ok(response);
+    }
 
-    let transcription_server_url = format!(
-        "{}/audio/transcriptions",
-        transcription_server.url.trim_end_matches('/')
-    );
-    dual_info!(
-        "Forward the audio transcription request to {} - request_id: {}",
-        transcription_server_url,
-        request_id
-    );
+    let user = user.unwrap_or_else(|| ANONYMOUS_USER.to_string());
+    let (parts, body) = response.into_parts();
+    if is_stream && strip_usage_chunk {
+        // drop the chunk carrying only the usage, which the client did not ask for
+        let splitter = Arc::new(std::sync::Mutex::new(SseEventSplitter::default()));
+        let mut stream_usage = StreamUsage::new(ledger, user, model);
+        let body_stream = body
+            .into_data_stream()
+            .map({
+                let splitter = splitter.clone();
+                move |chunk| {
+                    let chunk = chunk?;
+                    let mut kept = Vec::with_capacity(chunk.len());
+                    for event in splitter.lock().unwrap().feed(&chunk) {
+                        let data = SseParser::default().feed(&event).pop();
+                        let json = data
+                            .and_then(|data| serde_json::from_str::<serde_json::Value>(&data).ok());
+                        if let Some(json) = json {
+                            stream_usage.observe(&json);
+                            if is_usage_chunk(&json) {
+                                continue;
+                            }
+                        }
+                        kept.extend_from_slice(&event);
+                    }
+                    Ok::<_, axum::Error>(Bytes::from(kept))
+                }
+            })
+            .chain(futures_util::stream::once(async move {
+                Ok(Bytes::from(splitter.lock().unwrap().finish()))
+            }));
 
-    // Create request client
-    let mut ds_request = reqwest::Client::new().post(transcription_server_url);
-    if let Some(api_key) = &transcription_server.api_key
-        && !api_key.is_empty()
-    {
-        ds_request = ds_request.header(AUTHORIZATION, api_key);
+        return Ok(Response::from_parts(parts, Body::from_stream(body_stream)));
     }
-    for (name, value) in req.headers().iter() {
-        ds_request = ds_request.header(name, value);
+    if is_stream {
+        let mut parser = SseParser::default();
+        let mut stream_usage = StreamUsage::new(ledger, user, model);
+        let body_stream = body.into_data_stream().map(move |chunk| {
+            if let Ok(chunk) = &chunk {
+                for data in parser.feed(chunk) {
+                    if let Ok(event) = serde_json::from_str::<serde_json::Value>(&data) {
+                        stream_usage.observe(&event);
+                    }
+                }
+            }
+            chunk
+        });
+
+        return Ok(Response::from_parts(parts, Body::from_stream(body_stream)));
     }
 
-    // convert the request body into bytes
-    let body = req.into_body();
-    let body_bytes = axum::body::to_bytes(body, usize::MAX).await.map_err(|e| {
-        let err_msg = format!("Failed to convert the request body into bytes: {e}");
-        dual_error!("{err_msg} - request_id: {request_id}");
+    let bytes = axum::body::to_bytes(body, usize::MAX).await.map_err(|e| {
+        let err_msg = format!("Failed to read the response: {e}");
+        dual_error!("{err_msg}");
         ServerError::Operation(err_msg)
     })?;
+    if let Ok(json) = serde_json::from_slice::<serde_json::Value>(&bytes) {
+        record_usage(&ledger, &user, model.as_deref(), &json);
+    }
 
-    ds_request = ds_request.body(body_bytes);
-
-    // Use select! to handle request cancellation
-    let ds_response = select! {
-        response = ds_request.send() => {
-            response.map_err(|e| {
-                let err_msg = format!(
-                    "Failed to forward the request to the downstream server: {e}"
-                );
-                dual_error!("{err_msg} - request_id: {request_id}");
-                ServerError::Operation(err_msg)
-            })?
-        }
-        _ = cancel_token.cancelled() => {
-            let warn_msg = "Request was cancelled by client";
-            dual_warn!("{} - request_id: {}", warn_msg, request_id);
-            return Err(ServerError::Operation(warn_msg.to_string()));
-        }
-    };
+    Ok(Response::from_parts(parts, Body::from(bytes)))
+}
 
-    let status = ds_response.status();
+/// Whether the chunk of a stream carries only the usage, i.e. has no choices
+fn is_usage_chunk(chunk: &serde_json::Value) -> bool {
+    chunk["usage"].is_object() && chunk["choices"].as_array().is_some_and(Vec::is_empty)
+}
 
-    // Handle response body reading with cancellation
-    let bytes = select! {
-        bytes = ds_response.bytes() => {
-            bytes.map_err(|e| {
-                let err_msg = format!("Failed to get the full response as bytes: {e}");
-                dual_error!("{err_msg} - request_id: {request_id}");
-                ServerError::Operation(err_msg)
-            })?
-        }
-        _ = cancel_token.cancelled() => {
-            let warn_msg = "Request was cancelled while reading response";
-            dual_warn!("{} - request_id: {}", warn_msg, request_id);
-            return Err(ServerError::Operation(warn_msg.to_string()));
+/// The usage of a streaming response. Backends may send the cumulative usage on several chunks, so
+/// only the last one seen is recorded, when the stream is over or dropped by the client.
+struct StreamUsage {
+    ledger: Arc<UsageLedger>,
+    user: String,
+    model: Option<String>,
+    last_usage_chunk: Option<serde_json::Value>,
+}
+impl StreamUsage {
+    fn new(ledger: Arc<UsageLedger>, user: String, model: Option<String>) -> Self {
+        Self {
+            ledger,
+            user,
+            model,
+            last_usage_chunk: None,
         }
-    };
+    }
 
-    match Response::builder()
-        .status(status)
-        .header("Content-Type", "application/json")
-        .body(Body::from(bytes))
-    {
-        Ok(response) => {
-            dual_info!(
-                "Audio transcription request completed successfully - request_id: {}",
-                request_id
-            );
-            Ok(response)
+    fn observe(&mut self, chunk: &serde_json::Value) {
+        if chunk["usage"].is_object() {
+            self.last_usage_chunk = Some(chunk.clone());
         }
-        Err(e) => {
-            let err_msg = format!("Failed to create the response: {e}");
-            dual_error!("{err_msg} - request_id: {request_id}");
-            Err(ServerError::Operation(err_msg))
+    }
+}
+impl Drop for StreamUsage {
+    fn drop(&mut self) {
+        if let Some(chunk) = self.last_usage_chunk.take() {
+            record_usage(&self.ledger, &self.user, self.model.as_deref(), &chunk);
         }
     }
 }
 
-pub(crate) async fn audio_translations_handler(
+/// Record the `usage` of a response, or of a chunk of it, if it has one. The usage is accounted to
+/// the requested model, or to the model of the response if the request does not name one.
+fn record_usage(
+    ledger: &UsageLedger,
+    user: &str,
+    model: Option<&str>,
+    response: &serde_json::Value,
+) {
+    let usage = &response["usage"];
+    if !usage.is_object() {
+        return;
+    }
+
+    let model = model
+        .or_else(|| response["model"].as_str())
+        .unwrap_or_default();
+    ledger.record(
+        user,
+        model,
+        usage["prompt_tokens"].as_u64().unwrap_or(0),
+        usage["completion_tokens"].as_u64().unwrap_or(0),
+    );
+}
+
+pub(crate) async fn completions_handler(
     State(state): State<Arc<AppState>>,
     Extension(cancel_token): Extension<CancellationToken>,
-    req: axum::extract::Request<Body>,
+    headers: HeaderMap,
+    Json(request): Json<CompletionRequest>,
 ) -> ServerResult<axum::response::Response> {
-    // Get request ID from headers
-    let request_id = req
-        .headers()
-        .get("x-request-id")
-        .and_then(|h| h.to_str().ok())
-        .unwrap_or("unknown")
-        .to_string();
+    let usage_user = request.user.clone();
+    let usage_model = request.model.clone();
+    let is_stream = request.stream == Some(true);
 
-    dual_info!(
-        "Received a new audio translation request - request_id: {}",
-        request_id
-    );
+    let response = completions(&state, cancel_token, headers, request).await?;
+    let response = redact_response(&state, is_stream, response).await?;
 
-    // get the transcribe server
-    let translation_server = {
-        let servers = state.server_group.read().await;
-        let translate_servers = match servers.get(&ServerKind::translate) {
-            Some(servers) => servers,
-            None => {
-                let err_msg = "No translate server available";
-                dual_error!("{} - request_id: {}", err_msg, request_id);
-                return Err(ServerError::Operation(err_msg.to_string()));
-            }
-        };
+    account_usage(&state, usage_user, usage_model, is_stream, false, response).await
+}
 
-        match translate_servers.next().await {
-            Ok(target_server_info) => target_server_info,
-            Err(e) => {
-                let err_msg = format!("Failed to get the translate server: {e}");
-                dual_error!("{} - request_id: {}", err_msg, request_id);
-                return Err(ServerError::Operation(err_msg));
-            }
+async fn completions(
+    state: &Arc<AppState>,
+    cancel_token: CancellationToken,
+    headers: HeaderMap,
+    mut request: CompletionRequest,
+) -> ServerResult<axum::response::Response> {
+    dual_info!("Received a new completions request");
+
+    // reject the models hidden from the clients
+    check_model_allowed(state, request.model.as_deref()).await?;
+
+    // cap the tokens generated for the request
+    if let Some(cap) = state.config.read().await.server.max_completion_tokens {
+        if let Some(max_tokens) = request.max_tokens
+            && max_tokens > cap
+        {
+            dual_info!(
+                "Clamped the max tokens of the request from {} to {}",
+                max_tokens,
+                cap
+            );
         }
+        request.max_tokens = Some(request.max_tokens.map_or(cap, |n| n.min(cap)));
+    }
+
+    // Hold the permit until the streaming response is served
+    let stream_permit = match request.stream {
+        Some(true) => acquire_stream_permit(state)?,
+        _ => None,
     };
 
-    let translation_server_url = format!(
-        "{}/audio/translations",
-        translation_server.url.trim_end_matches('/')
-    );
+    // Get target server
+    let chat_server = resolve_chat_server(state, &headers, request.model.as_deref()).await?;
+
+    let completions_service_url = chat_server.endpoint("completions");
     dual_info!(
-        "Forward the audio translation request to {} - request_id: {}",
-        translation_server_url,
-        request_id
+        "Forward the completions request to {}",
+        completions_service_url
     );
 
     // Create request client
-    let mut ds_request = reqwest::Client::new().post(translation_server_url);
-    if let Some(api_key) = &translation_server.api_key
+    let mut ds_request = forward_tracing_headers(
+        http_client()
+            .post(&completions_service_url)
+            .header(CONTENT_TYPE, "application/json"),
+        &headers,
+    );
+    if let Some(api_key) = &chat_server.api_key
         && !api_key.is_empty()
     {
         ds_request = ds_request.header(AUTHORIZATION, api_key);
-    }
-    for (name, value) in req.headers().iter() {
-        ds_request = ds_request.header(name, value);
+    } else if let Some(auth) = headers.get("authorization")
+        && let Ok(auth_str) = auth.to_str()
+    {
+        ds_request = ds_request.header(AUTHORIZATION, auth_str);
     }
 
-    // convert the request body into bytes
-    let body = req.into_body();
-    let body_bytes = axum::body::to_bytes(body, usize::MAX).await.map_err(|e| {
-        let err_msg = format!("Failed to convert the request body into bytes: {e}");
-        dual_error!("{err_msg} - request_id: {request_id}");
+    let mut body = serde_json::to_value(&request).map_err(|e| {
+        let err_msg = format!("Failed to serialize the completions request: {e}");
+        dual_error!("{err_msg}");
         ServerError::Operation(err_msg)
     })?;
-
-    ds_request = ds_request.body(body_bytes);
+    strip_unsupported_params(&mut body, &chat_server);
 
     // Use select! to handle request cancellation
     let ds_response = select! {
-        response = ds_request.send() => {
+        response = ds_request.json(&body).send() => {
             response.map_err(|e| {
                 let err_msg = format!(
                     "Failed to forward the request to the downstream server: {e}"
                 );
-                dual_error!("{err_msg} - request_id: {request_id}");
+                dual_error!("{err_msg}");
                 ServerError::Operation(err_msg)
             })?
         }
         _ = cancel_token.cancelled() => {
             let warn_msg = "Request was cancelled by client";
-            dual_warn!("{} - request_id: {}", warn_msg, request_id);
+            dual_warn!("{}", warn_msg);
             return Err(ServerError::Operation(warn_msg.to_string()));
         }
     };
 
     let status = ds_response.status();
 
-    // Handle response body reading with cancellation
-    let bytes = select! {
-        bytes = ds_response.bytes() => {
-            bytes.map_err(|e| {
-                let err_msg = format!("Failed to get the full response as bytes: {e}");
-                dual_error!("{err_msg} - request_id: {request_id}");
-                ServerError::Operation(err_msg)
-            })?
-        }
-        _ = cancel_token.cancelled() => {
-            let warn_msg = "Request was cancelled while reading response";
-            dual_warn!("{} - request_id: {}", warn_msg, request_id);
-            return Err(ServerError::Operation(warn_msg.to_string()));
+    // The downstream server only serves the chat endpoint, so wrap the prompt into a chat request
+    if status == StatusCode::NOT_FOUND || status == StatusCode::METHOD_NOT_ALLOWED {
+        dual_info!(
+            "The chat server does not support the completions endpoint ({}), fall back to the chat endpoint",
+            status
+        );
+
+        return completions_via_chat(
+            request,
+            &headers,
+            &chat_server,
+            cancel_token,
+            state.request_queue.as_deref(),
+        )
+        .await;
+    }
+
+    let response_headers = ds_response.headers().clone();
+    match request.stream {
+        Some(true) if status == StatusCode::OK => {
+            handle_normal_stream(
+                ds_response,
+                status,
+                response_headers,
+                cancel_token,
+                stream_permit.into_iter().collect(),
+            )
+            .await
+        }
+        _ => {
+            let bytes = read_response_bytes(ds_response, cancel_token).await?;
+            build_response(status, response_headers, bytes)
+        }
+    }
+}
+
+/// Serve a legacy completions request by a chat-only downstream server
+///
+/// The prompt is wrapped into a single user message and sent to the chat endpoint. The chat
+/// response (or each chunk of it in stream mode) is then converted back into the legacy
+/// `text_completion` shape.
+async fn completions_via_chat(
+    request: CompletionRequest,
+    headers: &HeaderMap,
+    chat_server: &TargetServerInfo,
+    cancel_token: CancellationToken,
+    request_queue: Option<&RequestQueue>,
+) -> ServerResult<axum::response::Response> {
+    let prompt = match request.prompt {
+        CompletionPrompt::SingleText(prompt) => prompt,
+        CompletionPrompt::MultiText(mut prompts) if prompts.len() == 1 => prompts.remove(0),
+        CompletionPrompt::MultiText(_) => {
+            let err_msg = "Multiple prompts are not supported by the chat server";
+            dual_error!("{}", err_msg);
+            return Err(ServerError::BadRequest(err_msg.to_string()));
         }
     };
+    let echo = request.echo.unwrap_or(false);
+
+    let chat_request = ChatCompletionRequest {
+        model: request.model,
+        messages: vec![ChatCompletionRequestMessage::new_user_message(
+            ChatCompletionUserMessageContent::Text(prompt.clone()),
+            None,
+        )],
+        temperature: request.temperature.map(f64::from),
+        top_p: request.top_p.map(f64::from),
+        n_choice: request.n.map(u64::from),
+        stream: request.stream,
+        stop: request.stop,
+        max_completion_tokens: request.max_tokens.map(|n| n.min(i32::MAX as u32) as i32),
+        presence_penalty: request.presence_penalty.map(f64::from),
+        frequency_penalty: request.frequency_penalty.map(f64::from),
+        logit_bias: request.logit_bias.map(|bias| {
+            bias.into_iter()
+                .map(|(token, value)| (token, f64::from(value)))
+                .collect()
+        }),
+        user: request.user,
+        ..Default::default()
+    };
 
-    match Response::builder()
-        .status(status)
-        .header("Content-Type", "application/json")
-        .body(Body::from(bytes))
-    {
-        Ok(response) => {
-            dual_info!(
-                "Audio translation request completed successfully - request_id: {}",
-                request_id
-            );
-            Ok(response)
+    let (response, _server_permit) = build_and_send_request(
+        chat_server,
+        &chat_request,
+        headers,
+        cancel_token.clone(),
+        request_queue,
+    )
+    .await?;
+
+    let status = response.status();
+    let response_headers = response.headers().clone();
+    let bytes = read_response_bytes(response, cancel_token).await?;
+
+    if status != StatusCode::OK {
+        dual_error!("{}", status);
+        return build_response(status, response_headers, bytes);
+    }
+
+    let body = match chat_request.stream {
+        Some(true) => {
+            let text = String::from_utf8_lossy(&bytes);
+
+            let mut events = String::new();
+            for line in text.lines() {
+                let Some(data) = line.strip_prefix("data:") else {
+                    continue;
+                };
+                let data = data.trim();
+
+                if data == "[DONE]" {
+                    events.push_str("data: [DONE]\n\n");
+                    continue;
+                }
+
+                let chunk = serde_json::from_str::<serde_json::Value>(data).map_err(|e| {
+                    let err_msg = format!("Failed to parse the chat completion chunk: {e}");
+                    dual_error!("{}", err_msg);
+                    ServerError::Operation(err_msg)
+                })?;
+                let completion_chunk = chat_chunk_to_completion(&chunk);
+                events.push_str(&format!("data: {completion_chunk}\n\n"));
+            }
+
+            Bytes::from(events)
         }
-        Err(e) => {
-            let err_msg = format!("Failed to create the response: {e}");
-            dual_error!("{err_msg} - request_id: {request_id}");
-            Err(ServerError::Operation(err_msg))
+        Some(false) | None => {
+            let chat_completion = parse_chat_completion(&bytes)?;
+
+            let completion = CompletionObject {
+                id: chat_completion.id,
+                choices: chat_completion
+                    .choices
+                    .into_iter()
+                    .map(|choice| {
+                        let text = choice.message.content.unwrap_or_default();
+                        CompletionChoice {
+                            finish_reason: choice.finish_reason,
+                            index: choice.index,
+                            logprobs: None,
+                            text: match echo {
+                                true => format!("{prompt}{text}"),
+                                false => text,
+                            },
+                        }
+                    })
+                    .collect(),
+                created: chat_completion.created,
+                model: chat_completion.model,
+                object: "text_completion".to_string(),
+                usage: chat_completion.usage,
+            };
+
+            let json_body = serde_json::to_vec(&completion).map_err(|e| {
+                let err_msg = format!("Failed to serialize the completion: {e}");
+                dual_error!("{}", err_msg);
+                ServerError::Operation(err_msg)
+            })?;
+
+            Bytes::from(json_body)
         }
+    };
+
+    // the content length of the chat response no longer matches the converted body
+    let mut response_headers = response_headers;
+    response_headers.remove("content-length");
+
+    build_response(status, response_headers, body)
+}
+
+/// Convert a chat completion chunk into a legacy `text_completion` chunk
+fn chat_chunk_to_completion(chunk: &serde_json::Value) -> serde_json::Value {
+    let choices = chunk
+        .get("choices")
+        .and_then(|choices| choices.as_array())
+        .map(|choices| {
+            choices
+                .iter()
+                .map(|choice| {
+                    serde_json::json!({
+                        "index": choice.get("index").cloned().unwrap_or(serde_json::json!(0)),
+                        "text": choice
+                            .get("delta")
+                            .and_then(|delta| delta.get("content"))
+                            .and_then(|content| content.as_str())
+                            .unwrap_or_default(),
+                        "logprobs": null,
+                        "finish_reason": choice.get("finish_reason").cloned().unwrap_or_default(),
+                    })
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    let mut completion_chunk = serde_json::json!({
+        "id": chunk.get("id").cloned().unwrap_or_default(),
+        "object": "text_completion",
+        "created": chunk.get("created").cloned().unwrap_or_default(),
+        "model": chunk.get("model").cloned().unwrap_or_default(),
+        "choices": choices,
+    });
+    if let Some(usage) = chunk.get("usage")
+        && !usage.is_null()
+    {
+        completion_chunk["usage"] = usage.clone();
     }
+
+    completion_chunk
 }
 
-pub(crate) async fn audio_tts_handler(
+#[test]
+fn test_chat_chunk_to_completion() {
+    let chunk = serde_json::json!({
+        "id": "chatcmpl-123",
+        "object": "chat.completion.chunk",
+        "created": 1700000000,
+        "model": "Llama-3.2-3b",
+        "system_fingerprint": "fp_44709d6fcb",
+        "choices": [{"index": 0, "delta": {"role": "assistant", "content": "Paris"}, "logprobs": null, "finish_reason": null}]
+    });
+    let completion_chunk = chat_chunk_to_completion(&chunk);
+    assert_eq!(completion_chunk["id"], "chatcmpl-123");
+    assert_eq!(completion_chunk["object"], "text_completion");
+    assert_eq!(completion_chunk["model"], "Llama-3.2-3b");
+    assert_eq!(completion_chunk["choices"][0]["text"], "Paris");
+    assert!(completion_chunk["choices"][0]["finish_reason"].is_null());
+    assert!(completion_chunk.get("usage").is_none());
+}
+
+pub(crate) async fn embeddings_handler(
     State(state): State<Arc<AppState>>,
     Extension(cancel_token): Extension<CancellationToken>,
-    req: axum::extract::Request<Body>,
+    caller: IdempotencyCaller,
+    headers: HeaderMap,
+    body: Bytes,
 ) -> ServerResult<axum::response::Response> {
-    // Get request ID from headers
-    let request_id = req
-        .headers()
-        .get("x-request-id")
-        .and_then(|h| h.to_str().ok())
-        .unwrap_or("unknown")
-        .to_string();
+    dual_info!("Received a new embeddings request");
+
+    // the clients omitting the Content-Type header mostly send JSON anyway
+    check_json_content_type(&headers)?;
+    let request = serde_json::from_slice::<EmbeddingRequest>(&body).map_err(|e| {
+        let err_msg = format!("Failed to parse the embeddings request: {e}");
+        dual_error!("{}", err_msg);
+        ServerError::BadRequest(err_msg)
+    })?;
 
-    dual_info!(
-        "Received a new audio speech request - request_id: {}",
-        request_id
-    );
+    // replay the response of a repeated idempotency key instead of forwarding the request again
+    let idempotency_key = idempotency_key(&state, &headers);
+    let idempotency_guard = match reserve_idempotency_key(
+        &state,
+        "embeddings",
+        &caller,
+        idempotency_key.as_deref(),
+        &body,
+    )? {
+        ControlFlow::Break(response) => return Ok(response),
+        ControlFlow::Continue(idempotency_guard) => idempotency_guard,
+    };
 
-    // get the tts server
-    let tts_server = {
-        let servers = state.server_group.read().await;
-        let tts_servers = match servers.get(&ServerKind::tts) {
-            Some(servers) => servers,
-            None => {
-                let err_msg = "No tts server available";
-                dual_error!("{} - request_id: {}", err_msg, request_id);
-                return Err(ServerError::Operation(err_msg.to_string()));
-            }
-        };
+    let response = embeddings(
+        State(state.clone()),
+        Extension(cancel_token),
+        headers,
+        Json(request),
+    )
+    .await?;
 
-        match tts_servers.next().await {
-            Ok(target_server_info) => target_server_info,
-            Err(e) => {
-                let err_msg = format!("Failed to get the tts server: {e}");
-                dual_error!("{} - request_id: {}", err_msg, request_id);
-                return Err(ServerError::Operation(err_msg));
-            }
+    store_idempotent_response("embeddings", idempotency_guard, response).await
+}
+
+/// Reject the requests with a Content-Type other than JSON. The requests without the header are
+/// taken as JSON.
+fn check_json_content_type(headers: &HeaderMap) -> ServerResult<()> {
+    let Some(content_type) = headers.get(CONTENT_TYPE) else {
+        return Ok(());
+    };
+
+    let mime = content_type
+        .to_str()
+        .unwrap_or_default()
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    if mime == "application/json" || mime.ends_with("+json") {
+        return Ok(());
+    }
+
+    let err_msg = format!(
+        "Unsupported Content-Type: {content_type:?}. The request should be `application/json`"
+    );
+    dual_error!("{}", err_msg);
+    Err(ServerError::BadRequest(err_msg))
+}
+
+#[test]
+fn test_check_json_content_type() {
+    let headers = |content_type: Option<&str>| {
+        let mut headers = HeaderMap::new();
+        if let Some(content_type) = content_type {
+            headers.insert(CONTENT_TYPE, content_type.parse().unwrap());
         }
+        headers
+    };
+
+    assert!(check_json_content_type(&headers(None)).is_ok());
+    assert!(check_json_content_type(&headers(Some("application/json"))).is_ok());
+    assert!(check_json_content_type(&headers(Some("Application/JSON; charset=utf-8"))).is_ok());
+    assert!(check_json_content_type(&headers(Some("application/vnd.api+json"))).is_ok());
+    assert!(matches!(
+        check_json_content_type(&headers(Some("text/plain"))),
+        Err(ServerError::BadRequest(_))
+    ));
+}
+
+/// Forward an embeddings request to an embeddings server
+pub(crate) async fn embeddings(
+    State(state): State<Arc<AppState>>,
+    Extension(cancel_token): Extension<CancellationToken>,
+    headers: HeaderMap,
+    Json(request): Json<EmbeddingRequest>,
+) -> ServerResult<axum::response::Response> {
+    // reject the models hidden from the clients
+    check_model_allowed(&state, request.model.as_deref()).await?;
+
+    // reject the malformed or oversized inputs before they reach the embeddings server
+    let (max_inputs, max_chars) = {
+        let config = state.config.read().await;
+        (
+            config.server.max_embedding_inputs,
+            config.server.max_embedding_chars,
+        )
     };
+    validate_embedding_input(&request.input, max_inputs, max_chars)?;
+
+    // get the embeddings server
+    let servers = state.server_group.read().await;
+    let embeddings_servers = server_group_of(&servers, ServerKind::embeddings).await?;
 
-    let tts_server_url = format!("{}/audio/speech", tts_server.url.trim_end_matches('/'));
+    let embedding_server = match embeddings_servers.next().await {
+        Ok(target_server_info) => target_server_info,
+        Err(e) => {
+            let err_msg = format!("Failed to get the embeddings server: {e}");
+            dual_error!("{}", err_msg);
+            return Err(ServerError::Operation(err_msg));
+        }
+    };
+    let embeddings_service_url = embedding_server.endpoint("embeddings");
     dual_info!(
-        "Forward the audio speech request to {} - request_id: {}",
-        tts_server_url,
-        request_id
+        "Forward the embeddings request to {}",
+        embeddings_service_url
     );
 
-    // Create request client
-    let mut ds_request = reqwest::Client::new().post(tts_server_url);
-    if let Some(api_key) = &tts_server.api_key
+    // Create request client. The request is sent as JSON whatever the client sent it as.
+    let ds_request = if let Some(api_key) = &embedding_server.api_key
         && !api_key.is_empty()
     {
-        ds_request = ds_request.header(AUTHORIZATION, api_key);
-    }
-    for (name, value) in req.headers().iter() {
-        ds_request = ds_request.header(name, value);
-    }
-
-    let body = req.into_body();
-    let body_bytes = axum::body::to_bytes(body, usize::MAX).await.map_err(|e| {
-        let err_msg = format!("Failed to convert the request body into bytes: {e}");
-        dual_error!("{err_msg} - request_id: {request_id}");
-        ServerError::Operation(err_msg)
-    })?;
+        http_client()
+            .post(embeddings_service_url)
+            .header(AUTHORIZATION, api_key)
+            .json(&request)
+    } else if headers.contains_key("authorization") {
+        let authorization = headers
+            .get("authorization")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
 
-    ds_request = ds_request.body(body_bytes);
+        http_client()
+            .post(embeddings_service_url)
+            .header("Authorization", authorization)
+            .json(&request)
+    } else {
+        http_client().post(embeddings_service_url).json(&request)
+    };
+    let ds_request = forward_tracing_headers(ds_request, &headers);
 
     // Use select! to handle request cancellation
     let ds_response = select! {
         response = ds_request.send() => {
             response.map_err(|e| {
                 let err_msg = format!(
-                    "Failed to forward the request to the downstream server: {e}"
+                    "Failed to forward the request to the downstream server: {e}",
                 );
-                dual_error!("{err_msg} - request_id: {request_id}");
+                dual_error!("{err_msg}");
                 ServerError::Operation(err_msg)
             })?
         }
         _ = cancel_token.cancelled() => {
             let warn_msg = "Request was cancelled by client";
-            dual_warn!("{} - request_id: {}", warn_msg, request_id);
+            dual_warn!("{}", warn_msg);
             return Err(ServerError::Operation(warn_msg.to_string()));
         }
     };
 
-    // create a response builder with the status and headers of the downstream response
-    let mut response_builder = Response::builder().status(ds_response.status());
-    for (name, value) in ds_response.headers().iter() {
-        response_builder = response_builder.header(name, value);
-    }
+    let status = ds_response.status();
 
     // Handle response body reading with cancellation
     let bytes = select! {
         bytes = ds_response.bytes() => {
             bytes.map_err(|e| {
                 let err_msg = format!("Failed to get the full response as bytes: {e}");
-                dual_error!("{err_msg} - request_id: {request_id}");
+                dual_error!("{err_msg}");
                 ServerError::Operation(err_msg)
             })?
         }
         _ = cancel_token.cancelled() => {
             let warn_msg = "Request was cancelled while reading response";
-            dual_warn!("{} - request_id: {}", warn_msg, request_id);
+            dual_warn!("{}", warn_msg);
             return Err(ServerError::Operation(warn_msg.to_string()));
         }
     };
 
-    match response_builder.body(Body::from(bytes)) {
+    match Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(bytes))
+    {
         Ok(response) => {
-            dual_info!(
-                "Audio speech request completed successfully - request_id: {}",
-                request_id
-            );
+            dual_info!("Embeddings request completed successfully");
             Ok(response)
         }
         Err(e) => {
             let err_msg = format!("Failed to create the response: {e}");
-            dual_error!("{err_msg} - request_id: {request_id}");
+            dual_error!("{err_msg}");
             Err(ServerError::Operation(err_msg))
         }
     }
 }
 
-pub(crate) async fn image_handler(
+/// Check that the input of an embeddings request is not empty, has at most `max_inputs` inputs,
+/// and at most `max_chars` chars in total if set
+fn validate_embedding_input(
+    input: &InputText,
+    max_inputs: usize,
+    max_chars: Option<usize>,
+) -> ServerResult<()> {
+    let bad_request = |err_msg: String| {
+        dual_error!("{err_msg}");
+        Err(ServerError::BadRequest(err_msg))
+    };
+
+    let (num_inputs, empty_input, num_chars) = match input {
+        InputText::String(text) => (1, text.trim().is_empty().then_some(0), text.chars().count()),
+        InputText::ArrayOfStrings(texts) => (
+            texts.len(),
+            texts.iter().position(|text| text.trim().is_empty()),
+            texts.iter().map(|text| text.chars().count()).sum(),
+        ),
+        InputText::ArrayOfTokens(tokens) => (1, tokens.is_empty().then_some(0), 0),
+        InputText::ArrayOfTokenArrays(token_arrays) => (
+            token_arrays.len(),
+            token_arrays.iter().position(|tokens| tokens.is_empty()),
+            0,
+        ),
+    };
+
+    if num_inputs == 0 {
+        return bad_request("The `input` of the embeddings request is an empty array".to_string());
+    }
+    if let Some(index) = empty_input {
+        return match input {
+            InputText::String(_) | InputText::ArrayOfTokens(_) => {
+                bad_request("The `input` of the embeddings request is empty".to_string())
+            }
+            _ => bad_request(format!(
+                "The `input[{index}]` of the embeddings request is empty"
+            )),
+        };
+    }
+    if num_inputs > max_inputs {
+        return bad_request(format!(
+            "The embeddings request has {num_inputs} inputs, more than the maximum of {max_inputs} inputs"
+        ));
+    }
+    if let Some(max_chars) = max_chars
+        && num_chars > max_chars
+    {
+        return bad_request(format!(
+            "The `input` of the embeddings request has {num_chars} chars, more than the maximum of {max_chars} chars"
+        ));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_validate_embedding_input() {
+    let texts =
+        |texts: &[&str]| InputText::ArrayOfStrings(texts.iter().map(|t| t.to_string()).collect());
+    assert!(validate_embedding_input(&InputText::from("Hello"), 2, Some(10)).is_ok());
+    assert!(validate_embedding_input(&texts(&["Hello", "world"]), 2, Some(10)).is_ok());
+    assert!(validate_embedding_input(&InputText::ArrayOfTokens(vec![1, 2]), 2, None).is_ok());
+
+    // the empty inputs
+    for (input, message) in [
+        (
+            InputText::from(""),
+            "The `input` of the embeddings request is empty",
+        ),
+        (
+            InputText::from("  "),
+            "The `input` of the embeddings request is empty",
+        ),
+        (
+            texts(&[]),
+            "The `input` of the embeddings request is an empty array",
+        ),
+        (
+            texts(&["Hello", ""]),
+            "The `input[1]` of the embeddings request is empty",
+        ),
+        (
+            InputText::ArrayOfTokens(vec![]),
+            "The `input` of the embeddings request is empty",
+        ),
+        (
+            InputText::ArrayOfTokenArrays(vec![vec![1], vec![]]),
+            "The `input[1]` of the embeddings request is empty",
+        ),
+    ] {
+        assert!(matches!(
+            validate_embedding_input(&input, 2, None),
+            Err(ServerError::BadRequest(msg)) if msg == message
+        ));
+    }
+
+    // the oversized inputs
+    assert!(matches!(
+        validate_embedding_input(&texts(&["a", "b", "c"]), 2, None),
+        Err(ServerError::BadRequest(msg)) if msg.contains("3 inputs, more than the maximum of 2")
+    ));
+    assert!(matches!(
+        validate_embedding_input(&texts(&["Hello", "world!"]), 2, Some(10)),
+        Err(ServerError::BadRequest(msg)) if msg.contains("11 chars, more than the maximum of 10")
+    ));
+}
+
+/// Forward a rerank request, in the Cohere/Jina-compatible shape, to a rerank server
+pub(crate) async fn rerank_handler(
     State(state): State<Arc<AppState>>,
     Extension(cancel_token): Extension<CancellationToken>,
-    req: axum::extract::Request<Body>,
+    headers: HeaderMap,
+    Json(request): Json<serde_json::Value>,
 ) -> ServerResult<axum::response::Response> {
-    // Get request ID from headers
-    let request_id = req
-        .headers()
-        .get("x-request-id")
-        .and_then(|h| h.to_str().ok())
-        .unwrap_or("unknown")
-        .to_string();
+    dual_info!("Received a new rerank request");
 
-    dual_info!("Received a new image request - request_id: {}", request_id);
+    // reject the models hidden from the clients
+    check_model_allowed(&state, request["model"].as_str()).await?;
 
-    // get the image server
-    let image_server = {
+    // reject the requests without anything to rerank before they reach the rerank server
+    validate_rerank_request(&request)?;
+
+    // get the rerank server
+    let rerank_server = {
         let servers = state.server_group.read().await;
-        let image_servers = match servers.get(&ServerKind::image) {
-            Some(servers) => servers,
-            None => {
-                let err_msg = "No image server available";
-                dual_error!("{} - request_id: {}", err_msg, request_id);
-                return Err(ServerError::Operation(err_msg.to_string()));
-            }
-        };
+        let rerank_servers = server_group_of(&servers, ServerKind::rerank).await?;
 
-        match image_servers.next().await {
+        match rerank_servers.next().await {
             Ok(target_server_info) => target_server_info,
             Err(e) => {
-                let err_msg = format!("Failed to get the image server: {e}");
-                dual_error!("{} - request_id: {}", err_msg, request_id);
+                let err_msg = format!("Failed to get the rerank server: {e}");
+                dual_error!("{}", err_msg);
                 return Err(ServerError::Operation(err_msg));
             }
         }
     };
-
-    let image_server_url = format!(
-        "{}/images/generations",
-        image_server.url.trim_end_matches('/')
-    );
-    dual_info!(
-        "Forward the image request to {} - request_id: {}",
-        image_server_url,
-        request_id
-    );
+    let rerank_service_url = rerank_server.endpoint("rerank");
+    dual_info!("Forward the rerank request to {}", rerank_service_url);
 
     // Create request client
-    let mut ds_request = reqwest::Client::new().post(image_server_url);
-    if let Some(api_key) = &image_server.api_key
+    let mut ds_request = forward_tracing_headers(
+        http_client()
+            .post(&rerank_service_url)
+            .header(CONTENT_TYPE, "application/json"),
+        &headers,
+    );
+    if let Some(api_key) = &rerank_server.api_key
         && !api_key.is_empty()
     {
         ds_request = ds_request.header(AUTHORIZATION, api_key);
+    } else if let Some(auth) = headers.get("authorization")
+        && let Ok(auth_str) = auth.to_str()
+    {
+        ds_request = ds_request.header(AUTHORIZATION, auth_str);
     }
-    for (name, value) in req.headers().iter() {
-        ds_request = ds_request.header(name, value);
-    }
-
-    // convert the request body into bytes
-    let body = req.into_body();
-    let body_bytes = axum::body::to_bytes(body, usize::MAX).await.map_err(|e| {
-        let err_msg = format!("Failed to convert the request body into bytes: {e}");
-        dual_error!("{err_msg} - request_id: {request_id}");
-        ServerError::Operation(err_msg)
-    })?;
-
-    ds_request = ds_request.body(body_bytes);
 
     // Use select! to handle request cancellation
     let ds_response = select! {
-        response = ds_request.send() => {
+        response = ds_request.json(&request).send() => {
             response.map_err(|e| {
                 let err_msg = format!(
                     "Failed to forward the request to the downstream server: {e}"
                 );
-                dual_error!("{err_msg} - request_id: {request_id}");
+                dual_error!("{err_msg}");
                 ServerError::Operation(err_msg)
             })?
         }
         _ = cancel_token.cancelled() => {
             let warn_msg = "Request was cancelled by client";
-            dual_warn!("{} - request_id: {}", warn_msg, request_id);
+            dual_warn!("{}", warn_msg);
             return Err(ServerError::Operation(warn_msg.to_string()));
         }
     };
 
-    // create a response builder with the status and headers of the downstream response
-    let mut response_builder = Response::builder().status(ds_response.status());
-    for (name, value) in ds_response.headers().iter() {
-        response_builder = response_builder.header(name, value);
-    }
+    let status = ds_response.status();
+    let response_headers = ds_response.headers().clone();
+    let bytes = read_response_bytes(ds_response, cancel_token).await?;
+    dual_info!("Rerank request completed with status {}", status);
 
-    // Handle response body reading with cancellation
-    let bytes = select! {
-        bytes = ds_response.bytes() => {
-            bytes.map_err(|e| {
-                let err_msg = format!("Failed to get the full response as bytes: {e}");
-                dual_error!("{err_msg} - request_id: {request_id}");
-                ServerError::Operation(err_msg)
-            })?
-        }
-        _ = cancel_token.cancelled() => {
-            let warn_msg = "Request was cancelled while reading response";
-            dual_warn!("{} - request_id: {}", warn_msg, request_id);
-            return Err(ServerError::Operation(warn_msg.to_string()));
-        }
+    build_response(status, response_headers, bytes)
+}
+
+/// Check that a rerank request has a query and at least one document
+fn validate_rerank_request(request: &serde_json::Value) -> ServerResult<()> {
+    let err_msg = if request["query"].as_str().is_none_or(str::is_empty) {
+        "The `query` of the rerank request should be a non-empty string"
+    } else if request["documents"]
+        .as_array()
+        .is_none_or(|documents| documents.is_empty())
+    {
+        "The `documents` of the rerank request should be a non-empty list"
+    } else {
+        return Ok(());
     };
 
-    match response_builder.body(Body::from(bytes)) {
-        Ok(response) => {
-            dual_info!(
-                "Image request completed successfully - request_id: {}",
-                request_id
-            );
-            Ok(response)
-        }
-        Err(e) => {
-            let err_msg = format!("Failed to create the response: {e}");
-            dual_error!("{err_msg} - request_id: {request_id}");
-            Err(ServerError::Operation(err_msg))
-        }
+    dual_error!("{}", err_msg);
+    Err(ServerError::BadRequest(err_msg.to_string()))
+}
+
+#[test]
+fn test_validate_rerank_request() {
+    let request = serde_json::json!({
+        "model": "bge-reranker-v2-m3",
+        "query": "What is the capital of France?",
+        "documents": ["Paris is the capital of France.", {"text": "Berlin is in Germany."}],
+        "top_n": 1,
+    });
+    assert!(validate_rerank_request(&request).is_ok());
+
+    for invalid in [
+        serde_json::json!({"documents": ["Paris"]}),
+        serde_json::json!({"query": "", "documents": ["Paris"]}),
+        serde_json::json!({"query": "capital", "documents": []}),
+        serde_json::json!({"query": "capital", "documents": "Paris"}),
+    ] {
+        assert!(matches!(
+            validate_rerank_request(&invalid),
+            Err(ServerError::BadRequest(_))
+        ));
     }
 }
 
-pub(crate) async fn models_handler(
+pub(crate) async fn audio_transcriptions_handler(
     State(state): State<Arc<AppState>>,
-    headers: HeaderMap,
+    Extension(cancel_token): Extension<CancellationToken>,
+    req: axum::extract::Request<Body>,
 ) -> ServerResult<axum::response::Response> {
-    let request_id = headers
-        .get("x-request-id")
-        .and_then(|h| h.to_str().ok())
-        .unwrap_or("unknown")
-        .to_string();
+    dual_info!("Received a new audio transcription request");
 
-    let models = state.models.read().await;
-    let list_response = ListModelsResponse {
-        object: String::from("list"),
-        data: models.values().flatten().cloned().collect(),
+    // get the transcribe server
+    let transcription_server = {
+        let servers = state.server_group.read().await;
+        let transcribe_servers = server_group_of(&servers, ServerKind::transcribe).await?;
+
+        match transcribe_servers.next().await {
+            Ok(target_server_info) => target_server_info,
+            Err(e) => {
+                let err_msg = format!("Failed to get the transcribe server: {e}");
+                dual_error!("{}", err_msg);
+                return Err(ServerError::Operation(err_msg));
+            }
+        }
     };
 
-    let json_body = serde_json::to_string(&list_response).map_err(|e| {
-        let err_msg = format!("Failed to serialize the models: {e}");
-        dual_error!("{err_msg} - request_id: {request_id}");
-        ServerError::Operation(err_msg)
-    })?;
+    let transcription_server_url = transcription_server.endpoint("audio/transcriptions");
+    dual_info!(
+        "Forward the audio transcription request to {}",
+        transcription_server_url
+    );
 
-    Response::builder()
-        .status(StatusCode::OK)
-        .header("Content-Type", "application/json")
-        .body(Body::from(json_body))
-        .map_err(|e| {
-            let err_msg = format!("Failed to create response: {e}");
-            dual_error!("{err_msg} - request_id: {request_id}");
-            ServerError::Operation(err_msg)
-        })
-}
+    // Create request client
+    let mut ds_request = authorize_request(
+        http_client().post(transcription_server_url),
+        &transcription_server,
+        req.headers(),
+    );
 
-pub(crate) async fn info_handler(
-    State(state): State<Arc<AppState>>,
-    headers: HeaderMap,
-) -> ServerResult<axum::response::Response> {
-    let request_id = headers
-        .get("x-request-id")
-        .and_then(|h| h.to_str().ok())
-        .unwrap_or("unknown")
-        .to_string();
+    // convert the request body into bytes
+    let content_type = req.headers().get(CONTENT_TYPE).cloned();
+    let body_bytes = read_request_body(&state, req.into_body()).await?;
 
-    let mut chat_models = vec![];
-    let mut embedding_models = vec![];
-    let mut image_models = vec![];
-    let mut tts_models = vec![];
-    let mut translate_models = vec![];
-    let mut transcribe_models = vec![];
-    let server_info = state.server_info.read().await;
-    for server in server_info.servers.values() {
-        if let Some(ref model) = server.chat_model {
-            chat_models.push(model.clone());
+    // reject the models hidden from the clients
+    let model = request_body_model(content_type.as_ref(), &body_bytes).await;
+    check_model_allowed(&state, model.as_deref()).await?;
+
+    ds_request = ds_request.body(body_bytes);
+
+    // Use select! to handle request cancellation
+    let ds_response = select! {
+        response = ds_request.send() => {
+            response.map_err(|e| {
+                let err_msg = format!(
+                    "Failed to forward the request to the downstream server: {e}"
+                );
+                dual_error!("{err_msg}");
+                ServerError::Operation(err_msg)
+            })?
         }
-        if let Some(ref model) = server.embedding_model {
-            embedding_models.push(model.clone());
+        _ = cancel_token.cancelled() => {
+            let warn_msg = "Request was cancelled by client";
+            dual_warn!("{}", warn_msg);
+            return Err(ServerError::Operation(warn_msg.to_string()));
         }
-        if let Some(ref model) = server.image_model {
-            image_models.push(model.clone());
+    };
+
+    let status = ds_response.status();
+
+    // Handle response body reading with cancellation
+    let bytes = select! {
+        bytes = ds_response.bytes() => {
+            bytes.map_err(|e| {
+                let err_msg = format!("Failed to get the full response as bytes: {e}");
+                dual_error!("{err_msg}");
+                ServerError::Operation(err_msg)
+            })?
         }
-        if let Some(ref model) = server.tts_model {
-            tts_models.push(model.clone());
+        _ = cancel_token.cancelled() => {
+            let warn_msg = "Request was cancelled while reading response";
+            dual_warn!("{}", warn_msg);
+            return Err(ServerError::Operation(warn_msg.to_string()));
         }
-        if let Some(ref model) = server.translate_model {
-            translate_models.push(model.clone());
+    };
+
+    match Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(bytes))
+    {
+        Ok(response) => {
+            dual_info!("Audio transcription request completed successfully");
+            Ok(response)
         }
-        if let Some(ref model) = server.transcribe_model {
-            transcribe_models.push(model.clone());
+        Err(e) => {
+            let err_msg = format!("Failed to create the response: {e}");
+            dual_error!("{err_msg}");
+            Err(ServerError::Operation(err_msg))
         }
     }
+}
 
-    let json_body = serde_json::json!({
-        "models": {
-            "chat": chat_models,
-            "embedding": embedding_models,
-            "image": image_models,
-            "tts": tts_models,
-            "translate": translate_models,
-            "transcribe": transcribe_models,
-        },
-    });
+pub(crate) async fn audio_translations_handler(
+    State(state): State<Arc<AppState>>,
+    Extension(cancel_token): Extension<CancellationToken>,
+    req: axum::extract::Request<Body>,
+) -> ServerResult<axum::response::Response> {
+    dual_info!("Received a new audio translation request");
 
-    Response::builder()
-        .status(StatusCode::OK)
-        .header("Content-Type", "application/json")
-        .body(Body::from(json_body.to_string()))
-        .map_err(|e| {
-            let err_msg = format!("Failed to create response: {e}");
-            dual_error!("{err_msg} - request_id: {request_id}");
-            ServerError::Operation(err_msg)
-        })
-}
+    // get the transcribe server
+    let translation_server = {
+        let servers = state.server_group.read().await;
+        let translate_servers = server_group_of(&servers, ServerKind::translate).await?;
 
-pub(crate) mod admin {
-    use super::*;
+        match translate_servers.next().await {
+            Ok(target_server_info) => target_server_info,
+            Err(e) => {
+                let err_msg = format!("Failed to get the translate server: {e}");
+                dual_error!("{}", err_msg);
+                return Err(ServerError::Operation(err_msg));
+            }
+        }
+    };
 
-    pub(crate) async fn register_downstream_server_handler(
-        State(state): State<Arc<AppState>>,
-        headers: HeaderMap,
-        Json(mut server): Json<Server>,
-    ) -> ServerResult<axum::response::Response> {
-        // Get request ID from headers
-        let request_id = headers
-            .get("x-request-id")
-            .and_then(|h| h.to_str().ok())
-            .unwrap_or("unknown")
-            .to_string();
+    let translation_server_url = translation_server.endpoint("audio/translations");
+    dual_info!(
+        "Forward the audio translation request to {}",
+        translation_server_url
+    );
 
-        let server_url = server.url.clone();
-        let server_kind = server.kind;
-        let server_id = server.id.clone();
+    // Create request client
+    let mut ds_request = authorize_request(
+        http_client().post(translation_server_url),
+        &translation_server,
+        req.headers(),
+    );
 
-        // verify the server
-        if server_kind.contains(ServerKind::chat)
-            || server_kind.contains(ServerKind::embeddings)
-            || server_kind.contains(ServerKind::image)
-            || server_kind.contains(ServerKind::transcribe)
-            || server_kind.contains(ServerKind::translate)
-            || server_kind.contains(ServerKind::tts)
-        {
-            dual_warn!(
-                "Ignore the server verification for: {server_id} - request_id: {request_id}"
-            );
-            // _verify_server(State(state.clone()), &headers, &request_id, &server).await?;
-        }
+    // convert the request body into bytes
+    let content_type = req.headers().get(CONTENT_TYPE).cloned();
+    let body_bytes = read_request_body(&state, req.into_body()).await?;
 
-        // update the model list
-        update_model_list(State(state.clone()), &headers, &request_id, &server).await?;
+    // reject the models hidden from the clients
+    let model = request_body_model(content_type.as_ref(), &body_bytes).await;
+    check_model_allowed(&state, model.as_deref()).await?;
 
-        // update health status of the server
-        server.health_status.is_healthy = true;
-        server.health_status.last_check = SystemTime::now();
+    ds_request = ds_request.body(body_bytes);
 
-        // register the server
-        state.register_downstream_server(server).await?;
-        dual_info!(
-            "Registered successfully. Assigned Server Id: {} - request_id: {}",
-            server_id,
-            request_id
-        );
+    // Use select! to handle request cancellation
+    let ds_response = select! {
+        response = ds_request.send() => {
+            response.map_err(|e| {
+                let err_msg = format!(
+                    "Failed to forward the request to the downstream server: {e}"
+                );
+                dual_error!("{err_msg}");
+                ServerError::Operation(err_msg)
+            })?
+        }
+        _ = cancel_token.cancelled() => {
+            let warn_msg = "Request was cancelled by client";
+            dual_warn!("{}", warn_msg);
+            return Err(ServerError::Operation(warn_msg.to_string()));
+        }
+    };
 
-        // create a response with status code 200. Content-Type is JSON
-        let json_body = serde_json::json!({
-            "id": server_id,
-            "url": server_url,
-            "kind": server_kind
-        });
+    let status = ds_response.status();
 
-        let response = Response::builder()
-            .status(StatusCode::OK)
-            .header("Content-Type", "application/json")
-            .body(Body::from(json_body.to_string()))
-            .map_err(|e| {
-                let err_msg = format!("Failed to create response: {e}");
-                dual_error!("{err_msg} - request_id: {request_id}");
+    // Handle response body reading with cancellation
+    let bytes = select! {
+        bytes = ds_response.bytes() => {
+            bytes.map_err(|e| {
+                let err_msg = format!("Failed to get the full response as bytes: {e}");
+                dual_error!("{err_msg}");
                 ServerError::Operation(err_msg)
-            })?;
+            })?
+        }
+        _ = cancel_token.cancelled() => {
+            let warn_msg = "Request was cancelled while reading response";
+            dual_warn!("{}", warn_msg);
+            return Err(ServerError::Operation(warn_msg.to_string()));
+        }
+    };
 
-        Ok(response)
+    match Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(bytes))
+    {
+        Ok(response) => {
+            dual_info!("Audio translation request completed successfully");
+            Ok(response)
+        }
+        Err(e) => {
+            let err_msg = format!("Failed to create the response: {e}");
+            dual_error!("{err_msg}");
+            Err(ServerError::Operation(err_msg))
+        }
     }
+}
 
-    // verify the server and get the server info and model list
-    async fn _verify_server(
-        State(state): State<Arc<AppState>>,
-        headers: &HeaderMap,
-        request_id: impl AsRef<str>,
-        server: &Server,
-    ) -> ServerResult<()> {
-        let request_id = request_id.as_ref();
-        let server_url = &server.url;
-        let server_id = &server.id;
-        let server_kind = server.kind;
+pub(crate) async fn audio_tts_handler(
+    State(state): State<Arc<AppState>>,
+    Extension(cancel_token): Extension<CancellationToken>,
+    req: axum::extract::Request<Body>,
+) -> ServerResult<axum::response::Response> {
+    dual_info!("Received a new audio speech request");
 
-        let server_info_url = format!("{server_url}/info");
+    // get the tts server
+    let tts_server = {
+        let servers = state.server_group.read().await;
+        let tts_servers = server_group_of(&servers, ServerKind::tts).await?;
 
-        let client = reqwest::Client::new();
-        let response = if let Some(api_key) = &server.api_key
-            && !api_key.is_empty()
-        {
-            client
-                .get(&server_info_url)
-                .header(CONTENT_TYPE, "application/json")
-                .header(AUTHORIZATION, api_key)
-                .send()
-                .await
-                .map_err(|e| {
-                    let err_msg =
-                        format!("Failed to verify the {server_kind} downstream server: {e}",);
-                    dual_error!("{err_msg} - request_id: {request_id}");
-                    ServerError::Operation(err_msg)
-                })?
-        } else if headers.contains_key("authorization") {
-            let authorization = headers
-                .get("authorization")
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_string();
+        match tts_servers.next().await {
+            Ok(target_server_info) => target_server_info,
+            Err(e) => {
+                let err_msg = format!("Failed to get the tts server: {e}");
+                dual_error!("{}", err_msg);
+                return Err(ServerError::Operation(err_msg));
+            }
+        }
+    };
 
-            client
-                .get(&server_info_url)
-                .header(CONTENT_TYPE, "application/json")
-                .header(AUTHORIZATION, authorization)
-                .send()
-                .await
-                .map_err(|e| {
-                    let err_msg =
-                        format!("Failed to verify the {server_kind} downstream server: {e}",);
-                    dual_error!("{err_msg} - request_id: {request_id}");
-                    ServerError::Operation(err_msg)
-                })?
-        } else {
-            client.get(&server_info_url).send().await.map_err(|e| {
-                let err_msg = format!("Failed to verify the {server_kind} downstream server: {e}",);
-                dual_error!("{err_msg} - request_id: {request_id}");
+    let tts_server_url = tts_server.endpoint("audio/speech");
+    dual_info!("Forward the audio speech request to {}", tts_server_url);
+
+    // Create request client
+    let mut ds_request = authorize_request(
+        http_client().post(tts_server_url),
+        &tts_server,
+        req.headers(),
+    );
+
+    let content_type = req.headers().get(CONTENT_TYPE).cloned();
+    let body_bytes = read_request_body(&state, req.into_body()).await?;
+
+    // reject the models hidden from the clients
+    let model = request_body_model(content_type.as_ref(), &body_bytes).await;
+    check_model_allowed(&state, model.as_deref()).await?;
+
+    // reject the invalid requests before they reach the tts server
+    let tts_response_formats = state
+        .config
+        .read()
+        .await
+        .server
+        .tts_response_formats
+        .clone();
+    validate_tts_request(&body_bytes, &tts_response_formats)?;
+
+    ds_request = ds_request.body(body_bytes);
+
+    // Use select! to handle request cancellation
+    let ds_response = select! {
+        response = ds_request.send() => {
+            response.map_err(|e| {
+                let err_msg = format!(
+                    "Failed to forward the request to the downstream server: {e}"
+                );
+                dual_error!("{err_msg}");
                 ServerError::Operation(err_msg)
             })?
-        };
-        if !response.status().is_success() {
-            let err_msg = format!(
-                "Failed to verify the {} downstream server: {}",
-                server_kind,
-                response.status()
-            );
-            dual_error!("{} - request_id: {}", err_msg, request_id);
-            return Err(ServerError::Operation(err_msg));
         }
+        _ = cancel_token.cancelled() => {
+            let warn_msg = "Request was cancelled by client";
+            dual_warn!("{}", warn_msg);
+            return Err(ServerError::Operation(warn_msg.to_string()));
+        }
+    };
 
-        let mut api_server = response.json::<ApiServer>().await.map_err(|e| {
-            let err_msg = format!("Failed to parse the server info: {e}");
-            dual_error!("{err_msg} - request_id: {request_id}");
-            ServerError::Operation(err_msg)
-        })?;
-        api_server.server_id = Some(server_id.to_string());
+    // create a response builder with the status and headers of the downstream response
+    let mut response_builder = Response::builder().status(ds_response.status());
+    for (name, value) in ds_response.headers().iter() {
+        response_builder = response_builder.header(name, value);
+    }
 
-        dual_debug!("server kind: {}", server_kind.to_string());
-        dual_debug!("api server: {:?}", api_server);
+    // Handle response body reading with cancellation
+    let bytes = select! {
+        bytes = ds_response.bytes() => {
+            bytes.map_err(|e| {
+                let err_msg = format!("Failed to get the full response as bytes: {e}");
+                dual_error!("{err_msg}");
+                ServerError::Operation(err_msg)
+            })?
+        }
+        _ = cancel_token.cancelled() => {
+            let warn_msg = "Request was cancelled while reading response";
+            dual_warn!("{}", warn_msg);
+            return Err(ServerError::Operation(warn_msg.to_string()));
+        }
+    };
 
-        // verify the server kind
-        {
-            if server_kind.contains(ServerKind::chat) && api_server.chat_model.is_none() {
-                let err_msg = "You are trying to register a chat server. However, the server does not support `chat`. Please check the server kind.";
-                dual_error!("{} - request_id: {}", err_msg, request_id);
-                return Err(ServerError::Operation(err_msg.to_string()));
-            }
-            if server_kind.contains(ServerKind::embeddings) && api_server.embedding_model.is_none()
-            {
-                let err_msg = "You are trying to register an embedding server. However, the server does not support `embeddings`. Please check the server kind.";
-                dual_error!("{} - request_id: {}", err_msg, request_id);
-                return Err(ServerError::Operation(err_msg.to_string()));
-            }
-            if server_kind.contains(ServerKind::image) && api_server.image_model.is_none() {
-                let err_msg = "You are trying to register an image server. However, the server does not support `image`. Please check the server kind.";
-                dual_error!("{} - request_id: {}", err_msg, request_id);
-                return Err(ServerError::Operation(err_msg.to_string()));
-            }
-            if server_kind.contains(ServerKind::tts) && api_server.tts_model.is_none() {
-                let err_msg = "You are trying to register a TTS server. However, the server does not support `tts`. Please check the server kind.";
-                dual_error!("{} - request_id: {}", err_msg, request_id);
-                return Err(ServerError::Operation(err_msg.to_string()));
-            }
-            if server_kind.contains(ServerKind::translate) && api_server.translate_model.is_none() {
-                let err_msg = "You are trying to register a translation server. However, the server does not support `translate`. Please check the server kind.";
-                dual_error!("{} - request_id: {}", err_msg, request_id);
-                return Err(ServerError::Operation(err_msg.to_string()));
-            }
-            if server_kind.contains(ServerKind::transcribe) && api_server.transcribe_model.is_none()
-            {
-                let err_msg = "You are trying to register a transcription server. However, the server does not support `transcribe`. Please check the server kind.";
-                dual_error!("{} - request_id: {}", err_msg, request_id);
-                return Err(ServerError::Operation(err_msg.to_string()));
-            }
+    match response_builder.body(Body::from(bytes)) {
+        Ok(response) => {
+            dual_info!("Audio speech request completed successfully");
+            Ok(response)
+        }
+        Err(e) => {
+            let err_msg = format!("Failed to create the response: {e}");
+            dual_error!("{err_msg}");
+            Err(ServerError::Operation(err_msg))
         }
+    }
+}
 
-        // update the server info
-        let server_info = &mut state.server_info.write().await;
-        server_info
-            .servers
-            .insert(server_id.to_string(), api_server);
+/// Check the required fields and the `response_format` of a speech request
+fn validate_tts_request(body: &[u8], tts_response_formats: &[String]) -> ServerResult<()> {
+    let request: serde_json::Value = serde_json::from_slice(body).map_err(|e| {
+        let err_msg = format!("Failed to parse the audio speech request: {e}");
+        dual_error!("{err_msg}");
+        ServerError::BadRequest(err_msg)
+    })?;
 
-        Ok(())
+    for field in ["model", "input", "voice"] {
+        match request.get(field).and_then(|value| value.as_str()) {
+            Some(value) if !value.trim().is_empty() => {}
+            _ => {
+                let err_msg = format!("The audio speech request requires a non-empty `{field}`");
+                dual_error!("{err_msg}");
+                return Err(ServerError::BadRequest(err_msg));
+            }
+        }
     }
 
-    // update the model list
-    pub(crate) async fn update_model_list(
-        State(state): State<Arc<AppState>>,
-        headers: &HeaderMap,
-        request_id: impl AsRef<str>,
-        server: &Server,
-    ) -> ServerResult<()> {
-        let request_id = request_id.as_ref();
-        let server_url = &server.url;
-        let server_id = &server.id;
+    if let Some(format) = request.get("response_format")
+        && !format
+            .as_str()
+            .is_some_and(|format| tts_response_formats.iter().any(|f| f == format))
+    {
+        let err_msg = format!(
+            "Unsupported `response_format` {format} of the audio speech request, expected one of: {}",
+            tts_response_formats.join(", ")
+        );
+        dual_error!("{err_msg}");
+        return Err(ServerError::BadRequest(err_msg));
+    }
 
-        // get the models from the downstream server
-        let list_models_url = format!("{server_url}/models");
-        dual_debug!("list_models_url: {}", list_models_url);
-        let response = if let Some(api_key) = &server.api_key
-            && !api_key.is_empty()
-        {
-            reqwest::Client::new()
-                .get(&list_models_url)
-                .header(CONTENT_TYPE, "application/json")
-                .header(AUTHORIZATION, api_key)
-                .send()
-                .await
-                .map_err(|e| {
-                    let err_msg =
-                        format!("Failed to get the models from the downstream server: {e}");
-                    dual_error!("{err_msg} - request_id: {request_id}");
-                    ServerError::Operation(err_msg)
-                })?
-        } else if headers.contains_key("authorization") {
-            let authorization = headers
-                .get("authorization")
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_string();
-            reqwest::Client::new()
-                .get(&list_models_url)
-                .header(CONTENT_TYPE, "application/json")
-                .header(AUTHORIZATION, authorization)
-                .send()
-                .await
-                .map_err(|e| {
-                    let err_msg =
-                        format!("Failed to get the models from the downstream server: {e}");
-                    dual_error!("{err_msg} - request_id: {request_id}");
-                    ServerError::Operation(err_msg)
-                })?
-        } else {
-            reqwest::Client::new()
-                .get(&list_models_url)
-                .send()
-                .await
-                .map_err(|e| {
-                    let err_msg =
-                        format!("Failed to get the models from the downstream server: {e}");
-                    dual_error!("{err_msg} - request_id: {request_id}");
-                    ServerError::Operation(err_msg)
-                })?
-        };
-        let status = response.status();
-        if !status.is_success() {
-            let err_msg =
-                format!("Status: {status}. Failed to get model info from {list_models_url}.",);
-            dual_error!("{} - request_id: {}", err_msg, request_id);
-            return Err(ServerError::Operation(err_msg));
-        }
+    Ok(())
+}
 
-        match server_url.as_str() {
-            "https://openrouter.ai/api/v1" => {
-                let list_models_response =
-                    response.json::<serde_json::Value>().await.map_err(|e| {
-                        let err_msg =
-                            format!("Failed to get the models from {list_models_url}: {e}");
-                        dual_error!("{err_msg} - request_id: {request_id}");
-                        ServerError::Operation(err_msg)
-                    })?;
+#[test]
+fn test_validate_tts_request() {
+    let formats = vec!["mp3".to_string(), "wav".to_string()];
+
+    let body = br#"{"model": "tts-1", "input": "Hello", "voice": "alloy"}"#;
+    assert!(validate_tts_request(body, &formats).is_ok());
+    let body =
+        br#"{"model": "tts-1", "input": "Hello", "voice": "alloy", "response_format": "wav"}"#;
+    assert!(validate_tts_request(body, &formats).is_ok());
+
+    let body = br#"{"model": "tts-1", "voice": "alloy"}"#;
+    assert!(matches!(
+        validate_tts_request(body, &formats),
+        Err(ServerError::BadRequest(msg)) if msg.contains("`input`")
+    ));
+    let body =
+        br#"{"model": "tts-1", "input": "Hello", "voice": "alloy", "response_format": "ogg"}"#;
+    assert!(matches!(
+        validate_tts_request(body, &formats),
+        Err(ServerError::BadRequest(msg)) if msg.contains("mp3, wav")
+    ));
+    assert!(validate_tts_request(b"not json", &formats).is_err());
+}
 
-                match list_models_response.get("data") {
-                    Some(data) => {
-                        // get `id` field from each model
-                        let models = data.as_array().unwrap();
-                        let model_info_vec = models
-                            .iter()
-                            .map(|model| {
-                                let id = model.get("id").unwrap().as_str().unwrap();
-                                let created = model.get("created").unwrap().as_u64().unwrap();
-                                Model {
-                                    id: id.to_string(),
-                                    created,
-                                    object: "model".to_string(),
-                                    owned_by: "openrouter.ai".to_string(),
-                                }
-                            })
-                            .collect::<Vec<Model>>();
+pub(crate) async fn image_handler(
+    State(state): State<Arc<AppState>>,
+    Extension(cancel_token): Extension<CancellationToken>,
+    req: axum::extract::Request<Body>,
+) -> ServerResult<axum::response::Response> {
+    let endpoint = image_endpoint(req.uri().path());
+    dual_info!("Received a new image request for {endpoint}");
 
-                        // update the models
-                        let mut models = state.models.write().await;
-                        models.insert(server_id.to_string(), model_info_vec);
-                    }
-                    None => {
-                        let err_msg = format!(
-                            "Failed to get the models from {list_models_url}. Not found `data` field in the response."
-                        );
-                        dual_error!("{err_msg} - request_id: {request_id}");
-                        return Err(ServerError::Operation(err_msg.to_string()));
-                    }
-                }
-            }
-            _ => {
-                let list_models_response =
-                    response.json::<ListModelsResponse>().await.map_err(|e| {
-                        let err_msg =
-                            format!("Failed to get the models from {list_models_url}: {e}");
-                        dual_error!("{err_msg} - request_id: {request_id}");
-                        ServerError::Operation(err_msg)
-                    })?;
+    // get the image server
+    let image_server = {
+        let servers = state.server_group.read().await;
+        let image_servers = server_group_of(&servers, ServerKind::image).await?;
 
-                // update the models
-                let mut models = state.models.write().await;
-                models.insert(server_id.to_string(), list_models_response.data);
+        match image_servers.next().await {
+            Ok(target_server_info) => target_server_info,
+            Err(e) => {
+                let err_msg = format!("Failed to get the image server: {e}");
+                dual_error!("{}", err_msg);
+                return Err(ServerError::Operation(err_msg));
             }
         }
+    };
+
+    let image_server_url = image_server.endpoint(endpoint);
+    dual_info!("Forward the image request to {}", image_server_url);
+
+    // Create request client
+    let mut ds_request = authorize_request(
+        http_client().post(image_server_url),
+        &image_server,
+        req.headers(),
+    );
+
+    // convert the request body into bytes, which keeps the multipart body of the edits and the
+    // variations as it is
+    let content_type = req.headers().get(CONTENT_TYPE).cloned();
+    let body_bytes = read_request_body(&state, req.into_body()).await?;
+
+    // reject the models hidden from the clients
+    let model = request_body_model(content_type.as_ref(), &body_bytes).await;
+    check_model_allowed(&state, model.as_deref()).await?;
+
+    ds_request = ds_request.body(body_bytes);
+
+    // Use select! to handle request cancellation
+    let ds_response = select! {
+        response = ds_request.send() => {
+            response.map_err(|e| {
+                let err_msg = format!(
+                    "Failed to forward the request to the downstream server: {e}"
+                );
+                dual_error!("{err_msg}");
+                ServerError::Operation(err_msg)
+            })?
+        }
+        _ = cancel_token.cancelled() => {
+            let warn_msg = "Request was cancelled by client";
+            dual_warn!("{}", warn_msg);
+            return Err(ServerError::Operation(warn_msg.to_string()));
+        }
+    };
+
+    // create a response builder with the status and headers of the downstream response
+    let mut response_builder = Response::builder().status(ds_response.status());
+    for (name, value) in ds_response.headers().iter() {
+        response_builder = response_builder.header(name, value);
+    }
+
+    // Handle response body reading with cancellation
+    let bytes = select! {
+        bytes = ds_response.bytes() => {
+            bytes.map_err(|e| {
+                let err_msg = format!("Failed to get the full response as bytes: {e}");
+                dual_error!("{err_msg}");
+                ServerError::Operation(err_msg)
+            })?
+        }
+        _ = cancel_token.cancelled() => {
+            let warn_msg = "Request was cancelled while reading response";
+            dual_warn!("{}", warn_msg);
+            return Err(ServerError::Operation(warn_msg.to_string()));
+        }
+    };
+
+    match response_builder.body(Body::from(bytes)) {
+        Ok(response) => {
+            dual_info!("Image request completed successfully");
+            Ok(response)
+        }
+        Err(e) => {
+            let err_msg = format!("Failed to create the response: {e}");
+            dual_error!("{err_msg}");
+            Err(ServerError::Operation(err_msg))
+        }
+    }
+}
+
+/// The downstream endpoint of an image request, `images/edits`, `images/variations` or
+/// `images/generations`
+fn image_endpoint(path: &str) -> &'static str {
+    let path = path.trim_end_matches('/');
+    if path.ends_with("/images/edits") {
+        "images/edits"
+    } else if path.ends_with("/images/variations") {
+        "images/variations"
+    } else {
+        "images/generations"
+    }
+}
+
+#[test]
+fn test_image_endpoint() {
+    assert_eq!(
+        image_endpoint("/v1/images/generations"),
+        "images/generations"
+    );
+    assert_eq!(image_endpoint("/v1/images/edits"), "images/edits");
+    assert_eq!(image_endpoint("/v1/images/edits/"), "images/edits");
+    assert_eq!(image_endpoint("/v1/images/variations"), "images/variations");
+}
+
+pub(crate) async fn models_handler(
+    State(state): State<Arc<AppState>>,
+) -> ServerResult<axum::response::Response> {
+    let allowed_models = state.config.read().await.server.allowed_models.clone();
+    let list_response = ListModelsResponse {
+        object: String::from("list"),
+        data: unique_models(&*state.models.read().await)
+            .into_iter()
+            .filter(|model| is_model_allowed(&allowed_models, &model.id))
+            .collect(),
+    };
+
+    let json_body = serde_json::to_string(&list_response).map_err(|e| {
+        let err_msg = format!("Failed to serialize the models: {e}");
+        dual_error!("{err_msg}");
+        ServerError::Operation(err_msg)
+    })?;
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(json_body))
+        .map_err(|e| {
+            let err_msg = format!("Failed to create response: {e}");
+            dual_error!("{err_msg}");
+            ServerError::Operation(err_msg)
+        })
+}
+
+/// List the MCP tools offered to the model in the chat requests, as they are injected, along with
+/// the MCP server of each tool
+pub(crate) async fn tools_handler(
+    State(state): State<Arc<AppState>>,
+) -> ServerResult<axum::response::Response> {
+    let config = state.config.read().await;
+    let data = match config.mcp.as_ref() {
+        Some(mcp_config) => mcp_tools_by_server(&mcp_config.server.tool_servers, None)
+            .into_iter()
+            .map(|(server_name, tool)| {
+                let mut tool = serde_json::to_value(tool).unwrap_or_default();
+                tool["mcp_server"] = server_name.into();
+                tool
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+
+    let json_body = serde_json::json!({
+        "object": "list",
+        "data": data,
+    });
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(json_body.to_string()))
+        .map_err(|e| {
+            let err_msg = format!("Failed to create response: {e}");
+            dual_error!("{err_msg}");
+            ServerError::Operation(err_msg)
+        })
+}
+
+/// List the models of all servers, keeping a single entry for the models served by multiple servers
+fn unique_models(models: &HashMap<ServerId, Vec<Model>>) -> Vec<Model> {
+    // sort the servers so that the entry kept for a model does not depend on the map order
+    let mut server_ids = models.keys().collect::<Vec<_>>();
+    server_ids.sort();
+
+    let mut model_ids = HashSet::new();
+    server_ids
+        .into_iter()
+        .flat_map(|server_id| models[server_id].iter())
+        .filter(|model| model_ids.insert(model.id.as_str()))
+        .cloned()
+        .collect()
+}
+
+#[test]
+fn test_unique_models() {
+    let new_model = |id: &str| Model {
+        id: id.to_string(),
+        created: 0,
+        object: "model".to_string(),
+        owned_by: "Not specified".to_string(),
+    };
+    let models = HashMap::from([
+        (
+            "chat-server-1".to_string(),
+            vec![new_model("llama"), new_model("qwen")],
+        ),
+        ("chat-server-2".to_string(), vec![new_model("llama")]),
+        (
+            "chat-server-3".to_string(),
+            vec![new_model("llama"), new_model("gemma")],
+        ),
+    ]);
+
+    let model_ids = unique_models(&models)
+        .into_iter()
+        .map(|model| model.id)
+        .collect::<Vec<_>>();
+    assert_eq!(model_ids, ["llama", "qwen", "gemma"]);
+}
+
+/// Whether the clients may use the model. All models are allowed if the list is empty.
+fn is_model_allowed(allowed_models: &[String], model: &str) -> bool {
+    allowed_models.is_empty() || allowed_models.iter().any(|allowed| allowed == model)
+}
+
+/// Reject the request for a model not in the `allowed_models` of the config, or without a model if
+/// the models are restricted
+async fn check_model_allowed(state: &AppState, model: Option<&str>) -> ServerResult<()> {
+    let config = state.config.read().await;
+    let allowed_models = &config.server.allowed_models;
+    if allowed_models.is_empty() {
+        return Ok(());
+    }
+
+    let err_msg = match model.filter(|model| !model.is_empty()) {
+        Some(model) if is_model_allowed(allowed_models, model) => return Ok(()),
+        Some(model) => format!("The model is not allowed: {model}"),
+        None => "The request should set one of the allowed models".to_string(),
+    };
+    dual_error!("{}", err_msg);
+    Err(ServerError::Forbidden(err_msg))
+}
+
+/// The `model` field of a JSON or a multipart request body
+async fn request_body_model(content_type: Option<&HeaderValue>, body: &Bytes) -> Option<String> {
+    let content_type = content_type.and_then(|value| value.to_str().ok());
+    if content_type.is_some_and(|content_type| content_type.starts_with("multipart/form-data")) {
+        // only the `model` form field is read, the files are skipped
+        let boundary = multer::parse_boundary(content_type?).ok()?;
+        let body =
+            futures_util::stream::once(std::future::ready(Ok::<_, std::io::Error>(body.clone())));
+        let mut multipart = multer::Multipart::new(body, boundary);
+        while let Some(field) = multipart.next_field().await.ok()? {
+            if field.name() == Some("model") && field.file_name().is_none() {
+                return field.text().await.ok();
+            }
+        }
+        return None;
+    }
+
+    serde_json::from_slice::<serde_json::Value>(body)
+        .ok()?
+        .get("model")?
+        .as_str()
+        .map(String::from)
+}
+
+#[tokio::test]
+async fn test_check_model_allowed() {
+    let mut config = crate::config::Config::default();
+    config.server.allowed_models = vec!["llama".to_string()];
+    let state = test_state_with(config);
+
+    assert!(check_model_allowed(&state, Some("llama")).await.is_ok());
+    let err = check_model_allowed(&state, Some("gpt-internal"))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ServerError::Forbidden(_)));
+    assert!(check_model_allowed(&state, None).await.is_err());
+    assert!(check_model_allowed(&state, Some("")).await.is_err());
+
+    // all models are allowed by default
+    let state = test_state();
+    assert!(
+        check_model_allowed(&state, Some("gpt-internal"))
+            .await
+            .is_ok()
+    );
+    assert!(check_model_allowed(&state, None).await.is_ok());
+}
+
+#[tokio::test]
+async fn test_request_body_model() {
+    let json = HeaderValue::from_static("application/json");
+    let body = Bytes::from_static(br#"{"model": "tts-1", "input": "Hi"}"#);
+    assert_eq!(
+        request_body_model(Some(&json), &body).await.as_deref(),
+        Some("tts-1")
+    );
+    let body = Bytes::from_static(br#"{"input": "Hi"}"#);
+    assert_eq!(request_body_model(Some(&json), &body).await, None);
+
+    let multipart = HeaderValue::from_static("multipart/form-data; boundary=xyz");
+    let body = Bytes::from_static(b"--xyz\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.wav\"\r\n\r\nRIFF\r\n--xyz\r\nContent-Disposition: form-data; name=\"model\"\r\n\r\nwhisper-1\r\n--xyz--\r\n");
+    assert_eq!(
+        request_body_model(Some(&multipart), &body).await.as_deref(),
+        Some("whisper-1")
+    );
+    let body = Bytes::from_static(b"--xyz\r\n--xyz--\r\n");
+    assert_eq!(request_body_model(Some(&multipart), &body).await, None);
+
+    // neither a file named `model` nor the content of the files is taken for the field
+    let body = Bytes::from_static(b"--xyz\r\nContent-Disposition: form-data; name=\"file\"; filename=\"model\"\r\n\r\nname=\"model\"\r\n\r\nwhisper-2\r\n--xyz\r\nContent-Disposition: form-data; name=\"model\"\r\n\r\nwhisper-1\r\n--xyz--\r\n");
+    assert_eq!(
+        request_body_model(Some(&multipart), &body).await.as_deref(),
+        Some("whisper-1")
+    );
+}
+
+pub(crate) async fn model_handler(
+    State(state): State<Arc<AppState>>,
+    Path(model_id): Path<String>,
+) -> ServerResult<axum::response::Response> {
+    // the same model may be served by multiple servers, so return the first match. The models
+    // hidden from the clients are not found.
+    let allowed_models = state.config.read().await.server.allowed_models.clone();
+    let model = state
+        .models
+        .read()
+        .await
+        .values()
+        .flatten()
+        .find(|model| model.id == model_id && is_model_allowed(&allowed_models, &model.id))
+        .cloned()
+        .ok_or_else(|| {
+            dual_error!("Not found model: {model_id}");
+            ServerError::NotFoundModel {
+                model: model_id.clone(),
+                candidates: None,
+            }
+        })?;
+
+    let json_body = serde_json::to_string(&model).map_err(|e| {
+        let err_msg = format!("Failed to serialize the model: {e}");
+        dual_error!("{err_msg}");
+        ServerError::Operation(err_msg)
+    })?;
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(json_body))
+        .map_err(|e| {
+            let err_msg = format!("Failed to create response: {e}");
+            dual_error!("{err_msg}");
+            ServerError::Operation(err_msg)
+        })
+}
+
+pub(crate) async fn info_handler(
+    State(state): State<Arc<AppState>>,
+) -> ServerResult<axum::response::Response> {
+    let mut chat_models = vec![];
+    let mut embedding_models = vec![];
+    let mut image_models = vec![];
+    let mut tts_models = vec![];
+    let mut translate_models = vec![];
+    let mut transcribe_models = vec![];
+    let server_info = state.server_info.read().await;
+    for server in server_info.servers.values() {
+        if let Some(ref model) = server.chat_model {
+            chat_models.push(model.clone());
+        }
+        if let Some(ref model) = server.embedding_model {
+            embedding_models.push(model.clone());
+        }
+        if let Some(ref model) = server.image_model {
+            image_models.push(model.clone());
+        }
+        if let Some(ref model) = server.tts_model {
+            tts_models.push(model.clone());
+        }
+        if let Some(ref model) = server.translate_model {
+            translate_models.push(model.clone());
+        }
+        if let Some(ref model) = server.transcribe_model {
+            transcribe_models.push(model.clone());
+        }
+    }
+
+    let json_body = serde_json::json!({
+        "models": {
+            "chat": chat_models,
+            "embedding": embedding_models,
+            "image": image_models,
+            "tts": tts_models,
+            "translate": translate_models,
+            "transcribe": transcribe_models,
+        },
+    });
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(json_body.to_string()))
+        .map_err(|e| {
+            let err_msg = format!("Failed to create response: {e}");
+            dual_error!("{err_msg}");
+            ServerError::Operation(err_msg)
+        })
+}
+
+pub(crate) mod admin {
+    use super::*;
+
+    pub(crate) async fn register_downstream_server_handler(
+        State(state): State<Arc<AppState>>,
+        headers: HeaderMap,
+        Json(mut server): Json<Server>,
+    ) -> ServerResult<axum::response::Response> {
+        let server_url = server.url.clone();
+        let server_kind = server.kind;
+        let server_id = server.id.clone();
+
+        // a server without any kind would never be routed to
+        if server_kind.is_empty() {
+            let err_msg = "The `kind` of the server should not be empty. Possible kinds: chat, embeddings, image, tts, translate, transcribe, rerank.";
+            dual_error!("{}", err_msg);
+            return Err(ServerError::BadRequest(err_msg.to_string()));
+        }
+
+        // verify the server
+        if server_kind.contains(ServerKind::chat)
+            || server_kind.contains(ServerKind::embeddings)
+            || server_kind.contains(ServerKind::image)
+            || server_kind.contains(ServerKind::transcribe)
+            || server_kind.contains(ServerKind::translate)
+            || server_kind.contains(ServerKind::tts)
+            || server_kind.contains(ServerKind::rerank)
+        {
+            dual_warn!("Ignore the server verification for: {server_id}");
+            // _verify_server(State(state.clone()), &headers, &server).await?;
+        }
+
+        // update health status of the server
+        server.health_status.is_healthy = true;
+        server.health_status.last_check = SystemTime::now();
+
+        // get the model list before changing the registry, so that a server whose models are not
+        // available neither replaces a registered server nor is routed to
+        let models = fetch_model_list(&headers, &server).await?;
+
+        // register the server, and then its models
+        state.register_downstream_server(server).await?;
+        state
+            .models
+            .write()
+            .await
+            .insert(server_id.to_string(), models);
+        dual_info!("Registered successfully. Assigned Server Id: {}", server_id);
+
+        // create a response with status code 200. Content-Type is JSON
+        let json_body = serde_json::json!({
+            "id": server_id,
+            "url": server_url,
+            "kind": server_kind
+        });
+
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Body::from(json_body.to_string()))
+            .map_err(|e| {
+                let err_msg = format!("Failed to create response: {e}");
+                dual_error!("{err_msg}");
+                ServerError::Operation(err_msg)
+            })?;
+
+        Ok(response)
+    }
+
+    #[tokio::test]
+    async fn test_register_server_without_kind() {
+        use axum::response::IntoResponse;
+
+        let state = test_state();
+        let server: Server =
+            serde_json::from_str(r#"{"url": "http://localhost:8000/v1", "kind": ""}"#).unwrap();
+        assert!(server.kind.is_empty());
+
+        let err = register_downstream_server_handler(
+            State(state.clone()),
+            HeaderMap::new(),
+            Json(server),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.into_response().status(), StatusCode::BAD_REQUEST);
+        assert!(state.server_group.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_register_server_with_id() {
+        let state = test_state();
+        let server = |kind: &str| -> Server {
+            serde_json::from_str(&format!(
+                r#"{{"id": "prod-1", "url": "http://localhost:8000/v1", "kind": "{kind}"}}"#
+            ))
+            .unwrap()
+        };
+
+        state
+            .register_downstream_server(server("chat"))
+            .await
+            .unwrap();
+        // registering the same id again replaces the server, even with other kinds
+        state
+            .register_downstream_server(server("embeddings"))
+            .await
+            .unwrap();
+        let servers = state.list_downstream_servers().await.unwrap();
+        assert!(!servers.contains_key(&ServerKind::chat));
+        assert_eq!(servers[&ServerKind::embeddings].len(), 1);
+        assert_eq!(servers[&ServerKind::embeddings][0].id, "prod-1");
+
+        state.unregister_downstream_server("prod-1").await.unwrap();
+        assert!(state.list_downstream_servers().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_register_server_without_models() {
+        let state = test_state();
+        let registered: Server = serde_json::from_str(
+            r#"{"id": "prod-1", "url": "http://localhost:8000/v1", "kind": "chat"}"#,
+        )
+        .unwrap();
+        state.register_downstream_server(registered).await.unwrap();
+        state.models.write().await.insert(
+            "prod-1".to_string(),
+            vec![Model {
+                id: "Llama-3.2-3b".to_string(),
+                created: 0,
+                object: "model".to_string(),
+                owned_by: "Not specified".to_string(),
+            }],
+        );
+
+        // nothing listens on the port, so the models of the server are not available
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let server: Server = serde_json::from_str(&format!(
+            r#"{{"id": "prod-1", "url": "http://{addr}/v1", "kind": "chat"}}"#
+        ))
+        .unwrap();
+        assert!(
+            register_downstream_server_handler(
+                State(state.clone()),
+                HeaderMap::new(),
+                Json(server)
+            )
+            .await
+            .is_err()
+        );
+
+        // the registered server of the same id is left as it was
+        let servers = state.list_downstream_servers().await.unwrap();
+        assert_eq!(servers[&ServerKind::chat].len(), 1);
+        assert_eq!(
+            servers[&ServerKind::chat][0].url,
+            "http://localhost:8000/v1"
+        );
+        assert_eq!(state.models.read().await["prod-1"].len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_register_duplicate_server() {
+        use axum::response::IntoResponse;
+
+        let server = |kind: &str| -> Server {
+            serde_json::from_str(&format!(
+                r#"{{"url": "http://localhost:8000/v1", "kind": "{kind}"}}"#
+            ))
+            .unwrap()
+        };
+
+        let state = test_state();
+        state
+            .register_downstream_server(server("chat"))
+            .await
+            .unwrap();
+        let replacement = server("chat");
+        let replacement_id = replacement.id.clone();
+        state.register_downstream_server(replacement).await.unwrap();
+        let servers = state.list_downstream_servers().await.unwrap();
+        assert_eq!(servers[&ServerKind::chat].len(), 1);
+        assert_eq!(servers[&ServerKind::chat][0].id, replacement_id);
+
+        // only the shared kinds are taken over
+        let tts_server = server("embeddings,tts");
+        let tts_server_id = tts_server.id.clone();
+        state.register_downstream_server(tts_server).await.unwrap();
+        state
+            .register_downstream_server(server("chat,embeddings"))
+            .await
+            .unwrap();
+        let servers = state.list_downstream_servers().await.unwrap();
+        assert_eq!(servers[&ServerKind::chat].len(), 1);
+        assert_eq!(servers[&ServerKind::embeddings].len(), 1);
+        assert_eq!(servers[&ServerKind::tts].len(), 1);
+        assert_eq!(servers[&ServerKind::tts][0].id, tts_server_id);
+        assert_eq!(servers[&ServerKind::tts][0].kind, ServerKind::tts);
+
+        let mut config = crate::config::Config::default();
+        config.server.duplicate_servers = crate::config::DuplicateServerPolicy::Reject;
+        let state = test_state_with(config);
+        state
+            .register_downstream_server(server("chat"))
+            .await
+            .unwrap();
+        let err = state
+            .register_downstream_server(server("chat,tts"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.into_response().status(), StatusCode::CONFLICT);
+        // another kind of the same url is not a duplicate
+        state
+            .register_downstream_server(server("tts"))
+            .await
+            .unwrap();
+        let servers = state.list_downstream_servers().await.unwrap();
+        assert_eq!(servers[&ServerKind::chat].len(), 1);
+        assert_eq!(servers[&ServerKind::tts].len(), 1);
+    }
+
+    // verify the server and get the server info and model list
+    async fn _verify_server(
+        State(state): State<Arc<AppState>>,
+        headers: &HeaderMap,
+        server: &Server,
+    ) -> ServerResult<()> {
+        let server_id = &server.id;
+        let server_kind = server.kind;
+
+        let server_info_url = server.endpoint("info");
+
+        let client = http_client();
+        let response = if let Some(api_key) = &server.api_key
+            && !api_key.is_empty()
+        {
+            client
+                .get(&server_info_url)
+                .header(CONTENT_TYPE, "application/json")
+                .header(AUTHORIZATION, api_key)
+                .send()
+                .await
+                .map_err(|e| {
+                    let err_msg =
+                        format!("Failed to verify the {server_kind} downstream server: {e}",);
+                    dual_error!("{err_msg}");
+                    ServerError::Operation(err_msg)
+                })?
+        } else if headers.contains_key("authorization") {
+            let authorization = headers
+                .get("authorization")
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_string();
+
+            client
+                .get(&server_info_url)
+                .header(CONTENT_TYPE, "application/json")
+                .header(AUTHORIZATION, authorization)
+                .send()
+                .await
+                .map_err(|e| {
+                    let err_msg =
+                        format!("Failed to verify the {server_kind} downstream server: {e}",);
+                    dual_error!("{err_msg}");
+                    ServerError::Operation(err_msg)
+                })?
+        } else {
+            client.get(&server_info_url).send().await.map_err(|e| {
+                let err_msg = format!("Failed to verify the {server_kind} downstream server: {e}",);
+                dual_error!("{err_msg}");
+                ServerError::Operation(err_msg)
+            })?
+        };
+        if !response.status().is_success() {
+            let err_msg = format!(
+                "Failed to verify the {} downstream server: {}",
+                server_kind,
+                response.status()
+            );
+            dual_error!("{}", err_msg);
+            return Err(ServerError::Operation(err_msg));
+        }
+
+        let mut api_server = response.json::<ApiServer>().await.map_err(|e| {
+            let err_msg = format!("Failed to parse the server info: {e}");
+            dual_error!("{err_msg}");
+            ServerError::Operation(err_msg)
+        })?;
+        api_server.server_id = Some(server_id.to_string());
+
+        dual_debug!("server kind: {}", server_kind.to_string());
+        dual_debug!("api server: {:?}", api_server);
+
+        // verify the server kind
+        {
+            if server_kind.contains(ServerKind::chat) && api_server.chat_model.is_none() {
+                let err_msg = "You are trying to register a chat server. However, the server does not support `chat`. Please check the server kind.";
+                dual_error!("{}", err_msg);
+                return Err(ServerError::Operation(err_msg.to_string()));
+            }
+            if server_kind.contains(ServerKind::embeddings) && api_server.embedding_model.is_none()
+            {
+                let err_msg = "You are trying to register an embedding server. However, the server does not support `embeddings`. Please check the server kind.";
+                dual_error!("{}", err_msg);
+                return Err(ServerError::Operation(err_msg.to_string()));
+            }
+            if server_kind.contains(ServerKind::image) && api_server.image_model.is_none() {
+                let err_msg = "You are trying to register an image server. However, the server does not support `image`. Please check the server kind.";
+                dual_error!("{}", err_msg);
+                return Err(ServerError::Operation(err_msg.to_string()));
+            }
+            if server_kind.contains(ServerKind::tts) && api_server.tts_model.is_none() {
+                let err_msg = "You are trying to register a TTS server. However, the server does not support `tts`. Please check the server kind.";
+                dual_error!("{}", err_msg);
+                return Err(ServerError::Operation(err_msg.to_string()));
+            }
+            if server_kind.contains(ServerKind::translate) && api_server.translate_model.is_none() {
+                let err_msg = "You are trying to register a translation server. However, the server does not support `translate`. Please check the server kind.";
+                dual_error!("{}", err_msg);
+                return Err(ServerError::Operation(err_msg.to_string()));
+            }
+            if server_kind.contains(ServerKind::transcribe) && api_server.transcribe_model.is_none()
+            {
+                let err_msg = "You are trying to register a transcription server. However, the server does not support `transcribe`. Please check the server kind.";
+                dual_error!("{}", err_msg);
+                return Err(ServerError::Operation(err_msg.to_string()));
+            }
+        }
+
+        // update the server info
+        let server_info = &mut state.server_info.write().await;
+        server_info
+            .servers
+            .insert(server_id.to_string(), api_server);
+
+        Ok(())
+    }
+
+    /// Get the models served by the server
+    pub(crate) async fn fetch_model_list(
+        headers: &HeaderMap,
+        server: &Server,
+    ) -> ServerResult<Vec<Model>> {
+        let server_url = &server.url;
+
+        // the models of an azure server are its deployments, which it does not list
+        if server.provider == Provider::Azure {
+            let created = SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs());
+            let deployments = server
+                .deployments
+                .iter()
+                .map(|deployment| Model {
+                    id: deployment.clone(),
+                    created,
+                    object: "model".to_string(),
+                    owned_by: "azure".to_string(),
+                })
+                .collect();
+            return Ok(deployments);
+        }
+
+        // the models of an anthropic server are listed with its own headers and objects
+        if server.provider == Provider::Anthropic {
+            let list_models_url = server.endpoint("models");
+            let mut ds_request = http_client()
+                .get(&list_models_url)
+                .header("anthropic-version", anthropic::ANTHROPIC_VERSION);
+            if let Some(api_key) = &server.api_key
+                && !api_key.is_empty()
+            {
+                ds_request = ds_request.header(server.provider.api_key_header(), api_key);
+            }
+            let list_models_response = match ds_request
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+            {
+                Ok(response) => response.json::<serde_json::Value>().await,
+                Err(e) => Err(e),
+            }
+            .map_err(|e| {
+                let err_msg = format!("Failed to get the models from {list_models_url}: {e}");
+                dual_error!("{err_msg}");
+                ServerError::Operation(err_msg)
+            })?;
+
+            let created = SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs());
+            let models = list_models_response["data"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|model| model["id"].as_str())
+                .map(|id| Model {
+                    id: id.to_string(),
+                    created,
+                    object: "model".to_string(),
+                    owned_by: "anthropic".to_string(),
+                })
+                .collect();
+            return Ok(models);
+        }
+
+        // get the models from the downstream server
+        let list_models_url = server.endpoint("models");
+        dual_debug!("list_models_url: {}", list_models_url);
+        let response = if let Some(api_key) = &server.api_key
+            && !api_key.is_empty()
+        {
+            http_client()
+                .get(&list_models_url)
+                .header(CONTENT_TYPE, "application/json")
+                .header(AUTHORIZATION, api_key)
+                .send()
+                .await
+                .map_err(|e| {
+                    let err_msg =
+                        format!("Failed to get the models from the downstream server: {e}");
+                    dual_error!("{err_msg}");
+                    ServerError::Operation(err_msg)
+                })?
+        } else if headers.contains_key("authorization") {
+            let authorization = headers
+                .get("authorization")
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_string();
+            http_client()
+                .get(&list_models_url)
+                .header(CONTENT_TYPE, "application/json")
+                .header(AUTHORIZATION, authorization)
+                .send()
+                .await
+                .map_err(|e| {
+                    let err_msg =
+                        format!("Failed to get the models from the downstream server: {e}");
+                    dual_error!("{err_msg}");
+                    ServerError::Operation(err_msg)
+                })?
+        } else {
+            http_client()
+                .get(&list_models_url)
+                .send()
+                .await
+                .map_err(|e| {
+                    let err_msg =
+                        format!("Failed to get the models from the downstream server: {e}");
+                    dual_error!("{err_msg}");
+                    ServerError::Operation(err_msg)
+                })?
+        };
+        let status = response.status();
+        if !status.is_success() {
+            let err_msg =
+                format!("Status: {status}. Failed to get model info from {list_models_url}.",);
+            dual_error!("{}", err_msg);
+            return Err(ServerError::Operation(err_msg));
+        }
+
+        match server_url.as_str() {
+            "https://openrouter.ai/api/v1" => {
+                let list_models_response =
+                    response.json::<serde_json::Value>().await.map_err(|e| {
+                        let err_msg =
+                            format!("Failed to get the models from {list_models_url}: {e}");
+                        dual_error!("{err_msg}");
+                        ServerError::Operation(err_msg)
+                    })?;
+
+                match list_models_response.get("data") {
+                    Some(data) => {
+                        // get `id` field from each model
+                        let models = data.as_array().unwrap();
+                        let model_info_vec = models
+                            .iter()
+                            .map(|model| {
+                                let id = model.get("id").unwrap().as_str().unwrap();
+                                let created = model.get("created").unwrap().as_u64().unwrap();
+                                Model {
+                                    id: id.to_string(),
+                                    created,
+                                    object: "model".to_string(),
+                                    owned_by: "openrouter.ai".to_string(),
+                                }
+                            })
+                            .collect::<Vec<Model>>();
+
+                        Ok(model_info_vec)
+                    }
+                    None => {
+                        let err_msg = format!(
+                            "Failed to get the models from {list_models_url}. Not found `data` field in the response."
+                        );
+                        dual_error!("{err_msg}");
+                        Err(ServerError::Operation(err_msg.to_string()))
+                    }
+                }
+            }
+            _ => {
+                let list_models_response =
+                    response.json::<ListModelsResponse>().await.map_err(|e| {
+                        let err_msg =
+                            format!("Failed to get the models from {list_models_url}: {e}");
+                        dual_error!("{err_msg}");
+                        ServerError::Operation(err_msg)
+                    })?;
+
+                Ok(list_models_response.data)
+            }
+        }
+    }
+
+    pub(crate) async fn remove_downstream_server_handler(
+        State(state): State<Arc<AppState>>,
+        Json(server_id): Json<ServerIdToRemove>,
+    ) -> ServerResult<axum::response::Response> {
+        state
+            .unregister_downstream_server(&server_id.server_id)
+            .await?;
+
+        // create a response with status code 200. Content-Type is JSON
+        let json_body = serde_json::json!({
+            "message": "Server unregistered successfully.",
+            "id": server_id.server_id,
+        });
+
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Body::from(json_body.to_string()))
+            .map_err(|e| {
+                let err_msg = format!("Failed to create response: {e}");
+                dual_error!("{err_msg}");
+                ServerError::Operation(err_msg)
+            })?;
+
+        Ok(response)
+    }
+
+    pub(crate) async fn disable_downstream_server_handler(
+        State(state): State<Arc<AppState>>,
+        Path(server_id): Path<ServerId>,
+    ) -> ServerResult<axum::response::Response> {
+        set_downstream_server_enabled(State(state), server_id, false).await
+    }
+
+    pub(crate) async fn enable_downstream_server_handler(
+        State(state): State<Arc<AppState>>,
+        Path(server_id): Path<ServerId>,
+    ) -> ServerResult<axum::response::Response> {
+        set_downstream_server_enabled(State(state), server_id, true).await
+    }
+
+    async fn set_downstream_server_enabled(
+        State(state): State<Arc<AppState>>,
+        server_id: ServerId,
+        enabled: bool,
+    ) -> ServerResult<axum::response::Response> {
+        state
+            .set_downstream_server_enabled(&server_id, enabled)
+            .await
+            .inspect_err(|e| {
+                dual_error!("{e}");
+            })?;
+
+        // create a response with status code 200. Content-Type is JSON
+        let json_body = serde_json::json!({
+            "message": match enabled {
+                true => "Server enabled successfully.",
+                false => "Server disabled successfully.",
+            },
+            "id": server_id,
+            "enabled": enabled,
+        });
+
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Body::from(json_body.to_string()))
+            .map_err(|e| {
+                let err_msg = format!("Failed to create response: {e}");
+                dual_error!("{err_msg}");
+                ServerError::Operation(err_msg)
+            })?;
+
+        Ok(response)
+    }
+
+    pub(crate) async fn stats_handler(
+        State(state): State<Arc<AppState>>,
+    ) -> ServerResult<axum::response::Response> {
+        // create a response with status code 200. Content-Type is JSON
+        let mut json_body = serde_json::json!({
+            "enabled": state.stats.is_some(),
+            "endpoints": state.stats.as_ref().map(|stats| stats.snapshot()).unwrap_or_default(),
+        });
+        if let (Some(_), Some(request_queue)) = (&state.stats, &state.request_queue) {
+            json_body["queue_depth"] = request_queue.depth().into();
+        }
+
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Body::from(json_body.to_string()))
+            .map_err(|e| {
+                let err_msg = format!("Failed to create response: {e}");
+                dual_error!("{err_msg}");
+                ServerError::Operation(err_msg)
+            })?;
+
+        Ok(response)
+    }
+
+    /// The time window of a usage report, in unix seconds
+    #[derive(Debug, Default, serde::Deserialize)]
+    pub(crate) struct UsageQuery {
+        since: Option<u64>,
+        until: Option<u64>,
+    }
+
+    /// The tokens used in the time window, in total, per user and per model
+    pub(crate) async fn usage_handler(
+        State(state): State<Arc<AppState>>,
+        Query(query): Query<UsageQuery>,
+    ) -> ServerResult<axum::response::Response> {
+        let mut json_body = serde_json::json!({
+            "enabled": state.usage.is_some(),
+        });
+        if let Some(usage) = &state.usage {
+            let report = usage.report(query.since, query.until);
+            json_body["since"] = query.since.into();
+            json_body["until"] = query.until.into();
+            json_body["total"] = serde_json::json!(report.total);
+            json_body["users"] = serde_json::json!(report.users);
+            json_body["other_users"] = serde_json::json!(report.other_users);
+            json_body["models"] = serde_json::json!(report.models);
+        }
+
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Body::from(json_body.to_string()))
+            .map_err(|e| {
+                let err_msg = format!("Failed to create response: {e}");
+                dual_error!("{err_msg}");
+                ServerError::Operation(err_msg)
+            })?;
+
+        Ok(response)
+    }
+
+    /// The config in effect, with the secrets redacted
+    pub(crate) async fn config_handler(
+        State(state): State<Arc<AppState>>,
+    ) -> ServerResult<axum::response::Response> {
+        let config = state.config.read().await.redacted();
+        let json_body = serde_json::to_string(&config).map_err(|e| {
+            let err_msg = format!("Failed to serialize the config: {e}");
+            dual_error!("{err_msg}");
+            ServerError::Operation(err_msg)
+        })?;
+
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Body::from(json_body))
+            .map_err(|e| {
+                let err_msg = format!("Failed to create response: {e}");
+                dual_error!("{err_msg}");
+                ServerError::Operation(err_msg)
+            })?;
+
+        Ok(response)
+    }
+
+    /// The body of a request calling an MCP tool directly
+    #[derive(Debug, serde::Deserialize)]
+    pub(crate) struct CallMcpToolRequest {
+        #[serde(default)]
+        arguments: Option<serde_json::Map<String, serde_json::Value>>,
+    }
+
+    /// Call an MCP tool without a chat completion, e.g. to check the credentials, the arguments
+    /// and the connectivity of the tool
+    pub(crate) async fn call_mcp_tool_handler(
+        Path(tool_name): Path<String>,
+        headers: HeaderMap,
+        Json(request): Json<CallMcpToolRequest>,
+    ) -> ServerResult<axum::response::Response> {
+        // find the mcp client by the tool name
+        let mcp_client_name = match MCP_TOOLS.get() {
+            Some(mcp_tools) => mcp_tools.read().await.get(&tool_name).cloned(),
+            None => None,
+        };
+        let Some(mcp_client_name) = mcp_client_name else {
+            dual_error!("Mcp tool not found: {}", tool_name);
+            return Err(ServerError::McpNotFoundTool(tool_name));
+        };
+        let Some(services) = MCP_SERVICES.get() else {
+            dual_error!("Mcp server not found: {}", mcp_client_name);
+            return Err(ServerError::McpNotFoundClient);
+        };
+        let service_map = services.read().await;
+        let Some(service) = service_map.get(&mcp_client_name) else {
+            dual_error!("Mcp server not found: {}", mcp_client_name);
+            return Err(ServerError::McpNotFoundClient);
+        };
+
+        dual_info!("Call `{}::{}` mcp tool", mcp_client_name, tool_name);
+
+        // call the tool by its name on the server
+        let service = service.read().await;
+        let request_param = CallToolRequestParam {
+            name: service.tool_name_on_server(&tool_name).to_string().into(),
+            arguments: request.arguments,
+        };
+        service.ensure_connected()?;
+        let res = service
+            .call_tool(request_param, request_id(&headers))
+            .await
+            .map_err(|e| {
+                // the call fails with a retryable error if the connection dropped
+                if let Err(reconnecting) = service.ensure_connected() {
+                    return reconnecting;
+                }
+
+                let err_msg = format!("Failed to call the tool {tool_name}: {e}");
+                dual_error!("{}", err_msg);
+                ServerError::McpOperation(err_msg)
+            })?;
+        dual_debug!("{}", serde_json::to_string_pretty(&res).unwrap());
+
+        // create a response with status code 200. Content-Type is JSON
+        let content: Vec<&RawContent> = res
+            .content
+            .iter()
+            .flatten()
+            .map(|content| &content.raw)
+            .collect();
+        let json_body = serde_json::json!({
+            "tool": tool_name,
+            "server": mcp_client_name,
+            "is_error": res.is_error.unwrap_or(false),
+            "content": content,
+        });
+
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Body::from(json_body.to_string()))
+            .map_err(|e| {
+                let err_msg = format!("Failed to create response: {e}");
+                dual_error!("{err_msg}");
+                ServerError::Operation(err_msg)
+            })?;
+
+        Ok(response)
+    }
+
+    #[tokio::test]
+    async fn test_call_unknown_mcp_tool() {
+        use axum::response::IntoResponse;
+
+        let err = call_mcp_tool_handler(
+            Path("no_such_tool".to_string()),
+            HeaderMap::new(),
+            Json(CallMcpToolRequest { arguments: None }),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.into_response().status(), StatusCode::NOT_FOUND);
+    }
+
+    /// The body of a request turning the maintenance mode on or off
+    #[derive(Debug, serde::Deserialize)]
+    pub(crate) struct MaintenanceRequest {
+        enabled: bool,
+        /// The message returned by the proxy endpoints
+        message: Option<String>,
+        /// Seconds the clients should wait before retrying. Defaults to 60.
+        retry_after: Option<u64>,
+    }
+
+    pub(crate) async fn set_maintenance_handler(
+        State(state): State<Arc<AppState>>,
+        Json(request): Json<MaintenanceRequest>,
+    ) -> ServerResult<axum::response::Response> {
+        let maintenance = match request.enabled {
+            true => Some(crate::MaintenanceMode {
+                message: request.message.unwrap_or_else(|| {
+                    "The service is under maintenance. Please retry later.".to_string()
+                }),
+                retry_after: request.retry_after.unwrap_or(60),
+            }),
+            false => None,
+        };
+        match &maintenance {
+            Some(maintenance) => dual_warn!("Maintenance mode on: {}", maintenance.message),
+            None => dual_info!("Maintenance mode off"),
+        }
+        state.set_maintenance(maintenance.clone()).await;
+
+        // create a response with status code 200. Content-Type is JSON
+        let json_body = serde_json::json!({
+            "message": match request.enabled {
+                true => "Maintenance mode enabled successfully.",
+                false => "Maintenance mode disabled successfully.",
+            },
+            "enabled": request.enabled,
+            "maintenance": maintenance,
+        });
+
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Body::from(json_body.to_string()))
+            .map_err(|e| {
+                let err_msg = format!("Failed to create response: {e}");
+                dual_error!("{err_msg}");
+                ServerError::Operation(err_msg)
+            })?;
+
+        Ok(response)
+    }
+
+    /// The body of a request changing the log level
+    #[derive(Debug, serde::Deserialize)]
+    pub(crate) struct LogLevelRequest {
+        level: String,
+    }
+
+    pub(crate) async fn set_log_level_handler(
+        State(state): State<Arc<AppState>>,
+        Json(request): Json<LogLevelRequest>,
+    ) -> ServerResult<axum::response::Response> {
+        let level = crate::parse_log_level(&request.level).ok_or_else(|| {
+            let err_msg = format!(
+                "Invalid log level: {}. Valid values are 'trace', 'debug', 'info', 'warn' or 'error'",
+                request.level
+            );
+            dual_error!("{err_msg}");
+            ServerError::BadRequest(err_msg)
+        })?;
+
+        let (previous_level, new_level) = state.set_log_level(level).inspect_err(|e| {
+            dual_error!("{e}");
+        })?;
+        dual_info!("Changed the log level from {previous_level} to {new_level}");
+
+        // create a response with status code 200. Content-Type is JSON
+        let json_body = serde_json::json!({
+            "message": "Log level changed successfully.",
+            "previous_level": previous_level.to_string(),
+            "level": new_level.to_string(),
+        });
+
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Body::from(json_body.to_string()))
+            .map_err(|e| {
+                let err_msg = format!("Failed to create response: {e}");
+                dual_error!("{err_msg}");
+                ServerError::Operation(err_msg)
+            })?;
+
+        Ok(response)
+    }
+
+    #[tokio::test]
+    async fn test_set_log_level() {
+        use axum::response::IntoResponse;
+        use tracing_subscriber::{filter::LevelFilter, reload};
+
+        // the layer is kept alive as the global subscriber would
+        let (_level_filter, log_level_handle) = reload::Layer::new(LevelFilter::INFO);
+        let state = Arc::new(
+            AppState::new(
+                crate::config::Config::default(),
+                crate::info::ServerInfo::default(),
+            )
+            .with_log_level_handle(log_level_handle.clone()),
+        );
+
+        let response = set_log_level_handler(
+            State(state.clone()),
+            Json(LogLevelRequest {
+                level: "debug".to_string(),
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["previous_level"], "info");
+        assert_eq!(body["level"], "debug");
+        assert_eq!(log_level_handle.clone_current(), Some(LevelFilter::DEBUG));
+
+        let err = set_log_level_handler(
+            State(state),
+            Json(LogLevelRequest {
+                level: "verbose".to_string(),
+            }),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.into_response().status(), StatusCode::BAD_REQUEST);
+        assert_eq!(log_level_handle.clone_current(), Some(LevelFilter::DEBUG));
+    }
+
+    pub(crate) async fn list_downstream_servers_handler(
+        State(state): State<Arc<AppState>>,
+    ) -> ServerResult<axum::response::Response> {
+        let servers = state.list_downstream_servers().await?;
+
+        // compute the total number of servers
+        let total_servers = servers.values().fold(0, |acc, servers| acc + servers.len());
+        dual_info!("Found {} downstream servers", total_servers);
+
+        let json_body = serde_json::to_string(&servers).unwrap();
+
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Body::from(json_body))
+            .map_err(|e| {
+                let err_msg = format!("Failed to create response: {e}");
+                dual_error!("{err_msg}");
+                ServerError::Operation(err_msg)
+            })?;
+
+        Ok(response)
+    }
+
+    /// The health of a registered server, as reported by `/admin/servers/health`
+    #[derive(Debug, serde::Serialize)]
+    pub(crate) struct ServerHealth {
+        id: String,
+        url: String,
+        kind: ServerKind,
+        is_healthy: bool,
+        /// The time of the last health check, in RFC 3339
+        last_check: String,
+    }
+
+    /// The number of the healthy and the unhealthy servers of a kind
+    #[derive(Debug, Default, serde::Serialize)]
+    pub(crate) struct HealthSummary {
+        healthy: usize,
+        unhealthy: usize,
+    }
+
+    /// The health of each registered server and the number of healthy and unhealthy servers of
+    /// each kind
+    async fn servers_health(state: &AppState) -> ServerResult<serde_json::Value> {
+        let servers = state.list_downstream_servers().await?;
+
+        let mut summary = BTreeMap::new();
+        let mut health = BTreeMap::new();
+        for (kind, servers) in servers.iter() {
+            let counts: &mut HealthSummary = summary.entry(kind.to_string()).or_default();
+            for server in servers {
+                match server.health_status.is_healthy {
+                    true => counts.healthy += 1,
+                    false => counts.unhealthy += 1,
+                }
+
+                // a server of multiple kinds is listed in the group of each kind
+                health
+                    .entry(server.id.clone())
+                    .or_insert_with(|| ServerHealth {
+                        id: server.id.clone(),
+                        url: server.url.clone(),
+                        kind: server.kind,
+                        is_healthy: server.health_status.is_healthy,
+                        last_check: chrono::DateTime::<chrono::Utc>::from(
+                            server.health_status.last_check,
+                        )
+                        .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+                    });
+            }
+        }
+
+        Ok(serde_json::json!({
+            "servers": health.into_values().collect::<Vec<_>>(),
+            "summary": summary,
+        }))
+    }
+
+    pub(crate) async fn servers_health_handler(
+        State(state): State<Arc<AppState>>,
+    ) -> ServerResult<axum::response::Response> {
+        let json_body = servers_health(&state).await?;
+
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Body::from(json_body.to_string()))
+            .map_err(|e| {
+                let err_msg = format!("Failed to create response: {e}");
+                dual_error!("{err_msg}");
+                ServerError::Operation(err_msg)
+            })?;
+
+        Ok(response)
+    }
+
+    /// List the MCP tool servers with their tools and the result of their last health check
+    pub(crate) async fn mcp_servers_handler() -> ServerResult<axum::response::Response> {
+        let mut servers = Vec::new();
+        if let Some(services) = MCP_SERVICES.get() {
+            for (name, service) in services.read().await.iter() {
+                let service = service.read().await;
+                let health = mcp::mcp_health(name);
+                servers.push(serde_json::json!({
+                    "name": name,
+                    "server_name": service
+                        .raw
+                        .peer_info()
+                        .map(|peer_info| peer_info.server_info.name.clone()),
+                    "tools": service.tools,
+                    "is_healthy": health.as_ref().is_none_or(|health| health.is_healthy),
+                    "last_check": health.as_ref().map(|health| {
+                        chrono::DateTime::<chrono::Utc>::from(health.last_check)
+                            .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+                    }),
+                    "error": health.and_then(|health| health.error),
+                }));
+            }
+        }
+        servers.sort_by(|a, b| a["name"].as_str().cmp(&b["name"].as_str()));
+
+        let json_body = serde_json::json!({ "servers": servers });
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Body::from(json_body.to_string()))
+            .map_err(|e| {
+                let err_msg = format!("Failed to create response: {e}");
+                dual_error!("{err_msg}");
+                ServerError::Operation(err_msg)
+            })?;
+
+        Ok(response)
+    }
+
+    /// Check the health of all the registered servers at once, instead of waiting for the
+    /// periodic health check, and report their health after the check along with the unhealthy
+    /// servers unregistered by it
+    pub(crate) async fn run_health_check_handler(
+        State(state): State<Arc<AppState>>,
+    ) -> ServerResult<axum::response::Response> {
+        dual_info!("Running a health check on demand");
+        let unregistered = state.check_server_health(true).await?;
+
+        let mut json_body = servers_health(&state).await?;
+        json_body["unregistered"] = unregistered.into();
+
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Body::from(json_body.to_string()))
+            .map_err(|e| {
+                let err_msg = format!("Failed to create response: {e}");
+                dual_error!("{err_msg}");
+                ServerError::Operation(err_msg)
+            })?;
+
+        Ok(response)
+    }
+
+    #[tokio::test]
+    async fn test_servers_health() {
+        let state = test_state();
+        let server: Server = serde_json::from_str(
+            r#"{"url": "http://localhost:8000/v1", "kind": "chat,embeddings"}"#,
+        )
+        .unwrap();
+        let server_id = server.id.clone();
+        state.register_downstream_server(server).await.unwrap();
+        let mut server: Server =
+            serde_json::from_str(r#"{"url": "http://localhost:8001/v1", "kind": "chat"}"#).unwrap();
+        server.health_status.is_healthy = false;
+        state.register_downstream_server(server).await.unwrap();
+
+        let response = servers_health_handler(State(state)).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        let servers = body["servers"].as_array().unwrap();
+        assert_eq!(servers.len(), 2);
+        let server = servers.iter().find(|s| s["id"] == server_id).unwrap();
+        assert_eq!(server["kind"], "chat,embeddings");
+        assert_eq!(server["is_healthy"], true);
+        assert!(
+            chrono::DateTime::parse_from_rfc3339(server["last_check"].as_str().unwrap()).is_ok()
+        );
+        assert_eq!(
+            body["summary"],
+            serde_json::json!({
+                "chat": {"healthy": 1, "unhealthy": 1},
+                "embeddings": {"healthy": 1, "unhealthy": 0},
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_health_check() {
+        let app = axum::Router::new().route("/v1/info", axum::routing::get(|| async { "{}" }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let state = test_state();
+        let server: Server =
+            serde_json::from_str(&format!(r#"{{"url": "http://{addr}/v1", "kind": "chat"}}"#))
+                .unwrap();
+        let healthy_id = server.id.clone();
+        state.register_downstream_server(server).await.unwrap();
+        // nothing listens on the port, so the server is unhealthy
+        let unreachable = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let unreachable_addr = unreachable.local_addr().unwrap();
+        drop(unreachable);
+        let server: Server = serde_json::from_str(&format!(
+            r#"{{"url": "http://{unreachable_addr}/v1", "kind": "chat"}}"#
+        ))
+        .unwrap();
+        let unhealthy_id = server.id.clone();
+        state.register_downstream_server(server).await.unwrap();
+
+        let response = run_health_check_handler(State(state)).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(body["unregistered"], serde_json::json!([unhealthy_id]));
+        let servers = body["servers"].as_array().unwrap();
+        assert_eq!(servers.len(), 1);
+        assert_eq!(servers[0]["id"], healthy_id);
+        assert_eq!(
+            body["summary"],
+            serde_json::json!({"chat": {"healthy": 1, "unhealthy": 0}})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_health_check_without_eviction() {
+        use crate::server::RoutingPolicy;
+
+        let mut config = crate::config::Config::default();
+        config.server.evict_on_unhealthy = false;
+        config.server.unhealthy_probe_backoff_secs = 0;
+        let state = test_state_with(config);
+
+        // nothing listens on the port yet, so the server is unhealthy
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let server: Server =
+            serde_json::from_str(&format!(r#"{{"url": "http://{addr}/v1", "kind": "chat"}}"#))
+                .unwrap();
+        state.register_downstream_server(server).await.unwrap();
+
+        let next_chat_server = async || {
+            let group_map = state.server_group.read().await;
+            group_map.get(&ServerKind::chat).unwrap().next().await
+        };
+
+        assert!(state.check_server_health(true).await.unwrap().is_empty());
+        let body = servers_health(&state).await.unwrap();
+        assert_eq!(body["servers"][0]["is_healthy"], false);
+        assert!(next_chat_server().await.is_err());
+
+        // the server recovers once it is back, without waiting for the next health check
+        let app = axum::Router::new().route("/v1/info", axum::routing::get(|| async { "{}" }));
+        let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        state.probe_unhealthy_servers().await;
+        let body = servers_health(&state).await.unwrap();
+        assert_eq!(body["servers"][0]["is_healthy"], true);
+        assert!(next_chat_server().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_health_check_does_not_block_routing() {
+        // the server answers its health check only once released
+        let probed = Arc::new(tokio::sync::Notify::new());
+        let release = Arc::new(tokio::sync::Notify::new());
+        let app = axum::Router::new().route(
+            "/v1/info",
+            axum::routing::get({
+                let (probed, release) = (probed.clone(), release.clone());
+                move || async move {
+                    probed.notify_one();
+                    release.notified().await;
+                    "{}"
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let state = test_state();
+        let server: Server =
+            serde_json::from_str(&format!(r#"{{"url": "http://{addr}/v1", "kind": "chat"}}"#))
+                .unwrap();
+        state.register_downstream_server(server).await.unwrap();
+
+        let check = tokio::spawn({
+            let state = state.clone();
+            async move { state.check_server_health(true).await }
+        });
+        probed.notified().await;
+
+        // the requests are still routed while the check waits for the server
+        let next_chat_server = async {
+            let group_map = state.server_group.read().await;
+            group_map.get(&ServerKind::chat).unwrap().next().await
+        };
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_secs(1), next_chat_server)
+                .await
+                .expect("the routing is blocked by the health check")
+                .is_ok()
+        );
+
+        release.notify_one();
+        assert!(check.await.unwrap().unwrap().is_empty());
+        let body = servers_health(&state).await.unwrap();
+        assert_eq!(body["servers"][0]["is_healthy"], true);
+    }
+}
+
+/// Acquire a permit of the concurrent streaming responses. The permit is released once dropped.
+/// Returns `None` if the number of streams is not limited.
+fn acquire_stream_permit(state: &AppState) -> ServerResult<Option<OwnedSemaphorePermit>> {
+    let Some(stream_permits) = state.stream_permits.as_ref() else {
+        return Ok(None);
+    };
+
+    match stream_permits.clone().try_acquire_owned() {
+        Ok(permit) => Ok(Some(permit)),
+        Err(_) => {
+            let err = ServerError::TooManyStreams;
+            dual_warn!("{}", err);
+            Err(err)
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_acquire_stream_permit() {
+    use axum::response::IntoResponse;
+
+    let mut config = crate::config::Config::default();
+    config.server.max_concurrent_streams = Some(2);
+    let state = test_state_with(config);
+
+    let permit1 = acquire_stream_permit(&state).unwrap();
+    let _permit2 = acquire_stream_permit(&state).unwrap();
+    assert!(permit1.is_some());
+
+    // the limit is saturated
+    let err = acquire_stream_permit(&state).unwrap_err();
+    assert!(matches!(err, ServerError::TooManyStreams));
+    assert_eq!(
+        err.into_response().status(),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+
+    // the permit is released once the stream ends
+    drop(permit1);
+    assert!(acquire_stream_permit(&state).unwrap().is_some());
+
+    // the streams are unlimited by default
+    let state = test_state();
+    assert!(acquire_stream_permit(&state).unwrap().is_none());
+}
+
+// Generate a unique chat id for the chat completion request
+fn gen_chat_id() -> String {
+    format!("chatcmpl-{}", uuid::Uuid::new_v4())
+}
+
+/// Get the chat server of the request. With valid admin credentials, the `X-Override-Upstream`
+/// header sends the request to the given server instead of a registered one.
+async fn resolve_chat_server(
+    state: &Arc<AppState>,
+    headers: &HeaderMap,
+    model: Option<&str>,
+) -> ServerResult<crate::server::TargetServerInfo> {
+    let Some(upstream) = headers.get(OVERRIDE_UPSTREAM_HEADER) else {
+        let tag = headers
+            .get(MODEL_TAG_HEADER)
+            .and_then(|tag| tag.to_str().ok())
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty());
+        return get_chat_server(state, model, tag).await;
+    };
+
+    // only the admin can send requests to any url
+    let admin_api_key = state.config.read().await.server.admin_api_key.clone();
+    let is_admin = match (&admin_api_key, headers.get(ADMIN_KEY_HEADER)) {
+        (Some(admin_api_key), Some(key)) => {
+            !admin_api_key.is_empty()
+                && key.to_str().is_ok_and(|key| keys_match(key, admin_api_key))
+        }
+        _ => false,
+    };
+    if !is_admin {
+        let err_msg = format!("The `{OVERRIDE_UPSTREAM_HEADER}` header requires the admin key");
+        dual_error!("{}", err_msg);
+        return Err(ServerError::Forbidden(err_msg));
+    }
+
+    let url = upstream
+        .to_str()
+        .ok()
+        .and_then(|url| reqwest::Url::parse(url).ok())
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+        .ok_or_else(|| {
+            let err_msg =
+                format!("The `{OVERRIDE_UPSTREAM_HEADER}` header should be an http(s) url");
+            dual_error!("{}", err_msg);
+            ServerError::BadRequest(err_msg)
+        })?;
+    dual_warn!("Override the upstream of the request with {}", url);
+
+    Ok(TargetServerInfo {
+        id: "override-upstream".to_string(),
+        url: url.to_string(),
+        api_key: None,
+        unsupported_params: Vec::new(),
+        path_prefix: String::new(),
+        provider: Provider::OpenAi,
+        api_version: None,
+        permits: None,
+    })
+}
+
+#[tokio::test]
+async fn test_resolve_chat_server_with_override() {
+    let mut config = crate::config::Config::default();
+    config.server.admin_api_key = Some("admin-key".to_string());
+    let state = test_state_with(config);
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        OVERRIDE_UPSTREAM_HEADER,
+        "http://localhost:10086/v1".parse().unwrap(),
+    );
+
+    // rejected without the admin key or with a wrong one
+    let err = resolve_chat_server(&state, &headers, None)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ServerError::Forbidden(_)));
+    headers.insert(ADMIN_KEY_HEADER, "wrong-key".parse().unwrap());
+    let err = resolve_chat_server(&state, &headers, None)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ServerError::Forbidden(_)));
+
+    // routed to the given url with the admin key, even if no chat server is registered
+    headers.insert(ADMIN_KEY_HEADER, "admin-key".parse().unwrap());
+    let target = resolve_chat_server(&state, &headers, None).await.unwrap();
+    assert_eq!(target.url, "http://localhost:10086/v1");
+
+    // rejected if the admin key is not configured
+    let state = test_state();
+    let err = resolve_chat_server(&state, &headers, None)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ServerError::Forbidden(_)));
+}
+
+/// Get the group of the servers of the given kind. If no server of the kind is registered, the
+/// error names the missing capability along with the kinds of the registered servers.
+pub(crate) async fn server_group_of(
+    servers: &HashMap<ServerKind, ServerGroup>,
+    kind: ServerKind,
+) -> ServerResult<&ServerGroup> {
+    if let Some(group) = servers.get(&kind)
+        && !group.is_empty().await
+    {
+        return Ok(group);
+    }
+
+    let mut available_kinds = Vec::new();
+    for (kind, group) in servers.iter() {
+        if !group.is_empty().await {
+            available_kinds.push(*kind);
+        }
+    }
+    available_kinds.sort_by_key(|kind| kind.bits());
+
+    let err = ServerError::MissingCapability {
+        kind: kind.to_string(),
+        available_kinds: available_kinds
+            .iter()
+            .map(|kind| kind.to_string())
+            .collect(),
+    };
+    dual_error!("{}", err);
+    Err(err)
+}
+
+#[tokio::test]
+async fn test_server_group_of() {
+    use axum::response::IntoResponse;
+
+    let mut servers = HashMap::new();
+    for kind in [ServerKind::tts, ServerKind::chat, ServerKind::image] {
+        servers.insert(kind, ServerGroup::new(kind));
+    }
+    for kind in [ServerKind::tts, ServerKind::chat] {
+        let server: Server = serde_json::from_value(serde_json::json!({
+            "url": "http://localhost:10086/v1",
+            "kind": kind.to_string(),
+        }))
+        .unwrap();
+        servers[&kind].register(server).await.unwrap();
+    }
+
+    assert!(server_group_of(&servers, ServerKind::chat).await.is_ok());
+
+    // the empty groups count as missing
+    for kind in [ServerKind::image, ServerKind::transcribe] {
+        let err = server_group_of(&servers, kind).await.unwrap_err();
+        let response = err.into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["error"]["code"], "missing_capability");
+        assert_eq!(
+            body["error"]["available_kinds"],
+            serde_json::json!(["chat", "tts"])
+        );
+    }
+}
+
+/// The instructions of the chat model summarizing the oldest turns of a session
+const HISTORY_SUMMARY_PROMPT: &str = "Summarize the following conversation between a user and an assistant concisely. Keep the facts, the preferences and the decisions the rest of the conversation may refer to. Reply with the summary only.";
+
+/// Summarize the messages of a conversation by the chat model, e.g. to compact the history of a
+/// long-running `/responses` session
+pub(crate) async fn summarize_messages(
+    state: &Arc<AppState>,
+    model: Option<&str>,
+    messages: &[ChatMessage],
+) -> ServerResult<String> {
+    let transcript = messages
+        .iter()
+        .map(|message| format!("{}: {}", message.role, message.content))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let mut body = serde_json::json!({
+        "messages": [
+            {"role": "system", "content": HISTORY_SUMMARY_PROMPT},
+            {"role": "user", "content": transcript},
+        ],
+        "stream": false,
+    });
+    if let Some(model) = model {
+        body["model"] = model.into();
+    }
+    let request = serde_json::from_value::<ChatCompletionRequest>(body).map_err(|e| {
+        let err_msg = format!("Failed to build the summary request: {e}");
+        dual_error!("{}", err_msg);
+        ServerError::Operation(err_msg)
+    })?;
+
+    let chat_server = get_chat_server(state, model, None).await?;
+    let cancel_token = CancellationToken::new();
+    let (response, _permit) = build_and_send_request(
+        &chat_server,
+        &request,
+        &HeaderMap::new(),
+        cancel_token.clone(),
+        None,
+    )
+    .await?;
+    let status = response.status();
+    let bytes = read_response_bytes(response, cancel_token).await?;
+    if !status.is_success() {
+        let err_msg = format!("The chat server failed to summarize the messages: {status}");
+        dual_error!("{}", err_msg);
+        return Err(ServerError::Operation(err_msg));
+    }
 
-        Ok(())
+    let chat_completion = parse_chat_completion(&bytes)?;
+    match chat_completion
+        .choices
+        .into_iter()
+        .next()
+        .and_then(|choice| choice.message.content)
+        .filter(|summary| !summary.trim().is_empty())
+    {
+        Some(summary) => Ok(summary),
+        None => {
+            let err_msg = "The summary of the messages is empty";
+            dual_error!("{}", err_msg);
+            Err(ServerError::Operation(err_msg.to_string()))
+        }
     }
+}
 
-    pub(crate) async fn remove_downstream_server_handler(
-        State(state): State<Arc<AppState>>,
-        headers: HeaderMap,
-        Json(server_id): Json<ServerIdToRemove>,
-    ) -> ServerResult<axum::response::Response> {
-        // Get request ID from headers
-        let request_id = headers
-            .get("x-request-id")
-            .and_then(|h| h.to_str().ok())
-            .unwrap_or("unknown")
-            .to_string();
+/// Pick the chat server of the request, among the servers serving the model and having the tag if
+/// they are given
+async fn get_chat_server(
+    state: &Arc<AppState>,
+    model: Option<&str>,
+    tag: Option<&str>,
+) -> ServerResult<crate::server::TargetServerInfo> {
+    let model = model.filter(|model| !model.is_empty());
+    let debug_routing = state.config.read().await.server.debug_routing;
 
+    // the servers may register a little after the gateway starts
+    let wait_for_server_secs = state.config.read().await.server.wait_for_server_secs;
+    if let Some(wait_for_server_secs) = wait_for_server_secs {
         state
-            .unregister_downstream_server(&server_id.server_id)
-            .await?;
+            .wait_for_server(
+                ServerKind::chat,
+                std::time::Duration::from_secs(wait_for_server_secs),
+            )
+            .await;
+    }
 
-        // create a response with status code 200. Content-Type is JSON
-        let json_body = serde_json::json!({
-            "message": "Server unregistered successfully.",
-            "id": server_id.server_id,
-        });
+    // only route to the chat servers serving the requested model
+    let model_servers = match model {
+        Some(model) => {
+            let candidates = state
+                .routing_candidates(ServerKind::chat, Some(model), None)
+                .await;
+            let mut chat_servers = candidates
+                .iter()
+                .filter(|c| !c.excluded_because.contains(&ExclusionReason::WrongKind))
+                .peekable();
+            let has_chat_servers = chat_servers.peek().is_some();
+            let model_servers = chat_servers
+                .filter(|c| {
+                    !c.excluded_because
+                        .contains(&ExclusionReason::ModelNotServed)
+                })
+                .map(|c| c.id.clone())
+                .collect::<HashSet<_>>();
+
+            if has_chat_servers && model_servers.is_empty() {
+                let err_msg = format!("None of the chat servers serves the model: {model}");
+                dual_error!("{}", err_msg);
+                return Err(ServerError::NotFoundModel {
+                    model: model.to_string(),
+                    candidates: debug_routing.then_some(candidates),
+                });
+            }
 
-        let response = Response::builder()
-            .status(StatusCode::OK)
-            .header("Content-Type", "application/json")
-            .body(Body::from(json_body.to_string()))
-            .map_err(|e| {
-                let err_msg = format!("Failed to create response: {e}");
-                dual_error!("{err_msg} - request_id: {request_id}");
-                ServerError::Operation(err_msg)
-            })?;
+            Some(model_servers)
+        }
+        None => None,
+    };
 
-        Ok(response)
-    }
+    let err = {
+        let servers = state.server_group.read().await;
+        let target_server_info = server_group_of(&servers, ServerKind::chat)
+            .await?
+            .next_matching(|server| {
+                model_servers
+                    .as_ref()
+                    .is_none_or(|model_servers| model_servers.contains(&server.id))
+                    && tag.is_none_or(|tag| server.has_tag(tag))
+            })
+            .await;
+        match target_server_info {
+            Ok(target_server_info) => return Ok(target_server_info),
+            Err(e) => format!("Failed to get the chat server: {e}"),
+        }
+    };
+    dual_error!("{}", err);
 
-    pub(crate) async fn list_downstream_servers_handler(
-        State(state): State<Arc<AppState>>,
-        headers: HeaderMap,
-    ) -> ServerResult<axum::response::Response> {
-        // Get request ID from headers
-        let request_id = headers
-            .get("x-request-id")
-            .and_then(|h| h.to_str().ok())
-            .unwrap_or("unknown")
-            .to_string();
+    let candidates = match debug_routing {
+        true => Some(state.routing_candidates(ServerKind::chat, model, tag).await),
+        false => None,
+    };
+    Err(ServerError::NoAvailableServer {
+        message: err,
+        candidates,
+    })
+}
 
-        let servers = state.list_downstream_servers().await?;
+#[tokio::test]
+async fn test_get_chat_server_reports_routing_candidates() {
+    use axum::response::IntoResponse;
 
-        // compute the total number of servers
-        let total_servers = servers.values().fold(0, |acc, servers| acc + servers.len());
-        dual_info!(
-            "Found {} downstream servers - request_id: {}",
-            total_servers,
-            request_id
+    let mut config = crate::config::Config::default();
+    config.server.debug_routing = true;
+    let state = test_state_with(config);
+
+    let new_model = |id: &str| Model {
+        id: id.to_string(),
+        created: 0,
+        object: "model".to_string(),
+        owned_by: "Not specified".to_string(),
+    };
+    let mut server_ids = vec![];
+    for (kind, port, model) in [
+        ("chat", 8000, "llama"),
+        ("chat", 8001, "qwen"),
+        ("embeddings", 8002, "nomic"),
+    ] {
+        let server: Server = serde_json::from_value(serde_json::json!({
+            "url": format!("http://localhost:{port}/v1"),
+            "kind": kind,
+        }))
+        .unwrap();
+        state
+            .models
+            .write()
+            .await
+            .insert(server.id.clone(), vec![new_model(model)]);
+        server_ids.push(server.id.clone());
+        state.register_downstream_server(server).await.unwrap();
+    }
+    state
+        .set_downstream_server_enabled(&server_ids[1], false)
+        .await
+        .unwrap();
+
+    let err = get_chat_server(&state, Some("gpt-4"), None)
+        .await
+        .unwrap_err();
+    let candidates = match &err {
+        ServerError::NotFoundModel { model, candidates } => {
+            assert_eq!(model, "gpt-4");
+            candidates.clone().unwrap()
+        }
+        e => panic!("unexpected error: {e:?}"),
+    };
+    assert_eq!(candidates.len(), 3);
+    for candidate in candidates.iter() {
+        let expected = if candidate.id == server_ids[0] {
+            vec![ExclusionReason::ModelNotServed]
+        } else if candidate.id == server_ids[1] {
+            vec![ExclusionReason::ModelNotServed, ExclusionReason::Draining]
+        } else {
+            vec![ExclusionReason::WrongKind, ExclusionReason::ModelNotServed]
+        };
+        assert_eq!(
+            candidate.excluded_because, expected,
+            "server: {}",
+            candidate.id
         );
+    }
 
-        let json_body = serde_json::to_string(&servers).unwrap();
+    // the reasons are reported in the error body
+    let response = err.into_response();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(body["error"]["code"], "model_not_found");
+    assert_eq!(
+        body["error"]["routing_candidates"]
+            .as_array()
+            .unwrap()
+            .len(),
+        3
+    );
 
-        let response = Response::builder()
-            .status(StatusCode::OK)
-            .header("Content-Type", "application/json")
-            .body(Body::from(json_body))
-            .map_err(|e| {
-                let err_msg = format!("Failed to create response: {e}");
-                dual_error!("{err_msg} - request_id: {request_id}");
-                ServerError::Operation(err_msg)
-            })?;
+    // the served model is routed to the enabled server
+    let target = get_chat_server(&state, Some("llama"), None).await.unwrap();
+    assert_eq!(target.id, server_ids[0]);
+}
 
-        Ok(response)
+#[tokio::test]
+async fn test_get_chat_server_routes_by_model() {
+    let state = test_state();
+
+    let mut server_ids = vec![];
+    for (port, models) in [(8000, ["llama", "qwen"]), (8001, ["llama", "gemma"])] {
+        let server: Server = serde_json::from_value(serde_json::json!({
+            "url": format!("http://localhost:{port}/v1"),
+            "kind": "chat",
+        }))
+        .unwrap();
+        let models = models
+            .iter()
+            .map(|id| Model {
+                id: id.to_string(),
+                created: 0,
+                object: "model".to_string(),
+                owned_by: "Not specified".to_string(),
+            })
+            .collect();
+        state.models.write().await.insert(server.id.clone(), models);
+        server_ids.push(server.id.clone());
+        state.register_downstream_server(server).await.unwrap();
     }
-}
 
-// Generate a unique chat id for the chat completion request
-fn gen_chat_id() -> String {
-    format!("chatcmpl-{}", uuid::Uuid::new_v4())
+    // the requests are only routed to the server serving the model
+    for _ in 0..3 {
+        let target = get_chat_server(&state, Some("gemma"), None).await.unwrap();
+        assert_eq!(target.id, server_ids[1]);
+    }
+    let target = get_chat_server(&state, Some("qwen"), None).await.unwrap();
+    assert_eq!(target.id, server_ids[0]);
+
+    // the servers serving the same model take turns
+    let mut targets = HashSet::new();
+    for _ in 0..4 {
+        targets.insert(
+            get_chat_server(&state, Some("llama"), None)
+                .await
+                .unwrap()
+                .id,
+        );
+    }
+    assert_eq!(targets.len(), 2);
+
+    // no server serves the model
+    let err = get_chat_server(&state, Some("gpt-4"), None)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        ServerError::NotFoundModel {
+            candidates: None,
+            ..
+        }
+    ));
 }
 
-async fn get_chat_server(
-    state: &Arc<AppState>,
-    request_id: &str,
-) -> ServerResult<crate::server::TargetServerInfo> {
-    let servers = state.server_group.read().await;
-    let chat_servers = match servers.get(&ServerKind::chat) {
-        Some(servers) => servers,
-        None => {
-            let err_msg = "No chat server available. Please register a chat server via the `/admin/servers/register` endpoint.";
-            dual_error!("{} - request_id: {}", err_msg, request_id);
-            return Err(ServerError::Operation(err_msg.to_string()));
+#[tokio::test]
+async fn test_get_chat_server_waits_for_server() {
+    let mut config = crate::config::Config::default();
+    config.server.wait_for_server_secs = Some(5);
+    let state = test_state_with(config);
+
+    // the request waits for the server registered after it arrives
+    let registration = tokio::spawn({
+        let state = state.clone();
+        async move {
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            let server: Server = serde_json::from_value(serde_json::json!({
+                "url": "http://localhost:8000/v1",
+                "kind": "chat",
+            }))
+            .unwrap();
+            let server_id = server.id.clone();
+            state.register_downstream_server(server).await.unwrap();
+            server_id
         }
-    };
+    });
+    let target = get_chat_server(&state, None, None).await.unwrap();
+    assert_eq!(target.id, registration.await.unwrap());
+
+    // the wait ends once the time is up
+    let start = std::time::Instant::now();
+    state
+        .wait_for_server(ServerKind::image, std::time::Duration::from_millis(100))
+        .await;
+    assert!(start.elapsed() >= std::time::Duration::from_millis(100));
+}
 
-    match chat_servers.next().await {
-        Ok(target_server_info) => Ok(target_server_info),
-        Err(e) => {
-            let err_msg = format!("Failed to get the chat server: {e}");
-            dual_error!("{} - request_id: {}", err_msg, request_id);
-            Err(ServerError::Operation(err_msg))
-        }
+#[tokio::test]
+async fn test_resolve_chat_server_by_tag() {
+    let state = test_state();
+
+    let mut server_ids = vec![];
+    for (port, tags) in [
+        (8000, serde_json::json!(["vision", "fast"])),
+        (8001, serde_json::json!(["long-context"])),
+        (8002, serde_json::json!([])),
+    ] {
+        let server: Server = serde_json::from_value(serde_json::json!({
+            "url": format!("http://localhost:{port}/v1"),
+            "kind": "chat",
+            "tags": tags,
+        }))
+        .unwrap();
+        server_ids.push(server.id.clone());
+        state.register_downstream_server(server).await.unwrap();
+    }
+
+    // the requests with a tag are only routed to the servers having it
+    let mut headers = HeaderMap::new();
+    headers.insert(MODEL_TAG_HEADER, "vision".parse().unwrap());
+    for _ in 0..3 {
+        let target = resolve_chat_server(&state, &headers, None).await.unwrap();
+        assert_eq!(target.id, server_ids[0]);
+    }
+    headers.insert(MODEL_TAG_HEADER, "long-context".parse().unwrap());
+    let target = resolve_chat_server(&state, &headers, None).await.unwrap();
+    assert_eq!(target.id, server_ids[1]);
+
+    // no server has the tag
+    headers.insert(MODEL_TAG_HEADER, "audio".parse().unwrap());
+    let err = resolve_chat_server(&state, &headers, None)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, ServerError::NoAvailableServer { .. }));
+
+    // the requests without a tag are routed to any server
+    let mut targets = HashSet::new();
+    for _ in 0..6 {
+        targets.insert(
+            resolve_chat_server(&state, &HeaderMap::new(), None)
+                .await
+                .unwrap()
+                .id,
+        );
     }
+    assert_eq!(targets.len(), 3);
 }
 
 /// Send chat request to downstream server with intelligent retry mechanism
@@ -1352,7 +4884,6 @@ async fn get_chat_server(
 /// * `chat_server` - The downstream chat server to send request to
 /// * `request` - Chat completion request, may be modified (e.g., reset tool choice)
 /// * `headers` - HTTP request headers, including authentication info
-/// * `request_id` - Request ID for log tracking
 /// * `cancel_token` - Cancellation token for request cancellation support
 ///
 /// # Returns
@@ -1367,74 +4898,158 @@ async fn send_request_with_retry(
     chat_server: &TargetServerInfo,
     request: &mut ChatCompletionRequest,
     headers: &HeaderMap,
-    request_id: &str,
     cancel_token: CancellationToken,
-) -> ServerResult<reqwest::Response> {
+    request_queue: Option<&RequestQueue>,
+    strip_tools_on_retry: bool,
+) -> ServerResult<(reqwest::Response, Option<OwnedSemaphorePermit>)> {
     // First attempt to send request to downstream server
     let response = build_and_send_request(
         chat_server,
         request,
         headers,
         cancel_token.clone(),
-        request_id,
+        request_queue,
     )
     .await;
 
     match response {
         // If first request succeeds, return response directly
         Ok(response) => Ok(response),
-        Err(e) => {
-            let err_str = e.to_string();
-
-            // Check if it's a tool call deserialization error
-            // This error usually occurs when downstream server doesn't support tool calls
-            if err_str.contains("Failed to deserialize generated tool calls") {
-                // Verify if retry is possible:
-                // 1. Request must contain tool definitions
-                // 2. Tool definitions cannot be empty
-                if let Some(tools) = &request.tools
-                    && !tools.is_empty()
-                {
-                    // Check if current tool choice is non-None state
-                    // Only non-None state needs to be reset to None for retry
-                    if let Some(tool_choice) = &request.tool_choice
-                        && *tool_choice != ToolChoice::None
-                    {
-                        // Reset tool choice to None, disable tool call functionality
-                        request.tool_choice = None;
-                        dual_info!(
-                            "Retrying request without tool choice - request_id: {}",
-                            request_id
-                        );
-
-                        // Re-send with reset request
-                        let response = build_and_send_request(
-                            chat_server,
-                            request,
-                            headers,
-                            cancel_token,
-                            request_id,
-                        )
-                        .await
-                        .map_err(|e| {
-                            let err_msg = format!("Failed to send request: {e}");
-                            dual_error!("{} - request_id: {}", err_msg, request_id);
-                            ServerError::Operation(err_msg)
-                        })?;
-
-                        return Ok(response);
-                    }
-                }
-            }
+        // Retry without the tool call functionality if the server failed on the tool calls
+        Err(e) if prepare_tool_call_retry(&e, request, strip_tools_on_retry) => {
+            // Re-send with reset request
+            build_and_send_request(chat_server, request, headers, cancel_token, request_queue)
+                .await
+                .map_err(send_request_error)
+        }
+        // Non-tool call related error, return directly, no retry
+        Err(e) => Err(send_request_error(e)),
+    }
+}
 
-            // Non-tool call related error, return directly, no retry
-            let err_msg = format!("Failed to send request: {e}");
-            dual_error!("{} - request_id: {}", err_msg, request_id);
-            Err(ServerError::Operation(err_msg))
+/// The error of a chat request failed to be sent. The errors of the request itself, which the
+/// client has to fix, and of a busy server, which the client should retry later, keep their status.
+fn send_request_error(error: ServerError) -> ServerError {
+    match error {
+        ServerError::BadRequest(_)
+        | ServerError::PayloadTooLarge(_)
+        | ServerError::Unauthorized(_)
+        | ServerError::Forbidden(_)
+        | ServerError::ServerBusy(_)
+        | ServerError::QueueFull { .. }
+        | ServerError::QueueTimeout { .. } => error,
+        error => {
+            let err_msg = format!("Failed to send request: {error}");
+            dual_error!("{}", err_msg);
+            ServerError::Operation(err_msg)
         }
     }
 }
 
+/// Remove the tool call functionality from the request failed with the error, if the error is a
+/// tool call deserialization error. Returns whether the request should be retried.
+///
+/// The tool choice is reset to `None`, and the tools are removed too if `strip_tools` is set, for
+/// the servers failing on the tool definitions themselves.
+fn prepare_tool_call_retry(
+    error: &ServerError,
+    request: &mut ChatCompletionRequest,
+    strip_tools: bool,
+) -> bool {
+    // Check if it's a tool call deserialization error
+    // This error usually occurs when downstream server doesn't support tool calls
+    if !error
+        .to_string()
+        .contains("Failed to deserialize generated tool calls")
+    {
+        return false;
+    }
+
+    // Verify if retry is possible:
+    // 1. Request must contain tool definitions
+    // 2. Tool definitions cannot be empty
+    let Some(tools) = &request.tools else {
+        return false;
+    };
+    if tools.is_empty() {
+        return false;
+    }
+
+    let mut removed = Vec::new();
+    // Only non-None tool choice needs to be reset to None for retry
+    if request
+        .tool_choice
+        .as_ref()
+        .is_some_and(|tool_choice| *tool_choice != ToolChoice::None)
+    {
+        request.tool_choice = None;
+        removed.push("tool_choice".to_string());
+    }
+    if strip_tools {
+        let tool_names = tools
+            .iter()
+            .map(|tool| tool.function.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        removed.push(format!("tools ({tool_names})"));
+        request.tools = None;
+        request.tool_choice = None;
+    }
+    if removed.is_empty() {
+        return false;
+    }
+
+    dual_info!("Retrying request without {}", removed.join(" and "));
+    true
+}
+
+#[test]
+fn test_prepare_tool_call_retry() {
+    let request = || {
+        serde_json::from_value::<ChatCompletionRequest>(serde_json::json!({
+            "model": "Llama-3.2-3b",
+            "messages": [{"role": "user", "content": "What is the weather in Paris?"}],
+            "tools": [{
+                "type": "function",
+                "function": {"name": "get_weather", "parameters": {"type": "object", "properties": {}}},
+            }],
+            "tool_choice": "auto",
+        }))
+        .unwrap()
+    };
+    let error = ServerError::Operation(
+        "Failed to forward request: Failed to deserialize generated tool calls".to_string(),
+    );
+
+    // the other errors are not retried
+    let mut retry_request = request();
+    assert!(!prepare_tool_call_retry(
+        &ServerError::Operation("Failed to forward request: connection refused".to_string()),
+        &mut retry_request,
+        true,
+    ));
+    assert!(retry_request.tool_choice.is_some());
+
+    // the tools are kept by default
+    let mut retry_request = request();
+    assert!(prepare_tool_call_retry(&error, &mut retry_request, false));
+    let payload = serde_json::to_value(&retry_request).unwrap();
+    assert!(payload.get("tool_choice").is_none());
+    assert_eq!(payload["tools"][0]["function"]["name"], "get_weather");
+    // nothing is left to remove for a second retry
+    assert!(!prepare_tool_call_retry(&error, &mut retry_request, false));
+
+    let mut retry_request = request();
+    assert!(prepare_tool_call_retry(&error, &mut retry_request, true));
+    let payload = serde_json::to_value(&retry_request).unwrap();
+    assert!(payload.get("tool_choice").is_none());
+    assert!(payload.get("tools").is_none());
+    assert_eq!(
+        payload["messages"][0]["content"],
+        "What is the weather in Paris?"
+    );
+}
+
 /// Build and send HTTP request to downstream server with cancellation support
 ///
 /// This function implements the following features:
@@ -1449,24 +5064,34 @@ async fn send_request_with_retry(
 /// * `request` - Chat completion request object
 /// * `headers` - HTTP request headers, including authentication info
 /// * `cancel_token` - Cancellation token for request cancellation support
+/// * `request_queue` - The queue to wait in if the server is saturated, or `None` to wait briefly
 ///
 /// # Returns
-/// * `Ok(response)` - Successfully obtained downstream server response
-/// * `Err(ServerError)` - Request failed or was cancelled
+/// * `Ok((response, permit))` - Successfully obtained downstream server response, and the permit
+///   of the concurrent requests to the server, which should be held until the response is read
+/// * `Err(ServerError)` - Request failed or was cancelled, or the server is busy
 ///
 /// # Cancellation Features
 /// * When cancel_token is triggered, function immediately returns cancellation error
 /// * Cancellation logs warning messages for debugging and monitoring
 /// * Cancellation operation releases related resources to prevent leaks
+#[tracing::instrument(name = "downstream_request", skip_all, fields(server = %chat_server.id))]
 async fn build_and_send_request(
     chat_server: &TargetServerInfo,
     request: &ChatCompletionRequest,
     headers: &HeaderMap,
     cancel_token: CancellationToken,
-    request_id: &str,
-) -> ServerResult<reqwest::Response> {
-    let url = format!("{}/chat/completions", chat_server.url.trim_end_matches('/'));
-    let mut client = reqwest::Client::new().post(&url);
+    request_queue: Option<&RequestQueue>,
+) -> ServerResult<(reqwest::Response, Option<OwnedSemaphorePermit>)> {
+    let url = chat_server.chat_completions_url(request.model.as_deref())?;
+
+    // Wait for the server to finish a request if it takes too many requests at the same time
+    let permit = match request_queue {
+        Some(request_queue) => request_queue.acquire(chat_server).await?,
+        None => chat_server.acquire_permit(SERVER_PERMIT_WAIT).await?,
+    };
+
+    let mut client = forward_tracing_headers(http_client().post(&url), headers);
 
     // Add common headers
     client = client.header(CONTENT_TYPE, "application/json");
@@ -1475,32 +5100,162 @@ async fn build_and_send_request(
     if let Some(api_key) = &chat_server.api_key
         && !api_key.is_empty()
     {
-        client = client.header(AUTHORIZATION, api_key);
+        client = client.header(chat_server.api_key_header(), api_key);
     } else if let Some(auth) = headers.get("authorization")
         && let Ok(auth_str) = auth.to_str()
     {
         client = client.header(AUTHORIZATION, auth_str);
     }
 
+    let mut body = serde_json::to_value(request).map_err(|e| {
+        let err_msg = format!("Failed to serialize the chat request: {e}");
+        dual_error!("{}", err_msg);
+        ServerError::Operation(err_msg)
+    })?;
+    strip_unsupported_params(&mut body, chat_server);
+
+    let is_anthropic = chat_server.provider == Provider::Anthropic;
+    if is_anthropic {
+        body = anthropic::to_messages_request(&body)?;
+        client = client.header("anthropic-version", anthropic::ANTHROPIC_VERSION);
+    }
+
     dual_info!(
-        "Request to downstream chat server - request_id: {}\n{}",
-        request_id,
-        serde_json::to_string_pretty(request).unwrap()
+        "Request to downstream chat server:\n{}",
+        serde_json::to_string_pretty(&body).unwrap()
     );
 
     // Use select! to support cancellation
-    select! {
-        response = client.json(request).send() => {
-            response.map_err(|e| ServerError::Operation(format!("Failed to forward request: {e}")))
+    let response = select! {
+        response = client.json(&body).send() => {
+            response.map_err(|e| ServerError::Operation(format!("Failed to forward request: {e}")))?
         }
         _ = cancel_token.cancelled() => {
             let warn_msg = "Request was cancelled by client";
             dual_warn!("{}", warn_msg);
-            Err(ServerError::Operation(warn_msg.to_string()))
+            return Err(ServerError::Operation(warn_msg.to_string()));
         }
+    };
+
+    let response = if is_anthropic {
+        translate_anthropic_response(response, request, cancel_token).await?
+    } else {
+        response
+    };
+
+    Ok((response, permit))
+}
+
+/// Translate the response of an anthropic server into the OpenAI chat response, so that it is
+/// handled as the response of any other chat server
+async fn translate_anthropic_response(
+    response: reqwest::Response,
+    request: &ChatCompletionRequest,
+    cancel_token: CancellationToken,
+) -> ServerResult<reqwest::Response> {
+    let status = response.status();
+
+    // the events are translated as they arrive
+    if status == StatusCode::OK && request.stream == Some(true) {
+        let include_usage = request
+            .stream_options
+            .as_ref()
+            .and_then(|stream_options| stream_options.include_usage)
+            .unwrap_or(false);
+        let translator = anthropic::StreamTranslator::new(include_usage);
+        let stream = futures_util::stream::unfold(
+            Some((Box::pin(response.bytes_stream()), translator)),
+            |state| async move {
+                let (mut stream, mut translator) = state?;
+                match stream.next().await {
+                    Some(Ok(chunk)) => Some((
+                        Ok(Bytes::from(translator.translate(&chunk))),
+                        Some((stream, translator)),
+                    )),
+                    Some(Err(e)) => Some((Err(e), None)),
+                    None => Some((Ok(Bytes::from(translator.finish())), None)),
+                }
+            },
+        );
+
+        let response = Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "text/event-stream")
+            .body(reqwest::Body::wrap_stream(stream))
+            .map_err(|e| {
+                let err_msg = format!("Failed to build the translated stream: {e}");
+                dual_error!("{}", err_msg);
+                ServerError::Operation(err_msg)
+            })?;
+        return Ok(reqwest::Response::from(response));
+    }
+
+    let bytes = read_response_bytes(response, cancel_token).await?;
+    let body = match serde_json::from_slice::<serde_json::Value>(&bytes) {
+        Ok(message) if status.is_success() => anthropic::to_chat_completion(&message).to_string(),
+        Ok(error) if error.get("error").is_some() => anthropic::to_openai_error(&error).to_string(),
+        _ => String::from_utf8_lossy(&bytes).into_owned(),
+    };
+    let response = Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body)
+        .map_err(|e| {
+            let err_msg = format!("Failed to build the translated response: {e}");
+            dual_error!("{}", err_msg);
+            ServerError::Operation(err_msg)
+        })?;
+
+    Ok(reqwest::Response::from(response))
+}
+
+/// Remove the params the target server does not support from the request body
+fn strip_unsupported_params(body: &mut serde_json::Value, target_server: &TargetServerInfo) {
+    let Some(body) = body.as_object_mut() else {
+        return;
+    };
+
+    let removed: Vec<&str> = target_server
+        .unsupported_params
+        .iter()
+        .filter(|param| body.remove(param.as_str()).is_some())
+        .map(String::as_str)
+        .collect();
+    if !removed.is_empty() {
+        dual_info!(
+            "Removed the params unsupported by {}: {}",
+            target_server.id,
+            removed.join(", ")
+        );
     }
 }
 
+#[test]
+fn test_strip_unsupported_params() {
+    let mut body = serde_json::json!({
+        "model": "Llama-3.2-3b",
+        "messages": [{"role": "user", "content": "Hello"}],
+        "top_k": 40,
+        "top_p": 0.9,
+    });
+
+    // the server without any unsupported params receives the body as it is
+    let mut target_server = TargetServerInfo {
+        id: "chat-server-1".to_string(),
+        url: "http://localhost:8000/v1".to_string(),
+        ..Default::default()
+    };
+    let expected = body.clone();
+    strip_unsupported_params(&mut body, &target_server);
+    assert_eq!(body, expected);
+
+    target_server.unsupported_params = vec!["top_k".to_string(), "min_p".to_string()];
+    strip_unsupported_params(&mut body, &target_server);
+    assert!(body.get("top_k").is_none());
+    assert_eq!(body["top_p"], 0.9);
+    assert_eq!(body["model"], "Llama-3.2-3b");
+}
+
 /// Handle streaming chat responses, supporting tool calls and normal streaming responses
 ///
 /// Choose processing path based on tool call identifier in response headers:
@@ -1513,15 +5268,22 @@ async fn build_and_send_request(
 /// * `request` - Chat request, may be modified
 /// * `headers` - HTTP request headers
 /// * `chat_service_url` - Chat service URL
-/// * `request_id` - Request ID
 /// * `cancel_token` - Cancellation token
+/// * `allowed_mcp_tools` - The MCP tools permitted for the request, or `None` for all tools
+/// * `injected_mcp_tools` - The MCP tools the gateway added to the request
+/// * `passthrough_unknown_tools` - Return the tool calls of the unknown tools to the client
+/// * `stream_permits` - The permits held until the stream ends
+#[allow(clippy::too_many_arguments)]
 async fn handle_stream_response(
     response: reqwest::Response,
     request: &mut ChatCompletionRequest,
     headers: &HeaderMap,
     chat_server: &TargetServerInfo,
-    request_id: &str,
     cancel_token: CancellationToken,
+    allowed_mcp_tools: Option<&[String]>,
+    injected_mcp_tools: &InjectedMcpTools,
+    passthrough_unknown_tools: bool,
+    stream_permits: Vec<OwnedSemaphorePermit>,
 ) -> ServerResult<axum::response::Response> {
     let status = response.status();
 
@@ -1540,14 +5302,22 @@ async fn handle_stream_response(
                     request,
                     headers,
                     chat_server,
-                    request_id,
                     cancel_token,
+                    allowed_mcp_tools,
+                    injected_mcp_tools,
+                    passthrough_unknown_tools,
                 )
                 .await
             } else {
                 // Handle normal response in stream mode
-                handle_normal_stream(response, status, response_headers, request_id, cancel_token)
-                    .await
+                handle_normal_stream(
+                    response,
+                    status,
+                    response_headers,
+                    cancel_token,
+                    stream_permits,
+                )
+                .await
             }
         }
         _ => {
@@ -1555,16 +5325,16 @@ async fn handle_stream_response(
             let status = response.status();
 
             let err_msg = format!("{status}");
-            dual_error!("{} - request_id: {}", err_msg, request_id);
+            dual_error!("{}", err_msg);
 
             let headers = response.headers().clone();
             let bytes = response.bytes().await.map_err(|e| {
                 let err_msg = format!("Failed to get response bytes: {e}");
-                dual_error!("{} - request_id: {}", err_msg, request_id);
+                dual_error!("{}", err_msg);
                 ServerError::Operation(err_msg)
             })?;
 
-            build_response(status, headers, bytes, request_id)
+            build_response(status, headers, bytes)
         }
     }
 }
@@ -1585,8 +5355,10 @@ async fn handle_stream_response(
 /// * `request` - Chat completion request, may be modified (e.g., add tool call results)
 /// * `headers` - HTTP request headers for subsequent requests
 /// * `chat_service_url` - Chat service URL for re-requesting after tool calls
-/// * `request_id` - Request ID for log tracking and error handling
 /// * `cancel_token` - Cancellation token for request cancellation support
+/// * `allowed_mcp_tools` - The MCP tools permitted for the request, or `None` for all tools
+/// * `injected_mcp_tools` - The MCP tools the gateway added to the request
+/// * `passthrough_unknown_tools` - Return the tool calls of the unknown tools to the client
 ///
 /// # Returns
 /// * `Ok(response)` - Successfully built HTTP response
@@ -1605,13 +5377,16 @@ async fn handle_stream_response(
 /// * Cancellation operation: Log warning and return cancellation error
 /// * Tool call error: Decide whether to continue based on error type
 /// * Response building error: Return build failure error
+#[allow(clippy::too_many_arguments)]
 async fn handle_non_stream_response(
     response: reqwest::Response,
     request: &mut ChatCompletionRequest,
     headers: &HeaderMap,
     chat_server: &TargetServerInfo,
-    request_id: &str,
     cancel_token: CancellationToken,
+    allowed_mcp_tools: Option<&[String]>,
+    injected_mcp_tools: &InjectedMcpTools,
+    passthrough_unknown_tools: bool,
 ) -> ServerResult<axum::response::Response> {
     let status = response.status();
 
@@ -1621,25 +5396,33 @@ async fn handle_non_stream_response(
             let response_headers = response.headers().clone();
 
             // Read the response body
-            let bytes = read_response_bytes(response, request_id, cancel_token.clone()).await?;
-            let chat_completion = parse_chat_completion(&bytes, request_id)?;
+            let bytes = read_response_bytes(response, cancel_token.clone()).await?;
+            let chat_completion = parse_chat_completion(&bytes)?;
 
             // Check if the response requires tool call
-            let requires_tool_call = !chat_completion.choices[0].message.tool_calls.is_empty();
+            let tool_calls = chat_completion.choices[0].message.tool_calls.as_slice();
+            let requires_tool_call = !tool_calls.is_empty();
 
-            if requires_tool_call {
+            if requires_tool_call
+                && passthrough_unknown_tools
+                && injected_mcp_tools.has_client_tool(tool_calls)
+            {
+                // Let the client run the tools it defined
+                dual_info!("Return the tool calls of the unknown tools to the client");
+                build_response(status, response_headers, bytes)
+            } else if requires_tool_call {
                 call_mcp_server(
-                    chat_completion.choices[0].message.tool_calls.as_slice(),
+                    tool_calls,
                     request,
                     headers,
                     chat_server,
-                    request_id,
                     cancel_token,
+                    allowed_mcp_tools,
                 )
                 .await
             } else {
                 // Handle normal response in non-stream mode
-                build_response(status, response_headers, bytes, request_id)
+                build_response(status, response_headers, bytes)
             }
         }
         _ => {
@@ -1647,16 +5430,16 @@ async fn handle_non_stream_response(
             let status = response.status();
 
             let err_msg = format!("{status}");
-            dual_error!("{} - request_id: {}", err_msg, request_id);
+            dual_error!("{}", err_msg);
 
             let headers = response.headers().clone();
             let bytes = response.bytes().await.map_err(|e| {
                 let err_msg = format!("Failed to get response bytes: {e}");
-                dual_error!("{} - request_id: {}", err_msg, request_id);
+                dual_error!("{}", err_msg);
                 ServerError::Operation(err_msg)
             })?;
 
-            build_response(status, headers, bytes, request_id)
+            build_response(status, headers, bytes)
         }
     }
 }
@@ -1672,24 +5455,41 @@ async fn handle_non_stream_response(
 /// * `request` - Chat request, will be modified to include tool call results
 /// * `headers` - HTTP request headers
 /// * `chat_server` - Chat server information
-/// * `request_id` - Request ID
 /// * `cancel_token` - Cancellation token
+/// * `allowed_mcp_tools` - The MCP tools permitted for the request, or `None` for all tools
+/// * `injected_mcp_tools` - The MCP tools the gateway added to the request
+/// * `passthrough_unknown_tools` - Return the stream as it is if a tool is not an MCP tool
+#[allow(clippy::too_many_arguments)]
 async fn handle_tool_call_stream(
     response: reqwest::Response,
     request: &mut ChatCompletionRequest,
     headers: &HeaderMap,
     chat_server: &TargetServerInfo,
-    request_id: &str,
     cancel_token: CancellationToken,
+    allowed_mcp_tools: Option<&[String]>,
+    injected_mcp_tools: &InjectedMcpTools,
+    passthrough_unknown_tools: bool,
 ) -> ServerResult<axum::response::Response> {
-    let tool_calls = extract_tool_calls_from_stream(response, request_id).await?;
+    let status = response.status();
+    let response_headers = response.headers().clone();
+
+    // the stream is read as a whole, so it can be returned as it is
+    let bytes = read_sse_response_bytes(response, cancel_token.clone()).await?;
+    let tool_calls = extract_tool_calls_from_sse(&bytes)?;
+
+    if passthrough_unknown_tools && injected_mcp_tools.has_client_tool(&tool_calls) {
+        // Let the client run the tools it defined
+        dual_info!("Return the tool calls of the unknown tools to the client");
+        return build_response(status, response_headers, bytes);
+    }
+
     call_mcp_server(
         tool_calls.as_slice(),
         request,
         headers,
         chat_server,
-        request_id,
         cancel_token,
+        allowed_mcp_tools,
     )
     .await
 }
@@ -1709,25 +5509,29 @@ fn parse_requires_tool_call_header(headers: &HeaderMap) -> bool {
 async fn handle_normal_stream(
     response: reqwest::Response,
     status: StatusCode,
-    response_headers: HeaderMap,
-    request_id: &str,
+    mut response_headers: HeaderMap,
     cancel_token: CancellationToken,
+    stream_permits: Vec<OwnedSemaphorePermit>,
 ) -> ServerResult<axum::response::Response> {
-    // Handle response body reading with cancellation
-    let bytes = select! {
-        bytes = response.bytes() => {
-            bytes.map_err(|e| {
-                let err_msg = format!("Failed to get the full response as bytes: {e}");
-                dual_error!("{} - request_id: {}", err_msg, request_id);
-                ServerError::Operation(err_msg)
-            })?
-        }
-        _ = cancel_token.cancelled() => {
-            let warn_msg = "Request was cancelled while reading response";
-            dual_warn!("{} - request_id: {}", warn_msg, request_id);
-            return Err(ServerError::Operation(warn_msg.to_string()));
-        }
-    };
+    let keep_alive = STREAM_KEEP_ALIVE_INTERVAL
+        .get()
+        .map(|secs| std::time::Duration::from_secs(*secs));
+    if keep_alive.is_some() {
+        // the heartbeats change the length of the body
+        response_headers.remove(axum::http::header::CONTENT_LENGTH);
+    }
+
+    // pass the chunks through as they arrive. If the client goes away, the body is dropped, which
+    // cancels the request and stops pulling from the downstream server.
+    let cancel_guard = cancel_token.clone().drop_guard();
+    let body_stream = keep_alive_stream(Box::pin(response.bytes_stream()), keep_alive)
+        .take_until(cancel_token.cancelled_owned())
+        .map(move |chunk| {
+            // hold the permits and the guard until the stream ends
+            let _stream_permits = &stream_permits;
+            let _cancel_guard = &cancel_guard;
+            chunk
+        });
 
     // build the response builder
     let response_builder = Response::builder().status(status);
@@ -1735,47 +5539,199 @@ async fn handle_normal_stream(
     // copy the response headers
     let response_builder = copy_response_headers(response_builder, &response_headers);
 
-    match response_builder.body(Body::from(bytes)) {
+    match response_builder.body(Body::from_stream(body_stream)) {
         Ok(response) => {
-            dual_info!(
-                "Chat request completed successfully - request_id: {}",
-                request_id
-            );
+            dual_info!("Streaming the chat response");
             Ok(response)
         }
         Err(e) => {
             let err_msg = format!("Failed to create the response: {e}");
-            dual_error!("{} - request_id: {}", err_msg, request_id);
+            dual_error!("{}", err_msg);
             Err(ServerError::Operation(err_msg))
         }
     }
 }
 
+/// Interleave the SSE comments keeping the connection alive with the chunks of a stream, whenever
+/// no chunk arrives within `interval`. The comments are only sent between two events, so that the
+/// SSE framing is kept.
+fn keep_alive_stream<S, E>(
+    stream: S,
+    interval: Option<std::time::Duration>,
+) -> impl futures_util::Stream<Item = Result<Bytes, E>>
+where
+    S: futures_util::Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    // the stream and whether the chunks so far end at an event boundary
+    futures_util::stream::unfold(
+        (stream, true),
+        move |(mut stream, at_boundary)| async move {
+            let chunk = match interval {
+                Some(interval) if at_boundary => {
+                    match tokio::time::timeout(interval, stream.next()).await {
+                        Ok(chunk) => chunk,
+                        Err(_) => {
+                            dual_debug!("Send a keep-alive comment to the idle stream");
+                            let heartbeat = Bytes::from_static(SSE_KEEP_ALIVE);
+                            return Some((Ok(heartbeat), (stream, at_boundary)));
+                        }
+                    }
+                }
+                _ => stream.next().await,
+            }?;
+
+            let at_boundary = match &chunk {
+                Ok(bytes) if !bytes.is_empty() => {
+                    bytes.ends_with(b"\n\n") || bytes.ends_with(b"\r\n\r\n")
+                }
+                _ => at_boundary,
+            };
+            Some((chunk, (stream, at_boundary)))
+        },
+    )
+}
+
+#[tokio::test]
+async fn test_keep_alive_stream() {
+    use std::time::Duration;
+
+    // an event, a long pause, then an event split into two chunks by another pause
+    let chunks = vec![
+        (Duration::ZERO, "data: a\n\n"),
+        (Duration::from_millis(200), "data: b"),
+        (Duration::from_millis(200), "\n\n"),
+    ];
+    let stream = Box::pin(futures_util::stream::unfold(
+        chunks.into_iter(),
+        |mut chunks| async move {
+            let (delay, chunk) = chunks.next()?;
+            tokio::time::sleep(delay).await;
+            Some((
+                Ok::<_, std::convert::Infallible>(Bytes::from(chunk)),
+                chunks,
+            ))
+        },
+    ));
+
+    let chunks: Vec<Bytes> = keep_alive_stream(stream, Some(Duration::from_millis(50)))
+        .map(Result::unwrap)
+        .collect()
+        .await;
+    let b = chunks.iter().position(|chunk| chunk == "data: b").unwrap();
+    assert_eq!(chunks[0], "data: a\n\n");
+    assert!(b > 1);
+    assert!(chunks[1..b].iter().all(|chunk| chunk == SSE_KEEP_ALIVE));
+    // no keep-alive comment in the middle of an event
+    assert_eq!(chunks[b + 1..], ["\n\n"]);
+
+    // no keep-alive comments if disabled
+    let stream = Box::pin(futures_util::stream::iter([Ok::<
+        _,
+        std::convert::Infallible,
+    >(Bytes::from(
+        "data: a\n\n",
+    ))]));
+    let chunks: Vec<_> = keep_alive_stream(stream, None).collect().await;
+    assert_eq!(chunks.len(), 1);
+}
+
 /// Read HTTP response body data with cancellation support
 ///
 /// This function uses select! macro to simultaneously monitor response reading and cancellation signals.
 /// When the request is cancelled, it immediately returns an error to avoid resource waste.
 async fn read_response_bytes(
     response: reqwest::Response,
-    request_id: &str,
     cancel_token: CancellationToken,
 ) -> ServerResult<Bytes> {
     select! {
         bytes = response.bytes() => {
             bytes.map_err(|e| {
                 let err_msg = format!("Failed to get the full response as bytes: {e}");
-                dual_error!("{} - request_id: {}", err_msg, request_id);
+                dual_error!("{}", err_msg);
                 ServerError::Operation(err_msg)
             })
         }
         _ = cancel_token.cancelled() => {
             let warn_msg = "Request was cancelled while reading response";
-            dual_warn!("{} - request_id: {}", warn_msg, request_id);
+            dual_warn!("{}", warn_msg);
             Err(ServerError::Operation(warn_msg.to_string()))
         }
     }
 }
 
+/// Read the body of a streaming response until its `[DONE]` event, with cancellation support
+///
+/// The reading stops at the `[DONE]` event even if the downstream server keeps the connection
+/// open, or at the end of the body if the server sends no `[DONE]`.
+async fn read_sse_response_bytes(
+    response: reqwest::Response,
+    cancel_token: CancellationToken,
+) -> ServerResult<Bytes> {
+    let mut parser = SseParser::default();
+    let mut body = Vec::new();
+    let mut stream = response.bytes_stream();
+    loop {
+        let chunk = select! {
+            chunk = stream.next() => chunk,
+            _ = cancel_token.cancelled() => {
+                let warn_msg = "Request was cancelled while reading response";
+                dual_warn!("{}", warn_msg);
+                return Err(ServerError::Operation(warn_msg.to_string()));
+            }
+        };
+
+        let chunk = match chunk {
+            Some(Ok(chunk)) => chunk,
+            Some(Err(e)) => {
+                let err_msg = format!("Failed to get the full response as bytes: {e}");
+                dual_error!("{}", err_msg);
+                return Err(ServerError::Operation(err_msg));
+            }
+            None => break,
+        };
+
+        body.extend_from_slice(&chunk);
+        if parser.feed(&chunk).iter().any(|event| event == "[DONE]") {
+            break;
+        }
+    }
+
+    Ok(Bytes::from(body))
+}
+
+#[tokio::test]
+async fn test_read_sse_response_bytes() {
+    // the server sends a transcript ending in `[DONE]` but never closes the stream
+    let transcript = [
+        "data: {\"choices\": [{\"index\": 0, \"delta\": {\"content\": \"Hi\"}}]}\n\n",
+        "data: {\"choices\": [{\"index\": 0, \"delta\": {}, \"finish_reason\": \"stop\"}]}",
+        "\n\ndata: [DO",
+        "NE]\n\n",
+    ];
+    let app = axum::Router::new().route(
+        "/stream",
+        axum::routing::get(move || async move {
+            let chunks = futures_util::stream::iter(transcript)
+                .map(|chunk| Ok::<_, std::io::Error>(Bytes::from_static(chunk.as_bytes())))
+                .chain(futures_util::stream::pending());
+            Body::from_stream(chunks)
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    let response = reqwest::get(format!("http://{addr}/stream")).await.unwrap();
+    let bytes = tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        read_sse_response_bytes(response, CancellationToken::new()),
+    )
+    .await
+    .expect("the reading should stop at [DONE]")
+    .unwrap();
+    assert_eq!(bytes, transcript.concat());
+}
+
 /// Build HTTP response object
 ///
 /// Build complete HTTP response based on status code, response headers and response body data.
@@ -1786,12 +5742,10 @@ async fn read_response_bytes(
 /// * `status` - HTTP status code
 /// * `response_headers` - Response headers
 /// * `bytes` - Response body data
-/// * `request_id` - Request ID for logging
 fn build_response(
     status: StatusCode,
     response_headers: HeaderMap,
     bytes: Bytes,
-    request_id: &str,
 ) -> ServerResult<axum::response::Response> {
     // build the response builder
     let mut response_builder = Response::builder().status(status);
@@ -1801,16 +5755,13 @@ fn build_response(
 
     match response_builder.body(Body::from(bytes)) {
         Ok(response) => {
-            dual_info!(
-                "Chat request completed successfully - request_id: {}",
-                request_id
-            );
+            dual_info!("Chat request completed successfully");
 
             Ok(response)
         }
         Err(e) => {
             let err_msg = format!("Failed to create the response: {e}");
-            dual_error!("{} - request_id: {}", err_msg, request_id);
+            dual_error!("{}", err_msg);
             Err(ServerError::Operation(err_msg))
         }
     }
@@ -1818,79 +5769,192 @@ fn build_response(
 
 /// Extract tool call information from streaming response
 ///
-/// Parse streaming response data and extract tool call information.
-/// Process SSE format data stream, parse ChatCompletionChunk and extract tool_calls.
-async fn extract_tool_calls_from_stream(
-    response: reqwest::Response,
-    request_id: &str,
-) -> ServerResult<Vec<ToolCall>> {
-    let mut ds_stream = response.bytes_stream();
-    let mut tool_calls: Vec<ToolCall> = Vec::new();
-
-    while let Some(item) = ds_stream.next().await {
-        match item {
-            Ok(bytes) => {
-                match String::from_utf8(bytes.to_vec()) {
-                    Ok(s) => {
-                        let x = s
-                            .trim_start_matches("data:")
-                            .trim()
-                            .split("data:")
-                            .collect::<Vec<_>>();
-                        let s = x[0];
-
-                        dual_debug!("s: {}", s);
-
-                        // convert the bytes to ChatCompletionChunk
-                        if let Ok(chunk) = serde_json::from_str::<ChatCompletionChunk>(s) {
-                            dual_debug!("chunk: {:?} - request_id: {}", &chunk, request_id);
-
-                            if !chunk.choices.is_empty() {
-                                for tool in chunk.choices[0].delta.tool_calls.iter() {
-                                    let tool_call = tool.clone().into();
-
-                                    dual_debug!("tool_call: {:?}", &tool_call);
-
-                                    tool_calls.push(tool_call);
-                                }
+/// Parse the SSE events of a streaming response and assemble the tool calls from their deltas.
+/// The first delta of a tool call carries its id and name, and the following deltas of the same
+/// index carry the rest of its arguments. The tool calls are complete once the stream sends
+/// `[DONE]` or a `finish_reason` of `tool_calls` or `stop`, and fail if the stream ends before or
+/// with another finish reason, e.g. `length`.
+fn extract_tool_calls_from_sse(bytes: &Bytes) -> ServerResult<Vec<ToolCall>> {
+    let mut parser = SseParser::default();
+    let mut events = parser.feed(bytes);
+    events.extend(parser.finish());
+
+    let mut tool_calls: BTreeMap<u64, ToolCall> = BTreeMap::new();
+    let mut complete = false;
+    for event in events {
+        dual_debug!("s: {}", event);
+
+        if event == "[DONE]" {
+            complete = true;
+            break;
+        }
 
-                                break;
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        let err_msg = format!(
-                            "Failed to convert bytes from downstream server into string: {e}"
-                        );
-                        dual_error!("{} - request_id: {}", err_msg, request_id);
-                        return Err(ServerError::Operation(err_msg));
-                    }
-                }
-            }
+        let chunk = match serde_json::from_str::<serde_json::Value>(&event) {
+            Ok(chunk) => chunk,
             Err(e) => {
-                let err_msg = format!("Failed to get the full response as bytes: {e}");
-                dual_error!("{} - request_id: {}", err_msg, request_id);
+                dual_warn!("Skip the SSE event not being a chat completion chunk: {e}");
+                continue;
+            }
+        };
+        let deltas = chunk
+            .pointer("/choices/0/delta/tool_calls")
+            .and_then(|deltas| deltas.as_array())
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        for (position, delta) in deltas.iter().enumerate() {
+            dual_debug!("tool_call delta: {}", delta);
+
+            let index = delta["index"].as_u64().unwrap_or(position as u64);
+            let tool_call = tool_calls.entry(index).or_insert_with(|| ToolCall {
+                id: String::new(),
+                ty: "function".to_string(),
+                function: Function {
+                    name: String::new(),
+                    arguments: String::new(),
+                },
+            });
+            if let Some(id) = delta["id"].as_str().filter(|id| !id.is_empty()) {
+                tool_call.id = id.to_string();
+            }
+            if let Some(ty) = delta["type"].as_str().filter(|ty| !ty.is_empty()) {
+                tool_call.ty = ty.to_string();
+            }
+            if let Some(name) = delta["function"]["name"].as_str()
+                && tool_call.function.name.is_empty()
+            {
+                tool_call.function.name = name.to_string();
+            }
+            if let Some(arguments) = delta["function"]["arguments"].as_str() {
+                tool_call.function.arguments.push_str(arguments);
+            }
+        }
+
+        match chunk
+            .pointer("/choices/0/finish_reason")
+            .and_then(|finish_reason| finish_reason.as_str())
+        {
+            Some("tool_calls" | "stop") => {
+                complete = true;
+                break;
+            }
+            // e.g. `length`, the tool calls are cut off
+            Some(finish_reason) if !tool_calls.is_empty() => {
+                let err_msg =
+                    format!("The tool calls are incomplete with finish reason: {finish_reason}");
+                dual_error!("{}", err_msg);
                 return Err(ServerError::Operation(err_msg));
             }
+            _ => {}
         }
     }
 
-    Ok(tool_calls)
+    if !complete && !tool_calls.is_empty() {
+        let err_msg = "The stream of the tool calls ended before they were complete";
+        dual_error!("{}", err_msg);
+        return Err(ServerError::Operation(err_msg.to_string()));
+    }
+
+    Ok(tool_calls.into_values().collect())
+}
+
+#[test]
+fn test_extract_tool_calls_from_sse() {
+    let chunk = |delta: serde_json::Value| {
+        serde_json::json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion.chunk",
+            "created": 1700000000,
+            "model": "Llama-3.2-3b",
+            "system_fingerprint": "fp_1",
+            "choices": [{"index": 0, "delta": delta, "logprobs": null, "finish_reason": null}],
+        })
+    };
+    let tool_call = serde_json::json!({
+        "role": "assistant",
+        "tool_calls": [{
+            "index": 0,
+            "id": "call-1",
+            "type": "function",
+            "function": {"name": "get_local_time", "arguments": "{}"},
+        }],
+    });
+    let body = format!(
+        "data: {}\n\ndata: {}\n\ndata: [DONE]\n\n",
+        chunk(tool_call),
+        chunk(serde_json::json!({"role": "assistant", "content": "ignored"})),
+    );
+
+    let tool_calls = extract_tool_calls_from_sse(&Bytes::from(body)).unwrap();
+    assert_eq!(tool_calls.len(), 1);
+    assert_eq!(tool_calls[0].id, "call-1");
+    assert_eq!(tool_calls[0].function.name, "get_local_time");
+
+    // the arguments streamed in several deltas, split across TCP reads, are assembled
+    let delta =
+        |tool_calls: serde_json::Value| chunk(serde_json::json!({"tool_calls": tool_calls}));
+    let body = [
+        delta(serde_json::json!([
+            {"index": 0, "id": "call-1", "type": "function", "function": {"name": "get_weather", "arguments": ""}},
+        ])),
+        delta(serde_json::json!([{"index": 0, "function": {"arguments": "{\"city\": "}}])),
+        delta(serde_json::json!([
+            {"index": 0, "function": {"arguments": "\"Paris\"}"}},
+            {"index": 1, "id": "call-2", "type": "function", "function": {"name": "get_local_time", "arguments": "{}"}},
+        ])),
+    ]
+    .iter()
+    .map(|chunk| format!("data: {chunk}\n\n"))
+    .collect::<String>()
+        + "data: [DONE]\n\n";
+    let tool_calls = extract_tool_calls_from_sse(&Bytes::from(body)).unwrap();
+    assert_eq!(tool_calls.len(), 2);
+    assert_eq!(tool_calls[0].id, "call-1");
+    assert_eq!(tool_calls[0].function.name, "get_weather");
+    assert_eq!(tool_calls[0].function.arguments, r#"{"city": "Paris"}"#);
+    assert_eq!(tool_calls[1].id, "call-2");
+    assert_eq!(tool_calls[1].function.arguments, "{}");
+
+    // the deltas after the finish reason are ignored, and the truncated tool calls fail
+    let finish = serde_json::json!({
+        "id": "chatcmpl-1",
+        "object": "chat.completion.chunk",
+        "created": 1700000000,
+        "model": "Llama-3.2-3b",
+        "choices": [{"index": 0, "delta": {}, "finish_reason": "tool_calls"}],
+    });
+    let first = delta(serde_json::json!([
+        {"index": 0, "id": "call-1", "type": "function", "function": {"name": "get_weather", "arguments": "{}"}},
+    ]));
+    let extra = delta(serde_json::json!([{"index": 0, "function": {"arguments": "garbage"}}]));
+    let body = format!("data: {first}\n\ndata: {finish}\n\ndata: {extra}\n\n");
+    let tool_calls = extract_tool_calls_from_sse(&Bytes::from(body)).unwrap();
+    assert_eq!(tool_calls[0].function.arguments, "{}");
+    let body = format!("data: {first}\n\n");
+    assert!(extract_tool_calls_from_sse(&Bytes::from(body)).is_err());
+    let mut truncated = finish.clone();
+    truncated["choices"][0]["finish_reason"] = "length".into();
+    let body = format!("data: {first}\n\ndata: {truncated}\n\ndata: [DONE]\n\n");
+    assert!(extract_tool_calls_from_sse(&Bytes::from(body)).is_err());
+
+    assert!(
+        extract_tool_calls_from_sse(&Bytes::from_static(b"data: [DONE]\n\n"))
+            .unwrap()
+            .is_empty()
+    );
 }
 
-fn parse_chat_completion(bytes: &Bytes, request_id: &str) -> ServerResult<ChatCompletionObject> {
+fn parse_chat_completion(bytes: &Bytes) -> ServerResult<ChatCompletionObject> {
     serde_json::from_slice(bytes).map_err(|e| {
         let value = serde_json::from_slice::<serde_json::Value>(bytes).unwrap();
 
         dual_error!(
-            "The response body received from the downstream server - request_id: {}:\n{}",
-            request_id,
+            "The response body received from the downstream server:\n{}",
             serde_json::to_string_pretty(&value).unwrap()
         );
 
         let err_msg = format!("Failed to parse the response: {e}");
 
-        dual_error!("{} - request_id: {}", err_msg, request_id);
+        dual_error!("{}", err_msg);
 
         ServerError::Operation(err_msg)
     })
@@ -1908,530 +5972,625 @@ fn copy_response_headers(
     response_builder: axum::http::response::Builder,
     headers: &HeaderMap,
 ) -> axum::http::response::Builder {
-    let allowed_headers = [
-        "access-control-allow-origin",
-        "access-control-allow-headers",
-        "access-control-allow-methods",
-        "content-type",
-        "content-length",
-        "cache-control",
-        "connection",
-        "user",
-        "date",
-        "requires-tool-call",
-    ];
-
     headers
         .iter()
         .fold(response_builder, |builder, (name, value)| {
-            if allowed_headers.contains(&name.as_str()) {
-                dual_debug!("copy header: {} - {}", name, value.to_str().unwrap());
+            if is_header_allowed(
+                name.as_str(),
+                EXPOSE_RESPONSE_HEADERS.get(),
+                DEFAULT_EXPOSE_RESPONSE_HEADERS,
+            ) {
+                dual_debug!("copy header: {} - {:?}", name, value);
                 builder.header(name, value)
             } else {
-                dual_debug!("ignore header: {} - {}", name, value.to_str().unwrap());
+                dual_debug!("ignore header: {} - {:?}", name, value);
                 builder
             }
         })
 }
 
+/// Read the raw request body, decompressed already if the client sent it compressed, rejecting
+/// the bodies larger than `max_request_body_size`, e.g. a decompression bomb, with `413`
+async fn read_request_body(state: &AppState, body: Body) -> ServerResult<Bytes> {
+    let limit = state.config.read().await.server.max_request_body_size;
+    axum::body::to_bytes(body, limit).await.map_err(|e| {
+        let mut source: Option<&(dyn std::error::Error + 'static)> = Some(&e);
+        while let Some(error) = source {
+            if error.is::<http_body_util::LengthLimitError>() {
+                let err_msg = format!("The request body is larger than {limit} bytes");
+                dual_error!("{err_msg}");
+                return ServerError::PayloadTooLarge(err_msg);
+            }
+            source = error.source();
+        }
+
+        let err_msg = format!("Failed to convert the request body into bytes: {e}");
+        dual_error!("{err_msg}");
+        ServerError::Operation(err_msg)
+    })
+}
+
+/// Send the api key of the server, if any, with the downstream request, along with the request
+/// headers of the client allowed to cross the gateway
+fn authorize_request(
+    ds_request: reqwest::RequestBuilder,
+    server: &TargetServerInfo,
+    headers: &HeaderMap,
+) -> reqwest::RequestBuilder {
+    match server
+        .api_key
+        .as_deref()
+        .filter(|api_key| !api_key.is_empty())
+    {
+        Some(api_key) => {
+            copy_request_headers(ds_request.header(AUTHORIZATION, api_key), headers, true)
+        }
+        None => copy_request_headers(ds_request, headers, false),
+    }
+}
+
+/// Copy the request headers of the client allowed to cross the gateway to the downstream
+/// request. The `authorization` of the client is not forwarded if the server has an api key. The
+/// tracing headers are always forwarded, whatever the allowed headers.
+fn copy_request_headers(
+    ds_request: reqwest::RequestBuilder,
+    headers: &HeaderMap,
+    has_api_key: bool,
+) -> reqwest::RequestBuilder {
+    let ds_request =
+        strip_hop_by_hop_headers(headers)
+            .iter()
+            .fold(ds_request, |ds_request, (name, value)| {
+                if (has_api_key && name == AUTHORIZATION)
+                    || !is_header_allowed(
+                        name.as_str(),
+                        FORWARD_REQUEST_HEADERS.get(),
+                        DEFAULT_FORWARD_REQUEST_HEADERS,
+                    )
+                {
+                    return ds_request;
+                }
+                ds_request.header(name, value)
+            });
+
+    forward_tracing_headers(ds_request, headers)
+}
+
+/// The request headers without the hop-by-hop and the length headers, including the headers named
+/// by the `connection` header
+fn strip_hop_by_hop_headers(headers: &HeaderMap) -> HeaderMap {
+    let connection_headers = headers
+        .get_all(axum::http::header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .collect::<HashSet<_>>();
+
+    headers
+        .iter()
+        .filter(|(name, _)| {
+            !HOP_BY_HOP_HEADERS.contains(&name.as_str())
+                && !connection_headers.contains(name.as_str())
+        })
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect()
+}
+
+#[test]
+fn test_strip_hop_by_hop_headers() {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CONTENT_TYPE,
+        "multipart/form-data; boundary=x".parse().unwrap(),
+    );
+    headers.insert("host", "localhost:3389".parse().unwrap());
+    headers.insert("connection", "keep-alive, x-trace".parse().unwrap());
+    headers.insert("keep-alive", "timeout=5".parse().unwrap());
+    headers.insert(CONTENT_LENGTH, "1024".parse().unwrap());
+    headers.insert("transfer-encoding", "chunked".parse().unwrap());
+    headers.insert("x-trace", "1".parse().unwrap());
+
+    let headers = strip_hop_by_hop_headers(&headers);
+    assert_eq!(headers.len(), 1);
+    assert_eq!(headers[CONTENT_TYPE], "multipart/form-data; boundary=x");
+
+    // dropped even if the config lists them
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_LENGTH, "1024".parse().unwrap());
+    headers.insert("host", "localhost:3389".parse().unwrap());
+    let ds_request = copy_request_headers(
+        http_client().post("http://localhost:10086/v1").body("{}"),
+        &headers,
+        false,
+    )
+    .build()
+    .unwrap();
+    assert!(!ds_request.headers().contains_key("host"));
+    assert_ne!(
+        ds_request
+            .headers()
+            .get(CONTENT_LENGTH)
+            .map(|v| v.as_bytes()),
+        Some(&b"1024"[..])
+    );
+}
+
+/// Whether the header may cross the gateway, i.e. is in the configured list, or in the default
+/// list if none is configured, and is not sensitive
+fn is_header_allowed(name: &str, allowed: Option<&Vec<String>>, defaults: &[&str]) -> bool {
+    if SENSITIVE_HEADERS.contains(&name) {
+        return false;
+    }
+
+    match allowed {
+        Some(allowed) => allowed
+            .iter()
+            .any(|header| header.eq_ignore_ascii_case(name)),
+        None => defaults.contains(&name),
+    }
+}
+
+#[test]
+fn test_is_header_allowed() {
+    // the defaults
+    assert!(is_header_allowed(
+        "content-type",
+        None,
+        DEFAULT_EXPOSE_RESPONSE_HEADERS
+    ));
+    assert!(!is_header_allowed(
+        "openai-organization",
+        None,
+        DEFAULT_EXPOSE_RESPONSE_HEADERS
+    ));
+    assert!(!is_header_allowed(
+        "x-admin-key",
+        None,
+        DEFAULT_FORWARD_REQUEST_HEADERS
+    ));
+
+    // the configured list overrides the defaults, but not the sensitive headers
+    let allowed = vec!["OpenAI-Organization".to_string(), "set-cookie".to_string()];
+    assert!(is_header_allowed(
+        "openai-organization",
+        Some(&allowed),
+        DEFAULT_EXPOSE_RESPONSE_HEADERS
+    ));
+    assert!(!is_header_allowed(
+        "content-type",
+        Some(&allowed),
+        DEFAULT_EXPOSE_RESPONSE_HEADERS
+    ));
+    assert!(!is_header_allowed(
+        "set-cookie",
+        Some(&allowed),
+        DEFAULT_EXPOSE_RESPONSE_HEADERS
+    ));
+}
+
+#[test]
+fn test_copy_request_headers() {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CONTENT_TYPE,
+        "multipart/form-data; boundary=x".parse().unwrap(),
+    );
+    headers.insert(AUTHORIZATION, "Bearer client-key".parse().unwrap());
+    headers.insert(ADMIN_KEY_HEADER, "admin-key".parse().unwrap());
+    headers.insert("cookie", "session=1".parse().unwrap());
+    headers.insert(REQUEST_ID_HEADER, "request-1".parse().unwrap());
+
+    let ds_request = copy_request_headers(
+        http_client().post("http://localhost:10086/v1"),
+        &headers,
+        false,
+    )
+    .build()
+    .unwrap();
+    assert_eq!(
+        ds_request.headers()[CONTENT_TYPE],
+        "multipart/form-data; boundary=x"
+    );
+    assert_eq!(ds_request.headers()[AUTHORIZATION], "Bearer client-key");
+    assert!(!ds_request.headers().contains_key(ADMIN_KEY_HEADER));
+    assert!(!ds_request.headers().contains_key("cookie"));
+    // the id of the request is forwarded though not among the allowed headers
+    assert_eq!(ds_request.headers()[REQUEST_ID_HEADER], "request-1");
+
+    // the api key of the server takes the place of the key of the client
+    let ds_request = copy_request_headers(
+        http_client().post("http://localhost:10086/v1"),
+        &headers,
+        true,
+    )
+    .build()
+    .unwrap();
+    assert!(!ds_request.headers().contains_key(AUTHORIZATION));
+}
+
+/// Convert the content of an MCP tool result into the text of a tool message. Images and audio
+/// become data URLs, and embedded resources become JSON strings.
+fn mcp_content_to_text(content: &RawContent) -> String {
+    match content {
+        RawContent::Text(text) => text.text.clone(),
+        RawContent::Image(image) => format!("data:{};base64,{}", image.mime_type, image.data),
+        RawContent::Audio(audio) => {
+            format!("data:{};base64,{}", audio.raw.mime_type, audio.raw.data)
+        }
+        RawContent::Resource(resource) => {
+            serde_json::to_string(&resource.resource).unwrap_or_default()
+        }
+    }
+}
+
+#[test]
+fn test_mcp_content_to_text() {
+    assert_eq!(mcp_content_to_text(&RawContent::text("sunny")), "sunny");
+    assert_eq!(
+        mcp_content_to_text(&RawContent::image("iVBORw0KGgo=", "image/png")),
+        "data:image/png;base64,iVBORw0KGgo="
+    );
+
+    let resource = RawContent::resource(rmcp::model::ResourceContents::TextResourceContents {
+        uri: "file:///weather.json".to_string(),
+        mime_type: Some("application/json".to_string()),
+        text: "{}".to_string(),
+    });
+    let text = mcp_content_to_text(&resource);
+    let value: serde_json::Value = serde_json::from_str(&text).unwrap();
+    assert_eq!(value["uri"], "file:///weather.json");
+    assert_eq!(value["text"], "{}");
+}
+
+/// Run the MCP tool calls of the model, and send the request with their results back to the chat
+/// server
 async fn call_mcp_server(
     tool_calls: &[ToolCall],
     request: &mut ChatCompletionRequest,
     headers: &HeaderMap,
     chat_server: &TargetServerInfo,
-    request_id: impl AsRef<str>,
     cancel_token: CancellationToken,
+    allowed_mcp_tools: Option<&[String]>,
 ) -> ServerResult<axum::response::Response> {
-    let request_id = request_id.as_ref();
-    // let chat_service_url = chat_service_url.as_ref();
-    let chat_service_url = format!("{}/chat/completions", chat_server.url.trim_end_matches('/'));
+    let chat_service_url = chat_server.chat_completions_url(request.model.as_deref())?;
 
     dual_debug!(
         "tool calls:\n{}",
         serde_json::to_string_pretty(tool_calls).unwrap()
     );
-    dual_debug!(
-        "first tool call:\n{}",
-        serde_json::to_string_pretty(&tool_calls[0]).unwrap()
+
+    // the tool calls are independent of each other, so they run concurrently
+    let results = futures_util::future::join_all(
+        tool_calls
+            .iter()
+            .map(|tool_call| call_mcp_tool(tool_call, headers, allowed_mcp_tools)),
+    )
+    .await;
+
+    // append the assistant message with the tool calls and their results to request messages
+    request
+        .messages
+        .extend(tool_call_messages(tool_calls, results));
+
+    // disable tool choice
+    if request.tool_choice.is_some() {
+        request.tool_choice = Some(ToolChoice::None);
+    }
+
+    // Create a request client that can be cancelled
+    let ds_request = if let Some(api_key) = &chat_server.api_key
+        && !api_key.is_empty()
+    {
+        http_client()
+            .post(&chat_service_url)
+            .header(CONTENT_TYPE, "application/json")
+            .header(chat_server.api_key_header(), api_key)
+            .json(&request)
+    } else if headers.contains_key("authorization") {
+        let authorization = headers
+            .get("authorization")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        http_client()
+            .post(&chat_service_url)
+            .header(CONTENT_TYPE, "application/json")
+            .header(AUTHORIZATION, authorization)
+            .json(&request)
+    } else {
+        http_client()
+            .post(&chat_service_url)
+            .header(CONTENT_TYPE, "application/json")
+            .json(&request)
+    };
+    let ds_request = forward_tracing_headers(ds_request, headers);
+
+    dual_info!(
+        "Request to downstream chat server:\n{}",
+        serde_json::to_string_pretty(&request).unwrap()
+    );
+
+    // Use select! to handle request cancellation
+    let ds_response = select! {
+        response = ds_request.send() => {
+            response.map_err(|e| {
+                let err_msg = format!(
+                    "Failed to forward the request to the downstream server: {e}"
+                );
+                dual_error!("{}", err_msg);
+                ServerError::Operation(err_msg)
+            })?
+        }
+        _ = cancel_token.cancelled() => {
+            let warn_msg = "Request was cancelled by client";
+            dual_warn!("{}", warn_msg);
+            return Err(ServerError::Operation(warn_msg.to_string()));
+        }
+    };
+
+    let status = ds_response.status();
+    let mut response_builder = Response::builder().status(status);
+
+    // copy the response headers
+    response_builder = copy_response_headers(response_builder, ds_response.headers());
+
+    // Handle response body reading with cancellation
+    let bytes = select! {
+        bytes = ds_response.bytes() => {
+            bytes.map_err(|e| {
+                let err_msg = format!("Failed to get the full response as bytes: {e}");
+                dual_error!("{}", err_msg);
+                ServerError::Operation(err_msg)
+            })?
+        }
+        _ = cancel_token.cancelled() => {
+            let warn_msg = "Request was cancelled while reading response";
+            dual_warn!("{}", warn_msg);
+            return Err(ServerError::Operation(warn_msg.to_string()));
+        }
+    };
+
+    match response_builder.body(Body::from(bytes)) {
+        Ok(response) => {
+            dual_info!("Chat request completed successfully");
+            Ok(response)
+        }
+        Err(e) => {
+            let err_msg = format!("Failed to create the response: {e}");
+            dual_error!("{}", err_msg);
+            Err(ServerError::Operation(err_msg))
+        }
+    }
+}
+
+/// Build the assistant message with the tool calls, followed by one tool message per tool call
+/// with its result
+///
+/// A failed tool call gets the description of its error as the result, so that the model can
+/// reason around it, even if all the tool calls fail.
+fn tool_call_messages(
+    tool_calls: &[ToolCall],
+    results: Vec<ServerResult<String>>,
+) -> Vec<ChatCompletionRequestMessage> {
+    let mut messages = Vec::with_capacity(tool_calls.len() + 1);
+    messages.push(ChatCompletionRequestMessage::Assistant(
+        ChatCompletionAssistantMessage::new(None, None, Some(tool_calls.to_vec())),
+    ));
+    for (tool_call, result) in tool_calls.iter().zip(results) {
+        let content = match result {
+            Ok(content) => content,
+            Err(e) => {
+                dual_warn!(
+                    "The call of the tool {} failed: {}",
+                    tool_call.function.name,
+                    e
+                );
+                format!("Error: {e}")
+            }
+        };
+        messages.push(ChatCompletionRequestMessage::Tool(
+            ChatCompletionToolMessage::new(content, tool_call.id.as_str()),
+        ));
+    }
+
+    messages
+}
+
+#[test]
+fn test_tool_call_messages() {
+    let tool_call = |id: &str, name: &str| ToolCall {
+        id: id.to_string(),
+        ty: "function".to_string(),
+        function: endpoints::chat::Function {
+            name: name.to_string(),
+            arguments: "{}".to_string(),
+        },
+    };
+    let tool_calls = [
+        tool_call("call-1", "get_weather"),
+        tool_call("call-2", "get_news"),
+    ];
+
+    let tool_messages = |messages: &[ChatCompletionRequestMessage]| {
+        messages[1..]
+            .iter()
+            .map(|message| match message {
+                ChatCompletionRequestMessage::Tool(message) => (
+                    message.tool_call_id().to_string(),
+                    message.content().to_string(),
+                ),
+                _ => panic!("not a tool message: {message:?}"),
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // the failed call gets an error note instead of failing the turn
+    let messages = tool_call_messages(
+        &tool_calls,
+        vec![Ok("sunny".to_string()), Err(ServerError::McpNotFoundClient)],
+    );
+    assert_eq!(messages.len(), 3);
+    assert!(matches!(
+        &messages[0],
+        ChatCompletionRequestMessage::Assistant(message)
+            if message.tool_calls().is_some_and(|calls| calls.len() == 2)
+    ));
+    assert_eq!(
+        tool_messages(&messages),
+        [
+            ("call-1".to_string(), "sunny".to_string()),
+            (
+                "call-2".to_string(),
+                "Error: Mcp server not found".to_string()
+            ),
+        ]
     );
 
-    let tool_call = &tool_calls[0];
-    let tool_call_id = tool_call.id.as_str();
+    // the model gets the errors even if none of the calls succeeds
+    let messages = tool_call_messages(
+        &tool_calls,
+        vec![
+            Err(ServerError::McpNotFoundClient),
+            Err(ServerError::McpEmptyContent),
+        ],
+    );
+    assert_eq!(
+        tool_messages(&messages),
+        [
+            (
+                "call-1".to_string(),
+                "Error: Mcp server not found".to_string()
+            ),
+            (
+                "call-2".to_string(),
+                "Error: Mcp server returned empty content".to_string()
+            ),
+        ]
+    );
+}
+
+/// Run an MCP tool call of the model, and return the text of its result for the tool message
+async fn call_mcp_tool(
+    tool_call: &ToolCall,
+    headers: &HeaderMap,
+    allowed_mcp_tools: Option<&[String]>,
+) -> ServerResult<String> {
     let tool_name = tool_call.function.name.as_str();
     let tool_args = &tool_call.function.arguments;
 
-    dual_debug!(
-        "tool name: {}, tool args: {} - request_id: {}",
-        tool_name,
-        tool_args,
-        request_id
-    );
+    if !is_mcp_tool_allowed(allowed_mcp_tools, tool_name) {
+        let err_msg = format!("Tool not allowed for the request: {tool_name}");
+        dual_error!("{}", err_msg);
+        return Err(ServerError::Operation(err_msg));
+    }
+
+    dual_debug!("tool name: {}, tool args: {}", tool_name, tool_args);
 
     // convert the func_args to a json object
     let arguments =
         serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(tool_args).ok();
 
     // find mcp client by tool name
-    if let Some(mcp_tools) = MCP_TOOLS.get() {
-        let tools = mcp_tools.read().await;
-        dual_debug!("mcp_tools: {:?}", mcp_tools);
-
-        // look up the tool name in MCP_TOOLS
-        if let Some(mcp_client_name) = tools.get(tool_name) {
-            if let Some(services) = MCP_SERVICES.get() {
-                let service_map = services.read().await;
-                // get the mcp client
-                let service = match service_map.get(mcp_client_name) {
-                    Some(mcp_client) => mcp_client,
-                    None => {
-                        let err_msg = format!("Tool not found: {tool_name}");
-                        dual_error!("{} - request_id: {}", err_msg, request_id);
-                        return Err(ServerError::Operation(err_msg.to_string()));
-                    }
-                };
+    let Some(mcp_tools) = MCP_TOOLS.get() else {
+        let err_msg = "Empty MCP TOOLS";
+        dual_error!("{}", err_msg);
+        return Err(ServerError::Operation(err_msg.to_string()));
+    };
+    let tools = mcp_tools.read().await;
+    dual_debug!("mcp_tools: {:?}", mcp_tools);
+
+    // look up the tool name in MCP_TOOLS
+    let Some(mcp_client_name) = tools.get(tool_name) else {
+        let err_msg = format!("Failed to find the MCP client with tool name: {tool_name}");
+        dual_error!("{}", err_msg);
+        return Err(ServerError::McpNotFoundClient);
+    };
+    let Some(services) = MCP_SERVICES.get() else {
+        let err_msg = "Empty MCP CLIENTS";
+        dual_error!("{}", err_msg);
+        return Err(ServerError::Operation(err_msg.to_string()));
+    };
+    let service_map = services.read().await;
+    // get the mcp client
+    let service = match service_map.get(mcp_client_name) {
+        Some(mcp_client) => mcp_client,
+        None => {
+            let err_msg = format!("Tool not found: {tool_name}");
+            dual_error!("{}", err_msg);
+            return Err(ServerError::Operation(err_msg.to_string()));
+        }
+    };
 
-                // get the server name from the peer info
-                let raw_server_name = match service.read().await.raw.peer_info() {
-                    Some(peer_info) => {
-                        let server_name = peer_info.server_info.name.clone();
-                        dual_debug!(
-                            "server name from peer info: {} - request_id: {}",
-                            server_name,
-                            request_id
-                        );
-                        server_name
-                    }
-                    None => {
-                        dual_warn!(
-                            "Failed to get peer info from the MCP client: {mcp_client_name}"
-                        );
+    // get the server name from the peer info
+    let raw_server_name = match service.read().await.raw.peer_info() {
+        Some(peer_info) => {
+            let server_name = peer_info.server_info.name.clone();
+            dual_debug!("server name from peer info: {}", server_name);
+            server_name
+        }
+        None => {
+            dual_warn!("Failed to get peer info from the MCP client: {mcp_client_name}");
 
-                        String::new()
-                    }
-                };
+            String::new()
+        }
+    };
 
-                dual_info!(
-                    "Call `{}::{}` mcp tool - request_id: {}",
-                    raw_server_name,
-                    tool_name,
-                    request_id
-                );
+    dual_info!("Call `{}::{}` mcp tool", raw_server_name, tool_name);
 
-                // call a tool
-                let request_param = CallToolRequestParam {
-                    name: tool_name.to_string().into(),
-                    arguments,
-                };
-                let res = service
-                    .read()
-                    .await
-                    .raw
-                    .call_tool(request_param)
-                    .await
-                    .map_err(|e| {
-                        dual_error!("Failed to call the tool: {}", e);
-                        ServerError::Operation(e.to_string())
-                    })?;
-                dual_debug!("{}", serde_json::to_string_pretty(&res).unwrap());
-
-                match res.is_error {
-                    Some(false) => {
-                        match &res.content {
-                            None => {
-                                let err_msg = "The mcp tool result is empty";
-                                dual_error!("{} - request_id: {}", err_msg, request_id);
-                                Err(ServerError::McpEmptyContent)
-                            }
-                            Some(content) => {
-                                let content = &content[0];
-                                match &content.raw {
-                                    RawContent::Text(text) => {
-                                        dual_info!("The mcp tool call result: {:#?}", text.text);
-
-                                        match SEARCH_MCP_SERVER_NAMES
-                                            .contains(&raw_server_name.as_str())
-                                        {
-                                            true => {
-                                                // get the fallback message from the mcp client
-                                                let fallback = if service
-                                                    .read()
-                                                    .await
-                                                    .has_fallback_message()
-                                                {
-                                                    service
-                                                        .read()
-                                                        .await
-                                                        .fallback_message
-                                                        .clone()
-                                                        .unwrap()
-                                                } else {
-                                                    DEFAULT_SEARCH_FALLBACK_MESSAGE.to_string()
-                                                };
-
-                                                dual_debug!(
-                                                    "fallback message: {} - request_id: {}",
-                                                    fallback,
-                                                    request_id
-                                                );
-
-                                                // format the content
-                                                let content = format!(
-                                                    "Please answer the question based on the information between **---BEGIN CONTEXT---** and **---END CONTEXT---**. Do not use any external knowledge. If the information between **---BEGIN CONTEXT---** and **---END CONTEXT---** is empty, please respond with `{fallback}`. Note that DO NOT use any tools if provided.\n\n---BEGIN CONTEXT---\n\n{context}\n\n---END CONTEXT---",
-                                                    fallback = fallback,
-                                                    context = &text.text,
-                                                );
-
-                                                // append assistant message with tool call to request messages
-                                                let assistant_completion_message =
-                                                    ChatCompletionRequestMessage::Assistant(
-                                                        ChatCompletionAssistantMessage::new(
-                                                            None,
-                                                            None,
-                                                            Some(tool_calls.to_vec()),
-                                                        ),
-                                                    );
-                                                request.messages.push(assistant_completion_message);
-
-                                                // append tool message with tool result to request messages
-                                                let tool_completion_message =
-                                                    ChatCompletionRequestMessage::Tool(
-                                                        ChatCompletionToolMessage::new(
-                                                            &content,
-                                                            tool_call_id,
-                                                        ),
-                                                    );
-                                                request.messages.push(tool_completion_message);
-
-                                                // disable tool choice
-                                                if request.tool_choice.is_some() {
-                                                    request.tool_choice = Some(ToolChoice::None);
-                                                }
-
-                                                // Create a request client that can be cancelled
-                                                let ds_request = if let Some(api_key) =
-                                                    &chat_server.api_key
-                                                    && !api_key.is_empty()
-                                                {
-                                                    reqwest::Client::new()
-                                                        .post(&chat_service_url)
-                                                        .header(CONTENT_TYPE, "application/json")
-                                                        .header(AUTHORIZATION, api_key)
-                                                        .json(&request)
-                                                } else if headers.contains_key("authorization") {
-                                                    let authorization = headers
-                                                        .get("authorization")
-                                                        .unwrap()
-                                                        .to_str()
-                                                        .unwrap()
-                                                        .to_string();
-
-                                                    reqwest::Client::new()
-                                                        .post(&chat_service_url)
-                                                        .header(CONTENT_TYPE, "application/json")
-                                                        .header(AUTHORIZATION, authorization)
-                                                        .json(&request)
-                                                } else {
-                                                    reqwest::Client::new()
-                                                        .post(&chat_service_url)
-                                                        .header(CONTENT_TYPE, "application/json")
-                                                        .json(&request)
-                                                };
-
-                                                dual_info!(
-                                                    "Request to downstream chat server - request_id: {}\n{}",
-                                                    request_id,
-                                                    serde_json::to_string_pretty(&request).unwrap()
-                                                );
-
-                                                // Use select! to handle request cancellation
-                                                let ds_response = select! {
-                                                    response = ds_request.send() => {
-                                                        response.map_err(|e| {
-                                                            let err_msg = format!(
-                                                                "Failed to forward the request to the downstream server: {e}"
-                                                            );
-                                                            dual_error!("{} - request_id: {}", err_msg, request_id);
-                                                            ServerError::Operation(err_msg)
-                                                        })?
-                                                    }
-                                                    _ = cancel_token.cancelled() => {
-                                                        let warn_msg = "Request was cancelled by client";
-                                                        dual_warn!("{} - request_id: {}", warn_msg, request_id);
-                                                        return Err(ServerError::Operation(warn_msg.to_string()));
-                                                    }
-                                                };
-
-                                                let status = ds_response.status();
-                                                let headers = ds_response.headers().clone();
-
-                                                // Handle response body reading with cancellation
-                                                let bytes = select! {
-                                                    bytes = ds_response.bytes() => {
-                                                        bytes.map_err(|e| {
-                                                            let err_msg = format!("Failed to get the full response as bytes: {e}");
-                                                            dual_error!("{} - request_id: {}", err_msg, request_id);
-                                                            ServerError::Operation(err_msg)
-                                                        })?
-                                                    }
-                                                    _ = cancel_token.cancelled() => {
-                                                        let warn_msg = "Request was cancelled while reading response";
-                                                        dual_warn!("{} - request_id: {}", warn_msg, request_id);
-                                                        return Err(ServerError::Operation(warn_msg.to_string()));
-                                                    }
-                                                };
-
-                                                let mut response_builder =
-                                                    Response::builder().status(status);
-
-                                                // Copy all headers from downstream response
-                                                match request.stream {
-                                                    Some(true) => {
-                                                        for (name, value) in headers.iter() {
-                                                            match name.as_str() {
-                                                                "access-control-allow-origin" => {
-                                                                    response_builder =
-                                                                        response_builder
-                                                                            .header(name, value);
-                                                                }
-                                                                "access-control-allow-headers" => {
-                                                                    response_builder =
-                                                                        response_builder
-                                                                            .header(name, value);
-                                                                }
-                                                                "access-control-allow-methods" => {
-                                                                    response_builder =
-                                                                        response_builder
-                                                                            .header(name, value);
-                                                                }
-                                                                "content-type" => {
-                                                                    response_builder =
-                                                                        response_builder
-                                                                            .header(name, value);
-                                                                }
-                                                                "cache-control" => {
-                                                                    response_builder =
-                                                                        response_builder
-                                                                            .header(name, value);
-                                                                }
-                                                                "connection" => {
-                                                                    response_builder =
-                                                                        response_builder
-                                                                            .header(name, value);
-                                                                }
-                                                                "user" => {
-                                                                    response_builder =
-                                                                        response_builder
-                                                                            .header(name, value);
-                                                                }
-                                                                "date" => {
-                                                                    response_builder =
-                                                                        response_builder
-                                                                            .header(name, value);
-                                                                }
-                                                                _ => {
-                                                                    dual_debug!(
-                                                                        "ignore header: {} - {}",
-                                                                        name,
-                                                                        value.to_str().unwrap()
-                                                                    );
-                                                                }
-                                                            }
-                                                        }
-                                                    }
-                                                    Some(false) | None => {
-                                                        for (name, value) in headers.iter() {
-                                                            dual_debug!(
-                                                                "{}: {}",
-                                                                name,
-                                                                value.to_str().unwrap()
-                                                            );
-                                                            response_builder = response_builder
-                                                                .header(name, value);
-                                                        }
-                                                    }
-                                                }
-
-                                                match response_builder.body(Body::from(bytes)) {
-                                                    Ok(response) => {
-                                                        dual_info!(
-                                                            "Chat request completed successfully - request_id: {}",
-                                                            request_id
-                                                        );
-                                                        Ok(response)
-                                                    }
-                                                    Err(e) => {
-                                                        let err_msg = format!(
-                                                            "Failed to create the response: {e}"
-                                                        );
-                                                        dual_error!(
-                                                            "{} - request_id: {}",
-                                                            err_msg,
-                                                            request_id
-                                                        );
-                                                        Err(ServerError::Operation(err_msg))
-                                                    }
-                                                }
-                                            }
-                                            false => {
-                                                // create an assistant message
-                                                let tool_completion_message =
-                                                    ChatCompletionRequestMessage::Tool(
-                                                        ChatCompletionToolMessage::new(
-                                                            &text.text,
-                                                            tool_call_id,
-                                                        ),
-                                                    );
-
-                                                // append assistant message with tool call to request messages
-                                                let assistant_completion_message =
-                                                    ChatCompletionRequestMessage::Assistant(
-                                                        ChatCompletionAssistantMessage::new(
-                                                            None,
-                                                            None,
-                                                            Some(tool_calls.to_vec()),
-                                                        ),
-                                                    );
-                                                request.messages.push(assistant_completion_message);
-                                                // append tool message with tool result to request messages
-                                                request.messages.push(tool_completion_message);
-
-                                                // disable tool choice
-                                                if request.tool_choice.is_some() {
-                                                    request.tool_choice = Some(ToolChoice::None);
-                                                }
-
-                                                // Create a request client that can be cancelled
-                                                let ds_request = if let Some(api_key) =
-                                                    &chat_server.api_key
-                                                    && !api_key.is_empty()
-                                                {
-                                                    reqwest::Client::new()
-                                                        .post(&chat_service_url)
-                                                        .header(CONTENT_TYPE, "application/json")
-                                                        .header(AUTHORIZATION, api_key)
-                                                        .json(&request)
-                                                } else if headers.contains_key("authorization") {
-                                                    let authorization = headers
-                                                        .get("authorization")
-                                                        .unwrap()
-                                                        .to_str()
-                                                        .unwrap()
-                                                        .to_string();
-
-                                                    reqwest::Client::new()
-                                                        .post(&chat_service_url)
-                                                        .header(CONTENT_TYPE, "application/json")
-                                                        .header(AUTHORIZATION, authorization)
-                                                        .json(&request)
-                                                } else {
-                                                    reqwest::Client::new()
-                                                        .post(&chat_service_url)
-                                                        .header(CONTENT_TYPE, "application/json")
-                                                        .json(&request)
-                                                };
-
-                                                dual_info!(
-                                                    "Request to downstream chat server - request_id: {}\n{}",
-                                                    request_id,
-                                                    serde_json::to_string_pretty(&request).unwrap()
-                                                );
-
-                                                // Use select! to handle request cancellation
-                                                let ds_response = select! {
-                                                    response = ds_request.send() => {
-                                                        response.map_err(|e| {
-                                                            let err_msg = format!(
-                                                                "Failed to forward the request to the downstream server: {e}"
-                                                            );
-                                                            dual_error!("{} - request_id: {}", err_msg, request_id);
-                                                            ServerError::Operation(err_msg)
-                                                        })?
-                                                    }
-                                                    _ = cancel_token.cancelled() => {
-                                                        let warn_msg = "Request was cancelled by client";
-                                                        dual_warn!("{} - request_id: {}", warn_msg, request_id);
-                                                        return Err(ServerError::Operation(warn_msg.to_string()));
-                                                    }
-                                                };
-
-                                                let status = ds_response.status();
-                                                let mut response_builder =
-                                                    Response::builder().status(status);
-
-                                                // copy the response headers
-                                                response_builder = copy_response_headers(
-                                                    response_builder,
-                                                    ds_response.headers(),
-                                                );
-
-                                                // Handle response body reading with cancellation
-                                                let bytes = select! {
-                                                    bytes = ds_response.bytes() => {
-                                                        bytes.map_err(|e| {
-                                                            let err_msg = format!("Failed to get the full response as bytes: {e}");
-                                                            dual_error!("{} - request_id: {}", err_msg, request_id);
-                                                            ServerError::Operation(err_msg)
-                                                        })?
-                                                    }
-                                                    _ = cancel_token.cancelled() => {
-                                                        let warn_msg = "Request was cancelled while reading response";
-                                                        dual_warn!("{} - request_id: {}", warn_msg, request_id);
-                                                        return Err(ServerError::Operation(warn_msg.to_string()));
-                                                    }
-                                                };
-
-                                                match response_builder.body(Body::from(bytes)) {
-                                                    Ok(response) => {
-                                                        dual_info!(
-                                                            "Chat request completed successfully - request_id: {}",
-                                                            request_id
-                                                        );
-                                                        Ok(response)
-                                                    }
-                                                    Err(e) => {
-                                                        let err_msg = format!(
-                                                            "Failed to create the response: {e}"
-                                                        );
-                                                        dual_error!(
-                                                            "{} - request_id: {}",
-                                                            err_msg,
-                                                            request_id
-                                                        );
-                                                        Err(ServerError::Operation(err_msg))
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    }
-                                    _ => {
-                                        let err_msg =
-                                            "Only text content is supported for tool call results";
-                                        dual_error!("{} - request_id: {}", err_msg, request_id);
-                                        Err(ServerError::Operation(err_msg.to_string()))
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    _ => {
-                        let err_msg = format!("Failed to call the tool: {tool_name}");
-                        dual_error!("{} - request_id: {}", err_msg, request_id);
-                        Err(ServerError::Operation(err_msg))
-                    }
+    let res = {
+        // call the tool by its name on the server
+        let service = service.read().await;
+        let request_param = CallToolRequestParam {
+            name: service.tool_name_on_server(tool_name).to_string().into(),
+            arguments,
+        };
+        service.ensure_connected()?;
+        service
+            .call_tool(request_param, request_id(headers))
+            .await
+            .map_err(|e| {
+                // the call fails with a retryable error if the connection dropped
+                if let Err(reconnecting) = service.ensure_connected() {
+                    return reconnecting;
                 }
-            } else {
-                let err_msg = "Empty MCP CLIENTS";
-                dual_error!("{} - request_id: {}", err_msg, request_id);
-                Err(ServerError::Operation(err_msg.to_string()))
-            }
-        } else {
-            let err_msg = format!("Failed to find the MCP client with tool name: {tool_name}");
-            dual_error!("{} - request_id: {}", err_msg, request_id);
-            Err(ServerError::McpNotFoundClient)
-        }
-    } else {
-        let err_msg = "Empty MCP TOOLS";
-        dual_error!("{} - request_id: {}", err_msg, request_id);
-        Err(ServerError::Operation(err_msg.to_string()))
+
+                dual_error!("Failed to call the tool: {}", e);
+                ServerError::Operation(e.to_string())
+            })?
+    };
+    dual_debug!("{}", serde_json::to_string_pretty(&res).unwrap());
+
+    if res.is_error != Some(false) {
+        // the message of the error result, e.g. of a timed out call
+        let reason = res
+            .content
+            .iter()
+            .flatten()
+            .map(|content| mcp_content_to_text(&content.raw))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let err_msg = match reason.is_empty() {
+            true => format!("Failed to call the tool: {tool_name}"),
+            false => format!("Failed to call the tool: {tool_name}. {reason}"),
+        };
+        dual_error!("{}", err_msg);
+        return Err(ServerError::Operation(err_msg));
+    }
+    let Some(content) = &res.content else {
+        let err_msg = "The mcp tool result is empty";
+        dual_error!("{}", err_msg);
+        return Err(ServerError::McpEmptyContent);
+    };
+    let text = mcp_content_to_text(&content[0].raw);
+
+    dual_info!("The mcp tool call result: {:#?}", text);
+
+    match mcp::is_search_mcp_server(&raw_server_name) {
+        // wrap the search results in the grounding instructions
+        true => Ok(service.read().await.search_context_prompt(&text)),
+        false => Ok(text),
     }
 }
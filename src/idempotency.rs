@@ -0,0 +1,285 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    hash::{BuildHasher, RandomState},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{extract::FromRequestParts, http::request::Parts};
+
+use crate::{ApiKey, ClientIp, cache::CachedResponse, config::IdempotencyConfig};
+
+/// The header the clients set to the key of a request they may retry
+pub(crate) const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+/// The header set on the responses replayed for a repeated idempotency key
+pub(crate) const IDEMPOTENT_REPLAYED_HEADER: &str = "idempotent-replayed";
+
+/// The in-memory store of the responses of the requests sent with an `Idempotency-Key` header,
+/// keyed by the endpoint, the caller and the key, and kept for the TTL
+#[derive(Debug)]
+pub(crate) struct IdempotencyStore {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, IdempotentEntry>>,
+    // hashes the callers and the request bodies, so that neither the API keys nor the bodies are
+    // kept in the store
+    hasher: RandomState,
+    // the id of the next reservation
+    next_reservation: AtomicU64,
+}
+impl IdempotencyStore {
+    pub(crate) fn new(idempotency_config: &IdempotencyConfig) -> Self {
+        Self {
+            ttl: Duration::from_secs(idempotency_config.ttl_secs),
+            entries: Mutex::new(HashMap::new()),
+            hasher: RandomState::new(),
+            next_reservation: AtomicU64::new(0),
+        }
+    }
+
+    /// How often the expired responses are swept
+    pub(crate) fn sweep_interval(&self) -> Duration {
+        self.ttl.max(Duration::from_secs(1))
+    }
+
+    /// Reserve the key of the caller on the endpoint for the request with the body, unless the
+    /// key is in flight or has been seen within the TTL. The reservation is released if dropped
+    /// before the response is stored, so that the client may retry the failed requests.
+    pub(crate) fn reserve(
+        self: &Arc<Self>,
+        endpoint: &str,
+        caller: &IdempotencyCaller,
+        key: &str,
+        body: &[u8],
+    ) -> Reservation {
+        let store_key = format!("{endpoint} {:016x} {key}", self.hasher.hash_one(&caller.0));
+        let fingerprint = self.hasher.hash_one(body);
+
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.get(&store_key)
+            && !entry.is_expired(self.ttl)
+        {
+            return match &entry.response {
+                _ if entry.fingerprint != fingerprint => Reservation::Mismatch,
+                Some(response) => Reservation::Replay(response.clone()),
+                None => Reservation::InFlight,
+            };
+        }
+        let reservation = self.next_reservation.fetch_add(1, Ordering::Relaxed);
+        entries.insert(
+            store_key.clone(),
+            IdempotentEntry {
+                fingerprint,
+                reservation,
+                response: None,
+                inserted_at: Instant::now(),
+            },
+        );
+
+        Reservation::Reserved(IdempotencyGuard {
+            store: self.clone(),
+            store_key,
+            reservation,
+            stored: false,
+        })
+    }
+
+    /// Drop the expired responses. Returns the number of the entries dropped.
+    pub(crate) fn sweep(&self) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let len = entries.len();
+        entries.retain(|_, entry| !entry.is_expired(self.ttl));
+        len - entries.len()
+    }
+}
+
+/// What to do with a request sent with an idempotency key
+#[derive(Debug)]
+pub(crate) enum Reservation {
+    /// The key is new. The request is forwarded, and its response stored with the guard.
+    Reserved(IdempotencyGuard),
+    /// The response of the key is replayed
+    Replay(CachedResponse),
+    /// The request of the key is still being forwarded
+    InFlight,
+    /// The key was sent with another request body
+    Mismatch,
+}
+
+/// The reservation of an idempotency key, released when dropped unless the response is stored
+#[derive(Debug)]
+pub(crate) struct IdempotencyGuard {
+    store: Arc<IdempotencyStore>,
+    store_key: String,
+    // the id of the reservation, so that the guard only touches the entry it reserved
+    reservation: u64,
+    stored: bool,
+}
+impl IdempotencyGuard {
+    /// Store the response for the reserved key, to be replayed until it expires
+    pub(crate) fn store(mut self, response: CachedResponse) {
+        let mut entries = self.store.entries.lock().unwrap();
+        if let Some(entry) = entries
+            .get_mut(&self.store_key)
+            .filter(|entry| entry.reservation == self.reservation)
+        {
+            entry.response = Some(response);
+            entry.inserted_at = Instant::now();
+            self.stored = true;
+        }
+    }
+}
+impl Drop for IdempotencyGuard {
+    fn drop(&mut self) {
+        if self.stored {
+            return;
+        }
+        let mut entries = self.store.entries.lock().unwrap();
+        if entries
+            .get(&self.store_key)
+            .is_some_and(|entry| entry.reservation == self.reservation && entry.response.is_none())
+        {
+            entries.remove(&self.store_key);
+        }
+    }
+}
+
+/// The caller the idempotency keys are scoped to: the API key the request was authenticated
+/// with, or else the IP address of the client
+#[derive(Debug, Clone, Default)]
+pub(crate) struct IdempotencyCaller(String);
+impl<S: Send + Sync> FromRequestParts<S> for IdempotencyCaller {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let caller = match (
+            parts.extensions.get::<ApiKey>(),
+            parts.extensions.get::<ClientIp>(),
+        ) {
+            (Some(ApiKey(api_key)), _) => format!("key {api_key}"),
+            (None, Some(ClientIp(ip))) => format!("ip {ip}"),
+            (None, None) => String::new(),
+        };
+        Ok(Self(caller))
+    }
+}
+
+#[derive(Debug)]
+struct IdempotentEntry {
+    // the hash of the body of the request sent with the key
+    fingerprint: u64,
+    // the id of the reservation of the key
+    reservation: u64,
+    // the response of the request, or `None` while it is being forwarded
+    response: Option<CachedResponse>,
+    inserted_at: Instant,
+}
+impl IdempotentEntry {
+    /// Whether the response is past the TTL. The requests in flight never expire, however long
+    /// they take, so that their retries are not forwarded again.
+    fn is_expired(&self, ttl: Duration) -> bool {
+        self.response.is_some() && self.inserted_at.elapsed() > ttl
+    }
+}
+
+#[test]
+fn test_idempotency_store() {
+    use axum::http::{HeaderMap, StatusCode};
+    use bytes::Bytes;
+
+    let response = |body: &'static str| CachedResponse {
+        status: StatusCode::OK,
+        headers: HeaderMap::new(),
+        body: Bytes::from(body),
+    };
+    let alice = IdempotencyCaller("key alice".to_string());
+    let bob = IdempotencyCaller("key bob".to_string());
+
+    let store = Arc::new(IdempotencyStore::new(&IdempotencyConfig { ttl_secs: 60 }));
+    let Reservation::Reserved(guard) = store.reserve("chat", &alice, "key-1", b"hello") else {
+        panic!("the new key is not reserved");
+    };
+    // the key is reserved while the request is forwarded
+    assert!(matches!(
+        store.reserve("chat", &alice, "key-1", b"hello"),
+        Reservation::InFlight
+    ));
+    guard.store(response("one"));
+    let Reservation::Replay(kept) = store.reserve("chat", &alice, "key-1", b"hello") else {
+        panic!("the response is not replayed");
+    };
+    assert_eq!(kept.body, "one");
+    // the key cannot be reused with another body
+    assert!(matches!(
+        store.reserve("chat", &alice, "key-1", b"goodbye"),
+        Reservation::Mismatch
+    ));
+    // the keys are scoped to the endpoint and the caller
+    assert!(matches!(
+        store.reserve("embeddings", &alice, "key-1", b"hello"),
+        Reservation::Reserved(_)
+    ));
+    assert!(matches!(
+        store.reserve("chat", &bob, "key-1", b"hello"),
+        Reservation::Reserved(_)
+    ));
+    assert_eq!(store.sweep(), 0);
+
+    // the reservation of a failed request is released
+    drop(store.reserve("chat", &alice, "key-2", b"hello"));
+    assert!(matches!(
+        store.reserve("chat", &alice, "key-2", b"goodbye"),
+        Reservation::Reserved(_)
+    ));
+
+    // the expired responses are neither replayed nor kept
+    let store = Arc::new(IdempotencyStore::new(&IdempotencyConfig { ttl_secs: 0 }));
+    let Reservation::Reserved(guard) = store.reserve("chat", &alice, "key-1", b"hello") else {
+        panic!("the new key is not reserved");
+    };
+    // but the requests in flight are kept past the TTL
+    std::thread::sleep(Duration::from_millis(5));
+    assert!(matches!(
+        store.reserve("chat", &alice, "key-1", b"hello"),
+        Reservation::InFlight
+    ));
+    assert_eq!(store.sweep(), 0);
+    guard.store(response("one"));
+    std::thread::sleep(Duration::from_millis(5));
+    let Reservation::Reserved(guard) = store.reserve("chat", &alice, "key-1", b"goodbye") else {
+        panic!("the expired key is not reserved");
+    };
+    guard.store(response("two"));
+    std::thread::sleep(Duration::from_millis(5));
+    assert_eq!(store.sweep(), 1);
+
+    // a guard neither stores nor releases the reservation of another request of its key
+    let store = Arc::new(IdempotencyStore::new(&IdempotencyConfig { ttl_secs: 60 }));
+    let reserve = || match store.reserve("chat", &alice, "key-1", b"hello") {
+        Reservation::Reserved(guard) => guard,
+        _ => panic!("the new key is not reserved"),
+    };
+    let stale = reserve();
+    store.entries.lock().unwrap().clear();
+    let guard = reserve();
+    stale.store(response("stale"));
+    assert!(matches!(
+        store.reserve("chat", &alice, "key-1", b"hello"),
+        Reservation::InFlight
+    ));
+    store.entries.lock().unwrap().clear();
+    let current = reserve();
+    drop(guard);
+    assert!(matches!(
+        store.reserve("chat", &alice, "key-1", b"hello"),
+        Reservation::InFlight
+    ));
+    current.store(response("three"));
+    let Reservation::Replay(kept) = store.reserve("chat", &alice, "key-1", b"hello") else {
+        panic!("the response is not replayed");
+    };
+    assert_eq!(kept.body, "three");
+}
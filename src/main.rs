@@ -1,52 +1,102 @@
+mod anthropic;
+mod cache;
+mod client;
 mod config;
+mod database;
 mod error;
 mod handlers;
+mod idempotency;
 mod info;
 mod mcp;
+mod moderation;
+mod queue;
+// the RAG pipeline is not served yet, so it is only compiled for its tests
+#[cfg(test)]
+#[allow(dead_code)]
+mod rag;
+mod redaction;
 mod server;
+mod sse;
+mod stats;
+mod telemetry;
+mod usage;
 mod utils;
-mod database;
 
 use std::{
     collections::{HashMap, HashSet},
     net::{IpAddr, SocketAddr},
-    path::PathBuf,
-    str::FromStr,
+    path::{Path, PathBuf},
     sync::Arc,
+    time::{Duration, Instant},
 };
 
 use axum::{
+    ServiceExt,
     body::Body,
-    extract::{Json, State},
-    http::{self, HeaderMap, HeaderValue, Request, StatusCode},
+    extract::{ConnectInfo, Json, MatchedPath, State},
+    http::{self, HeaderMap, HeaderValue, Request, StatusCode, Uri},
     response::IntoResponse,
-    routing::{get, post, Router},
+    routing::{Router, get, post},
 };
+use cache::ResponseCache;
 use clap::Parser;
-use config::Config;
-use database::ChatMessage; 
+use config::{
+    CompressionConfig, Config, DatabaseConfig, DuplicateServerPolicy, TracingConfig, WebUiMount,
+};
+use database::{ChatMessage, SessionStore};
 use error::{ServerError, ServerResult};
 use futures_util::stream::{self, StreamExt};
+use idempotency::IdempotencyStore;
+use ipnet::IpNet;
 use once_cell::sync::OnceCell;
-use serde::Deserialize;
+use opentelemetry_sdk::trace::Tracer;
+use queue::RequestQueue;
+use redaction::ResponseRedactor;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use tokio::{signal, sync::RwLock};
+use stats::RequestStats;
+use tokio::{
+    signal,
+    sync::{RwLock, Semaphore},
+};
 use tokio_util::sync::CancellationToken;
+use tower::{
+    Layer,
+    util::{MapRequest, MapRequestLayer},
+};
 use tower_http::{
+    compression::{
+        CompressionLayer,
+        predicate::{NotForContentType, Predicate, SizeAbove},
+    },
     cors::{Any, CorsLayer},
+    decompression::RequestDecompressionLayer,
     services::ServeDir,
+    set_status::SetStatus,
     trace::TraceLayer,
 };
-use tracing::Level;
+use tracing::{Instrument, Level};
+use tracing_subscriber::{Registry, filter::LevelFilter, fmt::MakeWriter, prelude::*, reload};
+use usage::UsageLedger;
 use uuid::Uuid;
 
 use crate::{
+    client::{http_client, init_health_check_client, init_http_client},
     info::ServerInfo,
-    server::{Server, ServerGroup, ServerId, ServerKind},
+    server::{
+        ExclusionReason, HealthStatus, RoutingCandidate, Server, ServerGroup, ServerId, ServerKind,
+    },
+    utils::keys_match,
 };
 
 // Global health check interval for downstream servers in seconds
 pub(crate) static HEALTH_CHECK_INTERVAL: OnceCell<u64> = OnceCell::new();
+// Attempts to send a health check result to `server_health_push_url`, and the delay before the
+// first retry, which doubles on every retry
+const HEALTH_PUSH_ATTEMPTS: u32 = 3;
+const HEALTH_PUSH_RETRY_BACKOFF: Duration = Duration::from_millis(500);
+// Path prefixes of the API routes. The paths of the Web UI are served as they are.
+const API_PATH_PREFIXES: [&str; 4] = ["/v1/", "/admin/", "/responses", "/sessions/"];
 /// Defines the structure of the JSON body for a `/responses` request.
 #[derive(Deserialize)]
 pub struct ResponsesRequest {
@@ -58,7 +108,41 @@ pub(crate) struct AppState {
     config: Arc<RwLock<Config>>,
     server_info: Arc<RwLock<ServerInfo>>,
     models: Arc<RwLock<HashMap<ServerId, Vec<endpoints::models::Model>>>>,
+    // Permits of the concurrent streaming responses. Unlimited if not set.
+    stream_permits: Option<Arc<Semaphore>>,
+    // Handle to change the log level at runtime. Not set if the logging is not initialized.
+    log_level_handle: Option<LogLevelHandle>,
+    // Set while the proxy endpoints are down for maintenance
+    maintenance: Arc<RwLock<Option<MaintenanceMode>>>,
+    // Request stats of the endpoints. Not collected if disabled in the config.
+    stats: Option<Arc<RequestStats>>,
+    // Cached responses of the chat requests. Not cached if the `[cache]` section is not set.
+    response_cache: Option<Arc<ResponseCache>>,
+    // The chat requests waiting for a saturated server. Not queued if disabled in the config.
+    request_queue: Option<Arc<RequestQueue>>,
+    // Held while checking the health of the servers, so that the checks do not overlap
+    health_check_lock: Arc<tokio::sync::Mutex<()>>,
+    // The tokens used by the requests. Not accounted if the `[usage]` section is not set.
+    usage: Option<Arc<UsageLedger>>,
+    // Redacts the patterns from the responses. Not redacted if the `[redaction]` section is not set.
+    redactor: Option<Arc<ResponseRedactor>>,
+    // Notified when a server is registered, waking up the requests waiting for a server
+    server_registered: Arc<tokio::sync::Notify>,
+    // The responses replayed for the idempotency keys. Not kept if the `[idempotency]` section is
+    // not set.
+    idempotency: Option<Arc<IdempotencyStore>>,
+    // The store of the chat history of the `/responses` sessions, opened on the first use
+    session_store: Arc<tokio::sync::OnceCell<Arc<dyn SessionStore>>>,
+}
+/// The response of the proxy endpoints during maintenance
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct MaintenanceMode {
+    pub message: String,
+    /// Seconds the clients should wait before retrying, sent in the `Retry-After` header
+    pub retry_after: u64,
 }
+/// Handle to the level filter of the global subscriber
+pub(crate) type LogLevelHandle = reload::Handle<LevelFilter, Registry>;
 #[derive(Debug, Parser)]
 #[command(version = env!("CARGO_PKG_VERSION"), about = "LlamaEdge Nexus - A gateway service for LLM backends")]
 struct Cli {
@@ -80,6 +164,9 @@ struct Cli {
     /// Log file path (required when log_destination is "file" or "both")
     #[arg(long)]
     log_file: Option<String>,
+    /// Log format: "text" or "json"
+    #[arg(long, default_value = "text")]
+    log_format: String,
 }
 /// The handler for the stateful `/responses` API endpoint.
 pub(crate) async fn responses_handler(
@@ -89,12 +176,11 @@ pub(crate) async fn responses_handler(
     State(state): State<Arc<AppState>>,
     headers: HeaderMap,
     Json(payload): Json<ResponsesRequest>,
-) -> impl IntoResponse {
+) -> ServerResult<impl IntoResponse> {
     println!("[INFO] Received request for /responses");
 
     // 1. Establish a database connection.
-    // Panicking here is acceptable for the pre-test if the DB can't be opened.
-    let db_conn = database::connect().expect("Failed to connect to database");
+    let store = state.session_store().await?;
 
     // 2. Get or create a session ID.
     // Check for an "X-Session-ID" header to continue an existing conversation.
@@ -105,15 +191,43 @@ pub(crate) async fn responses_handler(
         .unwrap_or_else(|| {
             // If no header is found, create a new session ID.
             let new_id = Uuid::new_v4().to_string();
-            println!("[INFO] New conversation started. Session ID: {}", new_id);
+            println!("[INFO] New conversation started. Session ID: {new_id}");
             new_id
         });
 
     // 3. Retrieve this session's chat history from the database.
-    let history = database::get_history(&db_conn, &session_id).unwrap_or_else(|err| {
-        println!("[WARN] Could not retrieve history: {}. Starting fresh.", err);
-        Vec::new()
-    });
+    let history = store.get_history(&session_id).await.map_err(|e| {
+        store_error(
+            format!("Failed to read the history of the session {session_id}"),
+            e,
+        )
+    })?;
+    let database_config = state
+        .config
+        .read()
+        .await
+        .database
+        .clone()
+        .unwrap_or_default();
+    // replace the oldest turns of a long session with their summary
+    let history = match database_config.summarize_history
+        && history.len() > database_config.summarize_after_messages
+    {
+        true => match compact_history(&state, &history, &database_config).await {
+            Some(compacted) => {
+                if let Err(e) = store.replace_history(&session_id, &compacted).await {
+                    dual_warn!("Failed to save the compacted history of {session_id}: {e}");
+                }
+                compacted
+            }
+            None => history,
+        },
+        false => history,
+    };
+    let history = match database_config.max_history_messages {
+        Some(max_history_messages) => window_history(history, max_history_messages),
+        None => history,
+    };
 
     // 4. Create the new message from the user's prompt.
     let user_message = ChatMessage {
@@ -136,11 +250,19 @@ pub(crate) async fn responses_handler(
     // We will simulate the response.
     // In a real implementation, you would use `state.proxy_client` here,
     // similar to how `chat_completions_handler` does it.
-    println!("[INFO] Simulating LLM response for session {}", session_id);
-    let assistant_content = if user_message.content.to_lowercase().contains("favorite color") && history.iter().any(|m| m.content.contains("blue")) {
+    println!("[INFO] Simulating LLM response for session {session_id}");
+    let assistant_content = if user_message
+        .content
+        .to_lowercase()
+        .contains("favorite color")
+        && history.iter().any(|m| m.content.contains("blue"))
+    {
         "Of course, your favorite color is blue.".to_string()
     } else {
-        format!("This is a simulated response to: '{}'", user_message.content)
+        format!(
+            "This is a simulated response to: '{}'",
+            user_message.content
+        )
     };
     // --- End of Placeholder ---
 
@@ -150,9 +272,18 @@ pub(crate) async fn responses_handler(
     };
 
     // 6. Save the new user message and the assistant's response to the history.
-    database::save_message(&db_conn, &session_id, &user_message).expect("Failed to save user message");
-    database::save_message(&db_conn, &session_id, &assistant_message).expect("Failed to save assistant message");
-    println!("[INFO] Saved new messages to session {}", session_id);
+    for message in [&user_message, &assistant_message] {
+        store
+            .save_message(&session_id, message)
+            .await
+            .map_err(|e| {
+                store_error(
+                    format!("Failed to save the message to the session {session_id}"),
+                    e,
+                )
+            })?;
+    }
+    println!("[INFO] Saved new messages to session {session_id}");
 
     // 7. Create the final JSON response to send back to the client.
     let response_body = json!({
@@ -170,7 +301,337 @@ pub(crate) async fn responses_handler(
         }]
     });
 
-    (StatusCode::OK, Json(response_body))
+    Ok((StatusCode::OK, Json(response_body)))
+}
+
+/// The error of an operation on the store of the chat history
+fn store_error(err_msg: String, e: database::StoreError) -> ServerError {
+    let err_msg = format!("{err_msg}: {e}");
+    dual_error!("{err_msg}");
+    ServerError::Operation(err_msg)
+}
+
+/// The query of the session exports
+#[derive(Deserialize)]
+pub struct ExportSessionQuery {
+    /// `json` for the messages as they are stored, or `md` for a Markdown transcript
+    #[serde(default = "default_export_format")]
+    format: String,
+}
+
+fn default_export_format() -> String {
+    "json".to_string()
+}
+
+/// The handler exporting the transcript of a `/responses` session, e.g. to share or archive it
+pub(crate) async fn export_session_handler(
+    State(state): State<Arc<AppState>>,
+    axum::extract::Path(session_id): axum::extract::Path<String>,
+    axum::extract::Query(query): axum::extract::Query<ExportSessionQuery>,
+) -> ServerResult<axum::response::Response> {
+    let content_type = match query.format.as_str() {
+        "json" => "application/json",
+        "md" => "text/markdown; charset=utf-8",
+        format => {
+            let err_msg = format!("Unsupported export format: {format}. Use `json` or `md`.");
+            dual_error!("{err_msg}");
+            return Err(ServerError::BadRequest(err_msg));
+        }
+    };
+
+    let history = state
+        .session_store()
+        .await?
+        .get_history(&session_id)
+        .await
+        .map_err(|e| {
+            store_error(
+                format!("Failed to read the history of the session {session_id}"),
+                e,
+            )
+        })?;
+    // the sessions are only stored once they have a message
+    if history.is_empty() {
+        dual_error!("Not found session: {session_id}");
+        return Err(ServerError::NotFoundSession(session_id));
+    }
+
+    let body = match query.format.as_str() {
+        "md" => session_markdown(&session_id, &history),
+        _ => json!({
+            "session_id": session_id,
+            "messages": history,
+        })
+        .to_string(),
+    };
+
+    axum::response::Response::builder()
+        .status(StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, content_type)
+        .body(Body::from(body))
+        .map_err(|e| {
+            let err_msg = format!("Failed to create response: {e}");
+            dual_error!("{err_msg}");
+            ServerError::Operation(err_msg)
+        })
+}
+
+/// The handler listing the ids of the `/responses` sessions having a history
+pub(crate) async fn list_sessions_handler(
+    State(state): State<Arc<AppState>>,
+) -> ServerResult<axum::response::Response> {
+    let session_ids = state
+        .session_store()
+        .await?
+        .list_sessions()
+        .await
+        .map_err(|e| store_error("Failed to list the sessions".to_string(), e))?;
+
+    Ok(Json(json!({ "sessions": session_ids })).into_response())
+}
+
+/// The handler deleting the history of a `/responses` session
+pub(crate) async fn delete_session_handler(
+    State(state): State<Arc<AppState>>,
+    axum::extract::Path(session_id): axum::extract::Path<String>,
+) -> ServerResult<StatusCode> {
+    let deleted = state
+        .session_store()
+        .await?
+        .delete_session(&session_id)
+        .await
+        .map_err(|e| store_error(format!("Failed to delete the session {session_id}"), e))?;
+    if !deleted {
+        dual_error!("Not found session: {session_id}");
+        return Err(ServerError::NotFoundSession(session_id));
+    }
+
+    dual_info!("Deleted the session {session_id}");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Render the messages of a session as a Markdown transcript, with each message under the name of
+/// its role
+fn session_markdown(session_id: &str, history: &[ChatMessage]) -> String {
+    let mut markdown = format!("# Session {}\n", escape_markdown(session_id));
+    for message in history {
+        let mut role = message.role.chars();
+        let role = match role.next() {
+            Some(first) => first.to_uppercase().chain(role).collect::<String>(),
+            None => String::new(),
+        };
+        markdown.push_str(&format!(
+            "\n**{}:**\n\n{}\n",
+            escape_markdown(&role),
+            escape_markdown(&message.content)
+        ));
+    }
+
+    markdown
+}
+
+/// Escape the characters Markdown would read as formatting, e.g. emphasis, links, html, headings,
+/// lists or code blocks, so that the content is rendered as the text it is
+fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for line in text.split_inclusive('\n') {
+        // a line indented by 4 columns would be a code block, so its indentation is written as
+        // non-breaking spaces. The blank lines are left alone, so that they still end paragraphs.
+        let unindented = line.trim_start_matches(' ');
+        let is_code_indent = (line.len() - unindented.len() >= 4 || unindented.starts_with('\t'))
+            && !unindented.trim().is_empty();
+        let line = match is_code_indent {
+            true => {
+                let unindented = line.trim_start_matches([' ', '\t']);
+                for c in line[..line.len() - unindented.len()].chars() {
+                    escaped.push_str(match c {
+                        '\t' => "&nbsp;&nbsp;&nbsp;&nbsp;",
+                        _ => "&nbsp;",
+                    });
+                }
+                unindented
+            }
+            false => {
+                escaped.push_str(&line[..line.len() - unindented.len()]);
+                unindented
+            }
+        };
+
+        // the list markers and the setext heading underlines only count at the start of a line
+        if line.starts_with(['-', '+', '=']) {
+            escaped.push('\\');
+        }
+        let digits = line.chars().take_while(char::is_ascii_digit).count();
+        let ordered_marker = (1..=9).contains(&digits) && line[digits..].starts_with(['.', ')']);
+        for (i, c) in line.char_indices() {
+            if (ordered_marker && i == digits)
+                || matches!(
+                    c,
+                    '\\' | '`' | '*' | '_' | '[' | ']' | '<' | '>' | '#' | '|' | '~' | '!'
+                )
+            {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+    }
+
+    escaped
+}
+
+#[test]
+fn test_session_markdown() {
+    assert_eq!(
+        escape_markdown("**bold** <b>html</b> [link](url)"),
+        "\\*\\*bold\\*\\* \\<b\\>html\\</b\\> \\[link\\](url)"
+    );
+    assert_eq!(
+        escape_markdown("# title\n- item\n`code`"),
+        "\\# title\n\\- item\n\\`code\\`"
+    );
+    assert_eq!(
+        escape_markdown("1. one\n2) two\n  - nested\n1.5 kg"),
+        "1\\. one\n2\\) two\n  \\- nested\n1\\.5 kg"
+    );
+    assert_eq!(
+        escape_markdown("Title\n===\nSubtitle\n ---"),
+        "Title\n\\===\nSubtitle\n \\---"
+    );
+    assert_eq!(
+        escape_markdown("    let x = 1;\n\tlet y = 2;"),
+        "&nbsp;&nbsp;&nbsp;&nbsp;let x = 1;\n&nbsp;&nbsp;&nbsp;&nbsp;let y = 2;"
+    );
+    assert_eq!(escape_markdown("one\n    \ntwo"), "one\n    \ntwo");
+
+    let history = [
+        ChatMessage {
+            role: "user".to_string(),
+            content: "My favorite color is *blue*.".to_string(),
+        },
+        ChatMessage {
+            role: "assistant".to_string(),
+            content: "Noted.".to_string(),
+        },
+    ];
+    assert_eq!(
+        session_markdown("session-1", &history),
+        "# Session session-1\n\n**User:**\n\nMy favorite color is \\*blue\\*.\n\n**Assistant:**\n\nNoted.\n"
+    );
+}
+
+/// Summarize the messages of the history before the most recent ones kept as they are. Returns
+/// the summary followed by the kept messages, or `None` if there is nothing to summarize or the
+/// summarization fails.
+async fn compact_history(
+    state: &Arc<AppState>,
+    history: &[ChatMessage],
+    database_config: &DatabaseConfig,
+) -> Option<Vec<ChatMessage>> {
+    // a summary of an earlier compaction is summarized again with the oldest turns
+    let recent = recent_turns(history.to_vec(), database_config.summary_keep_messages);
+    let oldest = &history[..history.len() - recent.len()];
+    if oldest.is_empty() {
+        return None;
+    }
+
+    let summary =
+        handlers::summarize_messages(state, database_config.summary_model.as_deref(), oldest)
+            .await
+            .inspect_err(|e| dual_warn!("Failed to summarize the history: {e}"))
+            .ok()?;
+    dual_info!(
+        "Summarized the {} oldest messages of the history",
+        oldest.len()
+    );
+
+    let mut compacted = vec![ChatMessage {
+        role: "system".to_string(),
+        content: format!("Summary of the earlier conversation: {summary}"),
+    }];
+    compacted.extend(recent);
+    Some(compacted)
+}
+
+/// Keep the most recent messages of the history within the limit by dropping the oldest turns.
+/// A leading system message, e.g. the summary of the earlier conversation, is always kept on top
+/// of the limit.
+fn window_history(mut history: Vec<ChatMessage>, max_messages: usize) -> Vec<ChatMessage> {
+    if history
+        .first()
+        .is_none_or(|message| message.role != "system")
+    {
+        return recent_turns(history, max_messages);
+    }
+
+    let turns = recent_turns(history.split_off(1), max_messages);
+    history.extend(turns);
+    history
+}
+
+/// The most recent messages of the history within the limit. They start at a user message, so
+/// that no exchange is cut in half.
+fn recent_turns(mut history: Vec<ChatMessage>, max_messages: usize) -> Vec<ChatMessage> {
+    if history.len() <= max_messages {
+        return history;
+    }
+
+    let mut start = history.len() - max_messages;
+    while start < history.len() && history[start].role != "user" {
+        start += 1;
+    }
+
+    history.split_off(start)
+}
+
+#[test]
+fn test_window_history() {
+    let message = |role: &str, content: &str| ChatMessage {
+        role: role.to_string(),
+        content: content.to_string(),
+    };
+    let history = vec![
+        message("user", "My favorite color is blue."),
+        message("assistant", "Noted."),
+        message("user", "What is the capital of France?"),
+        message("assistant", "Paris."),
+    ];
+    let contents = |history: Vec<ChatMessage>| {
+        history
+            .into_iter()
+            .map(|message| message.content)
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(window_history(history.clone(), 4).len(), 4);
+    assert_eq!(
+        contents(window_history(history.clone(), 2)),
+        ["What is the capital of France?", "Paris."]
+    );
+    // the exchange cut in half is dropped as a whole
+    assert_eq!(
+        contents(window_history(history.clone(), 3)),
+        ["What is the capital of France?", "Paris."]
+    );
+    assert!(window_history(history.clone(), 0).is_empty());
+
+    // the summary of the earlier conversation is kept
+    let mut summarized = vec![message(
+        "system",
+        "Summary of the earlier conversation: blue",
+    )];
+    summarized.extend(history);
+    assert_eq!(
+        contents(window_history(summarized.clone(), 2)),
+        [
+            "Summary of the earlier conversation: blue",
+            "What is the capital of France?",
+            "Paris."
+        ]
+    );
+    assert_eq!(
+        contents(window_history(summarized, 0)),
+        ["Summary of the earlier conversation: blue"]
+    );
 }
 #[tokio::main]
 async fn main() -> ServerResult<()> {
@@ -183,8 +644,19 @@ async fn main() -> ServerResult<()> {
         return Err(ServerError::Operation("Missing log file path".to_string()));
     }
 
+    // Create the tracer exporting the spans if the tracing is configured
+    let tracer = match TracingConfig::load(&cli.config)? {
+        Some(tracing_config) => Some(telemetry::init_tracer(&tracing_config)?),
+        None => None,
+    };
+
     // Initialize logging based on destination
-    init_logging(&cli.log_destination, cli.log_file.as_deref())?;
+    let log_level_handle = init_logging(
+        &cli.log_destination,
+        cli.log_file.as_deref(),
+        &cli.log_format,
+        tracer,
+    )?;
 
     // log the version of the server
     dual_info!("Version: {}", env!("CARGO_PKG_VERSION"));
@@ -210,6 +682,82 @@ async fn main() -> ServerResult<()> {
 
     dual_debug!("MCP servers: {:?}", config.mcp);
 
+    // set up the shared http client for downstream servers
+    init_http_client(&config.connection.clone().unwrap_or_default())?;
+    init_health_check_client(&config.health_check.clone().unwrap_or_default())?;
+
+    // set the timeout of the mcp tool calls
+    if let Some(timeout) = config
+        .mcp
+        .as_ref()
+        .and_then(|mcp_config| mcp_config.mcp_tool_timeout_secs)
+    {
+        mcp::MCP_TOOL_TIMEOUT.set(timeout).map_err(|e| {
+            let err_msg = format!("Failed to set the mcp tool timeout: {e}");
+            dual_error!("{err_msg}");
+            ServerError::Operation(err_msg)
+        })?;
+    }
+
+    // set the interval of the checks of the mcp connections
+    if let Some(interval) = config
+        .mcp
+        .as_ref()
+        .and_then(|mcp_config| mcp_config.reconnect_interval_secs)
+    {
+        mcp::MCP_RECONNECT_INTERVAL.set(interval).map_err(|e| {
+            let err_msg = format!("Failed to set the mcp reconnect interval: {e}");
+            dual_error!("{err_msg}");
+            ServerError::Operation(err_msg)
+        })?;
+    }
+
+    // set the names of the search mcp servers besides the built-in ones
+    if let Some(mcp_config) = config.mcp.as_ref()
+        && !mcp_config.search_server_names.is_empty()
+    {
+        mcp::EXTRA_SEARCH_MCP_SERVER_NAMES
+            .set(mcp_config.search_server_names.clone())
+            .map_err(|_| {
+                let err_msg = "Failed to set the names of the search mcp servers";
+                dual_error!("{err_msg}");
+                ServerError::Operation(err_msg.to_string())
+            })?;
+    }
+
+    // set the interval of the keep-alive comments of the streaming responses
+    if let Some(keep_alive) = config.server.stream_keep_alive_secs
+        && keep_alive > 0
+    {
+        handlers::STREAM_KEEP_ALIVE_INTERVAL
+            .set(keep_alive)
+            .map_err(|e| {
+                let err_msg = format!("Failed to set the stream keep-alive interval: {e}");
+                dual_error!("{err_msg}");
+                ServerError::Operation(err_msg)
+            })?;
+    }
+
+    // set the headers crossing the gateway, if they replace the defaults
+    if let Some(headers) = config.server.forward_request_headers.clone() {
+        handlers::FORWARD_REQUEST_HEADERS
+            .set(headers)
+            .map_err(|_| {
+                let err_msg = "Failed to set the forwarded request headers";
+                dual_error!("{}", err_msg);
+                ServerError::Operation(err_msg.to_string())
+            })?;
+    }
+    if let Some(headers) = config.server.expose_response_headers.clone() {
+        handlers::EXPOSE_RESPONSE_HEADERS
+            .set(headers)
+            .map_err(|_| {
+                let err_msg = "Failed to set the exposed response headers";
+                dual_error!("{}", err_msg);
+                ServerError::Operation(err_msg.to_string())
+            })?;
+    }
+
     // set the health check interval
     HEALTH_CHECK_INTERVAL
         .set(cli.check_health_interval)
@@ -219,13 +767,26 @@ async fn main() -> ServerResult<()> {
             ServerError::Operation(err_msg)
         })?;
 
-    // socket address
-    let addr = SocketAddr::from((
-        config.server.host.parse::<IpAddr>().unwrap(),
-        config.server.port,
-    ));
+    // socket addresses
+    let addr = resolve_server_addr(&config.server.host, config.server.port).await?;
+    let admin_addr = match &config.server.admin_bind {
+        Some(admin_bind) => Some(resolve_server_addr(&admin_bind.host, admin_bind.port).await?),
+        None => None,
+    };
+
+    let normalize_trailing_slash = config.server.normalize_trailing_slash;
+    let web_ui_mounts = config.web_ui.clone().unwrap_or_default().mounts;
+
+    let warmup_database = config.database.clone().unwrap_or_default().warmup;
+    let compression_config = config.compression.clone();
+    let state = Arc::new(
+        AppState::new(config, ServerInfo::default()).with_log_level_handle(log_level_handle),
+    );
 
-    let state = Arc::new(AppState::new(config, ServerInfo::default()));
+    // create the schema and the indices of the chat history database
+    if warmup_database {
+        state.session_store().await?;
+    }
 
     // Start the health check task if enabled
     if cli.check_health {
@@ -233,75 +794,52 @@ async fn main() -> ServerResult<()> {
         Arc::clone(&state).start_health_check_task().await;
     }
 
-    // Set up CORS
-    let cors = CorsLayer::new()
-        .allow_methods([http::Method::GET, http::Method::POST])
-        .allow_headers(Any)
-        .allow_origin(Any);
-
-    // Set up the router
-    let app =
-        Router::new()
-            .route("/v1/chat/completions", post(handlers::chat_handler))
-            .route("/v1/embeddings", post(handlers::embeddings_handler))
-            .route(
-                "/v1/audio/transcriptions",
-                post(handlers::audio_transcriptions_handler),
-            )
-            .route(
-                "/v1/audio/translations",
-                post(handlers::audio_translations_handler),
-            )
-            .route("/v1/audio/speech", post(handlers::audio_tts_handler))
-            .route("/v1/images/generations", post(handlers::image_handler))
-            .route("/v1/images/edits", post(handlers::image_handler))
-            .route("/v1/models", get(handlers::models_handler))
-            .route("/v1/info", get(handlers::info_handler))
-            .route(
-                "/admin/servers/register",
-                post(handlers::admin::register_downstream_server_handler),
-            )
-            .route(
-                "/admin/servers/unregister",
-                post(handlers::admin::remove_downstream_server_handler),
-            )
-            .route(
-                "/admin/servers",
-                get(handlers::admin::list_downstream_servers_handler),
-            )
-          
-            .route("/responses", post(responses_handler))
-           
-            .layer(cors)
-            .layer(TraceLayer::new_for_http())
-            .layer(axum::middleware::from_fn(
-                |mut req: Request<Body>, next: axum::middleware::Next| async move {
-                    // Generate request ID
-                    let request_id = Uuid::new_v4().to_string();
-
-                    // Add request ID to headers
-                    req.headers_mut()
-                        .insert("x-request-id", HeaderValue::from_str(&request_id).unwrap());
-
-                    // Add cancellation token
-                    let cancel_token = CancellationToken::new();
-                    req.extensions_mut().insert(cancel_token);
-
-                    // Log request start
-                    dual_info!("Request started - ID: {}", request_id);
+    // Sweep the expired responses of the idempotency keys
+    if let Some(idempotency) = state.idempotency.clone() {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(idempotency.sweep_interval()).await;
+                let swept = idempotency.sweep();
+                if swept > 0 {
+                    dual_debug!("Swept {} expired idempotent responses", swept);
+                }
+            }
+        });
+    }
 
-                    let response = next.run(req).await;
+    // Reconnect the mcp servers whose connection dropped
+    if let Some(mcp_config) = state.config.read().await.mcp.clone() {
+        let interval = *mcp::MCP_RECONNECT_INTERVAL
+            .get()
+            .unwrap_or(&mcp::DEFAULT_MCP_RECONNECT_INTERVAL);
+        if interval > 0 && !mcp_config.server.tool_servers.is_empty() {
+            tokio::spawn(mcp::reconnect_mcp_services(
+                mcp_config.server.tool_servers,
+                mcp_config.oauth_callback,
+                Duration::from_secs(interval),
+            ));
+        }
+    }
 
-                    // Log request completion
-                    dual_info!("Request completed - ID: {}", request_id);
+    // ! DO NOT REMOVE THIS BLOCK
+    {
+        // check the embedding dimension of RAG against its Qdrant collection in the background
+        // tokio::spawn(rag::check_embedding_dimension(state.clone()));
+    }
 
-                    response
-                },
-            ))
-            .fallback_service(ServeDir::new(&cli.web_ui).not_found_service(
-                ServeDir::new(&cli.web_ui).append_index_html_on_directories(true),
-            ))
-            .with_state(state.clone());
+    // Set up the router
+    let app = normalize_path(
+        with_compression(
+            build_router(
+                state.clone(),
+                &cli.web_ui,
+                &web_ui_mounts,
+                admin_addr.is_none(),
+            ),
+            compression_config.as_ref(),
+        ),
+        normalize_trailing_slash,
+    );
 
     // Create the listener
     let listener = tokio::net::TcpListener::bind(&addr).await.map_err(|e| {
@@ -313,12 +851,53 @@ async fn main() -> ServerResult<()> {
     })?;
     dual_info!("Listening on {}", addr);
 
-    // Set up graceful shutdown
-    let server =
-        axum::serve(listener, app.into_make_service()).with_graceful_shutdown(shutdown_signal());
+    // Set up graceful shutdown, shared by the public and the admin servers
+    let shutdown_token = CancellationToken::new();
+    tokio::spawn({
+        let shutdown_token = shutdown_token.clone();
+        async move {
+            shutdown_signal().await;
+            shutdown_token.cancel();
+        }
+    });
+
+    let server = axum::serve(
+        listener,
+        ServiceExt::<Request<Body>>::into_make_service_with_connect_info::<SocketAddr>(app),
+    )
+    .with_graceful_shutdown(shutdown_token.clone().cancelled_owned());
+
+    // Start the servers
+    let result = match admin_addr {
+        Some(admin_addr) => {
+            let admin_app =
+                normalize_path(build_admin_router(state.clone()), normalize_trailing_slash);
+            let admin_listener = tokio::net::TcpListener::bind(&admin_addr)
+                .await
+                .map_err(|e| {
+                    let err_msg = format!("Failed to bind to the admin address: {e}");
+                    dual_error!("{err_msg}");
+                    ServerError::Operation(err_msg)
+                })?;
+            dual_info!("Serving the admin endpoints on {}", admin_addr);
+
+            let admin_server = axum::serve(
+                admin_listener,
+                ServiceExt::<Request<Body>>::into_make_service_with_connect_info::<SocketAddr>(
+                    admin_app,
+                ),
+            )
+            .with_graceful_shutdown(shutdown_token.cancelled_owned());
+
+            tokio::try_join!(server.into_future(), admin_server.into_future()).map(|_| ())
+        }
+        None => server.await,
+    };
+
+    // export the remaining spans before exiting
+    telemetry::shutdown_tracer();
 
-    // Start the server
-    match server.await {
+    match result {
         Ok(_) => {
             dual_info!("Server shutdown completed");
             Ok(())
@@ -331,158 +910,812 @@ async fn main() -> ServerResult<()> {
     }
 }
 
-async fn shutdown_signal() {
-    let ctrl_c = async {
-        signal::ctrl_c()
-            .await
-            .expect("Failed to install Ctrl+C handler");
-    };
+/// Build the router of the API routes and the Web UIs. The Web UI at `web_ui` serves the requests
+/// not matching any route or mount. The admin routes are left out if they are served on their own
+/// address.
+fn build_router(
+    state: Arc<AppState>,
+    web_ui: &Path,
+    web_ui_mounts: &[WebUiMount],
+    with_admin_routes: bool,
+) -> Router {
+    let mut router = Router::new()
+        .route("/v1/chat/completions", post(handlers::chat_handler))
+        .route("/v1/completions", post(handlers::completions_handler))
+        .route("/v1/embeddings", post(handlers::embeddings_handler))
+        .route("/v1/rerank", post(handlers::rerank_handler))
+        .route(
+            "/v1/audio/transcriptions",
+            post(handlers::audio_transcriptions_handler),
+        )
+        .route(
+            "/v1/audio/translations",
+            post(handlers::audio_translations_handler),
+        )
+        .route("/v1/audio/speech", post(handlers::audio_tts_handler))
+        .route("/v1/images/generations", post(handlers::image_handler))
+        .route("/v1/images/edits", post(handlers::image_handler))
+        .route("/v1/images/variations", post(handlers::image_handler))
+        .route("/v1/models", get(handlers::models_handler))
+        .route("/v1/models/{*model}", get(handlers::model_handler))
+        .route("/v1/info", get(handlers::info_handler))
+        .route("/v1/tools", get(handlers::tools_handler))
+        .route("/responses", post(responses_handler))
+        .route("/sessions/{id}/export", get(export_session_handler));
+    if with_admin_routes {
+        router = router.merge(admin_routes());
+    }
+    let mut router = with_middleware(router, state.clone()).fallback_service(serve_web_ui(web_ui));
 
-    #[cfg(unix)]
-    let terminate = async {
-        signal::unix::signal(signal::unix::SignalKind::terminate())
-            .expect("Failed to install signal handler")
-            .recv()
-            .await;
+    for mount in web_ui_mounts {
+        dual_info!(
+            "Serving the Web UI in {} under {}",
+            mount.dir.display(),
+            mount.path
+        );
+        router = router.nest_service(mount.path.trim_end_matches('/'), serve_web_ui(&mount.dir));
+    }
+
+    router.with_state(state)
+}
+
+/// Compress the responses for the clients accepting gzip or brotli, if enabled. The server-sent
+/// events are left uncompressed, so that each event reaches the client as soon as it is sent, and
+/// so are the images and audio, which are compressed already.
+fn with_compression(router: Router, compression_config: Option<&CompressionConfig>) -> Router {
+    let Some(compression_config) = compression_config else {
+        return router;
     };
 
-    #[cfg(not(unix))]
-    let terminate = std::future::pending::<()>();
+    let predicate = SizeAbove::new(compression_config.min_size)
+        .and(NotForContentType::GRPC)
+        .and(NotForContentType::IMAGES)
+        .and(NotForContentType::SSE)
+        .and(NotForContentType::const_new("audio/"));
+    router.layer(
+        CompressionLayer::new()
+            .gzip(true)
+            .br(true)
+            .compress_when(predicate),
+    )
+}
 
-    tokio::select! {
-        _ = ctrl_c => {
-            dual_info!("Received Ctrl+C, starting graceful shutdown");
-        },
-        _ = terminate => {
-            dual_info!("Received SIGTERM, starting graceful shutdown");
-        },
-    }
+/// Build the router serving only the admin routes, on the address set by `server.admin_bind`
+fn build_admin_router(state: Arc<AppState>) -> Router {
+    with_middleware(admin_routes(), state.clone()).with_state(state)
 }
 
-/// Initialize logging based on the specified destination
-fn init_logging(destination: &str, file_path: Option<&str>) -> ServerResult<()> {
-    // Store the log destination for later use
-    utils::LOG_DESTINATION
-        .set(destination.to_string())
-        .map_err(|_| {
-            let err_msg = "Failed to set log destination".to_string();
-            eprintln!("{err_msg}");
-            ServerError::Operation(err_msg)
-        })?;
+/// The routes of the `/admin/*` endpoints
+fn admin_routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route(
+            "/admin/servers/register",
+            post(handlers::admin::register_downstream_server_handler),
+        )
+        .route(
+            "/admin/servers/unregister",
+            post(handlers::admin::remove_downstream_server_handler),
+        )
+        .route(
+            "/admin/servers",
+            get(handlers::admin::list_downstream_servers_handler),
+        )
+        .route(
+            "/admin/servers/health",
+            get(handlers::admin::servers_health_handler),
+        )
+        .route(
+            "/admin/servers/health-check",
+            post(handlers::admin::run_health_check_handler),
+        )
+        .route(
+            "/admin/servers/{id}/disable",
+            post(handlers::admin::disable_downstream_server_handler),
+        )
+        .route(
+            "/admin/servers/{id}/enable",
+            post(handlers::admin::enable_downstream_server_handler),
+        )
+        .route(
+            "/admin/log-level",
+            post(handlers::admin::set_log_level_handler),
+        )
+        .route(
+            "/admin/mcp/servers",
+            get(handlers::admin::mcp_servers_handler),
+        )
+        .route(
+            "/admin/mcp/tools/{name}/call",
+            post(handlers::admin::call_mcp_tool_handler),
+        )
+        .route(
+            "/admin/maintenance",
+            post(handlers::admin::set_maintenance_handler),
+        )
+        .route("/admin/stats", get(handlers::admin::stats_handler))
+        .route("/admin/usage", get(handlers::admin::usage_handler))
+        .route("/admin/config", get(handlers::admin::config_handler))
+        .route("/admin/sessions", get(list_sessions_handler))
+        .route(
+            "/admin/sessions/{id}",
+            axum::routing::delete(delete_session_handler),
+        )
+}
 
-    let log_level = get_log_level_from_env();
+/// Apply the middleware shared by the public and the admin routers
+fn with_middleware(router: Router<Arc<AppState>>, state: Arc<AppState>) -> Router<Arc<AppState>> {
+    // Set up CORS
+    let cors = CorsLayer::new()
+        .allow_methods([http::Method::GET, http::Method::POST])
+        .allow_headers(Any)
+        .allow_origin(Any);
 
-    match destination {
-        "stdout" => {
-            // Terminal output preserves colors
-            tracing_subscriber::fmt()
-                .with_target(false)
-                .with_level(true)
-                .with_file(true)
-                .with_line_number(true)
-                .with_thread_ids(true)
-                .with_max_level(log_level)
-                .init();
-            Ok(())
-        }
-        "file" => {
-            if let Some(path) = file_path {
-                let file = std::fs::File::create(path).map_err(|e| {
-                    let err_msg = format!("Failed to create log file: {e}");
-                    eprintln!("{err_msg}");
-                    ServerError::Operation(err_msg)
-                })?;
+    router
+        // inflate the compressed request bodies before the handlers read them
+        .layer(RequestDecompressionLayer::new().gzip(true).br(true))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            reject_in_maintenance,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            authenticate,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            record_stats,
+        ))
+        .layer(cors)
+        .layer(TraceLayer::new_for_http())
+        .layer(axum::middleware::from_fn(log_requests))
+        .layer(axum::middleware::from_fn_with_state(
+            state,
+            resolve_client_ip,
+        ))
+}
 
-                // File output disables ANSI colors
-                tracing_subscriber::fmt()
-                    .with_target(false)
-                    .with_level(true)
-                    .with_file(true)
-                    .with_line_number(true)
-                    .with_thread_ids(true)
-                    .with_max_level(log_level)
-                    .with_writer(file)
-                    .with_ansi(false) // Disable ANSI colors
-                    .init();
-                Ok(())
-            } else {
-                Err(ServerError::Operation("Missing log file path".to_string()))
-            }
-        }
-        "both" => {
-            if let Some(path) = file_path {
-                // Create directory if it doesn't exist
-                if let Some(parent) = std::path::Path::new(path).parent()
-                    && !parent.exists()
-                {
-                    std::fs::create_dir_all(parent).map_err(|e| {
-                        let err_msg = format!("Failed to create directory for log file: {e}");
-                        eprintln!("{err_msg}");
-                        ServerError::Operation(err_msg)
-                    })?;
-                }
+/// Tag the request with a request ID and a cancellation token, and log its start and an access
+/// log line on its completion
+async fn log_requests(
+    mut req: Request<Body>,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    // Generate request ID
+    let request_id = Uuid::new_v4().to_string();
 
-                // Create file appender and disable colors
-                let file_appender = tracing_appender::rolling::never(
-                    std::path::Path::new(path)
-                        .parent()
-                        .unwrap_or_else(|| std::path::Path::new(".")),
-                    std::path::Path::new(path).file_name().unwrap_or_default(),
-                );
-                let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
+    // Add request ID to headers
+    req.headers_mut()
+        .insert("x-request-id", HeaderValue::from_str(&request_id).unwrap());
 
-                // Configure subscriber, disable ANSI colors
-                tracing_subscriber::fmt()
-                    .with_target(false)
-                    .with_level(true)
-                    .with_file(true)
-                    .with_line_number(true)
-                    .with_thread_ids(true)
-                    .with_max_level(log_level)
-                    .with_writer(non_blocking)
-                    .with_ansi(false) // Disable ANSI colors
-                    .init();
+    // Add cancellation token, cancelled if the client disconnects before the response is ready
+    let cancel_token = CancellationToken::new();
+    req.extensions_mut().insert(cancel_token.clone());
+    let disconnect_guard = CancelOnDisconnect {
+        cancel_token: Some(cancel_token),
+        request_id: request_id.clone(),
+    };
 
-                println!("Logging to both stdout and file: {path}");
+    let client_ip = req
+        .extensions()
+        .get::<ClientIp>()
+        .map(|ClientIp(ip)| ip.to_string())
+        .unwrap_or_else(|| "-".to_string());
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
 
-                Ok(())
-            } else {
-                Err(ServerError::Operation("Missing log file path".to_string()))
-            }
-        }
-        _ => {
-            let err_msg = format!(
-                "Invalid log destination: {destination}. Valid values are 'stdout', 'file', or 'both'",
-            );
-            eprintln!("{err_msg}");
-            Err(ServerError::Operation(err_msg))
-        }
+    // Attach the request ID to the logs of the request as a structured field
+    let span = tracing::info_span!("request", request_id = %request_id);
+
+    async move {
+        // Log request start
+        dual_info!("Request started - ID: {}", request_id);
+
+        let start = Instant::now();
+        let response = next.run(req).await;
+        // the response is ready, so the streaming responses cancel the request on their own
+        disconnect_guard.disarm();
+
+        // Log request completion, with the size of the body if known before it is streamed
+        let bytes = axum::body::HttpBody::size_hint(response.body())
+            .exact()
+            .map(|bytes| bytes.to_string())
+            .unwrap_or_else(|| "-".to_string());
+        dual_info!(
+            "Request completed - ID: {} - {} \"{} {}\" {} {} {}ms",
+            request_id,
+            client_ip,
+            method,
+            path,
+            response.status().as_u16(),
+            bytes,
+            start.elapsed().as_millis()
+        );
+
+        response
     }
+    .instrument(span)
+    .await
 }
 
-fn get_log_level_from_env() -> Level {
-    match std::env::var("LLAMA_LOG").ok().as_deref() {
-        Some("trace") => Level::TRACE,
-        Some("debug") => Level::DEBUG,
-        Some("info") => Level::INFO,
-        Some("warn") => Level::WARN,
-        Some("error") => Level::ERROR,
-        _ => Level::INFO,
+/// Cancels the token of a request if dropped before being disarmed. The future handling a request
+/// is dropped if the client disconnects, so that the work of the request, e.g. the spawned tasks
+/// and the downstream requests, is cancelled with it.
+struct CancelOnDisconnect {
+    cancel_token: Option<CancellationToken>,
+    request_id: String,
+}
+impl CancelOnDisconnect {
+    fn disarm(mut self) {
+        self.cancel_token = None;
+    }
+}
+impl Drop for CancelOnDisconnect {
+    fn drop(&mut self) {
+        if let Some(cancel_token) = self.cancel_token.take() {
+            dual_warn!("Client disconnected - ID: {}", self.request_id);
+            cancel_token.cancel();
+        }
+    }
+}
+
+/// The IP address of the client of a request, resolved by the `resolve_client_ip` middleware
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+/// The API key a request was authenticated with by the `authenticate` middleware
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiKey(pub String);
+
+/// Add the [`ClientIp`] of the request to its extensions, for the access logs and the handlers
+async fn resolve_client_ip(
+    State(state): State<Arc<AppState>>,
+    mut req: Request<Body>,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let peer_ip = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    if let Some(peer_ip) = peer_ip {
+        let trusted_proxies = state.config.read().await.server.trusted_proxies.clone();
+        let client_ip = client_ip(peer_ip, req.headers(), &trusted_proxies);
+        req.extensions_mut().insert(ClientIp(client_ip));
+    }
+
+    next.run(req).await
+}
+
+/// The IP address of the client of a request from `peer_ip`. If the peer is a trusted proxy, the
+/// client is the last address of the `X-Forwarded-For` header that is not a trusted proxy, or the
+/// earliest trusted one before an invalid address or the start of the header. The header is
+/// ignored otherwise, since the client may set it to any address.
+fn client_ip(peer_ip: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpNet]) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+    if !is_trusted(&peer_ip) {
+        return peer_ip;
+    }
+
+    let forwarded_for: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+    let mut client_ip = peer_ip;
+    for ip in forwarded_for.into_iter().rev() {
+        let Ok(ip) = ip.trim().parse() else {
+            break;
+        };
+        client_ip = ip;
+        if !is_trusted(&client_ip) {
+            break;
+        }
+    }
+
+    client_ip
+}
+
+/// Reject the requests to the proxy endpoints while in maintenance mode. The admin endpoints
+/// stay up to turn the maintenance mode off.
+async fn reject_in_maintenance(
+    State(state): State<Arc<AppState>>,
+    req: Request<Body>,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    if req.uri().path().starts_with("/v1/")
+        && let Some(maintenance) = state.maintenance.read().await.clone()
+    {
+        dual_warn!("Rejected the request to {} in maintenance mode", req.uri());
+        return ServerError::Maintenance {
+            message: maintenance.message,
+            retry_after: maintenance.retry_after,
+        }
+        .into_response();
+    }
+
+    next.run(req).await
+}
+
+/// Check the API key in the `Authorization: Bearer <key>` header of the requests to the API
+/// endpoints if the `[auth]` section is configured. The gateway key is not forwarded downstream.
+async fn authenticate(
+    State(state): State<Arc<AppState>>,
+    mut req: Request<Body>,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let (auth_config, admin_api_key) = {
+        let config = state.config.read().await;
+        match &config.auth {
+            // an empty admin key disables the admin endpoints, as if it were not set
+            Some(auth_config) => (
+                auth_config.clone(),
+                config
+                    .server
+                    .admin_api_key
+                    .clone()
+                    .filter(|admin_api_key| !admin_api_key.is_empty()),
+            ),
+            None => return next.run(req).await,
+        }
+    };
+
+    let path = req.uri().path().to_string();
+    let api_key = req
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|value| value.trim().to_string());
+    let result = if path.starts_with("/admin/") {
+        match (admin_api_key, api_key) {
+            (None, _) => Err("the admin endpoints are disabled without `server.admin_api_key`"),
+            (Some(admin_api_key), Some(api_key)) if keys_match(&api_key, &admin_api_key) => Ok(()),
+            _ => Err("invalid admin API key"),
+        }
+    } else if (path.starts_with("/v1/")
+        || path.starts_with("/responses")
+        || path.starts_with("/sessions/"))
+        && !auth_config.api_keys.is_empty()
+    {
+        match api_key {
+            // all the keys are compared, so that the timing does not tell which one is close
+            Some(api_key)
+                if auth_config
+                    .api_keys
+                    .iter()
+                    .fold(false, |found, key| found | keys_match(&api_key, key)) =>
+            {
+                req.headers_mut().remove(http::header::AUTHORIZATION);
+                req.extensions_mut().insert(ApiKey(api_key));
+                Ok(())
+            }
+            _ => Err("invalid API key"),
+        }
+    } else {
+        Ok(())
+    };
+
+    match result {
+        Ok(()) => next.run(req).await,
+        Err(reason) => {
+            dual_warn!("Rejected the request to {}: {}", req.uri(), reason);
+            ServerError::Unauthorized(reason.to_string()).into_response()
+        }
+    }
+}
+
+/// Count the request, and its error and latency, in the stats of its route
+async fn record_stats(
+    State(state): State<Arc<AppState>>,
+    req: Request<Body>,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let (Some(stats), Some(route)) = (
+        state.stats.clone(),
+        req.extensions().get::<MatchedPath>().cloned(),
+    ) else {
+        return next.run(req).await;
+    };
+
+    let start = Instant::now();
+    let response = next.run(req).await;
+    let status = response.status();
+    stats.record(
+        route.as_str(),
+        status.is_client_error() || status.is_server_error(),
+        start.elapsed(),
+    );
+
+    response
+}
+
+/// Serve the static files of a Web UI
+fn serve_web_ui(dir: &Path) -> ServeDir<SetStatus<ServeDir>> {
+    ServeDir::new(dir).not_found_service(ServeDir::new(dir).append_index_html_on_directories(true))
+}
+
+/// Normalize the request path before routing
+fn normalize_path(
+    app: Router,
+    normalize_trailing_slash: bool,
+) -> MapRequest<Router, impl Fn(Request<Body>) -> Request<Body> + Clone> {
+    MapRequestLayer::new(move |req: Request<Body>| match normalize_trailing_slash {
+        true => trim_trailing_slash(req),
+        false => req,
+    })
+    .layer(app)
+}
+
+/// Remove the trailing slash of an API route, e.g. `/v1/chat/completions/` -> `/v1/chat/completions`
+fn trim_trailing_slash(mut req: Request<Body>) -> Request<Body> {
+    let path = req.uri().path();
+    if path.len() > 1
+        && path.ends_with('/')
+        && API_PATH_PREFIXES
+            .iter()
+            .any(|prefix| path.starts_with(prefix))
+    {
+        let path_and_query = match req.uri().query() {
+            Some(query) => format!("{}?{query}", path.trim_end_matches('/')),
+            None => path.trim_end_matches('/').to_string(),
+        };
+
+        let mut parts = req.uri().clone().into_parts();
+        if let Ok(path_and_query) = path_and_query.parse() {
+            parts.path_and_query = Some(path_and_query);
+            if let Ok(uri) = Uri::from_parts(parts) {
+                *req.uri_mut() = uri;
+            }
+        }
+    }
+
+    req
+}
+
+/// Resolve the socket address to listen on from an IP address or a hostname
+async fn resolve_server_addr(host: &str, port: u16) -> ServerResult<SocketAddr> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(SocketAddr::from((ip, port)));
+    }
+
+    let invalid_host = |reason: String| {
+        let err_msg = format!(
+            "Invalid `server.host` in the config: '{host}' is neither an IP address nor a resolvable hostname ({reason})"
+        );
+        dual_error!("{err_msg}");
+        ServerError::FailedToLoadConfig(err_msg)
+    };
+    match tokio::net::lookup_host((host, port)).await {
+        Ok(mut addrs) => addrs
+            .next()
+            .ok_or_else(|| invalid_host("no address found".to_string())),
+        Err(e) => Err(invalid_host(e.to_string())),
+    }
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("Failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("Failed to install signal handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {
+            dual_info!("Received Ctrl+C, starting graceful shutdown");
+        },
+        _ = terminate => {
+            dual_info!("Received SIGTERM, starting graceful shutdown");
+        },
+    }
+}
+
+/// Initialize logging based on the specified destination
+fn init_logging(
+    destination: &str,
+    file_path: Option<&str>,
+    format: &str,
+    tracer: Option<Tracer>,
+) -> ServerResult<LogLevelHandle> {
+    if format != "text" && format != "json" {
+        let err_msg = format!("Invalid log format: {format}. Valid values are 'text' or 'json'");
+        eprintln!("{err_msg}");
+        return Err(ServerError::Operation(err_msg));
+    }
+
+    // Store the log destination for later use
+    utils::LOG_DESTINATION
+        .set(destination.to_string())
+        .map_err(|_| {
+            let err_msg = "Failed to set log destination".to_string();
+            eprintln!("{err_msg}");
+            ServerError::Operation(err_msg)
+        })?;
+
+    let log_level = get_log_level_from_env();
+
+    match destination {
+        "stdout" => {
+            // Terminal output preserves colors
+            Ok(init_subscriber(
+                std::io::stdout,
+                true,
+                format,
+                log_level,
+                tracer,
+            ))
+        }
+        "file" => {
+            if let Some(path) = file_path {
+                let file = std::fs::File::create(path).map_err(|e| {
+                    let err_msg = format!("Failed to create log file: {e}");
+                    eprintln!("{err_msg}");
+                    ServerError::Operation(err_msg)
+                })?;
+
+                // File output disables ANSI colors
+                Ok(init_subscriber(file, false, format, log_level, tracer))
+            } else {
+                Err(ServerError::Operation("Missing log file path".to_string()))
+            }
+        }
+        "both" => {
+            if let Some(path) = file_path {
+                // Create directory if it doesn't exist
+                if let Some(parent) = std::path::Path::new(path).parent()
+                    && !parent.exists()
+                {
+                    std::fs::create_dir_all(parent).map_err(|e| {
+                        let err_msg = format!("Failed to create directory for log file: {e}");
+                        eprintln!("{err_msg}");
+                        ServerError::Operation(err_msg)
+                    })?;
+                }
+
+                // Create file appender and disable colors
+                let file_appender = tracing_appender::rolling::never(
+                    std::path::Path::new(path)
+                        .parent()
+                        .unwrap_or_else(|| std::path::Path::new(".")),
+                    std::path::Path::new(path).file_name().unwrap_or_default(),
+                );
+                let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
+
+                // Configure subscriber, disable ANSI colors
+                let log_level_handle =
+                    init_subscriber(non_blocking, false, format, log_level, tracer);
+
+                println!("Logging to both stdout and file: {path}");
+
+                Ok(log_level_handle)
+            } else {
+                Err(ServerError::Operation("Missing log file path".to_string()))
+            }
+        }
+        _ => {
+            let err_msg = format!(
+                "Invalid log destination: {destination}. Valid values are 'stdout', 'file', or 'both'",
+            );
+            eprintln!("{err_msg}");
+            Err(ServerError::Operation(err_msg))
+        }
+    }
+}
+
+/// Install the global subscriber writing the logs in the given format, and exporting the spans
+/// with the tracer if any. The returned handle changes the log level of the subscriber.
+fn init_subscriber<W>(
+    writer: W,
+    ansi: bool,
+    format: &str,
+    log_level: Level,
+    tracer: Option<Tracer>,
+) -> LogLevelHandle
+where
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let (level_filter, log_level_handle) = reload::Layer::new(LevelFilter::from_level(log_level));
+    let layer = tracing_subscriber::fmt::layer()
+        .with_level(true)
+        .with_file(true)
+        .with_line_number(true)
+        .with_thread_ids(true)
+        .with_writer(writer);
+
+    let registry = tracing_subscriber::registry()
+        .with(level_filter)
+        .with(tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer)));
+    match format {
+        // the fields of the current span, e.g. the request ID, are written as keys of each record
+        "json" => registry
+            .with(
+                layer
+                    .json()
+                    .with_target(true)
+                    .with_current_span(true)
+                    .with_span_list(false),
+            )
+            .init(),
+        _ => registry
+            .with(layer.with_target(false).with_ansi(ansi))
+            .init(),
     }
+
+    log_level_handle
+}
+
+fn get_log_level_from_env() -> Level {
+    std::env::var("LLAMA_LOG")
+        .ok()
+        .as_deref()
+        .and_then(parse_log_level)
+        .unwrap_or(Level::INFO)
 }
 
+/// Parse a log level, i.e. one of `trace`, `debug`, `info`, `warn` and `error`
+pub(crate) fn parse_log_level(level: &str) -> Option<Level> {
+    match level {
+        "trace" => Some(Level::TRACE),
+        "debug" => Some(Level::DEBUG),
+        "info" => Some(Level::INFO),
+        "warn" => Some(Level::WARN),
+        "error" => Some(Level::ERROR),
+        _ => None,
+    }
+}
 
 impl AppState {
     pub(crate) fn new(config: Config, server_info: ServerInfo) -> Self {
+        let stream_permits = config
+            .server
+            .max_concurrent_streams
+            .map(|max_streams| Arc::new(Semaphore::new(max_streams)));
+        let stats = config
+            .server
+            .collect_stats
+            .then(|| Arc::new(RequestStats::default()));
+        let response_cache = config
+            .cache
+            .as_ref()
+            .map(|cache_config| Arc::new(ResponseCache::new(cache_config)));
+        let usage = config
+            .usage
+            .as_ref()
+            .map(|usage_config| Arc::new(UsageLedger::new(usage_config)));
+        let redactor = config
+            .redaction
+            .as_ref()
+            .map(|redaction_config| Arc::new(ResponseRedactor::new(redaction_config)));
+        let idempotency = config
+            .idempotency
+            .as_ref()
+            .map(|idempotency_config| Arc::new(IdempotencyStore::new(idempotency_config)));
+        let request_queue = config
+            .server
+            .max_queue_wait_secs
+            .map(|max_queue_wait_secs| {
+                Arc::new(RequestQueue::new(
+                    config.server.max_queue_length,
+                    Duration::from_secs(max_queue_wait_secs),
+                ))
+            });
+
         Self {
             server_group: Arc::new(RwLock::new(HashMap::new())),
             config: Arc::new(RwLock::new(config)),
             server_info: Arc::new(RwLock::new(server_info)),
             models: Arc::new(RwLock::new(HashMap::new())),
+            stream_permits,
+            log_level_handle: None,
+            maintenance: Arc::new(RwLock::new(None)),
+            stats,
+            response_cache,
+            request_queue,
+            health_check_lock: Arc::new(tokio::sync::Mutex::new(())),
+            usage,
+            redactor,
+            server_registered: Arc::new(tokio::sync::Notify::new()),
+            idempotency,
+            session_store: Arc::new(tokio::sync::OnceCell::new()),
         }
     }
 
+    /// The store of the chat history selected by the `[database]` section of the config. It is
+    /// opened on the first call, which creates the schema if it does not exist yet.
+    pub(crate) async fn session_store(&self) -> ServerResult<Arc<dyn SessionStore>> {
+        let database_config = self
+            .config
+            .read()
+            .await
+            .database
+            .clone()
+            .unwrap_or_default();
+        self.session_store
+            .get_or_try_init(|| database::open_store(&database_config))
+            .await
+            .cloned()
+            .map_err(|e| {
+                let err_msg = format!("Failed to open the chat history database: {e}");
+                dual_error!("{err_msg}");
+                ServerError::Operation(err_msg)
+            })
+    }
+
+    /// Set the handle changing the log level at runtime
+    pub(crate) fn with_log_level_handle(mut self, log_level_handle: LogLevelHandle) -> Self {
+        self.log_level_handle = Some(log_level_handle);
+        self
+    }
+
+    /// Turn the maintenance mode on, or off with `None`
+    pub(crate) async fn set_maintenance(&self, maintenance: Option<MaintenanceMode>) {
+        *self.maintenance.write().await = maintenance;
+    }
+
+    /// Change the log level. Returns the previous and the new level.
+    pub(crate) fn set_log_level(&self, level: Level) -> ServerResult<(LevelFilter, LevelFilter)> {
+        let log_level_handle = self.log_level_handle.as_ref().ok_or_else(|| {
+            ServerError::Operation("The log level can not be changed at runtime".to_string())
+        })?;
+
+        let new_level = LevelFilter::from_level(level);
+        let mut previous_level = new_level;
+        log_level_handle
+            .modify(|filter| previous_level = std::mem::replace(filter, new_level))
+            .map_err(|e| ServerError::Operation(format!("Failed to change the log level: {e}")))?;
+
+        Ok((previous_level, new_level))
+    }
+
+    /// Register the server to the groups of its kinds. A server registered with the id of a
+    /// registered server replaces it, so registering the same server again is idempotent.
     pub(crate) async fn register_downstream_server(&self, server: Server) -> ServerResult<()> {
+        // the other servers of the same backend sharing some kinds of the server
+        let duplicates = self.duplicate_servers(&server).await;
+        if !duplicates.is_empty() {
+            match self.config.read().await.server.duplicate_servers {
+                DuplicateServerPolicy::Reject => {
+                    let ids = duplicates
+                        .iter()
+                        .map(|(id, _)| id.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    let err_msg = format!(
+                        "The {} server at {} is already registered as {}",
+                        server.kind, server.url, ids
+                    );
+                    dual_warn!("{err_msg}");
+                    return Err(ServerError::Conflict(err_msg));
+                }
+                DuplicateServerPolicy::Replace => {
+                    for (id, shared_kinds) in duplicates {
+                        dual_info!(
+                            "Replacing the {} server {} at the same url: {}",
+                            shared_kinds,
+                            id,
+                            &server.url
+                        );
+                        self.release_server_kinds(&id, shared_kinds).await?;
+                    }
+                }
+            }
+        }
+
+        if self.unregister_from_groups(&server.id).await? {
+            dual_info!("Replacing the registered server: {}", &server.id);
+        }
+
         if server.kind.contains(ServerKind::chat) {
             self.server_group
                 .write()
@@ -537,41 +1770,48 @@ impl AppState {
                 .register(server.clone())
                 .await?;
         }
+        if server.kind.contains(ServerKind::rerank) {
+            self.server_group
+                .write()
+                .await
+                .entry(ServerKind::rerank)
+                .or_insert(ServerGroup::new(ServerKind::rerank))
+                .register(server.clone())
+                .await?;
+        }
+
+        // wake up the requests waiting for a server
+        self.server_registered.notify_waiters();
 
         Ok(())
     }
 
-    pub(crate) async fn unregister_downstream_server(
-        &self,
-        server_id: impl AsRef<str>,
-    ) -> ServerResult<()> {
-        let mut found = false;
-
-        // unregister the server from the servers
-        {
-            // parse server kind from server id
-            let kinds = server_id
-                .as_ref()
-                .split("-server-")
-                .next()
-                .unwrap()
-                .split("-")
-                .collect::<Vec<&str>>();
-
-            let group_map = self.server_group.read().await;
+    /// Wait up to `wait` for a server of the kind to be registered, if none is yet
+    pub(crate) async fn wait_for_server(&self, kind: ServerKind, wait: Duration) {
+        let deadline = tokio::time::Instant::now() + wait;
+        loop {
+            // listen before checking, so that a registration in between is not missed
+            let registered = self.server_registered.notified();
+            tokio::pin!(registered);
+            registered.as_mut().enable();
 
-            for kind in kinds {
-                let kind = ServerKind::from_str(kind).unwrap();
-                if let Some(group) = group_map.get(&kind) {
-                    group.unregister(server_id.as_ref()).await?;
-                    dual_info!("Unregistered {} server: {}", &kind, server_id.as_ref());
+            match self.server_group.read().await.get(&kind) {
+                Some(group) if !group.is_empty().await => return,
+                _ => {}
+            }
 
-                    if !found {
-                        found = true;
-                    }
-                }
+            if tokio::time::timeout_at(deadline, registered).await.is_err() {
+                dual_warn!("No {} server was registered within {:?}", kind, wait);
+                return;
             }
         }
+    }
+
+    pub(crate) async fn unregister_downstream_server(
+        &self,
+        server_id: impl AsRef<str>,
+    ) -> ServerResult<()> {
+        let found = self.unregister_from_groups(server_id.as_ref()).await?;
 
         if found {
             // remove the server info from the server_info
@@ -584,152 +1824,2399 @@ impl AppState {
         }
 
         if !found {
-            return Err(ServerError::Operation(format!(
-                "Server {} not found",
-                server_id.as_ref()
-            )));
+            dual_error!("Not found server: {}", server_id.as_ref());
+            return Err(ServerError::NotFoundServerId(
+                server_id.as_ref().to_string(),
+            ));
         }
 
         Ok(())
     }
 
-    pub(crate) async fn list_downstream_servers(
-        &self,
-    ) -> ServerResult<HashMap<ServerKind, Vec<Server>>> {
-        let servers = self.server_group.read().await;
-
-        let mut server_groups = HashMap::new();
-        for (kind, group) in servers.iter() {
-            if !group.is_empty().await {
-                let servers = group.servers.read().await;
-
-                // Create a new Vec with cloned Server instances using async stream
-                let server_vec = stream::iter(servers.iter())
-                    .then(|server_lock| async move {
-                        let server = server_lock.read().await;
-                        server.clone()
-                    })
-                    .collect::<Vec<_>>()
-                    .await;
+    /// The registered servers of another id with the same url and path prefix as `server`, along
+    /// with the kinds they share with it
+    async fn duplicate_servers(&self, server: &Server) -> Vec<(ServerId, ServerKind)> {
+        let base_url = server.base_url();
 
-                server_groups.insert(*kind, server_vec);
+        let mut duplicates: Vec<(ServerId, ServerKind)> = Vec::new();
+        let group_map = self.server_group.read().await;
+        for group in group_map.values() {
+            for server_lock in group.servers.read().await.iter() {
+                let registered = server_lock.read().await;
+                let shared_kinds = registered.kind & server.kind;
+                if registered.id != server.id
+                    && !shared_kinds.is_empty()
+                    && registered.base_url() == base_url
+                    && !duplicates.iter().any(|(id, _)| *id == registered.id)
+                {
+                    duplicates.push((registered.id.clone(), shared_kinds));
+                }
             }
         }
+        duplicates.sort_by(|a, b| a.0.cmp(&b.0));
 
-        Ok(server_groups)
+        duplicates
     }
 
-    pub(crate) async fn check_server_health(&self) -> ServerResult<()> {
-        if !self.server_group.read().await.is_empty() {
-            let mut unhealthy_servers = Vec::new();
+    /// Unregister the server from the groups of the given kinds only. The server is unregistered
+    /// altogether if it has no kinds left.
+    async fn release_server_kinds(&self, server_id: &str, kinds: ServerKind) -> ServerResult<()> {
+        let group_map = self.server_group.read().await;
 
-            // Check health status of downstream servers
-            // 1. Get all registered downstream servers
+        let mut remaining_kinds = ServerKind::empty();
+        for (kind, group) in group_map.iter() {
+            if !group.healthy_servers.read().await.contains(server_id) {
+                continue;
+            }
+
+            if kinds.contains(*kind) {
+                group.unregister(server_id).await?;
+                dual_info!("Unregistered {} server: {}", kind, server_id);
+            } else {
+                remaining_kinds |= *kind;
+            }
+        }
+
+        if remaining_kinds.is_empty() {
+            drop(group_map);
+            self.server_info.write().await.servers.remove(server_id);
+            self.models.write().await.remove(server_id);
+        } else {
+            for group in group_map.values() {
+                for server_lock in group.servers.read().await.iter() {
+                    let mut server = server_lock.write().await;
+                    if server.id == server_id {
+                        server.kind = remaining_kinds;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remove the server from the groups it is registered to. Returns `false` if the server is
+    /// not registered. The server ids set at registration do not tell the kinds of the server, so
+    /// all the groups are searched.
+    async fn unregister_from_groups(&self, server_id: &str) -> ServerResult<bool> {
+        let mut found = false;
+
+        let group_map = self.server_group.read().await;
+        for (kind, group) in group_map.iter() {
+            if group.healthy_servers.read().await.contains(server_id) {
+                group.unregister(server_id).await?;
+                dual_info!("Unregistered {} server: {}", kind, server_id);
+
+                found = true;
+            }
+        }
+
+        Ok(found)
+    }
+
+    pub(crate) async fn set_downstream_server_enabled(
+        &self,
+        server_id: impl AsRef<str>,
+        enabled: bool,
+    ) -> ServerResult<()> {
+        let mut found = false;
+        {
+            let group_map = self.server_group.read().await;
+            for (kind, group) in group_map.iter() {
+                if group.set_enabled(server_id.as_ref(), enabled).await {
+                    match enabled {
+                        true => dual_info!("Enabled {} server: {}", kind, server_id.as_ref()),
+                        false => dual_info!("Disabled {} server: {}", kind, server_id.as_ref()),
+                    }
+
+                    found = true;
+                }
+            }
+        }
+
+        if !found {
+            dual_error!("Not found server: {}", server_id.as_ref());
+            return Err(ServerError::NotFoundServerId(
+                server_id.as_ref().to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Collect the registered servers considered when routing a request of the given kind and
+    /// model, along with the reasons each of them is excluded
+    pub(crate) async fn routing_candidates(
+        &self,
+        kind: ServerKind,
+        model: Option<&str>,
+        tag: Option<&str>,
+    ) -> Vec<RoutingCandidate> {
+        let models = self.models.read().await;
+        let group_map = self.server_group.read().await;
+
+        // a server of multiple kinds is registered to multiple groups, so only visit it once
+        let mut visited = HashSet::new();
+        let mut candidates = Vec::new();
+        for group in group_map.values() {
+            let servers = group.servers.read().await;
+            for server_lock in servers.iter() {
+                let server = server_lock.read().await;
+                if !visited.insert(server.id.clone()) {
+                    continue;
+                }
+
+                let mut excluded_because = Vec::new();
+                if !server.kind.contains(kind) {
+                    excluded_because.push(ExclusionReason::WrongKind);
+                }
+                // servers that have not reported their models are assumed to serve any model
+                if let Some(model) = model
+                    && let Some(served_models) = models.get(&server.id)
+                    && !served_models.is_empty()
+                    && !served_models.iter().any(|m| m.id == model)
+                {
+                    excluded_because.push(ExclusionReason::ModelNotServed);
+                }
+                if let Some(tag) = tag
+                    && !server.has_tag(tag)
+                {
+                    excluded_because.push(ExclusionReason::TagNotMatched);
+                }
+                if !server.health_status.is_healthy {
+                    excluded_because.push(ExclusionReason::Unhealthy);
+                }
+                if !server.enabled {
+                    excluded_because.push(ExclusionReason::Draining);
+                }
+
+                candidates.push(RoutingCandidate {
+                    id: server.id.clone(),
+                    kind: server.kind,
+                    excluded_because,
+                });
+            }
+        }
+        candidates.sort_by(|a, b| a.id.cmp(&b.id));
+
+        candidates
+    }
+
+    pub(crate) async fn list_downstream_servers(
+        &self,
+    ) -> ServerResult<HashMap<ServerKind, Vec<Server>>> {
+        let servers = self.server_group.read().await;
+
+        let mut server_groups = HashMap::new();
+        for (kind, group) in servers.iter() {
+            if !group.is_empty().await {
+                let servers = group.servers.read().await;
+
+                // Create a new Vec with cloned Server instances using async stream
+                let server_vec = stream::iter(servers.iter())
+                    .then(|server_lock| async move {
+                        let server = server_lock.read().await;
+                        server.clone()
+                    })
+                    .collect::<Vec<_>>()
+                    .await;
+
+                server_groups.insert(*kind, server_vec);
+            }
+        }
+
+        Ok(server_groups)
+    }
+
+    /// Check the health of the registered servers, and unregister the unhealthy ones if
+    /// `evict_on_unhealthy` is set. Otherwise the unhealthy servers stay registered, are skipped
+    /// by the routing, and are checked again in the next health check. The healthy servers checked
+    /// within the health check interval are not checked again, unless `force` is set. Returns the
+    /// ids of the unregistered servers.
+    pub(crate) async fn check_server_health(&self, force: bool) -> ServerResult<Vec<ServerId>> {
+        let _guard = self.health_check_lock.lock().await;
+
+        let mut unregistered_servers = Vec::new();
+        if !self.server_group.read().await.is_empty() {
+            let mut unhealthy_servers = Vec::new();
+
+            // Check health status of downstream servers
+            // 1. Get all registered downstream servers
             // 2. Check health status of downstream servers
             //   2.1 If a downstream server has multiple types, only perform one health check
             //   2.2 If there are multiple downstream servers of the same type, health checks are needed for all
             //   2.3 If two or more downstream servers have different types but the same URL, only perform one health check
-            // 3. Remove unhealthy downstream servers
+            //   2.4 The servers not checked take the health status of the checked server of the same URL
+            // 3. Remove unhealthy downstream servers if evicting them
+            //
+            // The servers are checked without holding the locks, so that a slow server does not
+            // hold up the routing of the requests for the length of its check.
+
+            // one server per url, along with whether it is due for a check
+            let mut targets = Vec::new();
             {
                 let group_map = self.server_group.read().await;
-
-                // check health of unique servers
-                let mut unique_server_ids = HashSet::new();
                 for (kind, group) in group_map.iter() {
-                    if !group.is_empty().await {
-                        let servers = group.servers.read().await;
-                        for server_lock in servers.iter() {
-                            let mut server = server_lock.write().await;
-
-                            if !unique_server_ids.contains(&server.id)
-                                && unique_server_ids.contains(&server.url)
-                            {
-                                dual_info!("Checking health of {}", &server.id);
-
-                                unique_server_ids.insert(server.id.clone());
-                                unique_server_ids.insert(server.url.clone());
-
-                                let is_healthy = server.check_health().await;
-                                if !is_healthy {
-                                    dual_warn!("{} server {} is unhealthy", kind, &server.id);
-                                    unhealthy_servers.push(server.id.clone());
-                                }
-                            }
+                    for server_lock in group.servers.read().await.iter() {
+                        let server = server_lock.read().await;
+                        if !targets
+                            .iter()
+                            .any(|(_, _, url, _, _, _)| *url == server.url)
+                        {
+                            targets.push((
+                                *kind,
+                                server.id.clone(),
+                                server.url.clone(),
+                                server.endpoint("info"),
+                                server.health_status.clone(),
+                                server.is_health_check_due(force),
+                            ));
+                        }
+                    }
+                }
+            }
+
+            // check the health of each url once
+            let mut checked_urls: HashMap<String, HealthStatus> = HashMap::new();
+            for (kind, id, url, health_url, health_status, is_due) in targets {
+                let health_status = match is_due {
+                    true => {
+                        dual_info!("Checking health of {}", &id);
+
+                        let is_healthy = server::probe_health(kind, &id, &health_url).await;
+                        if !is_healthy {
+                            dual_warn!("{} server {} is unhealthy", kind, &id);
+                        } else if !health_status.is_healthy {
+                            dual_info!("{} server {} recovered", kind, &id);
+                        }
+
+                        health_status.after_check(is_healthy)
+                    }
+                    false => health_status,
+                };
+                checked_urls.insert(url, health_status);
+            }
+
+            // the servers take the health status of their url
+            {
+                let group_map = self.server_group.read().await;
+                for group in group_map.values() {
+                    for server_lock in group.servers.read().await.iter() {
+                        let mut server = server_lock.write().await;
+                        if let Some(health_status) = checked_urls.get(&server.url) {
+                            server.health_status = health_status.clone();
+                        }
+
+                        if !server.health_status.is_healthy
+                            && !unhealthy_servers.contains(&server.id)
+                        {
+                            unhealthy_servers.push(server.id.clone());
                         }
                     }
                 }
             }
 
             // Unregister unhealthy servers
-            if !unhealthy_servers.is_empty() {
+            if self.config.read().await.server.evict_on_unhealthy {
                 for server_id in unhealthy_servers {
                     self.unregister_downstream_server(&server_id).await?;
+                    unregistered_servers.push(server_id);
                 }
             }
 
-            // Push the healthy servers to the external service if configured
-            if let Some(push_url) = &self.config.read().await.server_health_push_url {
-                // collect the healthy servers by kind
-                let mut healthy_servers: HashMap<ServerKind, Vec<String>> = HashMap::new();
-                {
-                    let group_map = self.server_group.read().await;
-                    for (kind, group) in group_map.iter() {
-                        if group.is_empty().await {
-                            dual_warn!("No {} servers available after health check", kind);
-                        }
+            self.report_health_status().await;
+        } else {
+            dual_warn!("No servers registered, skipping health check");
+        }
+
+        Ok(unregistered_servers)
+    }
+
+    /// Probe the unhealthy servers whose backoff since their last failed check has passed, so
+    /// that they are routed to again soon after they come back
+    pub(crate) async fn probe_unhealthy_servers(&self) {
+        let _guard = self.health_check_lock.lock().await;
 
-                        healthy_servers.insert(
+        let (base, max) = {
+            let config = self.config.read().await;
+            (
+                Duration::from_secs(config.server.unhealthy_probe_backoff_secs),
+                Duration::from_secs(config.server.unhealthy_probe_max_backoff_secs),
+            )
+        };
+
+        // the unhealthy servers due for a probe, one per url, taken without holding the locks
+        // across the probes
+        let mut due = Vec::new();
+        {
+            let group_map = self.server_group.read().await;
+            for (kind, group) in group_map.iter() {
+                for server_lock in group.servers.read().await.iter() {
+                    let server = server_lock.read().await;
+                    if !server.health_status.is_healthy
+                        && server.health_status.is_probe_due(base, max)
+                        && !due.iter().any(|(_, _, url, _, _)| *url == server.url)
+                    {
+                        due.push((
                             *kind,
-                            group.healthy_servers.read().await.iter().cloned().collect(),
-                        );
+                            server.id.clone(),
+                            server.url.clone(),
+                            server.endpoint("info"),
+                            server.health_status.clone(),
+                        ));
                     }
                 }
+            }
+        }
 
-                let health_status = serde_json::json!({
-                    "rag": self.config.read().await.rag.as_ref().unwrap().enable,
-                    "servers": healthy_servers,
-                });
+        // probe each url once
+        let mut recovered = false;
+        let mut probed_urls: HashMap<String, HealthStatus> = HashMap::new();
+        for (kind, id, url, health_url, health_status) in due {
+            dual_debug!("Probing the unhealthy {} server {}", kind, &id);
+            let is_healthy = server::probe_health(kind, &id, &health_url).await;
+            if is_healthy {
+                dual_info!("{} server {} recovered", kind, &id);
+                recovered = true;
+            }
+            probed_urls.insert(url, health_status.after_check(is_healthy));
+        }
 
-                dual_debug!(
-                    "Healthy servers:\n{}",
-                    serde_json::to_string_pretty(&health_status).unwrap()
-                );
+        // the unhealthy servers of a probed url take its health status
+        {
+            let group_map = self.server_group.read().await;
+            for group in group_map.values() {
+                for server_lock in group.servers.read().await.iter() {
+                    let mut server = server_lock.write().await;
+                    if !server.health_status.is_healthy
+                        && let Some(health_status) = probed_urls.get(&server.url)
+                    {
+                        server.health_status = health_status.clone();
+                    }
+                }
+            }
+        }
 
-                // Send the healthy servers to the external service
-                reqwest::Client::new()
-                    .post(push_url)
-                    .json(&health_status)
-                    .send()
-                    .await
-                    .map_err(|e| {
-                        let err_msg = format!("Failed to send health check result: {e}");
+        if recovered {
+            self.report_health_status().await;
+        }
+    }
 
-                        dual_error!("{}", err_msg);
+    /// Push the healthy servers to `server_health_push_url` if configured
+    async fn report_health_status(&self) {
+        let push_url = self.config.read().await.server_health_push_url.clone();
+        if let Some(push_url) = push_url {
+            let health_status = self.health_status().await;
 
-                        ServerError::Operation(err_msg)
-                    })?;
+            dual_debug!(
+                "Healthy servers:\n{}",
+                serde_json::to_string_pretty(&health_status).unwrap()
+            );
+
+            // Send the healthy servers to the external service without holding up the health
+            // check
+            tokio::spawn(push_health_status(
+                push_url,
+                health_status,
+                HEALTH_PUSH_RETRY_BACKOFF,
+            ));
+        }
+    }
+
+    /// The healthy servers by kind, and whether RAG is enabled, pushed to `server_health_push_url`
+    async fn health_status(&self) -> serde_json::Value {
+        let mut healthy_servers: HashMap<ServerKind, Vec<String>> = HashMap::new();
+        {
+            let group_map = self.server_group.read().await;
+            for (kind, group) in group_map.iter() {
+                // the unhealthy servers stay registered if they are not evicted
+                let mut server_ids = Vec::new();
+                for server_lock in group.servers.read().await.iter() {
+                    let server = server_lock.read().await;
+                    if server.health_status.is_healthy {
+                        server_ids.push(server.id.clone());
+                    }
+                }
+                if server_ids.is_empty() {
+                    dual_warn!("No {} servers available after health check", kind);
+                }
+
+                healthy_servers.insert(*kind, server_ids);
             }
-        } else {
-            dual_warn!("No servers registered, skipping health check");
         }
 
-        Ok(())
+        let rag_enabled = self
+            .config
+            .read()
+            .await
+            .rag
+            .as_ref()
+            .is_some_and(|rag_config| rag_config.enable);
+
+        serde_json::json!({
+            "rag": rag_enabled,
+            "servers": healthy_servers,
+        })
     }
 
     pub(crate) async fn start_health_check_task(self: Arc<Self>) {
         let check_interval = HEALTH_CHECK_INTERVAL.get().unwrap_or(&60);
         let check_interval = tokio::time::Duration::from_secs(*check_interval);
 
+        // the unhealthy servers are only kept, and probed on their own schedule, if not evicted
+        let probe_interval = {
+            let config = self.config.read().await;
+            (!config.server.evict_on_unhealthy)
+                .then(|| Duration::from_secs(config.server.unhealthy_probe_backoff_secs.max(1)))
+        };
+        if let Some(probe_interval) = probe_interval {
+            let state = Arc::clone(&self);
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(probe_interval).await;
+                    state.probe_unhealthy_servers().await;
+                }
+            });
+        }
+
         tokio::spawn(async move {
             loop {
                 dual_debug!("Starting health check");
 
-                if let Err(e) = self.check_server_health().await {
+                if let Err(e) = self.check_server_health(false).await {
                     dual_error!("Health check error: {}", e);
                 }
+                mcp::check_mcp_health().await;
 
                 tokio::time::sleep(check_interval).await;
             }
         });
     }
 }
+
+/// Send the health check result to `server_health_push_url`, retrying the failed attempts with an
+/// exponential backoff. The result is dropped with a warning if all the attempts fail.
+async fn push_health_status(
+    push_url: String,
+    health_status: serde_json::Value,
+    retry_backoff: Duration,
+) {
+    let mut backoff = retry_backoff;
+    for attempt in 1..=HEALTH_PUSH_ATTEMPTS {
+        let result = http_client()
+            .post(&push_url)
+            .json(&health_status)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        match result {
+            Ok(_) => return,
+            Err(e) if attempt < HEALTH_PUSH_ATTEMPTS => {
+                dual_debug!(
+                    "Failed to send health check result (attempt {}/{}): {}. Retry in {:?}",
+                    attempt,
+                    HEALTH_PUSH_ATTEMPTS,
+                    e,
+                    backoff
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(e) => {
+                dual_warn!(
+                    "Failed to send health check result after {} attempts: {}",
+                    HEALTH_PUSH_ATTEMPTS,
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// The state of the tests, with the default config
+#[cfg(test)]
+pub(crate) fn test_state() -> Arc<AppState> {
+    test_state_with(Config::default())
+}
+
+/// The state of the tests, with the given config
+#[cfg(test)]
+pub(crate) fn test_state_with(config: Config) -> Arc<AppState> {
+    Arc::new(AppState::new(config, ServerInfo::default()))
+}
+
+#[tokio::test]
+async fn test_cancel_on_client_disconnect() {
+    let state = test_state();
+    let (cancelled_tx, cancelled_rx) = tokio::sync::oneshot::channel();
+    let cancelled_tx = Arc::new(std::sync::Mutex::new(Some(cancelled_tx)));
+
+    // the handler never responds, and reports the cancellation of its request
+    let router = Router::new().route(
+        "/v1/slow",
+        get(
+            move |axum::Extension(cancel_token): axum::Extension<CancellationToken>| async move {
+                let cancelled = cancel_token.clone().cancelled_owned();
+                tokio::spawn(async move {
+                    cancelled.await;
+                    if let Some(cancelled_tx) = cancelled_tx.lock().unwrap().take() {
+                        let _ = cancelled_tx.send(());
+                    }
+                });
+                std::future::pending::<()>().await
+            },
+        ),
+    );
+    let app = with_middleware(router, state.clone()).with_state(state);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    // the client gives up on the request and closes the connection
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_millis(200))
+        .build()
+        .unwrap();
+    assert!(
+        client
+            .get(format!("http://{addr}/v1/slow"))
+            .send()
+            .await
+            .is_err()
+    );
+    drop(client);
+
+    tokio::time::timeout(Duration::from_secs(5), cancelled_rx)
+        .await
+        .expect("the request should be cancelled once the client disconnects")
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_normalize_trailing_slash() {
+    use tower::ServiceExt;
+
+    let state = test_state();
+
+    // with normalization, both forms are routed to the API routes
+    let app = normalize_path(
+        build_router(state.clone(), Path::new("chatbot-ui"), &[], true),
+        true,
+    );
+    for uri in [
+        "/v1/models",
+        "/v1/models/",
+        "/v1/info/",
+        "/admin/servers/?kind=chat",
+    ] {
+        let response = app
+            .clone()
+            .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "uri: {uri}");
+    }
+
+    // without normalization, the trailing slash form falls through to the Web UI
+    let app = normalize_path(
+        build_router(state, Path::new("chatbot-ui"), &[], true),
+        false,
+    );
+    let response = app
+        .clone()
+        .oneshot(Request::get("/v1/models").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app
+        .oneshot(Request::get("/v1/models/").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_retrieve_model() {
+    use tower::ServiceExt;
+
+    let state = test_state();
+    state.models.write().await.insert(
+        "chat-server-1".to_string(),
+        vec![endpoints::models::Model {
+            id: "second-state/Llama-3.2-3B".to_string(),
+            created: 0,
+            object: "model".to_string(),
+            owned_by: "Not specified".to_string(),
+        }],
+    );
+    let app = normalize_path(
+        build_router(state, Path::new("chatbot-ui"), &[], true),
+        true,
+    );
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::get("/v1/models/second-state/Llama-3.2-3B")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let model: endpoints::models::Model = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(model.id, "second-state/Llama-3.2-3B");
+    assert_eq!(model.object, "model");
+
+    let response = app
+        .oneshot(
+            Request::get("/v1/models/gpt-4")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn test_client_ip() {
+    let trusted_proxies: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+    let peer_ip = IpAddr::from([10, 0, 0, 1]);
+    let forwarded_for = |value: &str| {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_str(value).unwrap());
+        headers
+    };
+
+    // the header of an untrusted peer is ignored
+    let client = IpAddr::from([203, 0, 113, 7]);
+    let headers = forwarded_for("198.51.100.1");
+    assert_eq!(client_ip(client, &headers, &trusted_proxies), client);
+    assert_eq!(client_ip(peer_ip, &headers, &[]), peer_ip);
+
+    // the spoofed addresses before the last untrusted one are skipped
+    let headers = forwarded_for("198.51.100.1, 203.0.113.7, 10.0.0.2");
+    assert_eq!(client_ip(peer_ip, &headers, &trusted_proxies), client);
+
+    let headers = forwarded_for("10.0.0.3, 10.0.0.2");
+    assert_eq!(
+        client_ip(peer_ip, &headers, &trusted_proxies),
+        IpAddr::from([10, 0, 0, 3])
+    );
+    assert_eq!(
+        client_ip(peer_ip, &HeaderMap::new(), &trusted_proxies),
+        peer_ip
+    );
+}
+
+#[test]
+fn test_trim_trailing_slash() {
+    let req = trim_trailing_slash(
+        Request::post("/v1/chat/completions/")
+            .body(Body::empty())
+            .unwrap(),
+    );
+    assert_eq!(req.uri().path(), "/v1/chat/completions");
+
+    let req = trim_trailing_slash(
+        Request::get("/admin/servers/?a=1")
+            .body(Body::empty())
+            .unwrap(),
+    );
+    assert_eq!(req.uri().path(), "/admin/servers");
+    assert_eq!(req.uri().query(), Some("a=1"));
+
+    // the paths of the Web UI are kept as they are
+    for uri in ["/", "/assets/"] {
+        let req = trim_trailing_slash(Request::get(uri).body(Body::empty()).unwrap());
+        assert_eq!(req.uri().path(), uri);
+    }
+}
+
+#[tokio::test]
+async fn test_web_ui_mounts() {
+    use tower::ServiceExt;
+
+    let dir = std::env::temp_dir().join(format!("llama-nexus-{}", uuid::Uuid::new_v4()));
+    for name in ["chat-ui", "settings-ui"] {
+        std::fs::create_dir_all(dir.join(name)).unwrap();
+        std::fs::write(dir.join(name).join("index.html"), name).unwrap();
+    }
+    let mounts = [WebUiMount {
+        path: "/settings".to_string(),
+        dir: dir.join("settings-ui"),
+    }];
+
+    let state = test_state();
+    let app = normalize_path(
+        build_router(state, &dir.join("chat-ui"), &mounts, true),
+        true,
+    );
+    for (uri, expected) in [
+        ("/index.html", "chat-ui"),
+        ("/settings/index.html", "settings-ui"),
+        ("/settings/", "settings-ui"),
+    ] {
+        let response = app
+            .clone()
+            .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "uri: {uri}");
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(bytes, expected.as_bytes(), "uri: {uri}");
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_maintenance_mode() {
+    use tower::ServiceExt;
+
+    let state = test_state();
+    let app = normalize_path(
+        build_router(state, Path::new("chatbot-ui"), &[], true),
+        true,
+    );
+    let set_maintenance = |body: serde_json::Value| {
+        Request::post("/admin/maintenance")
+            .header("Content-Type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+
+    let response = app
+        .clone()
+        .oneshot(set_maintenance(json!({
+            "enabled": true,
+            "message": "Upgrading the backends",
+            "retry_after": 120,
+        })))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // the proxy endpoints are down
+    for request in [
+        Request::get("/v1/models").body(Body::empty()).unwrap(),
+        Request::post("/v1/chat/completions")
+            .header("Content-Type", "application/json")
+            .body(Body::from("{}"))
+            .unwrap(),
+    ] {
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[http::header::RETRY_AFTER], "120");
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["error"]["message"], "Upgrading the backends");
+        assert_eq!(body["error"]["code"], "maintenance");
+    }
+
+    // the admin endpoints are up
+    let response = app
+        .clone()
+        .oneshot(Request::get("/admin/servers").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = app
+        .clone()
+        .oneshot(set_maintenance(json!({"enabled": false})))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app
+        .oneshot(Request::get("/v1/models").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_request_stats() {
+    use tower::ServiceExt;
+
+    let state = test_state();
+    let app = normalize_path(
+        build_router(state, Path::new("chatbot-ui"), &[], true),
+        true,
+    );
+    for request in [
+        Request::get("/v1/models").body(Body::empty()).unwrap(),
+        Request::get("/v1/models").body(Body::empty()).unwrap(),
+        Request::get("/v1/models/no-such-model")
+            .body(Body::empty())
+            .unwrap(),
+    ] {
+        app.clone().oneshot(request).await.unwrap();
+    }
+
+    let response = app
+        .oneshot(Request::get("/admin/stats").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(body["enabled"], true);
+    let endpoints = &body["endpoints"];
+    assert_eq!(endpoints["/v1/models"]["requests"], 2);
+    assert_eq!(endpoints["/v1/models"]["errors"], 0);
+    assert_eq!(endpoints["/v1/models/{*model}"]["requests"], 1);
+    assert_eq!(endpoints["/v1/models/{*model}"]["errors"], 1);
+    assert!(endpoints.get("/v1/chat/completions").is_none());
+}
+
+#[tokio::test]
+async fn test_resolve_server_addr() {
+    let addr = resolve_server_addr("127.0.0.1", 3389).await.unwrap();
+    assert_eq!(addr, SocketAddr::from(([127, 0, 0, 1], 3389)));
+
+    let addr = resolve_server_addr("localhost", 3389).await.unwrap();
+    assert!(addr.ip().is_loopback());
+    assert_eq!(addr.port(), 3389);
+
+    assert!(matches!(
+        resolve_server_addr("not a host!", 3389).await,
+        Err(ServerError::FailedToLoadConfig(msg)) if msg.contains("server.host")
+    ));
+}
+
+#[tokio::test]
+async fn test_authenticate() {
+    use tower::ServiceExt;
+
+    let mut config = Config::default();
+    config.server.admin_api_key = Some("admin-key".to_string());
+    config.auth = Some(config::AuthConfig {
+        api_keys: vec!["client-key".to_string()],
+    });
+    let state = test_state_with(config);
+    let app = build_router(state, Path::new("chatbot-ui"), &[], true);
+    let request = |path: &str, api_key: Option<&str>| {
+        let mut request = Request::get(path);
+        if let Some(api_key) = api_key {
+            request = request.header(http::header::AUTHORIZATION, format!("Bearer {api_key}"));
+        }
+        request.body(Body::empty()).unwrap()
+    };
+
+    for (path, api_key, status) in [
+        ("/v1/models", None, StatusCode::UNAUTHORIZED),
+        ("/v1/models", Some("wrong-key"), StatusCode::UNAUTHORIZED),
+        ("/v1/models", Some("client-key"), StatusCode::OK),
+        ("/admin/stats", Some("client-key"), StatusCode::UNAUTHORIZED),
+        ("/admin/stats", Some("admin-kez"), StatusCode::UNAUTHORIZED),
+        ("/admin/stats", Some("admin-key"), StatusCode::OK),
+    ] {
+        let response = app.clone().oneshot(request(path, api_key)).await.unwrap();
+        assert_eq!(response.status(), status, "{path} with {api_key:?}");
+    }
+
+    // an empty admin key disables the admin endpoints
+    let mut config = Config::default();
+    config.server.admin_api_key = Some(String::new());
+    config.auth = Some(config::AuthConfig {
+        api_keys: vec!["client-key".to_string()],
+    });
+    let state = test_state_with(config);
+    let app = build_router(state, Path::new("chatbot-ui"), &[], true);
+    for api_key in [None, Some(""), Some("client-key")] {
+        let response = app
+            .clone()
+            .oneshot(request("/admin/stats", api_key))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{api_key:?}");
+    }
+}
+
+#[tokio::test]
+async fn test_admin_router() {
+    use tower::ServiceExt;
+
+    let state = test_state();
+    let request = |path: &str| Request::get(path).body(Body::empty()).unwrap();
+
+    // the public router without the admin routes
+    let app = build_router(state.clone(), Path::new("chatbot-ui"), &[], false);
+    let response = app.clone().oneshot(request("/v1/models")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app.oneshot(request("/admin/stats")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let admin_app = build_admin_router(state);
+    let response = admin_app
+        .clone()
+        .oneshot(request("/admin/stats"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = admin_app
+        .clone()
+        .oneshot(request("/v1/models"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // an unknown server cannot be disabled or enabled
+    for action in ["disable", "enable"] {
+        let response = admin_app
+            .clone()
+            .oneshot(
+                Request::post(format!("/admin/servers/unknown-server/{action}"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
+
+#[tokio::test]
+async fn test_push_health_status() {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    // the monitoring endpoint fails the first attempt
+    let attempts = Arc::new(AtomicU32::new(0));
+    let app = Router::new().route(
+        "/health",
+        post({
+            let attempts = attempts.clone();
+            move || async move {
+                match attempts.fetch_add(1, Ordering::SeqCst) {
+                    0 => StatusCode::SERVICE_UNAVAILABLE,
+                    _ => StatusCode::OK,
+                }
+            }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    let health_status = json!({"rag": false, "servers": {}});
+    push_health_status(
+        format!("http://{addr}/health"),
+        health_status.clone(),
+        Duration::from_millis(10),
+    )
+    .await;
+    assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+    // the result is dropped without an error if all the attempts fail
+    push_health_status(
+        format!("http://{addr}/no-such-path"),
+        health_status,
+        Duration::from_millis(10),
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_health_status_without_rag() {
+    // no `[rag]` section in the config
+    let state = test_state();
+    let server: Server = serde_json::from_value(json!({
+        "url": "http://localhost:10011/v1",
+        "kind": "chat",
+    }))
+    .unwrap();
+    let server_id = server.id.clone();
+    state.register_downstream_server(server).await.unwrap();
+
+    let health_status = state.health_status().await;
+    assert_eq!(health_status["rag"], false);
+    assert_eq!(health_status["servers"]["chat"], json!([server_id]));
+}
+
+#[tokio::test]
+async fn test_stream_chat_response_incrementally() {
+    use tower::ServiceExt;
+
+    // a chat server sending an event every 200ms
+    let chat_app = Router::new().route(
+        "/v1/chat/completions",
+        post(|| async {
+            let events = stream::unfold(0, |i| async move {
+                if i == 3 {
+                    return None;
+                }
+                if i > 0 {
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                }
+                Some((
+                    Ok::<_, std::convert::Infallible>(format!("data: {i}\n\n")),
+                    i + 1,
+                ))
+            });
+            (
+                [(http::header::CONTENT_TYPE, "text/event-stream")],
+                Body::from_stream(events),
+            )
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, chat_app).await.unwrap() });
+
+    let state = test_state();
+    let server: Server = serde_json::from_value(json!({
+        "url": format!("http://{addr}/v1"),
+        "kind": "chat",
+    }))
+    .unwrap();
+    state.register_downstream_server(server).await.unwrap();
+    let app = build_router(state, Path::new("chatbot-ui"), &[], true);
+
+    let start = Instant::now();
+    let request = json!({
+        "messages": [{"role": "user", "content": "Hello"}],
+        "stream": true,
+    });
+    let response = app
+        .oneshot(
+            Request::post("/v1/chat/completions")
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(request.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // the first event arrives before the downstream server finishes
+    let mut body = response.into_body().into_data_stream();
+    let first_chunk = body.next().await.unwrap().unwrap();
+    let first_chunk_latency = start.elapsed();
+    assert_eq!(first_chunk, "data: 0\n\n");
+
+    let mut rest = Vec::new();
+    while let Some(chunk) = body.next().await {
+        rest.extend_from_slice(&chunk.unwrap());
+    }
+    let total_latency = start.elapsed();
+    assert_eq!(rest, b"data: 1\n\ndata: 2\n\n");
+    assert!(first_chunk_latency < Duration::from_millis(200));
+    assert!(total_latency >= Duration::from_millis(400));
+}
+
+#[tokio::test]
+async fn test_passthrough_unknown_tool_calls() {
+    use tower::ServiceExt;
+
+    // a chat server calling a function defined by the client, and echoing the tool result
+    let completion = json!({
+        "id": "chatcmpl-1",
+        "object": "chat.completion",
+        "created": 1700000000,
+        "model": "Llama-3.2-3b",
+        "choices": [{
+            "index": 0,
+            "message": {
+                "role": "assistant",
+                "content": null,
+                "tool_calls": [{
+                    "id": "call-1",
+                    "type": "function",
+                    "function": {"name": "get_local_time", "arguments": "{}"},
+                }],
+            },
+            "finish_reason": "tool_calls",
+            "logprobs": null,
+        }],
+        "usage": {"prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15},
+    });
+    let chat_app = Router::new().route(
+        "/v1/chat/completions",
+        post({
+            let completion = completion.clone();
+            |axum::Json(body): axum::Json<serde_json::Value>| async move {
+                let last_message = body["messages"].as_array().unwrap().last().unwrap();
+                if last_message["role"] != "tool" {
+                    return axum::Json(completion);
+                }
+                axum::Json(json!({
+                    "id": "chatcmpl-2",
+                    "object": "chat.completion",
+                    "created": 1700000000,
+                    "model": "Llama-3.2-3b",
+                    "choices": [{
+                        "index": 0,
+                        "message": {"role": "assistant", "content": last_message["content"]},
+                        "finish_reason": "stop",
+                        "logprobs": null,
+                    }],
+                }))
+            }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, chat_app).await.unwrap() });
+
+    let request = json!({
+        "messages": [{"role": "user", "content": "What time is it?"}],
+        "tools": [{
+            "type": "function",
+            "function": {"name": "get_local_time", "parameters": {"type": "object", "properties": {}}},
+        }],
+        "tool_choice": {"type": "function", "function": {"name": "get_local_time"}},
+    });
+    for passthrough_unknown_tools in [true, false] {
+        let mut config = Config::default();
+        config.server.passthrough_unknown_tools = passthrough_unknown_tools;
+        let state = test_state_with(config);
+        let server: Server = serde_json::from_value(json!({
+            "url": format!("http://{addr}/v1"),
+            "kind": "chat",
+        }))
+        .unwrap();
+        state.register_downstream_server(server).await.unwrap();
+        let app = build_router(state, Path::new("chatbot-ui"), &[], true);
+
+        let response = app
+            .oneshot(
+                Request::post("/v1/chat/completions")
+                    .header(http::header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(request.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        if passthrough_unknown_tools {
+            // the client receives the tool call to run the function itself
+            assert_eq!(body, completion);
+        } else {
+            // the model is told the tool call failed
+            let content = body["choices"][0]["message"]["content"].as_str().unwrap();
+            assert!(content.starts_with("Error: "), "{content}");
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_fan_out_choices() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tower::ServiceExt;
+
+    // a chat server answering one choice per request, and rejecting `n`
+    let requests = Arc::new(AtomicUsize::new(0));
+    let chat_app = Router::new().route(
+        "/v1/chat/completions",
+        post({
+            let requests = requests.clone();
+            |axum::Json(body): axum::Json<serde_json::Value>| async move {
+                assert!(body.get("n").is_none());
+                let i = requests.fetch_add(1, Ordering::SeqCst);
+                axum::Json(json!({
+                    "id": format!("chatcmpl-{i}"),
+                    "object": "chat.completion",
+                    "created": 1700000000,
+                    "model": "Llama-3.2-3b",
+                    "choices": [{
+                        "index": 0,
+                        "message": {"role": "assistant", "content": format!("answer {i}")},
+                        "finish_reason": "stop",
+                        "logprobs": null,
+                    }],
+                    "usage": {"prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15},
+                }))
+            }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, chat_app).await.unwrap() });
+
+    let mut config = Config::default();
+    config.server.fan_out_choices = true;
+    let state = test_state_with(config);
+    let server: Server = serde_json::from_value(json!({
+        "url": format!("http://{addr}/v1"),
+        "kind": "chat",
+    }))
+    .unwrap();
+    state.register_downstream_server(server).await.unwrap();
+    let app = build_router(state, Path::new("chatbot-ui"), &[], true);
+    let chat_request = |n: u64, stream: bool| {
+        let request = json!({
+            "messages": [{"role": "user", "content": "Hello"}],
+            "n": n,
+            "stream": stream,
+        });
+        Request::post("/v1/chat/completions")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(request.to_string()))
+            .unwrap()
+    };
+
+    let response = app.clone().oneshot(chat_request(3, false)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(requests.load(Ordering::SeqCst), 3);
+    let choices = body["choices"].as_array().unwrap();
+    assert_eq!(choices.len(), 3);
+    for (index, choice) in choices.iter().enumerate() {
+        assert_eq!(choice["index"], index);
+    }
+    assert_eq!(body["usage"]["prompt_tokens"], 30);
+    assert_eq!(body["usage"]["completion_tokens"], 15);
+    assert_eq!(body["usage"]["total_tokens"], 45);
+
+    // the streaming requests for several choices are rejected
+    let response = app.clone().oneshot(chat_request(3, true)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+    // so are the requests for more choices than `max_fan_out_choices`, before any is sent
+    let response = app.oneshot(chat_request(9, false)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(requests.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn test_usage_accounting() {
+    use tower::ServiceExt;
+
+    // a chat server reporting the usage in the response, or cumulatively in the chunks of the
+    // stream, the last one carrying only the usage
+    let chat_app = Router::new().route(
+        "/v1/chat/completions",
+        post(|axum::Json(body): axum::Json<serde_json::Value>| async move {
+            if body["stream"] == true {
+                assert_eq!(body["stream_options"]["include_usage"], true);
+                let events = [
+                    json!({"model": "Llama-3.2-3b", "choices": [{"index": 0, "delta": {"content": "Hi"}}], "usage": {"prompt_tokens": 7, "completion_tokens": 1, "total_tokens": 8}}),
+                    json!({"model": "Llama-3.2-3b", "choices": [], "usage": {"prompt_tokens": 7, "completion_tokens": 3, "total_tokens": 10}}),
+                ]
+                .iter()
+                .map(|event| format!("data: {event}\n\n"))
+                .collect::<String>();
+                return (
+                    [(http::header::CONTENT_TYPE, "text/event-stream")],
+                    format!("{events}data: [DONE]\n\n"),
+                );
+            }
+
+            let response = json!({
+                "id": "chatcmpl-1",
+                "object": "chat.completion",
+                "created": 1700000000,
+                "model": "Llama-3.2-3b",
+                "choices": [{
+                    "index": 0,
+                    "message": {"role": "assistant", "content": "Hi"},
+                    "finish_reason": "stop",
+                    "logprobs": null,
+                }],
+                "usage": {"prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15},
+            });
+            (
+                [(http::header::CONTENT_TYPE, "application/json")],
+                response.to_string(),
+            )
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, chat_app).await.unwrap() });
+
+    let mut config = Config::default();
+    config.usage = Some(config::UsageConfig {
+        retention_secs: 3600,
+        max_users: 2,
+    });
+    let state = test_state_with(config);
+    let server: Server = serde_json::from_value(json!({
+        "url": format!("http://{addr}/v1"),
+        "kind": "chat",
+    }))
+    .unwrap();
+    state.register_downstream_server(server).await.unwrap();
+    let app = build_router(state, Path::new("chatbot-ui"), &[], true);
+    let chat_request = |user: Option<&str>, stream: bool, include_usage: bool| {
+        let mut request = json!({
+            "model": "Llama-3.2-3b",
+            "messages": [{"role": "user", "content": "Hello"}],
+            "stream": stream,
+        });
+        if let Some(user) = user {
+            request["user"] = user.into();
+        }
+        if include_usage {
+            request["stream_options"] = json!({"include_usage": true});
+        }
+        Request::post("/v1/chat/completions")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(request.to_string()))
+            .unwrap()
+    };
+
+    for (user, stream, include_usage) in [
+        (Some("alice"), false, false),
+        (Some("alice"), true, false),
+        (None, false, false),
+        (Some("bob"), true, true),
+    ] {
+        let response = app
+            .clone()
+            .oneshot(chat_request(user, stream, include_usage))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        // the usage of a stream is accounted once it is read
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        // the usage chunk is only passed to the clients asking for it
+        if stream {
+            let body = String::from_utf8(bytes.to_vec()).unwrap();
+            assert!(body.contains("\"Hi\"") && body.ends_with("data: [DONE]\n\n"));
+            assert_eq!(body.contains("\"completion_tokens\":3"), include_usage);
+        }
+    }
+
+    let response = app
+        .clone()
+        .oneshot(Request::get("/admin/usage").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let usage: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(usage["enabled"], true);
+    assert_eq!(
+        usage["total"],
+        json!({"requests": 4, "prompt_tokens": 34, "completion_tokens": 16, "total_tokens": 50})
+    );
+    assert_eq!(usage["users"]["alice"]["total_tokens"], 25);
+    // the users past `max_users` are tallied together, whatever they send in the `user` field
+    assert_eq!(usage["users"]["anonymous"]["total_tokens"], 15);
+    assert_eq!(usage["other_users"]["total_tokens"], 10);
+    assert!(usage["users"].get("bob").is_none());
+    assert_eq!(usage["models"]["Llama-3.2-3b"]["requests"], 4);
+
+    // nothing was used in a window in the future
+    let response = app
+        .oneshot(
+            Request::get("/admin/usage?since=99999999999")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let usage: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(usage["total"]["requests"], 0);
+}
+
+#[tokio::test]
+async fn test_content_moderation() {
+    use tower::ServiceExt;
+
+    // a moderation endpoint flagging the messages about weapons
+    let moderation_app = Router::new().route(
+        "/v1/moderations",
+        post(
+            |axum::Json(body): axum::Json<serde_json::Value>| async move {
+                let score = match body["input"].as_str().unwrap().contains("weapon") {
+                    true => 0.9,
+                    false => 0.1,
+                };
+                axum::Json(json!({
+                    "results": [{"flagged": score > 0.5, "category_scores": {"violence": score}}],
+                }))
+            },
+        ),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, moderation_app).await.unwrap() });
+
+    let mut config = Config::default();
+    config.moderation = Some(config::ModerationConfig {
+        url: format!("http://{addr}/v1/moderations"),
+        api_key: None,
+        model: None,
+        threshold: 0.5,
+        timeout_secs: 10,
+    });
+    let state = test_state_with(config);
+    let app = build_router(state, Path::new("chatbot-ui"), &[], true);
+    let chat_request = |content: &str| {
+        let request = json!({"messages": [{"role": "user", "content": content}]});
+        Request::post("/v1/chat/completions")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(request.to_string()))
+            .unwrap()
+    };
+
+    let response = app
+        .clone()
+        .oneshot(chat_request("How to build a weapon?"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(body["error"]["code"], "content_flagged");
+    assert_eq!(body["error"]["flagged_categories"], json!(["violence"]));
+
+    // the request passing the moderation is routed, and fails without a chat server
+    let response = app.oneshot(chat_request("Hello")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[tokio::test]
+async fn test_redact_chat_response() {
+    use tower::ServiceExt;
+
+    // a chat server leaking a secret
+    let chat_app = Router::new().route(
+        "/v1/chat/completions",
+        post(|| async {
+            axum::Json(json!({
+                "id": "chatcmpl-1",
+                "object": "chat.completion",
+                "created": 1700000000,
+                "model": "Llama-3.2-3b",
+                "choices": [{
+                    "index": 0,
+                    "message": {"role": "assistant", "content": "The key is sk-abcdef123456"},
+                    "finish_reason": "stop",
+                    "logprobs": null,
+                }],
+                "usage": {"prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15},
+            }))
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, chat_app).await.unwrap() });
+
+    let mut config = Config::default();
+    config.redaction = Some(config::RedactionConfig {
+        patterns: vec![config::RedactionPattern {
+            pattern: r"sk-[A-Za-z0-9]{8,}".to_string(),
+            replacement: None,
+        }],
+        replacement: "[REDACTED]".to_string(),
+    });
+    let state = test_state_with(config);
+    let server: Server = serde_json::from_value(json!({
+        "url": format!("http://{addr}/v1"),
+        "kind": "chat",
+    }))
+    .unwrap();
+    state.register_downstream_server(server).await.unwrap();
+    let app = build_router(state, Path::new("chatbot-ui"), &[], true);
+
+    let request = json!({"messages": [{"role": "user", "content": "What is the key?"}]});
+    let response = app
+        .oneshot(
+            Request::post("/v1/chat/completions")
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(request.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(
+        body["choices"][0]["message"]["content"],
+        "The key is [REDACTED]"
+    );
+}
+
+#[tokio::test]
+async fn test_rerank() {
+    use tower::ServiceExt;
+
+    // a rerank server ranking the documents mentioning the query first
+    let rerank_app = Router::new().route(
+        "/v1/rerank",
+        post(
+            |axum::Json(body): axum::Json<serde_json::Value>| async move {
+                let query = body["query"].as_str().unwrap();
+                let results = body["documents"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .enumerate()
+                    .map(|(index, document)| {
+                        let score = match document.as_str().unwrap().contains(query) {
+                            true => 0.9,
+                            false => 0.1,
+                        };
+                        json!({"index": index, "relevance_score": score})
+                    })
+                    .collect::<Vec<_>>();
+                axum::Json(json!({"model": body["model"], "results": results}))
+            },
+        ),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, rerank_app).await.unwrap() });
+
+    let state = test_state();
+    let app = build_router(state.clone(), Path::new("chatbot-ui"), &[], true);
+    let rerank_request = || {
+        let request = json!({
+            "model": "bge-reranker-v2-m3",
+            "query": "Paris",
+            "documents": ["Berlin is in Germany.", "Paris is the capital of France."],
+        });
+        Request::post("/v1/rerank")
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(request.to_string()))
+            .unwrap()
+    };
+
+    // no rerank server is registered
+    let response = app.clone().oneshot(rerank_request()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(body["error"]["code"], "missing_capability");
+    assert_eq!(body["error"]["available_kinds"], json!([]));
+
+    let server: Server = serde_json::from_value(json!({
+        "url": format!("http://{addr}/v1"),
+        "kind": "rerank",
+    }))
+    .unwrap();
+    state.register_downstream_server(server).await.unwrap();
+
+    let response = app.oneshot(rerank_request()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(body["model"], "bge-reranker-v2-m3");
+    assert_eq!(body["results"][1]["relevance_score"], 0.9);
+}
+
+#[tokio::test]
+async fn test_idempotency_key() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tower::ServiceExt;
+
+    // a chat server answering with the number of the requests it served, slowly if asked to
+    let served = Arc::new(AtomicUsize::new(0));
+    let chat_app = Router::new().route(
+        "/v1/chat/completions",
+        post({
+            let served = served.clone();
+            |axum::Json(body): axum::Json<serde_json::Value>| async move {
+                if body["messages"][0]["content"] == "Slow" {
+                    tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+                }
+                let served = served.fetch_add(1, Ordering::SeqCst) + 1;
+                axum::Json(json!({
+                    "id": "chatcmpl-1",
+                    "object": "chat.completion",
+                    "created": 1700000000,
+                    "model": "Llama-3.2-3b",
+                    "choices": [{
+                        "index": 0,
+                        "message": {"role": "assistant", "content": format!("Request {served}")},
+                        "finish_reason": "stop",
+                        "logprobs": null,
+                    }],
+                    "usage": {"prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15},
+                }))
+            }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, chat_app).await.unwrap() });
+
+    let mut config = Config::default();
+    config.idempotency = Some(config::IdempotencyConfig { ttl_secs: 60 });
+    let state = test_state_with(config);
+    let server: Server = serde_json::from_value(json!({
+        "url": format!("http://{addr}/v1"),
+        "kind": "chat",
+    }))
+    .unwrap();
+    state.register_downstream_server(server).await.unwrap();
+    let app = build_router(state, Path::new("chatbot-ui"), &[], true);
+
+    let send_from = |client_ip: [u8; 4], idempotency_key: &'static str, content: &str| {
+        let request = json!({"messages": [{"role": "user", "content": content}]});
+        app.clone().oneshot(
+            Request::post("/v1/chat/completions")
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .header("idempotency-key", idempotency_key)
+                .extension(ClientIp(client_ip.into()))
+                .body(Body::from(request.to_string()))
+                .unwrap(),
+        )
+    };
+    let send = |idempotency_key: &'static str| send_from([10, 0, 0, 1], idempotency_key, "Hello");
+    let content = |bytes: &[u8]| {
+        let body: serde_json::Value = serde_json::from_slice(bytes).unwrap();
+        body["choices"][0]["message"]["content"].clone()
+    };
+
+    let response = send("key-1").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get("idempotent-replayed").is_none());
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert_eq!(content(&bytes), "Request 1");
+
+    // the repeated key is replayed without forwarding the request again
+    let response = send("key-1").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["idempotent-replayed"], "true");
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert_eq!(content(&bytes), "Request 1");
+    assert_eq!(served.load(Ordering::SeqCst), 1);
+
+    // another key is forwarded
+    let response = send("key-2").await.unwrap();
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert_eq!(content(&bytes), "Request 2");
+    assert_eq!(served.load(Ordering::SeqCst), 2);
+
+    // the keys are scoped to the client
+    let response = send_from([10, 0, 0, 2], "key-1", "Hello").await.unwrap();
+    assert!(response.headers().get("idempotent-replayed").is_none());
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert_eq!(content(&bytes), "Request 3");
+
+    // the key cannot be reused with another request
+    let response = send_from([10, 0, 0, 1], "key-1", "Goodbye").await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // a repeated key is rejected while its request is forwarded
+    let (first, second) = tokio::join!(send_from([10, 0, 0, 1], "key-3", "Slow"), async {
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        send_from([10, 0, 0, 1], "key-3", "Slow").await
+    });
+    assert_eq!(first.unwrap().status(), StatusCode::OK);
+    assert_eq!(second.unwrap().status(), StatusCode::CONFLICT);
+    assert_eq!(served.load(Ordering::SeqCst), 4);
+}
+
+#[tokio::test]
+async fn test_response_compression() {
+    use tower::ServiceExt;
+
+    // a chat server answering at length, or with a stream
+    let content = "All work and no play makes Jack a dull boy. ".repeat(100);
+    let chat_app = Router::new().route(
+        "/v1/chat/completions",
+        post(
+            |axum::Json(body): axum::Json<serde_json::Value>| async move {
+                if body["stream"] == true {
+                    let event = json!({"model": "Llama-3.2-3b", "choices": [{"index": 0, "delta": {"content": content}}]});
+                    return (
+                        [(http::header::CONTENT_TYPE, "text/event-stream")],
+                        format!("data: {event}\n\ndata: [DONE]\n\n"),
+                    );
+                }
+
+                let response = json!({
+                    "id": "chatcmpl-1",
+                    "object": "chat.completion",
+                    "created": 1700000000,
+                    "model": "Llama-3.2-3b",
+                    "choices": [{
+                        "index": 0,
+                        "message": {"role": "assistant", "content": content},
+                        "finish_reason": "stop",
+                        "logprobs": null,
+                    }],
+                    "usage": {"prompt_tokens": 10, "completion_tokens": 500, "total_tokens": 510},
+                });
+                (
+                    [(http::header::CONTENT_TYPE, "application/json")],
+                    response.to_string(),
+                )
+            },
+        ),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, chat_app).await.unwrap() });
+
+    let state = test_state();
+    let server: Server = serde_json::from_value(json!({
+        "url": format!("http://{addr}/v1"),
+        "kind": "chat",
+    }))
+    .unwrap();
+    state.register_downstream_server(server).await.unwrap();
+    let app = with_compression(
+        build_router(state, Path::new("chatbot-ui"), &[], true),
+        Some(&CompressionConfig { min_size: 1024 }),
+    );
+    let send = |stream: bool, accept_encoding: Option<&'static str>| {
+        let request = json!({
+            "model": "Llama-3.2-3b",
+            "messages": [{"role": "user", "content": "Hello"}],
+            "stream": stream,
+        });
+        let mut builder = Request::post("/v1/chat/completions")
+            .header(http::header::CONTENT_TYPE, "application/json");
+        if let Some(accept_encoding) = accept_encoding {
+            builder = builder.header(http::header::ACCEPT_ENCODING, accept_encoding);
+        }
+        app.clone()
+            .oneshot(builder.body(Body::from(request.to_string())).unwrap())
+    };
+
+    let response = send(false, None).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(
+        response
+            .headers()
+            .get(http::header::CONTENT_ENCODING)
+            .is_none()
+    );
+    let plain = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+
+    for encoding in ["gzip", "br"] {
+        let response = send(false, Some(encoding)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[http::header::CONTENT_ENCODING], encoding);
+        let compressed = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(compressed.len() < plain.len() / 4);
+    }
+
+    // the streams are left uncompressed
+    let response = send(true, Some("gzip")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(
+        response
+            .headers()
+            .get(http::header::CONTENT_ENCODING)
+            .is_none()
+    );
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert!(String::from_utf8_lossy(&bytes).contains("data: [DONE]"));
+}
+
+#[tokio::test]
+async fn test_request_decompression() {
+    use std::io::Write;
+
+    use flate2::{Compression, write::GzEncoder};
+    use tower::ServiceExt;
+
+    let gzip = |bytes: &[u8]| {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap()
+    };
+
+    // an image server echoing the request it received
+    let image_app = Router::new().route(
+        "/v1/images/generations",
+        post(|axum::Json(body): axum::Json<serde_json::Value>| async move { axum::Json(body) }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, image_app).await.unwrap() });
+
+    let mut config = Config::default();
+    config.server.max_request_body_size = 1024;
+    let state = test_state_with(config);
+    let server: Server = serde_json::from_value(json!({
+        "url": format!("http://{addr}/v1"),
+        "kind": "image",
+    }))
+    .unwrap();
+    state.register_downstream_server(server).await.unwrap();
+    let app = build_router(state, Path::new("chatbot-ui"), &[], true);
+    let send = |body: Vec<u8>| {
+        app.clone().oneshot(
+            Request::post("/v1/images/generations")
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .header(http::header::CONTENT_ENCODING, "gzip")
+                .body(Body::from(body))
+                .unwrap(),
+        )
+    };
+
+    // the body is inflated before it is forwarded
+    let request = json!({"model": "flux", "prompt": "A cat in a hat"});
+    let response = send(gzip(request.to_string().as_bytes())).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(body, request);
+
+    // a small body inflating beyond the limit is rejected
+    let prompt = "a".repeat(64 * 1024);
+    let bomb = gzip(
+        json!({"model": "flux", "prompt": prompt})
+            .to_string()
+            .as_bytes(),
+    );
+    assert!(bomb.len() < 1024);
+    let response = send(bomb).await.unwrap();
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[tokio::test]
+async fn test_dry_run() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tower::ServiceExt;
+
+    // a chat server counting the requests it receives
+    let served = Arc::new(AtomicUsize::new(0));
+    let chat_app = Router::new().route(
+        "/v1/chat/completions",
+        post({
+            let served = served.clone();
+            || async move {
+                served.fetch_add(1, Ordering::SeqCst);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, chat_app).await.unwrap() });
+
+    let request = json!({
+        "model": "Llama-3.2-3b",
+        "messages": [{"role": "user", "content": "Hello"}],
+        "top_k": 40,
+    });
+    let send = |app: Router| {
+        app.oneshot(
+            Request::post("/v1/chat/completions")
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .header("x-dry-run", "true")
+                .body(Body::from(request.to_string()))
+                .unwrap(),
+        )
+    };
+
+    for allow_dry_run in [false, true] {
+        let mut config = Config::default();
+        config.server.allow_dry_run = allow_dry_run;
+        let state = test_state_with(config);
+        let server: Server = serde_json::from_value(json!({
+            "url": format!("http://{addr}/v1"),
+            "kind": "chat",
+            "unsupported_params": ["top_k"],
+        }))
+        .unwrap();
+        state.register_downstream_server(server).await.unwrap();
+        let app = build_router(state, Path::new("chatbot-ui"), &[], true);
+
+        let response = send(app).await.unwrap();
+        if !allow_dry_run {
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+            continue;
+        }
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["dry_run"], true);
+        assert_eq!(
+            body["server"]["url"],
+            format!("http://{addr}/v1/chat/completions")
+        );
+        assert_eq!(body["request"]["model"], "Llama-3.2-3b");
+        assert!(body["request"]["user"].is_string());
+        // the request is shown as the server would receive it
+        assert!(body["request"].get("top_k").is_none());
+    }
+    assert_eq!(served.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test]
+async fn test_azure_chat_server() {
+    use axum::extract::Query;
+    use tower::ServiceExt;
+
+    // an azure server serving the `gpt-4o` deployment
+    let azure_app = Router::new().route(
+        "/openai/deployments/{deployment}/chat/completions",
+        post(
+            |axum::extract::Path(deployment): axum::extract::Path<String>,
+             Query(query): Query<HashMap<String, String>>,
+             headers: HeaderMap| async move {
+                assert_eq!(deployment, "gpt-4o");
+                assert_eq!(query["api-version"], "2024-10-21");
+                assert_eq!(headers["api-key"], "azure-key");
+                assert!(headers.get(http::header::AUTHORIZATION).is_none());
+                axum::Json(json!({
+                    "id": "chatcmpl-1",
+                    "object": "chat.completion",
+                    "created": 1700000000,
+                    "model": "gpt-4o",
+                    "choices": [{
+                        "index": 0,
+                        "message": {"role": "assistant", "content": "Hi from Azure"},
+                        "finish_reason": "stop",
+                        "logprobs": null,
+                    }],
+                    "usage": {"prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15},
+                }))
+            },
+        ),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, azure_app).await.unwrap() });
+
+    let state = test_state();
+    let app = build_router(state, Path::new("chatbot-ui"), &[], true);
+
+    // the deployments are registered as the models of the server
+    let server = json!({
+        "url": format!("http://{addr}"),
+        "kind": "chat",
+        "api_key": "azure-key",
+        "provider": "azure",
+        "api_version": "2024-10-21",
+        "deployments": ["gpt-4o"],
+    });
+    let response = app
+        .clone()
+        .oneshot(
+            Request::post("/admin/servers/register")
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(server.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let request = json!({
+        "model": "gpt-4o",
+        "messages": [{"role": "user", "content": "Hello"}],
+    });
+    let response = app
+        .clone()
+        .oneshot(
+            Request::post("/v1/chat/completions")
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(request.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(body["choices"][0]["message"]["content"], "Hi from Azure");
+
+    // the request without the deployment is rejected as a bad request
+    let request = json!({"messages": [{"role": "user", "content": "Hello"}]});
+    let response = app
+        .oneshot(
+            Request::post("/v1/chat/completions")
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(request.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_anthropic_chat_server() {
+    use tower::ServiceExt;
+
+    // an anthropic server serving `claude-sonnet-4-5`
+    let anthropic_app = Router::new()
+        .route(
+            "/v1/models",
+            axum::routing::get(|headers: HeaderMap| async move {
+                assert_eq!(headers["x-api-key"], "anthropic-key");
+                axum::Json(json!({
+                    "data": [{"type": "model", "id": "claude-sonnet-4-5", "display_name": "Claude Sonnet 4.5"}],
+                    "has_more": false,
+                }))
+            }),
+        )
+        .route(
+            "/v1/messages",
+            post(
+                |headers: HeaderMap, axum::Json(body): axum::Json<serde_json::Value>| async move {
+                    assert_eq!(headers["x-api-key"], "anthropic-key");
+                    assert_eq!(headers["anthropic-version"], "2023-06-01");
+                    assert_eq!(body["system"], "Be brief.");
+                    assert_eq!(body["messages"], json!([{"role": "user", "content": "Hello"}]));
+                    assert_eq!(body["max_tokens"], 4096);
+
+                    if body["stream"] == true {
+                        let events = [
+                            json!({"type": "message_start", "message": {"id": "msg_1", "model": "claude-sonnet-4-5", "usage": {"input_tokens": 10, "output_tokens": 1}}}),
+                            json!({"type": "content_block_delta", "index": 0, "delta": {"type": "text_delta", "text": "Hi from Anthropic"}}),
+                            json!({"type": "message_delta", "delta": {"stop_reason": "end_turn"}, "usage": {"output_tokens": 5}}),
+                            json!({"type": "message_stop"}),
+                        ]
+                        .iter()
+                        .map(|event| format!("event: {}\ndata: {event}\n\n", event["type"].as_str().unwrap()))
+                        .collect::<String>();
+                        return ([(http::header::CONTENT_TYPE, "text/event-stream")], events)
+                            .into_response();
+                    }
+
+                    axum::Json(json!({
+                        "id": "msg_1",
+                        "type": "message",
+                        "role": "assistant",
+                        "model": "claude-sonnet-4-5",
+                        "content": [{"type": "text", "text": "Hi from Anthropic"}],
+                        "stop_reason": "end_turn",
+                        "usage": {"input_tokens": 10, "output_tokens": 5},
+                    }))
+                    .into_response()
+                },
+            ),
+        );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, anthropic_app).await.unwrap() });
+
+    let state = test_state();
+    let app = build_router(state, Path::new("chatbot-ui"), &[], true);
+
+    let server = json!({
+        "url": format!("http://{addr}/v1"),
+        "kind": "chat",
+        "api_key": "anthropic-key",
+        "provider": "anthropic",
+    });
+    let response = app
+        .clone()
+        .oneshot(
+            Request::post("/admin/servers/register")
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(server.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let mut request = json!({
+        "model": "claude-sonnet-4-5",
+        "messages": [
+            {"role": "system", "content": "Be brief."},
+            {"role": "user", "content": "Hello"},
+        ],
+    });
+    let response = app
+        .clone()
+        .oneshot(
+            Request::post("/v1/chat/completions")
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(request.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(
+        body["choices"][0]["message"]["content"],
+        "Hi from Anthropic"
+    );
+    assert_eq!(body["choices"][0]["finish_reason"], "stop");
+    assert_eq!(body["usage"]["total_tokens"], 15);
+
+    // the events are translated into chunks
+    request["stream"] = true.into();
+    let response = app
+        .clone()
+        .oneshot(
+            Request::post("/v1/chat/completions")
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(request.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let events = sse::SseParser::default().feed(&bytes);
+    assert_eq!(events.last().unwrap(), "[DONE]");
+    let content = events[..events.len() - 1]
+        .iter()
+        .map(|event| serde_json::from_str::<serde_json::Value>(event).unwrap())
+        .filter_map(|chunk| {
+            chunk["choices"][0]["delta"]["content"]
+                .as_str()
+                .map(String::from)
+        })
+        .collect::<String>();
+    assert_eq!(content, "Hi from Anthropic");
+
+    // the tool messages not translated yet are rejected as a bad request
+    let request = json!({
+        "model": "claude-sonnet-4-5",
+        "messages": [
+            {"role": "user", "content": "Hello"},
+            {"role": "tool", "content": "{}", "tool_call_id": "call-1"},
+        ],
+    });
+    let response = app
+        .oneshot(
+            Request::post("/v1/chat/completions")
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(request.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_compact_history() {
+    // a chat server summarizing the conversations
+    let chat_app = Router::new().route(
+        "/v1/chat/completions",
+        post(|axum::Json(body): axum::Json<serde_json::Value>| async move {
+            assert_eq!(body["model"], "Llama-3.2-3b");
+            assert_eq!(
+                body["messages"][1]["content"],
+                "user: My favorite color is blue.\nassistant: Noted."
+            );
+            axum::Json(json!({
+                "id": "chatcmpl-1",
+                "object": "chat.completion",
+                "created": 1700000000,
+                "model": "Llama-3.2-3b",
+                "choices": [{
+                    "index": 0,
+                    "message": {"role": "assistant", "content": "The favorite color of the user is blue."},
+                    "finish_reason": "stop",
+                    "logprobs": null,
+                }],
+                "usage": {"prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15},
+            }))
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, chat_app).await.unwrap() });
+
+    let state = test_state();
+    let server: Server = serde_json::from_value(json!({
+        "url": format!("http://{addr}/v1"),
+        "kind": "chat",
+    }))
+    .unwrap();
+    state.register_downstream_server(server).await.unwrap();
+
+    let message = |role: &str, content: &str| ChatMessage {
+        role: role.to_string(),
+        content: content.to_string(),
+    };
+    let history = vec![
+        message("user", "My favorite color is blue."),
+        message("assistant", "Noted."),
+        message("user", "What is the capital of France?"),
+        message("assistant", "Paris."),
+    ];
+    let database_config = DatabaseConfig {
+        summarize_history: true,
+        summary_keep_messages: 2,
+        summary_model: Some("Llama-3.2-3b".to_string()),
+        ..Default::default()
+    };
+
+    // the oldest turn is replaced with its summary, which the window of the history keeps too
+    let compacted = compact_history(&state, &history, &database_config)
+        .await
+        .unwrap();
+    assert_eq!(window_history(compacted.clone(), 2).len(), 3);
+    let compacted = compacted
+        .iter()
+        .map(|message| (message.role.as_str(), message.content.as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        compacted,
+        [
+            (
+                "system",
+                "Summary of the earlier conversation: The favorite color of the user is blue."
+            ),
+            ("user", "What is the capital of France?"),
+            ("assistant", "Paris."),
+        ]
+    );
+
+    // nothing to summarize if all the messages are kept
+    let database_config = DatabaseConfig {
+        summary_keep_messages: 4,
+        ..database_config
+    };
+    assert!(
+        compact_history(&state, &history, &database_config)
+            .await
+            .is_none()
+    );
+}
+
+#[tokio::test]
+async fn test_export_session() {
+    use tower::ServiceExt;
+
+    let dir = std::env::temp_dir().join(format!("llama-nexus-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config = Config {
+        database: Some(DatabaseConfig {
+            database_url: Some(format!(
+                "sqlite://{}",
+                dir.join("chat_history.db").display()
+            )),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let state = test_state_with(config);
+    let store = state.session_store().await.unwrap();
+    for (role, content) in [("user", "Hello"), ("assistant", "Hi, how can I help?")] {
+        let message = ChatMessage {
+            role: role.to_string(),
+            content: content.to_string(),
+        };
+        store.save_message("session-1", &message).await.unwrap();
+    }
+    let app = build_router(state, Path::new("chatbot-ui"), &[], true);
+    let export = |uri: &str| {
+        app.clone()
+            .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+    };
+
+    let response = export("/sessions/session-1/export?format=json")
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(body["session_id"], "session-1");
+    assert_eq!(
+        body["messages"],
+        json!([
+            {"role": "user", "content": "Hello"},
+            {"role": "assistant", "content": "Hi, how can I help?"},
+        ])
+    );
+
+    let response = export("/sessions/session-1/export?format=md")
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()[http::header::CONTENT_TYPE],
+        "text/markdown; charset=utf-8"
+    );
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert!(String::from_utf8_lossy(&bytes).contains("**Assistant:**\n\nHi, how can I help?"));
+
+    let response = export("/sessions/session-1/export?format=pdf")
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = export("/sessions/session-2/export").await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // the sessions are listed, and deleted, through the admin endpoints
+    let response = export("/sessions").await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = export("/admin/sessions").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(body, json!({"sessions": ["session-1"]}));
+    let delete = |uri: &str| {
+        app.clone()
+            .oneshot(Request::delete(uri).body(Body::empty()).unwrap())
+    };
+    let response = delete("/sessions/session-1").await.unwrap();
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    let response = delete("/admin/sessions/session-1").await.unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = delete("/admin/sessions/session-1").await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = export("/sessions/session-1/export").await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -1,18 +1,49 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
 
 use once_cell::sync::OnceCell;
 use rmcp::{
-    RoleClient,
-    service::{DynService, RunningService},
+    RoleClient, ServiceError,
+    model::{
+        CallToolRequest, CallToolRequestParam, CallToolResult, ClientRequest, Content, Meta,
+        ServerResult,
+    },
+    service::{DynService, PeerRequestOptions, RunningService},
 };
 use tokio::sync::RwLock as TokioRwLock;
 
+use crate::{
+    config::{McpOAuthCallbackConfig, McpToolServerConfig},
+    dual_debug, dual_error, dual_info, dual_warn,
+    error::ServerError,
+};
+
 // Global MCP tools and clients
 pub static MCP_TOOLS: OnceCell<TokioRwLock<HashMap<McpToolName, ServiceName>>> = OnceCell::new();
 // Global MCP clients
 pub static MCP_SERVICES: OnceCell<TokioRwLock<HashMap<ServiceName, TokioRwLock<McpService>>>> =
     OnceCell::new();
 
+// Timeout of a single MCP tool call in seconds
+pub(crate) static MCP_TOOL_TIMEOUT: OnceCell<u64> = OnceCell::new();
+/// Default timeout of a single MCP tool call in seconds
+pub(crate) const DEFAULT_MCP_TOOL_TIMEOUT: u64 = 60;
+
+// Interval of the checks of the MCP connections in seconds
+pub(crate) static MCP_RECONNECT_INTERVAL: OnceCell<u64> = OnceCell::new();
+/// Default interval of the checks of the MCP connections in seconds
+pub(crate) const DEFAULT_MCP_RECONNECT_INTERVAL: u64 = 10;
+/// The MCP services whose connection dropped, until they are reconnected
+static RECONNECTING_MCP_SERVICES: Mutex<BTreeSet<ServiceName>> = Mutex::new(BTreeSet::new());
+
+/// Timeout of the probe of an MCP service by the health check
+const MCP_HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+/// The result of the last health check of each MCP service
+static MCP_HEALTH: Mutex<BTreeMap<ServiceName, McpHealth>> = Mutex::new(BTreeMap::new());
+
 pub(crate) const SEARCH_MCP_SERVER_NAMES: [&str; 5] = [
     "cardea-agentic-search-mcp-server",
     "cardea-tidb-mcp-server",
@@ -20,7 +51,29 @@ pub(crate) const SEARCH_MCP_SERVER_NAMES: [&str; 5] = [
     "cardea-elastic-mcp-server",
     "cardea-kwsearch-mcp-server",
 ];
+/// The names of the search mcp servers set in the config, besides `SEARCH_MCP_SERVER_NAMES`
+pub(crate) static EXTRA_SEARCH_MCP_SERVER_NAMES: OnceCell<Vec<String>> = OnceCell::new();
+
+/// Whether the mcp server is a search server, whose results are wrapped in the grounding
+/// instructions
+pub(crate) fn is_search_mcp_server(server_name: &str) -> bool {
+    is_search_mcp_server_among(
+        server_name,
+        EXTRA_SEARCH_MCP_SERVER_NAMES
+            .get()
+            .map_or(&[], |names| names.as_slice()),
+    )
+}
+
+/// Whether the mcp server is a search server, given the search server names set in the config
+fn is_search_mcp_server_among(server_name: &str, extra_names: &[String]) -> bool {
+    SEARCH_MCP_SERVER_NAMES.contains(&server_name)
+        || extra_names.iter().any(|name| name == server_name)
+}
+
 pub(crate) const DEFAULT_SEARCH_FALLBACK_MESSAGE: &str = "I’m unable to retrieve the necessary information to answer your question right now. Please try rephrasing or asking about something else.";
+/// The instructions wrapping the results of a search server if its `context_prompt_template` is not set
+pub(crate) const DEFAULT_SEARCH_CONTEXT_PROMPT_TEMPLATE: &str = "Please answer the question based on the information between **---BEGIN CONTEXT---** and **---END CONTEXT---**. Do not use any external knowledge. If the information between **---BEGIN CONTEXT---** and **---END CONTEXT---** is empty, please respond with `{fallback}`. Note that DO NOT use any tools if provided.\n\n---BEGIN CONTEXT---\n\n{context}\n\n---END CONTEXT---";
 
 pub type RawMcpService = RunningService<RoleClient, Box<dyn DynService<RoleClient>>>;
 pub type ServiceName = String;
@@ -32,6 +85,12 @@ pub struct McpService {
     pub raw: RawMcpService,
     pub tools: Vec<McpToolName>,
     pub fallback_message: Option<String>,
+    pub context_prompt_template: Option<String>,
+    /// The prefix of the names the tools are offered to the model under
+    pub tool_prefix: Option<String>,
+    /// Whether a dropped connection is reconnected in the background. The services authorized by
+    /// OAuth are not.
+    pub reconnectable: bool,
 }
 impl McpService {
     pub fn new(name: ServiceName, raw: RawMcpService) -> Self {
@@ -40,6 +99,124 @@ impl McpService {
             raw,
             tools: Vec::new(),
             fallback_message: None,
+            context_prompt_template: None,
+            tool_prefix: None,
+            reconnectable: true,
+        }
+    }
+
+    /// The name of the tool on the server, without the prefix it is offered to the model with
+    pub fn tool_name_on_server<'a>(&self, tool_name: &'a str) -> &'a str {
+        self.tool_prefix
+            .as_deref()
+            .and_then(|tool_prefix| tool_name.strip_prefix(tool_prefix))
+            .unwrap_or(tool_name)
+    }
+
+    /// Call a tool of the service. The request id, if any, is sent in the `_meta` of the call.
+    ///
+    /// If the call times out, an error result is returned, whose message is fed back to the model
+    /// as the tool message so that the chat can proceed.
+    #[tracing::instrument(
+        name = "mcp_tool_call",
+        skip_all,
+        fields(tool = %request_param.name, server = %self.name)
+    )]
+    pub async fn call_tool(
+        &self,
+        request_param: CallToolRequestParam,
+        request_id: Option<&str>,
+    ) -> Result<CallToolResult, ServiceError> {
+        let timeout =
+            Duration::from_secs(*MCP_TOOL_TIMEOUT.get().unwrap_or(&DEFAULT_MCP_TOOL_TIMEOUT));
+        self.call_tool_with_timeout(request_param, request_id, timeout)
+            .await
+    }
+
+    /// Call a tool of the service, giving up after the timeout
+    async fn call_tool_with_timeout(
+        &self,
+        request_param: CallToolRequestParam,
+        request_id: Option<&str>,
+        timeout: Duration,
+    ) -> Result<CallToolResult, ServiceError> {
+        let tool_name = request_param.name.clone();
+        let start = Instant::now();
+
+        // the request id and the trace context, e.g. `traceparent`, of the call
+        let mut meta = Meta::new();
+        if let Some(request_id) = request_id {
+            meta.insert("request_id".to_string(), request_id.into());
+        }
+        for (key, value) in crate::telemetry::trace_context() {
+            meta.insert(key, value.into());
+        }
+        let mut options = PeerRequestOptions::no_options();
+        if !meta.is_empty() {
+            options.meta = Some(meta);
+        }
+        let call = async {
+            let request = ClientRequest::CallToolRequest(CallToolRequest {
+                method: Default::default(),
+                params: request_param,
+                extensions: Default::default(),
+            });
+            match self
+                .raw
+                .send_request_with_option(request, options)
+                .await?
+                .await_response()
+                .await?
+            {
+                ServerResult::CallToolResult(result) => Ok(result),
+                _ => Err(ServiceError::UnexpectedResponse),
+            }
+        };
+
+        match tokio::time::timeout(timeout, call).await {
+            Ok(res) => res,
+            Err(_) => {
+                let elapsed = start.elapsed();
+                dual_warn!(
+                    "The call to the mcp tool {} of {} timed out after {:.1}s",
+                    tool_name,
+                    self.name,
+                    elapsed.as_secs_f64()
+                );
+
+                Ok(CallToolResult::error(vec![Content::text(format!(
+                    "Error: the tool `{tool_name}` did not respond within {} seconds.",
+                    timeout.as_secs()
+                ))]))
+            }
+        }
+    }
+
+    /// Check that the connection to the service is alive. A dropped connection is reconnected in
+    /// the background, and the calls fail with a retryable error meanwhile. The calls to a service
+    /// that cannot be reconnected fail for good.
+    pub fn ensure_connected(&self) -> Result<(), ServerError> {
+        let mut reconnecting = RECONNECTING_MCP_SERVICES.lock().unwrap();
+        if self.raw.is_transport_closed() {
+            if !self.reconnectable {
+                let err_msg = format!(
+                    "The connection to the mcp server {} dropped, and it is not reconnected without the OAuth authorization of the user",
+                    self.name
+                );
+                dual_error!("{}", err_msg);
+                return Err(ServerError::McpOperation(err_msg));
+            }
+            reconnecting.insert(self.name.clone());
+        }
+
+        match reconnecting.contains(&self.name) {
+            true => Err(ServerError::McpReconnecting {
+                server: self.name.clone(),
+                retry_after: *MCP_RECONNECT_INTERVAL
+                    .get()
+                    .unwrap_or(&DEFAULT_MCP_RECONNECT_INTERVAL),
+            }),
+            false => Ok(()),
         }
     }
 
@@ -50,4 +227,412 @@ impl McpService {
             false
         }
     }
+
+    /// Whether the service offers the tool under the given name. Only the RAG module uses it,
+    /// which is compiled for its tests.
+    #[cfg(test)]
+    pub fn has_tool(&self, tool_name: &str) -> bool {
+        self.tools.iter().any(|tool| tool == tool_name)
+    }
+
+    /// Wrap the results of a search server in the grounding instructions of the service
+    pub fn search_context_prompt(&self, context: &str) -> String {
+        let fallback = match self.has_fallback_message() {
+            true => self.fallback_message.as_deref().unwrap(),
+            false => DEFAULT_SEARCH_FALLBACK_MESSAGE,
+        };
+        dual_debug!("fallback message: {}", fallback);
+
+        render_context_prompt(
+            self.context_prompt_template
+                .as_deref()
+                .filter(|template| !template.is_empty())
+                .unwrap_or(DEFAULT_SEARCH_CONTEXT_PROMPT_TEMPLATE),
+            context,
+            fallback,
+        )
+    }
+}
+
+/// Check the MCP connections every interval, and reconnect the services whose connection dropped,
+/// e.g. after the restart of their server, refreshing their tools
+///
+/// The services authorized by OAuth are not reconnected, since their authorization needs the
+/// browser of the user.
+pub(crate) async fn reconnect_mcp_services(
+    mut tool_servers: Vec<McpToolServerConfig>,
+    oauth_callback: McpOAuthCallbackConfig,
+    interval: Duration,
+) {
+    tool_servers.retain(|tool_server| tool_server.enable && tool_server.oauth_url.is_none());
+    loop {
+        tokio::time::sleep(interval).await;
+        let Some(services) = MCP_SERVICES.get() else {
+            continue;
+        };
+
+        // find the dropped connections, releasing the services before reconnecting them
+        let mut dropped = Vec::new();
+        for (name, service) in services.read().await.iter() {
+            let service = service.read().await;
+            if service.raw.is_transport_closed()
+                || service.raw.peer_info().is_none()
+                || is_reconnecting(name)
+            {
+                dropped.push(name.clone());
+            }
+        }
+
+        for tool_server in tool_servers
+            .iter_mut()
+            .filter(|tool_server| dropped.contains(&tool_server.name))
+        {
+            RECONNECTING_MCP_SERVICES
+                .lock()
+                .unwrap()
+                .insert(tool_server.name.clone());
+            dual_warn!(
+                "The connection to the mcp server {} dropped. Reconnecting",
+                tool_server.name
+            );
+
+            match tool_server.connect_mcp_server(&oauth_callback).await {
+                Ok(()) => {
+                    RECONNECTING_MCP_SERVICES
+                        .lock()
+                        .unwrap()
+                        .remove(&tool_server.name);
+                    dual_info!("Reconnected to the mcp server {}", tool_server.name);
+                }
+                Err(e) => dual_warn!(
+                    "Failed to reconnect to the mcp server {}, retrying in {}s: {}",
+                    tool_server.name,
+                    interval.as_secs(),
+                    e
+                ),
+            }
+        }
+    }
+}
+
+fn is_reconnecting(name: &str) -> bool {
+    RECONNECTING_MCP_SERVICES.lock().unwrap().contains(name)
+}
+
+/// The result of the health check of an MCP service
+#[derive(Debug, Clone)]
+pub(crate) struct McpHealth {
+    pub is_healthy: bool,
+    pub last_check: SystemTime,
+    /// Why the service is unhealthy
+    pub error: Option<String>,
+}
+
+/// The result of the last health check of the MCP service, or `None` if not checked yet
+pub(crate) fn mcp_health(name: &str) -> Option<McpHealth> {
+    MCP_HEALTH.lock().unwrap().get(name).cloned()
+}
+
+/// Whether the tools of the MCP service can be offered to the model. The services not checked
+/// yet are taken as healthy.
+pub(crate) fn is_mcp_service_healthy(name: &str) -> bool {
+    mcp_health(name).is_none_or(|health| health.is_healthy)
+}
+
+pub(crate) fn record_mcp_health(name: &str, result: Result<(), String>) {
+    let was_healthy = is_mcp_service_healthy(name);
+    match &result {
+        Ok(()) if !was_healthy => dual_info!("The mcp server {} is healthy again", name),
+        Err(e) if was_healthy => dual_warn!("The mcp server {} is unhealthy: {}", name, e),
+        _ => {}
+    }
+
+    MCP_HEALTH.lock().unwrap().insert(
+        name.to_string(),
+        McpHealth {
+            is_healthy: result.is_ok(),
+            last_check: SystemTime::now(),
+            error: result.err(),
+        },
+    );
+}
+
+/// Probe each MCP service with a `tools/list` request, the tools of the unhealthy ones being left
+/// out of the chat requests until they pass a later check. The services are probed concurrently,
+/// without holding their locks, so that a slow service neither delays the others nor blocks the
+/// chat requests using it.
+pub(crate) async fn check_mcp_health() {
+    let Some(services) = MCP_SERVICES.get() else {
+        return;
+    };
+
+    // snapshot the peers of the connected services, the locks being released before the probes
+    let mut probes = Vec::new();
+    for (name, service) in services.read().await.iter() {
+        let service = service.read().await;
+        let peer = service
+            .ensure_connected()
+            .map(|()| service.raw.peer().clone())
+            .map_err(|e| e.to_string());
+        probes.push((name.clone(), peer));
+    }
+
+    let results =
+        futures_util::future::join_all(probes.into_iter().map(|(name, peer)| async move {
+            let result = match peer {
+                Ok(peer) => {
+                    match tokio::time::timeout(MCP_HEALTH_CHECK_TIMEOUT, peer.list_tools(None))
+                        .await
+                    {
+                        Ok(Ok(_)) => Ok(()),
+                        Ok(Err(e)) => Err(format!("Failed to list the tools: {e}")),
+                        Err(_) => Err(format!(
+                            "No response within {} seconds",
+                            MCP_HEALTH_CHECK_TIMEOUT.as_secs()
+                        )),
+                    }
+                }
+                Err(e) => Err(e),
+            };
+            (name, result)
+        }))
+        .await;
+
+    for (name, result) in results {
+        record_mcp_health(&name, result);
+    }
+}
+
+/// Fill the `{fallback}` and `{context}` placeholders of a context prompt template. The context is
+/// filled last so that the placeholders in the search results are kept as they are.
+fn render_context_prompt(template: &str, context: &str, fallback: &str) -> String {
+    template
+        .replace("{fallback}", fallback)
+        .replace("{context}", context)
+}
+
+#[test]
+fn test_render_context_prompt() {
+    let prompt = render_context_prompt(
+        DEFAULT_SEARCH_CONTEXT_PROMPT_TEMPLATE,
+        "Paris is the capital of France.",
+        DEFAULT_SEARCH_FALLBACK_MESSAGE,
+    );
+    assert!(prompt.contains(&format!("respond with `{DEFAULT_SEARCH_FALLBACK_MESSAGE}`")));
+    assert!(
+        prompt.ends_with(
+            "---BEGIN CONTEXT---\n\nParis is the capital of France.\n\n---END CONTEXT---"
+        )
+    );
+
+    let prompt = render_context_prompt(
+        "Answer from the docs only, or say `{fallback}`.\n\n{context}",
+        "The docs mention {fallback}.",
+        "I don't know",
+    );
+    assert_eq!(
+        prompt,
+        "Answer from the docs only, or say `I don't know`.\n\nThe docs mention {fallback}."
+    );
+}
+
+#[test]
+fn test_is_search_mcp_server() {
+    assert!(is_search_mcp_server_among("cardea-qdrant-mcp-server", &[]));
+    assert!(!is_search_mcp_server_among("my-search-mcp-server", &[]));
+
+    // the configured names extend the built-in ones
+    let extra_names = ["my-search-mcp-server".to_string()];
+    assert!(is_search_mcp_server_among(
+        "my-search-mcp-server",
+        &extra_names
+    ));
+    assert!(is_search_mcp_server_among(
+        "cardea-qdrant-mcp-server",
+        &extra_names
+    ));
+    assert!(!is_search_mcp_server_among(
+        "cardea-weather-mcp-server",
+        &extra_names
+    ));
+}
+
+#[test]
+fn test_mcp_reconnecting_error() {
+    use axum::{http::StatusCode, response::IntoResponse};
+
+    RECONNECTING_MCP_SERVICES
+        .lock()
+        .unwrap()
+        .insert("cardea-qdrant".to_string());
+    assert!(is_reconnecting("cardea-qdrant"));
+    assert!(!is_reconnecting("cardea-weather"));
+
+    // the calls during the reconnection are told to retry after the next check
+    let response = ServerError::McpReconnecting {
+        server: "cardea-qdrant".to_string(),
+        retry_after: DEFAULT_MCP_RECONNECT_INTERVAL,
+    }
+    .into_response();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers()["retry-after"], "10");
+}
+
+#[tokio::test]
+async fn test_ensure_connected() {
+    let mut service = TestMcpServer {
+        name: "cardea-oauth".to_string(),
+        tools: vec![("echo".to_string(), Some("hello".to_string()))],
+    }
+    .connect()
+    .await;
+    service.reconnectable = false;
+    assert!(service.ensure_connected().is_ok());
+
+    // the dropped connection of a service that cannot be reconnected is not retryable
+    service.raw.cancellation_token().cancel();
+    while !service.raw.is_transport_closed() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(matches!(
+        service.ensure_connected(),
+        Err(ServerError::McpOperation(_))
+    ));
+    assert!(!is_reconnecting("cardea-oauth"));
+}
+
+#[tokio::test]
+async fn test_call_tool_timeout() {
+    let timeout = Duration::from_secs(1);
+    let service = TestMcpServer {
+        name: "cardea-stalled".to_string(),
+        tools: vec![
+            ("echo".to_string(), Some("hello".to_string())),
+            ("stall".to_string(), None),
+        ],
+    }
+    .connect()
+    .await;
+
+    let result = service
+        .call_tool_with_timeout(
+            CallToolRequestParam {
+                name: "echo".into(),
+                arguments: None,
+            },
+            None,
+            timeout,
+        )
+        .await
+        .unwrap();
+    assert_eq!(result.is_error, Some(false));
+
+    // the stalled call is an error, not a successful result
+    let result = service
+        .call_tool_with_timeout(
+            CallToolRequestParam {
+                name: "stall".into(),
+                arguments: None,
+            },
+            None,
+            timeout,
+        )
+        .await
+        .unwrap();
+    assert_eq!(result.is_error, Some(true));
+    let text = result.content.unwrap()[0].as_text().unwrap().text.clone();
+    assert!(text.contains("did not respond within"));
+}
+
+#[test]
+fn test_mcp_health() {
+    assert!(is_mcp_service_healthy("cardea-calculator"));
+    assert!(mcp_health("cardea-calculator").is_none());
+
+    record_mcp_health("cardea-calculator", Err("No response".to_string()));
+    assert!(!is_mcp_service_healthy("cardea-calculator"));
+    let health = mcp_health("cardea-calculator").unwrap();
+    assert_eq!(health.error.as_deref(), Some("No response"));
+
+    // the service is healthy again once it passes a check
+    record_mcp_health("cardea-calculator", Ok(()));
+    assert!(is_mcp_service_healthy("cardea-calculator"));
+    assert!(mcp_health("cardea-calculator").unwrap().error.is_none());
+}
+
+/// An MCP server run in the process by the tests, answering the calls of each of its tools with
+/// the given text, or never if it is `None`
+#[cfg(test)]
+#[derive(Clone)]
+pub(crate) struct TestMcpServer {
+    pub name: String,
+    pub tools: Vec<(String, Option<String>)>,
+}
+#[cfg(test)]
+impl TestMcpServer {
+    /// Start the server and connect to it, returning the service its tools are called through
+    pub(crate) async fn connect(self) -> McpService {
+        use rmcp::{model::ClientInfo, service::ServiceExt};
+
+        let (client_stream, server_stream) = tokio::io::duplex(64 * 1024);
+        let name = self.name.clone();
+        let tools = self.tools.iter().map(|(tool, _)| tool.clone()).collect();
+        tokio::spawn(async move {
+            if let Ok(server) = self.serve(server_stream).await {
+                let _ = server.waiting().await;
+            }
+        });
+
+        let raw = ClientInfo::default()
+            .into_dyn()
+            .serve(client_stream)
+            .await
+            .unwrap();
+        let mut service = McpService::new(name, raw);
+        service.tools = tools;
+        service
+    }
+}
+#[cfg(test)]
+impl rmcp::ServerHandler for TestMcpServer {
+    fn get_info(&self) -> rmcp::model::ServerInfo {
+        rmcp::model::ServerInfo {
+            capabilities: rmcp::model::ServerCapabilities::builder()
+                .enable_tools()
+                .build(),
+            server_info: rmcp::model::Implementation {
+                name: self.name.clone(),
+                version: "0.1.0".to_string(),
+            },
+            ..Default::default()
+        }
+    }
+
+    async fn list_tools(
+        &self,
+        _request: Option<rmcp::model::PaginatedRequestParam>,
+        _context: rmcp::service::RequestContext<rmcp::RoleServer>,
+    ) -> Result<rmcp::model::ListToolsResult, rmcp::ErrorData> {
+        let tools = self
+            .tools
+            .iter()
+            .map(|(tool, _)| rmcp::model::Tool::new(tool.clone(), "", serde_json::Map::new()))
+            .collect();
+        Ok(rmcp::model::ListToolsResult {
+            tools,
+            next_cursor: None,
+        })
+    }
+
+    async fn call_tool(
+        &self,
+        request: CallToolRequestParam,
+        _context: rmcp::service::RequestContext<rmcp::RoleServer>,
+    ) -> Result<CallToolResult, rmcp::ErrorData> {
+        match self.tools.iter().find(|(tool, _)| *tool == request.name) {
+            Some((_, Some(text))) => Ok(CallToolResult::success(vec![Content::text(text)])),
+            Some((_, None)) => std::future::pending().await,
+            None => Err(rmcp::ErrorData::invalid_params("Unknown tool", None)),
+        }
+    }
 }
@@ -0,0 +1,160 @@
+use std::time::Duration;
+
+use endpoints::chat::{
+    ChatCompletionRequest, ChatCompletionRequestMessage, ChatCompletionUserMessageContent,
+    ContentPart,
+};
+use reqwest::header::AUTHORIZATION;
+use tokio::select;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    client::http_client,
+    config::ModerationConfig,
+    dual_error, dual_info, dual_warn,
+    error::{ServerError, ServerResult},
+};
+
+/// Reject the chat request if the moderation endpoint flags its last user message
+pub(crate) async fn check_chat_request(
+    moderation_config: &ModerationConfig,
+    request: &ChatCompletionRequest,
+    cancel_token: &CancellationToken,
+) -> ServerResult<()> {
+    let Some(text) = last_user_message_text(request) else {
+        return Ok(());
+    };
+
+    let mut ds_request = http_client()
+        .post(&moderation_config.url)
+        .timeout(Duration::from_secs(moderation_config.timeout_secs));
+    if let Some(api_key) = &moderation_config.api_key
+        && !api_key.is_empty()
+    {
+        ds_request = ds_request.header(AUTHORIZATION, api_key);
+    }
+    let mut body = serde_json::json!({ "input": text });
+    if let Some(model) = &moderation_config.model {
+        body["model"] = model.as_str().into();
+    }
+
+    let response = select! {
+        response = ds_request.json(&body).send() => response,
+        _ = cancel_token.cancelled() => {
+            let warn_msg = "Request was cancelled by client";
+            dual_warn!("{}", warn_msg);
+            return Err(ServerError::Operation(warn_msg.to_string()));
+        }
+    };
+    let moderation = match response.and_then(|response| response.error_for_status()) {
+        Ok(response) => select! {
+            json = response.json::<serde_json::Value>() => json,
+            _ = cancel_token.cancelled() => {
+                let warn_msg = "Request was cancelled by client";
+                dual_warn!("{}", warn_msg);
+                return Err(ServerError::Operation(warn_msg.to_string()));
+            }
+        },
+        Err(e) => Err(e),
+    }
+    .map_err(|e| {
+        let err_msg = format!("Failed to moderate the chat request: {e}");
+        dual_error!("{}", err_msg);
+        ServerError::Operation(err_msg)
+    })?;
+
+    match flagged_categories(&moderation, moderation_config.threshold) {
+        Some(categories) => {
+            dual_info!(
+                "The chat request was flagged by the content moderation: {}",
+                categories.join(", ")
+            );
+            Err(ServerError::ContentFlagged { categories })
+        }
+        None => Ok(()),
+    }
+}
+
+/// The text of the last message of the request, if it is a user message
+fn last_user_message_text(request: &ChatCompletionRequest) -> Option<String> {
+    let Some(ChatCompletionRequestMessage::User(user_message)) = request.messages.last() else {
+        return None;
+    };
+
+    let text = match user_message.content() {
+        ChatCompletionUserMessageContent::Text(text) => text.clone(),
+        ChatCompletionUserMessageContent::Parts(parts) => parts
+            .iter()
+            .filter_map(|part| match part {
+                ContentPart::Text(text_part) => Some(text_part.text()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+    };
+
+    (!text.is_empty()).then_some(text)
+}
+
+/// The categories the moderation result is flagged for, or `None` if it is not flagged
+///
+/// A category is flagged if its score reaches the threshold. If the endpoint reports no scores,
+/// its own `flagged` verdict and categories are taken instead.
+fn flagged_categories(moderation: &serde_json::Value, threshold: f64) -> Option<Vec<String>> {
+    let mut categories = Vec::new();
+    let mut flagged = false;
+    for result in moderation["results"].as_array().into_iter().flatten() {
+        match result["category_scores"].as_object() {
+            Some(scores) => categories.extend(
+                scores
+                    .iter()
+                    .filter(|(_, score)| score.as_f64().is_some_and(|score| score >= threshold))
+                    .map(|(category, _)| category.clone()),
+            ),
+            None if result["flagged"] == true => {
+                flagged = true;
+                categories.extend(
+                    result["categories"]
+                        .as_object()
+                        .into_iter()
+                        .flatten()
+                        .filter(|(_, is_flagged)| **is_flagged == true)
+                        .map(|(category, _)| category.clone()),
+                );
+            }
+            None => {}
+        }
+    }
+    categories.sort();
+    categories.dedup();
+
+    (flagged || !categories.is_empty()).then_some(categories)
+}
+
+#[test]
+fn test_flagged_categories() {
+    let moderation = serde_json::json!({
+        "results": [{
+            "flagged": true,
+            "categories": {"violence": true, "hate": false},
+            "category_scores": {"violence": 0.7, "hate": 0.2, "harassment": 0.5},
+        }]
+    });
+    assert_eq!(
+        flagged_categories(&moderation, 0.5),
+        Some(vec!["harassment".to_string(), "violence".to_string()])
+    );
+    // the scores take precedence over the verdict of the endpoint
+    assert_eq!(flagged_categories(&moderation, 0.8), None);
+
+    // the verdict of the endpoint is taken without scores
+    let moderation = serde_json::json!({
+        "results": [{"flagged": true, "categories": {"violence": true, "hate": false}}]
+    });
+    assert_eq!(
+        flagged_categories(&moderation, 0.5),
+        Some(vec!["violence".to_string()])
+    );
+    let moderation = serde_json::json!({"results": [{"flagged": false}]});
+    assert_eq!(flagged_categories(&moderation, 0.5), None);
+}
@@ -0,0 +1,123 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{
+    dual_warn,
+    error::{ServerError, ServerResult},
+    server::TargetServerInfo,
+};
+
+/// The bounded FIFO queue of the requests waiting for a permit of a server taking as many
+/// requests as it is allowed to
+#[derive(Debug)]
+pub(crate) struct RequestQueue {
+    max_length: usize,
+    max_wait: Duration,
+    depth: AtomicUsize,
+}
+impl RequestQueue {
+    pub(crate) fn new(max_length: usize, max_wait: Duration) -> Self {
+        Self {
+            max_length,
+            max_wait,
+            depth: AtomicUsize::new(0),
+        }
+    }
+
+    /// The number of the requests waiting in the queue
+    pub(crate) fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    /// Acquire a permit of the concurrent requests to the server, waiting in the queue if the
+    /// server is saturated. The permit is released once dropped. Returns `None` if the concurrent
+    /// requests to the server are not limited.
+    pub(crate) async fn acquire(
+        &self,
+        target_server: &TargetServerInfo,
+    ) -> ServerResult<Option<OwnedSemaphorePermit>> {
+        match &target_server.permits {
+            Some(permits) => self.acquire_permit(permits.clone()).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    async fn acquire_permit(&self, permits: Arc<Semaphore>) -> ServerResult<OwnedSemaphorePermit> {
+        if let Ok(permit) = permits.clone().try_acquire_owned() {
+            return Ok(permit);
+        }
+
+        // join the queue unless it is full
+        let retry_after = self.max_wait.as_secs().max(1);
+        if self
+            .depth
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |depth| {
+                (depth < self.max_length).then_some(depth + 1)
+            })
+            .is_err()
+        {
+            let err = ServerError::QueueFull { retry_after };
+            dual_warn!("{}", err);
+            return Err(err);
+        }
+        // leave the queue once served, timed out or cancelled
+        let _in_queue = QueueSlot(&self.depth);
+
+        // the waiters of a semaphore are served first in, first out
+        match tokio::time::timeout(self.max_wait, permits.acquire_owned()).await {
+            Ok(Ok(permit)) => Ok(permit),
+            _ => {
+                let err = ServerError::QueueTimeout { retry_after };
+                dual_warn!("{}", err);
+                Err(err)
+            }
+        }
+    }
+}
+
+/// A request waiting in the queue, which leaves the queue once dropped
+struct QueueSlot<'a>(&'a AtomicUsize);
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[tokio::test]
+async fn test_request_queue() {
+    let queue = Arc::new(RequestQueue::new(1, Duration::from_millis(200)));
+    let permits = Arc::new(Semaphore::new(1));
+
+    // the permit is taken at once if available
+    let permit = queue.acquire_permit(permits.clone()).await.unwrap();
+    assert_eq!(queue.depth(), 0);
+
+    // the next request waits in the queue, and the one after it is rejected
+    let waiting = tokio::spawn({
+        let queue = queue.clone();
+        let permits = permits.clone();
+        async move { queue.acquire_permit(permits).await.map(|_| ()) }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(queue.depth(), 1);
+    let err = queue.acquire_permit(permits.clone()).await.unwrap_err();
+    assert!(matches!(err, ServerError::QueueFull { retry_after: 1 }));
+
+    // the waiting request is served once the permit is released
+    drop(permit);
+    assert!(waiting.await.unwrap().is_ok());
+    assert_eq!(queue.depth(), 0);
+
+    // the request waiting too long times out
+    let _permit = queue.acquire_permit(permits.clone()).await.unwrap();
+    let err = queue.acquire_permit(permits).await.unwrap_err();
+    assert!(matches!(err, ServerError::QueueTimeout { retry_after: 1 }));
+    assert_eq!(queue.depth(), 0);
+}
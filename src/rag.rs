@@ -1,7 +1,7 @@
 use std::{
     collections::{HashMap, HashSet, hash_map::DefaultHasher},
     hash::{Hash, Hasher},
-    sync::Arc,
+    sync::{Arc, Mutex},
 };
 
 use axum::{
@@ -9,10 +9,6 @@ use axum::{
     extract::{Extension, State},
     http::HeaderMap,
 };
-use cardea_elastic_mcp_common::SearchResponse;
-use cardea_kwsearch_mcp_common::{KwSearchHit, SearchDocumentsResponse};
-use cardea_qdrant_mcp_common::{ScoredPoint, SearchPointsResponse};
-use cardea_tidb_mcp_common::TidbSearchResponse;
 use chat_prompts::{MergeRagContext, MergeRagContextPolicy, error as ChatPromptsError};
 use endpoints::{
     chat::{
@@ -22,39 +18,43 @@ use endpoints::{
     embeddings::{EmbeddingRequest, EmbeddingsResponse, InputText},
     rag::vector_search::{DataFrom, RagScoredPoint, RetrieveObject},
 };
-use rmcp::model::CallToolRequestParam;
+use once_cell::sync::OnceCell;
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use rmcp::model::{CallToolRequestParam, CallToolResult};
 use serde_json::Value;
-use text_splitter::{MarkdownSplitter, TextSplitter};
+use tokio::select;
 use tokio_util::sync::CancellationToken;
 
 use crate::{
-    AppState, dual_debug, dual_error, dual_info, dual_warn,
+    AppState,
+    client::http_client,
+    config::RagEmbeddingsServerConfig,
+    dual_debug, dual_error, dual_info, dual_warn,
     error::{ServerError, ServerResult},
+    handlers,
+    info::ModelConfig,
     mcp::MCP_SERVICES,
     server::{RoutingPolicy, ServerKind},
 };
 
 const DEFAULT_FILTER_WEIGHTED_ALPHA: f64 = 0.5;
 
+/// Answer the chat request with the context retrieved by the search MCP servers. The allowed MCP
+/// tools of the caller and the MCP tools added to the request are passed on to the chat.
 pub async fn chat(
     State(state): State<Arc<AppState>>,
     Extension(cancel_token): Extension<CancellationToken>,
     headers: HeaderMap,
     Json(mut chat_request): Json<ChatCompletionRequest>,
-    request_id: impl AsRef<str>,
+    allowed_mcp_tools: Option<&[String]>,
+    injected_mcp_tools: &handlers::InjectedMcpTools,
 ) -> ServerResult<axum::response::Response> {
-    let request_id = request_id.as_ref();
-
     // * filter parameters
     let weighted_alpha = match chat_request.weighted_alpha {
         Some(weighted_alpha) => weighted_alpha,
         None => DEFAULT_FILTER_WEIGHTED_ALPHA,
     };
-    dual_debug!(
-        "weighted_alpha: {} - request_id: {}",
-        weighted_alpha,
-        request_id
-    );
+    dual_debug!("weighted_alpha: {}", weighted_alpha);
 
     // Get the last user message text
     let query_text = match chat_request.messages.last() {
@@ -62,64 +62,43 @@ pub async fn chat(
             ChatCompletionUserMessageContent::Text(text) => text.clone(),
             _ => {
                 let err_msg = "The last message in the request is not a text-only user message";
-                dual_error!("{} - request_id: {}", err_msg, request_id);
+                dual_error!("{}", err_msg);
                 return Err(ServerError::BadRequest(err_msg.to_string()));
             }
         },
         _ => {
             let err_msg = "The last message in the request is not a user message";
-            dual_error!("{} - request_id: {}", err_msg, request_id);
+            dual_error!("{}", err_msg);
             return Err(ServerError::BadRequest(err_msg.to_string()));
         }
     };
 
     // vector search
-    dual_info!("Performing vector search - request_id: {}", request_id);
+    dual_info!("Performing vector search");
     let vector_hits = perform_vector_search(
         State(state.clone()),
         Extension(cancel_token.clone()),
         &headers,
         &chat_request,
-        request_id,
     )
     .await?;
     if !vector_hits.is_empty() {
         dual_info!(
-            "Retrieved {} points from the vector search - request_id: {}",
-            vector_hits.len(),
-            request_id
+            "Retrieved {} points from the vector search",
+            vector_hits.len()
         );
     } else {
-        dual_info!(
-            "Ignore vector search: No vector mcp server available - request_id: {}",
-            request_id
-        );
+        dual_info!("Ignore vector search: No vector mcp server available");
     }
 
     // keyword search
-    dual_info!(
-        "Performing agentic keyword search - request_id: {}",
-        request_id
-    );
-    let kw_hits = perform_keyword_search(
-        State(state.clone()),
-        &query_text,
-        &chat_request,
-        &headers,
-        &request_id,
-    )
-    .await?;
+    dual_info!("Performing agentic keyword search");
+    let kw_hits =
+        perform_keyword_search(State(state.clone()), &query_text, &chat_request, &headers).await?;
     if !kw_hits.is_empty() {
-        dual_info!(
-            "Retrieved {} hits from the keyword search - request_id: {}",
-            kw_hits.len(),
-            request_id
-        );
+        dual_info!("Retrieved {} hits from the keyword search", kw_hits.len());
     } else {
-        dual_info!(
-            "Ignore keyword search: No keyword search mcp server available - request_id: {}",
-            request_id
-        );
+        dual_info!("Ignore keyword search: No keyword search mcp server available");
     }
 
     // * rerank
@@ -137,11 +116,7 @@ pub async fn chat(
             // normalize the kw_scores
             scores_kwsearch_hits = min_max_normalize(&scores_kwsearch_hits);
 
-            dual_debug!(
-                "kw_scores: {:#?} - request_id: {}",
-                &scores_kwsearch_hits,
-                request_id
-            );
+            dual_debug!("kw_scores: {:#?}", &scores_kwsearch_hits);
         }
 
         // create a hash map from retrieve_object_vec: key is the hash value of the source of the point, value is the point
@@ -159,19 +134,12 @@ pub async fn chat(
                 // normalize the em_scores
                 scores_vector_search_hits = min_max_normalize(&scores_vector_search_hits);
 
-                dual_debug!(
-                    "em_scores: {:#?} - request_id: {}",
-                    &scores_vector_search_hits,
-                    request_id
-                );
+                dual_debug!("em_scores: {:#?}", &scores_vector_search_hits);
             }
         }
 
         // fuse the two hash maps
-        dual_info!(
-            "Fusing vector and keyword search results - request_id: {}",
-            request_id
-        );
+        dual_info!("Fusing vector and keyword search results");
         let fused_scores = weighted_fusion(
             scores_kwsearch_hits,
             scores_vector_search_hits,
@@ -179,17 +147,10 @@ pub async fn chat(
         );
 
         if !fused_scores.is_empty() {
-            dual_debug!(
-                "final_scores: {:#?} - request_id: {}",
-                &fused_scores,
-                request_id
-            );
+            dual_debug!("final_scores: {:#?}", &fused_scores);
 
             // Sort by score from high to low
-            dual_info!(
-                "Re-ranking the fused search results - request_id: {}",
-                request_id
-            );
+            dual_info!("Re-ranking the fused search results");
             let mut final_ranking: Vec<(u64, f64)> = fused_scores.into_iter().collect();
             final_ranking.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
             // if final_ranking.len() > filter_limit as usize {
@@ -221,22 +182,18 @@ pub async fn chat(
 
             vec![retrieve_object]
         } else {
-            dual_warn!("No point retrieved - request_id: {}", request_id);
+            dual_warn!("No point retrieved");
 
             vec![]
         }
     };
 
-    dual_debug!(
-        "Retrieved {} points in total - request_id: {}",
-        hits.len(),
-        request_id
-    );
+    dual_debug!("Retrieved {} points in total", hits.len());
 
     // * generate context
-    dual_info!("Generating context - request_id: {}", request_id);
-    let mut context = String::new();
-    if !hits.is_empty() {
+    dual_info!("Generating context");
+    let context = if !hits.is_empty() {
+        let mut retrieved_points = Vec::new();
         for retrieve_object in hits.iter() {
             match retrieve_object.points.as_ref() {
                 Some(scored_points) => {
@@ -245,97 +202,105 @@ pub async fn chat(
                             for (idx, point) in scored_points.iter().enumerate() {
                                 // log
                                 dual_debug!(
-                                    "request_id: {} - Point-{}, score: {}, source: {}",
-                                    request_id,
+                                    "Point-{}, score: {}, source: {}",
                                     idx,
                                     point.score,
                                     &point.source
                                 );
 
-                                context.push_str(&point.source);
-                                context.push_str("\n\n");
+                                retrieved_points.push(point);
                             }
                         }
                         true => {
                             // log
-                            dual_warn!(
-                                "No search results used as context - request_id: {}",
-                                request_id
-                            );
+                            dual_warn!("No search results used as context");
                         }
                     }
                 }
                 None => {
                     // log
-                    dual_warn!(
-                        "No search results used as context - request_id: {}",
-                        request_id
-                    );
+                    dual_warn!("No search results used as context");
                 }
             }
         }
+
+        // keep the context within the context window of the model
+        let max_context_chars = state
+            .config
+            .read()
+            .await
+            .rag
+            .as_ref()
+            .and_then(|rag_config| rag_config.max_context_chars);
+        let (context, truncated_chars) = assemble_context(&retrieved_points, max_context_chars);
+        if truncated_chars > 0 {
+            dual_warn!(
+                "Truncated {} chars of the retrieved context to fit in {} chars",
+                truncated_chars,
+                max_context_chars.unwrap_or_default()
+            );
+        }
+        context
     } else {
-        context = "No context retrieved".to_string();
-    }
-    dual_debug!("request_id: {} - context:\n{}", request_id, context);
+        "No context retrieved".to_string()
+    };
+    dual_debug!("context:\n{}", context);
 
     // * merge context into chat request
-    dual_info!(
-        "Merging context into chat request - request_id: {}",
-        request_id
-    );
+    dual_info!("Merging context into chat request");
     if chat_request.messages.is_empty() {
         let err_msg = "Found empty chat messages";
 
         // log
-        dual_error!("{} - request_id: {}", err_msg, request_id);
+        dual_error!("{}", err_msg);
 
         return Err(ServerError::BadRequest(err_msg.to_string()));
     }
-    // get the prompt template from the chat server
-    let prompt_template = {
+    // get whether the prompt template of the chat server has a system prompt
+    let has_system_prompt = {
         let server_info = state.server_info.read().await;
         let chat_server = server_info
             .servers
             .iter()
             .find(|(_server_id, server)| server.chat_model.is_some());
         match chat_server {
-            Some((_server_id, chat_server)) => {
+            Some((server_id, chat_server)) => {
                 let chat_model = chat_server.chat_model.as_ref().unwrap();
-                chat_model.prompt_template.unwrap()
+                has_system_prompt(server_id, chat_model)
             }
             None => {
                 let err_msg = "No chat server available";
-                dual_error!("{} - request_id: {}", err_msg, request_id);
+                dual_error!("{}", err_msg);
                 return Err(ServerError::Operation(err_msg.to_string()));
             }
         }
     };
     // get the rag policy
-    let (rag_policy, rag_prompt) = {
-        let config = state.config.read().await;
-        (
-            config.rag.as_ref().unwrap().policy,
-            config.rag.as_ref().unwrap().prompt.clone(),
-        )
+    let (rag_policy, rag_prompt) = match state.config.read().await.rag.as_ref() {
+        Some(rag_config) => (rag_config.policy, rag_config.prompt.clone()),
+        None => {
+            let err_msg = "RAG is not configured";
+            dual_error!("{}", err_msg);
+            return Err(ServerError::Operation(err_msg.to_string()));
+        }
     };
     if let Err(e) = RagPromptBuilder::build(
         &mut chat_request.messages,
         &[context],
-        prompt_template.has_system_prompt(),
+        has_system_prompt,
         rag_policy,
         rag_prompt,
     ) {
         let err_msg = e.to_string();
 
         // log
-        dual_error!("{} - request_id: {}", err_msg, request_id);
+        dual_error!("{}", err_msg);
 
         return Err(ServerError::Operation(err_msg));
     }
 
     // * perform chat completion
-    dual_info!("Performing chat completion - request_id: {}", request_id);
+    dual_info!("Performing chat completion");
     if chat_request.tool_choice.is_some() {
         chat_request.tool_choice = None;
 
@@ -348,7 +313,8 @@ pub async fn chat(
         Extension(cancel_token.clone()),
         headers,
         Json(chat_request),
-        &request_id,
+        allowed_mcp_tools,
+        injected_mcp_tools,
     )
     .await
 }
@@ -358,16 +324,13 @@ async fn perform_keyword_search(
     query: impl AsRef<str>,
     chat_request: &ChatCompletionRequest,
     headers: &HeaderMap,
-    request_id: impl AsRef<str>,
 ) -> ServerResult<Vec<KwSearchHit>> {
-    let request_id = request_id.as_ref();
-
     // get the user id from the request
     let user_id = match chat_request.user.as_ref() {
         Some(user_id) => user_id,
         None => {
             let err_msg = "User ID is not found in the request";
-            dual_error!("{} - request_id: {}", err_msg, request_id);
+            dual_error!("{}", err_msg);
             return Err(ServerError::Operation(err_msg.to_string()));
         }
     };
@@ -389,42 +352,27 @@ async fn perform_keyword_search(
         .with_user(user_id)
         .build();
     dual_debug!(
-        "request for getting keywords:\n{} - request_id: {}",
-        serde_json::to_string_pretty(&request).unwrap(),
-        request_id
+        "request for getting keywords:\n{}",
+        serde_json::to_string_pretty(&request).unwrap()
     );
 
     // get the chat server
     let target_server_info = {
         let servers = state.server_group.read().await;
-        let chat_servers = match servers.get(&ServerKind::chat) {
-            Some(servers) => servers,
-            None => {
-                let err_msg = "No chat server available";
-                dual_error!("{} - request_id: {}", err_msg, request_id);
-                return Err(ServerError::Operation(err_msg.to_string()));
-            }
-        };
+        let chat_servers = handlers::server_group_of(&servers, ServerKind::chat).await?;
 
         match chat_servers.next().await {
             Ok(target_server_info) => target_server_info,
             Err(e) => {
                 let err_msg = format!("Failed to get the chat server: {e}");
-                dual_error!("{} - request_id: {}", err_msg, request_id);
+                dual_error!("{}", err_msg);
                 return Err(ServerError::Operation(err_msg));
             }
         }
     };
 
-    let chat_service_url = format!(
-        "{}/chat/completions",
-        target_server_info.url.trim_end_matches('/')
-    );
-    dual_debug!(
-        "Forward the chat request to {} - request_id: {}",
-        chat_service_url,
-        request_id
-    );
+    let chat_service_url = target_server_info.chat_completions_url(request.model.as_deref())?;
+    dual_debug!("Forward the chat request to {}", chat_service_url);
 
     // Create a request client
     let ds_response = if headers.contains_key("authorization") {
@@ -435,8 +383,7 @@ async fn perform_keyword_search(
             .unwrap()
             .to_string();
 
-        reqwest::Client::new()
-            .post(&chat_service_url)
+        handlers::forward_tracing_headers(http_client().post(&chat_service_url), headers)
             .header(reqwest::header::CONTENT_TYPE, "application/json")
             .header(reqwest::header::AUTHORIZATION, authorization)
             .json(&request)
@@ -444,19 +391,18 @@ async fn perform_keyword_search(
             .await
             .map_err(|e| {
                 let err_msg = format!("Failed to send the chat request: {e}");
-                dual_error!("{} - request_id: {}", err_msg, request_id);
+                dual_error!("{}", err_msg);
                 ServerError::Operation(err_msg)
             })?
     } else {
-        reqwest::Client::new()
-            .post(&chat_service_url)
+        handlers::forward_tracing_headers(http_client().post(&chat_service_url), headers)
             .header(reqwest::header::CONTENT_TYPE, "application/json")
             .json(&request)
             .send()
             .await
             .map_err(|e| {
                 let err_msg = format!("Failed to send the chat request: {e}");
-                dual_error!("{} - request_id: {}", err_msg, request_id);
+                dual_error!("{}", err_msg);
                 ServerError::Operation(err_msg)
             })?
     };
@@ -464,25 +410,20 @@ async fn perform_keyword_search(
     let status = ds_response.status();
     if !status.is_success() {
         let err_msg = format!("Failed to get the response from the chat server: {status}");
-        dual_error!("{} - request_id: {}", err_msg, request_id);
+        dual_error!("{}", err_msg);
         return Ok(vec![]);
     }
 
-    let headers = ds_response.headers().clone();
     // check if the response has a header with the key "requires-tool-call"
-    if let Some(value) = headers.get("requires-tool-call") {
+    if let Some(value) = ds_response.headers().get("requires-tool-call") {
         // convert the value to a boolean
         let requires_tool_call: bool = value.to_str().unwrap().parse().unwrap();
-        dual_debug!(
-            "requires_tool_call: {} - request_id: {}",
-            requires_tool_call,
-            request_id
-        );
+        dual_debug!("requires_tool_call: {}", requires_tool_call);
 
         if requires_tool_call {
             let bytes = ds_response.bytes().await.map_err(|e| {
                 let err_msg = format!("Failed to get the response bytes: {e}");
-                dual_error!("{} - request_id: {}", err_msg, request_id);
+                dual_error!("{}", err_msg);
                 ServerError::Operation(err_msg)
             })?;
 
@@ -490,24 +431,23 @@ async fn perform_keyword_search(
                 Ok(completion) => completion,
                 Err(e) => {
                     let err_msg = format!("Failed to parse the response: {e}");
-                    dual_error!("{} - request_id: {}", err_msg, request_id);
+                    dual_error!("{}", err_msg);
                     return Err(ServerError::Operation(err_msg));
                 }
             };
 
             let assistant_message = &chat_completion.choices[0].message;
 
-            match call_keyword_search_service(assistant_message.tool_calls.as_slice(), &request_id)
+            match call_keyword_search_service(assistant_message.tool_calls.as_slice(), headers)
                 .await
             {
                 Ok(kw_hits) => return Ok(kw_hits),
                 Err(ServerError::McpNotFoundClient) => {
-                    dual_warn!("Not found MCP server - request_id: {}", request_id);
+                    dual_warn!("Not found MCP server");
                     return Ok(vec![]);
                 }
                 Err(e) => {
-                    let err_msg =
-                        format!("Failed to call MCP server: {e} - request_id: {request_id}");
+                    let err_msg = format!("Failed to call MCP server: {e}");
                     dual_error!("{}", err_msg);
                     return Err(ServerError::Operation(err_msg));
                 }
@@ -523,13 +463,11 @@ async fn perform_vector_search(
     Extension(cancel_token): Extension<CancellationToken>,
     headers: &HeaderMap,
     chat_request: &ChatCompletionRequest,
-    request_id: &str,
 ) -> ServerResult<Vec<RetrieveObject>> {
     retrieve_context_with_multiple_qdrant_configs(
         State(state),
         Extension(cancel_token),
         headers,
-        request_id,
         chat_request,
     )
     .await
@@ -539,7 +477,6 @@ async fn retrieve_context_with_multiple_qdrant_configs(
     State(state): State<Arc<AppState>>,
     Extension(cancel_token): Extension<CancellationToken>,
     headers: &HeaderMap,
-    request_id: impl AsRef<str>,
     chat_request: &ChatCompletionRequest,
 ) -> ServerResult<Vec<RetrieveObject>> {
     let mut retrieve_object_vec: Vec<RetrieveObject> = Vec::new();
@@ -549,7 +486,6 @@ async fn retrieve_context_with_multiple_qdrant_configs(
         State(state.clone()),
         Extension(cancel_token.clone()),
         headers,
-        request_id.as_ref(),
         chat_request,
     )
     .await?;
@@ -575,11 +511,7 @@ async fn retrieve_context_with_multiple_qdrant_configs(
                 points.remove(*idx);
             }
 
-            dual_info!(
-                "Removed {} duplicated vector search results - request_id: {}",
-                num,
-                request_id.as_ref()
-            );
+            dual_info!("Removed {} duplicated vector search results", num);
         }
 
         if !points.is_empty() {
@@ -594,17 +526,14 @@ async fn retrieve_context_with_single_qdrant_config(
     State(state): State<Arc<AppState>>,
     Extension(cancel_token): Extension<CancellationToken>,
     headers: &HeaderMap,
-    request_id: impl AsRef<str>,
     chat_request: &ChatCompletionRequest,
 ) -> ServerResult<RetrieveObject> {
-    let request_id = request_id.as_ref();
-
     // get the user id from the request
     let user_id = match chat_request.user.as_ref() {
         Some(user_id) => user_id,
         None => {
             let err_msg = "User ID is not found in the request";
-            dual_error!("{} - request_id: {}", err_msg, request_id);
+            dual_error!("{}", err_msg);
             return Err(ServerError::Operation(err_msg.to_string()));
         }
     };
@@ -616,27 +545,22 @@ async fn retrieve_context_with_single_qdrant_config(
         .await
         .rag
         .as_ref()
-        .unwrap()
-        .context_window;
+        .map(|rag_config| rag_config.context_window);
 
     // get context_window: chat_request.context_window prioritized CONTEXT_WINDOW
     let context_window = chat_request
         .context_window
-        .or(Some(config_ctx_window))
+        .or(config_ctx_window)
         .unwrap_or(1);
-    dual_info!(
-        "Context window: {} - request_id: {}",
-        context_window,
-        request_id
-    );
+    dual_info!("Context window: {}", context_window);
 
     // compute embeddings for user query by embedding server
-    let embedding_response = match chat_request.messages.is_empty() {
+    let query_embedding: Vec<f64> = match chat_request.messages.is_empty() {
         true => {
             let err_msg = "Found empty chat messages";
 
             // log
-            dual_error!("{} - request_id: {}", err_msg, request_id);
+            dual_error!("{}", err_msg);
 
             return Err(ServerError::BadRequest(err_msg.to_string()));
         }
@@ -670,69 +594,73 @@ async fn retrieve_context_with_single_qdrant_config(
                 let error_msg = "No user messages found.";
 
                 // log
-                dual_error!("{} - request_id: {}", error_msg, request_id);
+                dual_error!("{}", error_msg);
 
                 return Err(ServerError::BadRequest(error_msg.to_string()));
             };
 
-            dual_info!(
-                "Computing embeddings for user query: {} - request_id: {}",
-                query_text,
-                request_id
-            );
-            // create a embedding request
-            let embedding_request = EmbeddingRequest {
-                model: None,
-                input: InputText::String(query_text),
-                encoding_format: None,
-                user: chat_request.user.clone(),
-                vdb_server_url: None,
-                vdb_collection_name: None,
-                vdb_api_key: None,
-            };
-
-            // compute embeddings for query
-            let response = crate::handlers::embeddings_handler(
-                State(state.clone()),
-                Extension(cancel_token.clone()),
-                headers.clone(),
-                Json(embedding_request),
-            )
-            .await?;
-
-            // parse the response
-            let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
-                .await
-                .map_err(|e| {
-                    let err_msg = format!("Failed to parse embeddings response: {e}");
-
-                    // log
-                    dual_error!("{} - request_id: {}", err_msg, request_id);
-
-                    ServerError::Operation(err_msg)
-                })?;
-
-            // parse the response
-            serde_json::from_slice::<EmbeddingsResponse>(&bytes).map_err(|e| {
-                let err_msg = format!("Failed to parse embeddings response: {e}");
-
-                // log
-                dual_error!("{} - request_id: {}", err_msg, request_id);
-
-                ServerError::Operation(err_msg)
-            })?
-        }
-    };
+            // get the model matching the vector dimension of the collection if configured
+            let (embedding_model, embedding_cache_capacity) =
+                match state.config.read().await.rag.as_ref() {
+                    Some(rag_config) => (
+                        rag_config.embedding_model.clone(),
+                        rag_config.embedding_cache_capacity,
+                    ),
+                    None => (None, 0),
+                };
+            let embedding_cache = QUERY_EMBEDDING_CACHE
+                .get_or_init(|| QueryEmbeddingCache::new(embedding_cache_capacity));
+
+            match embedding_cache.get(embedding_model.as_deref(), &query_text) {
+                Some(query_embedding) => {
+                    dual_info!(
+                        "Reused the cached embeddings for user query: {}",
+                        query_text
+                    );
+                    query_embedding
+                }
+                None => {
+                    dual_info!("Computing embeddings for user query: {}", query_text);
+                    // create a embedding request
+                    let embedding_request = EmbeddingRequest {
+                        model: embedding_model.clone(),
+                        input: InputText::String(query_text.clone()),
+                        encoding_format: None,
+                        user: chat_request.user.clone(),
+                        vdb_server_url: None,
+                        vdb_collection_name: None,
+                        vdb_api_key: None,
+                    };
+
+                    // compute embeddings for query
+                    let embedding_response = compute_query_embeddings(
+                        State(state.clone()),
+                        Extension(cancel_token.clone()),
+                        headers,
+                        embedding_request,
+                    )
+                    .await?;
+
+                    let query_embedding = match embedding_response.data.first() {
+                        Some(embedding) => embedding.embedding.to_vec(),
+                        None => {
+                            let err_msg = "No embeddings returned";
 
-    let query_embedding: Vec<f64> = match embedding_response.data.first() {
-        Some(embedding) => embedding.embedding.to_vec(),
-        None => {
-            let err_msg = "No embeddings returned";
+                            // log
+                            dual_error!("{}", err_msg);
 
-            // log
-            dual_error!("{} - request_id: {}", err_msg, request_id);
+                            return Err(ServerError::Operation(err_msg.to_string()));
+                        }
+                    };
+                    embedding_cache.insert(
+                        embedding_model.as_deref(),
+                        &query_text,
+                        query_embedding.clone(),
+                    );
 
-            return Err(ServerError::Operation(err_msg.to_string()));
+                    query_embedding
+                }
+            }
         }
     };
 
@@ -752,42 +680,27 @@ async fn retrieve_context_with_single_qdrant_config(
             .with_user(user_id)
             .build();
         dual_debug!(
-            "request for getting keywords:\n{} - request_id: {}",
-            serde_json::to_string_pretty(&request).unwrap(),
-            request_id
+            "request for getting keywords:\n{}",
+            serde_json::to_string_pretty(&request).unwrap()
         );
 
         // get the chat server
         let target_server_info = {
             let servers = state.server_group.read().await;
-            let chat_servers = match servers.get(&ServerKind::chat) {
-                Some(servers) => servers,
-                None => {
-                    let err_msg = "No chat server available";
-                    dual_error!("{} - request_id: {}", err_msg, request_id);
-                    return Err(ServerError::Operation(err_msg.to_string()));
-                }
-            };
+            let chat_servers = handlers::server_group_of(&servers, ServerKind::chat).await?;
 
             match chat_servers.next().await {
                 Ok(target_server_info) => target_server_info,
                 Err(e) => {
                     let err_msg = format!("Failed to get the chat server: {e}");
-                    dual_error!("{} - request_id: {}", err_msg, request_id);
+                    dual_error!("{}", err_msg);
                     return Err(ServerError::Operation(err_msg));
                 }
             }
         };
 
-        let chat_service_url = format!(
-            "{}/chat/completions",
-            target_server_info.url.trim_end_matches('/')
-        );
-        dual_debug!(
-            "Forward the chat request to {} - request_id: {}",
-            chat_service_url,
-            request_id
-        );
+        let chat_service_url = target_server_info.chat_completions_url(request.model.as_deref())?;
+        dual_debug!("Forward the chat request to {}", chat_service_url);
 
         // generate tool call by chat server
         let ds_response = if headers.contains_key("authorization") {
@@ -799,8 +712,7 @@ async fn retrieve_context_with_single_qdrant_config(
                 .to_string();
 
             // Create a request client
-            reqwest::Client::new()
-                .post(&chat_service_url)
+            handlers::forward_tracing_headers(http_client().post(&chat_service_url), headers)
                 .header(reqwest::header::CONTENT_TYPE, "application/json")
                 .header(reqwest::header::AUTHORIZATION, authorization)
                 .json(&request)
@@ -808,20 +720,19 @@ async fn retrieve_context_with_single_qdrant_config(
                 .await
                 .map_err(|e| {
                     let err_msg = format!("Failed to send the chat request: {e}");
-                    dual_error!("{} - request_id: {}", err_msg, request_id);
+                    dual_error!("{}", err_msg);
                     ServerError::Operation(err_msg)
                 })?
         } else {
             // Create a request client
-            reqwest::Client::new()
-                .post(&chat_service_url)
+            handlers::forward_tracing_headers(http_client().post(&chat_service_url), headers)
                 .header(reqwest::header::CONTENT_TYPE, "application/json")
                 .json(&request)
                 .send()
                 .await
                 .map_err(|e| {
                     let err_msg = format!("Failed to send the chat request: {e}");
-                    dual_error!("{} - request_id: {}", err_msg, request_id);
+                    dual_error!("{}", err_msg);
                     ServerError::Operation(err_msg)
                 })?
         };
@@ -830,7 +741,7 @@ async fn retrieve_context_with_single_qdrant_config(
         match status.is_success() {
             false => {
                 let err_msg = format!("Failed to get the response: {status}");
-                dual_error!("{} - request_id: {}", err_msg, request_id);
+                dual_error!("{}", err_msg);
                 RetrieveObject {
                     points: Some(Vec::new()),
                     limit: 0,
@@ -848,16 +759,12 @@ async fn retrieve_context_with_single_qdrant_config(
                 if let Some(value) = ds_response.headers().get("requires-tool-call") {
                     // convert the value to a boolean
                     let requires_tool_call: bool = value.to_str().unwrap().parse().unwrap();
-                    dual_debug!(
-                        "requires_tool_call: {} - request_id: {}",
-                        requires_tool_call,
-                        request_id
-                    );
+                    dual_debug!("requires_tool_call: {}", requires_tool_call);
 
                     if requires_tool_call {
                         let bytes = ds_response.bytes().await.map_err(|e| {
                             let err_msg = format!("Failed to get the response bytes: {e}");
-                            dual_error!("{} - request_id: {}", err_msg, request_id);
+                            dual_error!("{}", err_msg);
                             ServerError::Operation(err_msg)
                         })?;
 
@@ -866,7 +773,7 @@ async fn retrieve_context_with_single_qdrant_config(
                                 Ok(completion) => completion,
                                 Err(e) => {
                                     let err_msg = format!("Failed to parse the response: {e}");
-                                    dual_error!("{} - request_id: {}", err_msg, request_id);
+                                    dual_error!("{}", err_msg);
                                     return Err(ServerError::Operation(err_msg));
                                 }
                             };
@@ -876,7 +783,7 @@ async fn retrieve_context_with_single_qdrant_config(
                         match call_vector_search_service(
                             assistant_message.tool_calls.as_slice(),
                             query_embedding.as_slice(),
-                            &request_id,
+                            headers,
                         )
                         .await
                         {
@@ -885,12 +792,10 @@ async fn retrieve_context_with_single_qdrant_config(
                             }
                             Err(ServerError::McpNotFoundClient) => {
                                 let err_msg = "Not found MCP server for vector search";
-                                dual_warn!("{} - request_id: {}", err_msg, request_id);
+                                dual_warn!("{}", err_msg);
                             }
                             Err(e) => {
-                                let err_msg = format!(
-                                    "Failed to call MCP server: {e} - request_id: {request_id}"
-                                );
+                                let err_msg = format!("Failed to call MCP server: {e}");
                                 dual_error!("{}", err_msg);
                                 return Err(ServerError::Operation(err_msg));
                             }
@@ -904,14 +809,64 @@ async fn retrieve_context_with_single_qdrant_config(
     };
 
     dual_debug!(
-        "Got {} point(s) by vector search - request_id: {}",
-        retrieve_object.points.as_ref().unwrap().len(),
-        request_id
+        "Got {} point(s) by vector search",
+        retrieve_object.points.as_ref().unwrap().len()
     );
 
     Ok(retrieve_object)
 }
 
+/// Assemble the context from the retrieved points in their order. If the context exceeds
+/// `max_context_chars`, the lowest-scored points are dropped first, and the highest-scored point is
+/// cut if it does not fit alone. Returns the context and the number of truncated chars.
+fn assemble_context(
+    points: &[&RagScoredPoint],
+    max_context_chars: Option<usize>,
+) -> (String, usize) {
+    let chunk_chars = |point: &RagScoredPoint| point.source.chars().count() + 2;
+    let total_chars: usize = points.iter().map(|point| chunk_chars(point)).sum();
+
+    let mut kept = vec![true; points.len()];
+    if let Some(max_context_chars) = max_context_chars
+        && total_chars > max_context_chars
+    {
+        let mut lowest_first: Vec<usize> = (0..points.len()).collect();
+        lowest_first.sort_by(|a, b| {
+            points[*a]
+                .score
+                .partial_cmp(&points[*b].score)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+
+        // never drop the highest-scored point
+        let mut chars = total_chars;
+        for idx in lowest_first
+            .into_iter()
+            .take(points.len().saturating_sub(1))
+        {
+            if chars <= max_context_chars {
+                break;
+            }
+            kept[idx] = false;
+            chars -= chunk_chars(points[idx]);
+        }
+    }
+
+    let mut context = String::new();
+    for (point, _) in points.iter().zip(kept).filter(|(_, kept)| *kept) {
+        context.push_str(&point.source);
+        context.push_str("\n\n");
+    }
+    if let Some(max_context_chars) = max_context_chars
+        && context.chars().count() > max_context_chars
+    {
+        context = context.chars().take(max_context_chars).collect();
+    }
+
+    let truncated_chars = total_chars - context.chars().count();
+    (context, truncated_chars)
+}
+
 #[derive(Debug, Default)]
 struct RagPromptBuilder;
 impl MergeRagContext for RagPromptBuilder {
@@ -1020,69 +975,369 @@ impl MergeRagContext for RagPromptBuilder {
     }
 }
 
-// Segment the given text into chunks
-pub(crate) fn chunk_text(
-    text: impl AsRef<str>,
-    ty: impl AsRef<str>,
-    chunk_capacity: usize,
-    request_id: impl AsRef<str>,
-) -> Result<Vec<String>, ServerError> {
-    let request_id = request_id.as_ref();
+/// The embeddings of the recent queries, shared by the RAG requests
+static QUERY_EMBEDDING_CACHE: OnceCell<QueryEmbeddingCache> = OnceCell::new();
 
-    if ty.as_ref().to_lowercase().as_str() != "txt" && ty.as_ref().to_lowercase().as_str() != "md" {
-        let err_msg = "Failed to upload the target file. Only files with 'txt' and 'md' extensions are supported.";
+/// The in-memory LRU cache of the embeddings of the queries, keyed by the embedding model and the
+/// query text
+#[derive(Debug)]
+struct QueryEmbeddingCache {
+    capacity: usize,
+    entries: Mutex<QueryEmbeddingEntries>,
+}
+impl QueryEmbeddingCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(QueryEmbeddingEntries::default()),
+        }
+    }
 
-        dual_error!("{} - request_id: {}", err_msg, request_id);
+    /// Get the cached embedding of the query computed by the model
+    fn get(&self, model: Option<&str>, query: &str) -> Option<Vec<f64>> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.clock += 1;
+        let clock = entries.clock;
+        let entry = entries.map.get_mut(&query_embedding_key(model, query))?;
 
-        return Err(ServerError::Operation(err_msg.into()));
+        entry.last_used = clock;
+        Some(entry.embedding.clone())
     }
 
-    match ty.as_ref().to_lowercase().as_str() {
-        "txt" => {
-            dual_info!("Chunk the plain text contents - request_id: {}", request_id);
+    /// Cache the embedding of the query computed by the model, evicting the least recently used
+    /// one if full
+    fn insert(&self, model: Option<&str>, query: &str, embedding: Vec<f64>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let key = query_embedding_key(model, query);
+        let mut entries = self.entries.lock().unwrap();
+        if entries.map.len() >= self.capacity
+            && !entries.map.contains_key(&key)
+            && let Some(lru_key) = entries
+                .map
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+        {
+            entries.map.remove(&lru_key);
+        }
 
-            // create a text splitter
-            let splitter = TextSplitter::new(chunk_capacity);
+        entries.clock += 1;
+        let last_used = entries.clock;
+        entries.map.insert(
+            key,
+            QueryEmbeddingEntry {
+                embedding,
+                last_used,
+            },
+        );
+    }
+}
 
-            let chunks = splitter
-                .chunks(text.as_ref())
-                .map(|s| s.to_string())
-                .collect::<Vec<_>>();
+fn query_embedding_key(model: Option<&str>, query: &str) -> (String, String) {
+    (model.unwrap_or_default().to_string(), query.to_string())
+}
 
-            dual_info!("{} chunks - request_id: {}", chunks.len(), request_id);
+#[derive(Debug, Default)]
+struct QueryEmbeddingEntries {
+    map: HashMap<(String, String), QueryEmbeddingEntry>,
+    // incremented on every access to order the entries by recency
+    clock: u64,
+}
 
-            Ok(chunks)
-        }
-        "md" => {
-            dual_info!("Chunk the markdown contents - request_id: {}", request_id);
+#[derive(Debug)]
+struct QueryEmbeddingEntry {
+    embedding: Vec<f64>,
+    last_used: u64,
+}
 
-            // create a markdown splitter
-            let splitter = MarkdownSplitter::new(chunk_capacity);
+#[test]
+fn test_query_embedding_cache() {
+    let cache = QueryEmbeddingCache::new(2);
+    cache.insert(Some("nomic-embed"), "What is RAG?", vec![0.1, 0.2]);
+    // the embeddings of another model are not shared
+    assert!(cache.get(Some("bge-m3"), "What is RAG?").is_none());
+    assert!(cache.get(None, "What is RAG?").is_none());
+    assert_eq!(
+        cache.get(Some("nomic-embed"), "What is RAG?"),
+        Some(vec![0.1, 0.2])
+    );
 
-            let chunks = splitter
-                .chunks(text.as_ref())
-                .map(|s| s.to_string())
-                .collect::<Vec<_>>();
+    // the least recently used embedding is evicted
+    cache.insert(None, "What is MCP?", vec![0.3]);
+    assert!(cache.get(Some("nomic-embed"), "What is RAG?").is_some());
+    cache.insert(None, "What is LLM?", vec![0.4]);
+    assert!(cache.get(None, "What is MCP?").is_none());
+    assert!(cache.get(Some("nomic-embed"), "What is RAG?").is_some());
+    assert_eq!(cache.get(None, "What is LLM?"), Some(vec![0.4]));
+
+    // nothing is cached without capacity
+    let cache = QueryEmbeddingCache::new(0);
+    cache.insert(None, "What is RAG?", vec![0.1]);
+    assert!(cache.get(None, "What is RAG?").is_none());
+}
 
-            dual_info!(
-                "Number of chunks: {} - request_id: {}",
-                chunks.len(),
-                request_id
-            );
+/// Compute the embeddings of the query for vector search
+///
+/// The registered embeddings servers are tried first. If they fail, the request is retried with the
+/// fallback embeddings server in the RAG config (if any) before giving up on vector search.
+async fn compute_query_embeddings(
+    State(state): State<Arc<AppState>>,
+    Extension(cancel_token): Extension<CancellationToken>,
+    headers: &HeaderMap,
+    embedding_request: EmbeddingRequest,
+) -> ServerResult<EmbeddingsResponse> {
+    let primary_result = compute_embeddings_by_registered_server(
+        State(state.clone()),
+        Extension(cancel_token.clone()),
+        headers,
+        embedding_request.clone(),
+    )
+    .await;
+
+    match primary_result {
+        Ok(embedding_response) => Ok(embedding_response),
+        Err(e) => {
+            let fallback_server = state
+                .config
+                .read()
+                .await
+                .rag
+                .as_ref()
+                .and_then(|rag| rag.fallback_embeddings_server.clone());
+
+            match fallback_server {
+                Some(fallback_server) => {
+                    dual_warn!(
+                        "Failed to compute embeddings by the registered embeddings server: {}. Retry with the fallback embeddings server {}",
+                        e,
+                        fallback_server.url
+                    );
 
-            Ok(chunks)
+                    compute_embeddings_by_fallback_server(
+                        &fallback_server,
+                        headers,
+                        &embedding_request,
+                        cancel_token,
+                    )
+                    .await
+                }
+                None => Err(e),
+            }
         }
-        _ => {
-            let err_msg =
-                "Failed to upload the target file. Only text and markdown files are supported.";
+    }
+}
+
+async fn compute_embeddings_by_registered_server(
+    State(state): State<Arc<AppState>>,
+    Extension(cancel_token): Extension<CancellationToken>,
+    headers: &HeaderMap,
+    embedding_request: EmbeddingRequest,
+) -> ServerResult<EmbeddingsResponse> {
+    let response = crate::handlers::embeddings(
+        State(state),
+        Extension(cancel_token),
+        headers.clone(),
+        Json(embedding_request),
+    )
+    .await?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let err_msg = format!("The embeddings server responded with status {status}");
+        dual_error!("{}", err_msg);
+        return Err(ServerError::Operation(err_msg));
+    }
 
+    // parse the response
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .map_err(|e| {
+            let err_msg = format!("Failed to parse embeddings response: {e}");
+
+            // log
             dual_error!("{}", err_msg);
 
-            Err(ServerError::Operation(err_msg.into()))
+            ServerError::Operation(err_msg)
+        })?;
+
+    // parse the response
+    serde_json::from_slice::<EmbeddingsResponse>(&bytes).map_err(|e| {
+        let err_msg = format!("Failed to parse embeddings response: {e}");
+
+        // log
+        dual_error!("{}", err_msg);
+
+        ServerError::Operation(err_msg)
+    })
+}
+
+async fn compute_embeddings_by_fallback_server(
+    fallback_server: &RagEmbeddingsServerConfig,
+    headers: &HeaderMap,
+    embedding_request: &EmbeddingRequest,
+    cancel_token: CancellationToken,
+) -> ServerResult<EmbeddingsResponse> {
+    let embeddings_service_url =
+        format!("{}/embeddings", fallback_server.url.trim_end_matches('/'));
+
+    let mut ds_request =
+        handlers::forward_tracing_headers(http_client().post(&embeddings_service_url), headers)
+            .header(CONTENT_TYPE, "application/json");
+    if let Some(api_key) = &fallback_server.api_key
+        && !api_key.is_empty()
+    {
+        ds_request = ds_request.header(AUTHORIZATION, api_key);
+    } else if let Some(auth) = headers.get("authorization")
+        && let Ok(auth_str) = auth.to_str()
+    {
+        ds_request = ds_request.header(AUTHORIZATION, auth_str);
+    }
+
+    // Use select! to handle request cancellation
+    let ds_response = select! {
+        response = ds_request.json(embedding_request).send() => {
+            response.map_err(|e| {
+                let err_msg = format!(
+                    "Failed to send the request to the fallback embeddings server: {e}"
+                );
+                dual_error!("{}", err_msg);
+                ServerError::Operation(err_msg)
+            })?
+        }
+        _ = cancel_token.cancelled() => {
+            let warn_msg = "Request was cancelled by client";
+            dual_warn!("{}", warn_msg);
+            return Err(ServerError::Operation(warn_msg.to_string()));
+        }
+    };
+
+    let status = ds_response.status();
+    if !status.is_success() {
+        let err_msg = format!("The fallback embeddings server responded with status {status}");
+        dual_error!("{}", err_msg);
+        return Err(ServerError::Operation(err_msg));
+    }
+
+    ds_response.json::<EmbeddingsResponse>().await.map_err(|e| {
+        let err_msg = format!("Failed to parse embeddings response: {e}");
+
+        // log
+        dual_error!("{}", err_msg);
+
+        ServerError::Operation(err_msg)
+    })
+}
+
+/// Whether the prompt template of the chat model has a system prompt. A model reporting no prompt
+/// template is assumed to have none, so that the context is merged into the last user message.
+fn has_system_prompt(server_id: &str, chat_model: &ModelConfig) -> bool {
+    match &chat_model.prompt_template {
+        Some(prompt_template) => prompt_template.has_system_prompt(),
+        None => {
+            dual_warn!(
+                "The chat server {} reports no prompt template, assuming it has no system prompt",
+                server_id
+            );
+            false
         }
     }
 }
 
+#[test]
+fn test_has_system_prompt() {
+    let chat_model: ModelConfig = serde_json::from_value(serde_json::json!({
+        "name": "Llama-3.2-3b",
+        "type": "chat",
+        "prompt_template": "Llama3Chat",
+    }))
+    .unwrap();
+    assert!(has_system_prompt("chat-server-1", &chat_model));
+
+    // the server info lacking the prompt template
+    let chat_model: ModelConfig =
+        serde_json::from_value(serde_json::json!({"name": "Llama-3.2-3b", "type": "chat"}))
+            .unwrap();
+    assert!(!has_system_prompt("chat-server-1", &chat_model));
+}
+
+#[tokio::test]
+async fn test_compute_query_embeddings_with_fallback() {
+    use axum::{Router, routing::post};
+
+    use crate::config::{Config, RagConfig};
+
+    // the fallback embeddings server
+    let app = Router::new().route(
+        "/v1/embeddings",
+        post(|| async {
+            Json(serde_json::json!({
+                "object": "list",
+                "data": [{"index": 0, "object": "embedding", "embedding": [0.1, 0.2, 0.3]}],
+                "model": "fallback-embedding-model",
+                "usage": {"prompt_tokens": 3, "completion_tokens": 0, "total_tokens": 3}
+            }))
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let fallback_addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+
+    let config = Config {
+        rag: Some(RagConfig {
+            enable: true,
+            prompt: None,
+            policy: MergeRagContextPolicy::SystemMessage,
+            context_window: 1,
+            max_context_chars: None,
+            fallback_embeddings_server: Some(RagEmbeddingsServerConfig {
+                url: format!("http://{fallback_addr}/v1"),
+                api_key: None,
+            }),
+            embedding_model: None,
+            embedding_cache_capacity: 0,
+        }),
+        ..Default::default()
+    };
+    let state = crate::test_state_with(config);
+
+    // the primary embeddings server is down
+    let primary_server: crate::server::Server =
+        serde_json::from_str(r#"{"url": "http://127.0.0.1:1/v1", "kind": "embeddings"}"#).unwrap();
+    state
+        .register_downstream_server(primary_server)
+        .await
+        .unwrap();
+
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, "application/json".parse().unwrap());
+
+    let embedding_request = EmbeddingRequest {
+        model: None,
+        input: InputText::String("What is the capital of France?".to_string()),
+        encoding_format: None,
+        user: None,
+        vdb_server_url: None,
+        vdb_collection_name: None,
+        vdb_api_key: None,
+    };
+
+    let embedding_response = compute_query_embeddings(
+        State(state),
+        Extension(CancellationToken::new()),
+        &headers,
+        embedding_request,
+    )
+    .await
+    .unwrap();
+    assert_eq!(embedding_response.model, "fallback-embedding-model");
+    assert_eq!(embedding_response.data[0].embedding, vec![0.1, 0.2, 0.3]);
+}
+
 fn calculate_hash(s: &str) -> u64 {
     let mut hasher = DefaultHasher::new();
     s.hash(&mut hasher);
@@ -1262,22 +1517,248 @@ fn weighted_fusion(
     }
 }
 
+/// Check that the embedding model of RAG produces vectors of the dimension of the Qdrant
+/// collection, and warn about a mismatch, which makes the vector search return nothing useful.
+/// Returns whether the dimensions match, or `None` if the check is skipped.
+pub(crate) async fn check_embedding_dimension(state: Arc<AppState>) -> Option<bool> {
+    let embedding_model = match state.config.read().await.rag.as_ref() {
+        Some(rag_config) if rag_config.enable => rag_config.embedding_model.clone(),
+        _ => return None,
+    };
+
+    // the dimension of the vectors of the embedding model
+    let embedding_request = EmbeddingRequest {
+        model: embedding_model.clone(),
+        input: InputText::String("dimension probe".to_string()),
+        encoding_format: None,
+        user: None,
+        vdb_server_url: None,
+        vdb_collection_name: None,
+        vdb_api_key: None,
+    };
+    let embedding_dimension = match compute_query_embeddings(
+        State(state.clone()),
+        Extension(CancellationToken::new()),
+        &HeaderMap::new(),
+        embedding_request,
+    )
+    .await
+    {
+        Ok(embedding_response) => match embedding_response.data.first() {
+            Some(embedding) => embedding.embedding.len() as u64,
+            None => {
+                dual_warn!("Skipped the check of the embedding dimension: no embeddings returned");
+                return None;
+            }
+        },
+        Err(e) => {
+            dual_warn!("Skipped the check of the embedding dimension: {}", e);
+            return None;
+        }
+    };
+
+    // the dimension of the vectors of the collection
+    let collection_info = match call_qdrant_collection_info().await {
+        Ok(collection_info) => collection_info,
+        Err(e) => {
+            dual_warn!("Skipped the check of the embedding dimension: {}", e);
+            return None;
+        }
+    };
+    let Some(collection_dimension) = collection_vector_size(&collection_info) else {
+        dual_warn!(
+            "Skipped the check of the embedding dimension: no vector size in the collection info"
+        );
+        return None;
+    };
+
+    let model = embedding_model.as_deref().unwrap_or("the default model");
+    let matched = embedding_dimension == collection_dimension;
+    if matched {
+        dual_info!(
+            "The embeddings of {} match the dimension {} of the Qdrant collection",
+            model,
+            collection_dimension
+        );
+    } else {
+        dual_warn!(
+            "The embeddings of {} have the dimension {}, but the Qdrant collection expects {}. The vector search of RAG will return no relevant context. Set `embedding_model` in the `[rag]` section to a model of the dimension {}.",
+            model,
+            embedding_dimension,
+            collection_dimension,
+            collection_dimension
+        );
+    }
+
+    Some(matched)
+}
+
+/// The tool of the Qdrant MCP server returning the info of its collection
+const QDRANT_COLLECTION_INFO_TOOL: &str = "collection_info";
+
+async fn call_qdrant_collection_info() -> ServerResult<Value> {
+    let Some(services) = MCP_SERVICES.get() else {
+        let err_msg = "MCP_SERVICES is not initialized";
+        dual_error!("{}", err_msg);
+        return Err(ServerError::Operation(err_msg.to_string()));
+    };
+
+    for (_service_name, service) in services.read().await.iter() {
+        let service = service.read().await;
+        if !service.has_tool(QDRANT_COLLECTION_INFO_TOOL) {
+            continue;
+        }
+
+        let request_param = CallToolRequestParam {
+            name: QDRANT_COLLECTION_INFO_TOOL.into(),
+            arguments: None,
+        };
+        let mcp_tool_result = service.call_tool(request_param, None).await.map_err(|e| {
+            let err_msg = format!("Failed to call the tool: {e}");
+            dual_error!("{}", err_msg);
+            ServerError::Operation(err_msg)
+        })?;
+
+        let text = mcp_tool_result
+            .content
+            .iter()
+            .flatten()
+            .filter_map(|content| content.as_text())
+            .map(|text| text.text.as_str())
+            .collect::<String>();
+        return serde_json::from_str(&text).map_err(|e| {
+            let err_msg = format!("Failed to parse the collection info: {e}");
+            dual_error!("{}", err_msg);
+            ServerError::Operation(err_msg)
+        });
+    }
+
+    Err(ServerError::McpNotFoundClient)
+}
+
+/// The vector size in the info of a Qdrant collection, either the response of the
+/// `GET /collections/{name}` endpoint or its `result`. A collection of named vectors has no
+/// single size.
+fn collection_vector_size(collection_info: &Value) -> Option<u64> {
+    let collection_info = collection_info.get("result").unwrap_or(collection_info);
+    collection_info["config"]["params"]["vectors"]["size"].as_u64()
+}
+
+#[test]
+fn test_collection_vector_size() {
+    let collection_info = serde_json::json!({
+        "result": {
+            "status": "green",
+            "config": {"params": {"vectors": {"size": 768, "distance": "Cosine"}}},
+        },
+        "status": "ok",
+    });
+    assert_eq!(collection_vector_size(&collection_info), Some(768));
+    assert_eq!(
+        collection_vector_size(&collection_info["result"]),
+        Some(768)
+    );
+
+    // the named vectors
+    let collection_info = serde_json::json!({
+        "config": {"params": {"vectors": {"text": {"size": 768, "distance": "Cosine"}}}},
+    });
+    assert_eq!(collection_vector_size(&collection_info), None);
+}
+
+#[tokio::test]
+async fn test_check_embedding_dimension() {
+    use axum::{Router, routing::post};
+
+    use crate::{
+        config::{Config, RagConfig},
+        mcp::TestMcpServer,
+    };
+
+    // the embeddings server, computing vectors of the dimension 3
+    let app = Router::new().route(
+        "/v1/embeddings",
+        post(|| async {
+            Json(serde_json::json!({
+                "object": "list",
+                "data": [{"index": 0, "object": "embedding", "embedding": [0.1, 0.2, 0.3]}],
+                "model": "embedding-model",
+                "usage": {"prompt_tokens": 2, "completion_tokens": 0, "total_tokens": 2}
+            }))
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let embeddings_addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+
+    let rag_config = RagConfig {
+        enable: true,
+        prompt: None,
+        policy: MergeRagContextPolicy::SystemMessage,
+        context_window: 1,
+        max_context_chars: None,
+        fallback_embeddings_server: Some(RagEmbeddingsServerConfig {
+            url: format!("http://{embeddings_addr}/v1"),
+            api_key: None,
+        }),
+        embedding_model: None,
+        embedding_cache_capacity: 0,
+    };
+    let state = crate::test_state_with(Config {
+        rag: Some(rag_config.clone()),
+        ..Default::default()
+    });
+
+    // the Qdrant MCP server reporting the vector size of its collection
+    let connect_qdrant = |size: u64| {
+        TestMcpServer {
+            name: "gaia-qdrant-mcp-server".to_string(),
+            tools: vec![(
+                QDRANT_COLLECTION_INFO_TOOL.to_string(),
+                Some(
+                    serde_json::json!({"config": {"params": {"vectors": {"size": size}}}})
+                        .to_string(),
+                ),
+            )],
+        }
+        .connect()
+    };
+    let services = MCP_SERVICES.get_or_init(|| tokio::sync::RwLock::new(HashMap::new()));
+    services.write().await.insert(
+        "gaia-qdrant".to_string(),
+        tokio::sync::RwLock::new(connect_qdrant(3).await),
+    );
+    assert_eq!(check_embedding_dimension(state.clone()).await, Some(true));
+
+    services.write().await.insert(
+        "gaia-qdrant".to_string(),
+        tokio::sync::RwLock::new(connect_qdrant(768).await),
+    );
+    assert_eq!(check_embedding_dimension(state).await, Some(false));
+    services.write().await.remove("gaia-qdrant");
+
+    // no check without RAG
+    let state = crate::test_state_with(Config {
+        rag: Some(RagConfig {
+            enable: false,
+            ..rag_config
+        }),
+        ..Default::default()
+    });
+    assert_eq!(check_embedding_dimension(state).await, None);
+}
+
 async fn call_keyword_search_service(
     tool_calls: &[ToolCall],
-    request_id: impl AsRef<str>,
+    headers: &HeaderMap,
 ) -> ServerResult<Vec<KwSearchHit>> {
-    let request_id = request_id.as_ref();
-
     // get the tool call from the tool calls
     let tool_call = &tool_calls[0];
     let tool_name = tool_call.function.name.as_str();
     let tool_args = &tool_call.function.arguments;
-    dual_debug!(
-        "tool name: {}, tool args: {} - request_id: {}",
-        tool_name,
-        tool_args,
-        request_id
-    );
+    dual_debug!("tool name: {}, tool args: {}", tool_name, tool_args);
 
     // convert the func_args to a json object
     let arguments =
@@ -1299,19 +1780,17 @@ async fn call_keyword_search_service(
                                     let mcp_tool_result = service
                                         .read()
                                         .await
-                                        .raw
-                                        .call_tool(request_param)
+                                        .call_tool(request_param, handlers::request_id(headers))
                                         .await
                                         .map_err(|e| {
                                             let err_msg = format!("Failed to call the tool: {e}");
-                                            dual_error!("{} - request_id: {}", err_msg, request_id);
+                                            dual_error!("{}", err_msg);
                                             ServerError::Operation(err_msg)
                                         })?;
 
                                     dual_debug!(
-                                        "{} - request_id: {}",
-                                        serde_json::to_string_pretty(&mcp_tool_result).unwrap(),
-                                        request_id
+                                        "{}",
+                                        serde_json::to_string_pretty(&mcp_tool_result).unwrap()
                                     );
 
                                     let search_response =
@@ -1320,11 +1799,7 @@ async fn call_keyword_search_service(
                                     let kw_hits_str =
                                         serde_json::to_string_pretty(&search_response.hits)
                                             .unwrap();
-                                    dual_debug!(
-                                        "kw_hits: {} - request_id: {}",
-                                        kw_hits_str,
-                                        request_id
-                                    );
+                                    dual_debug!("kw_hits: {}", kw_hits_str);
 
                                     return Ok(search_response.hits);
                                 }
@@ -1337,8 +1812,7 @@ async fn call_keyword_search_service(
                                     let mcp_tool_result = service
                                         .read()
                                         .await
-                                        .raw
-                                        .call_tool(request_param)
+                                        .call_tool(request_param, handlers::request_id(headers))
                                         .await
                                         .map_err(|e| {
                                             dual_error!("Failed to call the tool: {}", e);
@@ -1346,9 +1820,8 @@ async fn call_keyword_search_service(
                                         })?;
 
                                     dual_debug!(
-                                        "{} - request_id: {}",
-                                        serde_json::to_string_pretty(&mcp_tool_result).unwrap(),
-                                        request_id
+                                        "{}",
+                                        serde_json::to_string_pretty(&mcp_tool_result).unwrap()
                                     );
 
                                     // parse tool result
@@ -1379,12 +1852,11 @@ async fn call_keyword_search_service(
                                     let mcp_tool_result = service
                                         .read()
                                         .await
-                                        .raw
-                                        .call_tool(request_param)
+                                        .call_tool(request_param, handlers::request_id(headers))
                                         .await
                                         .map_err(|e| {
                                             let err_msg = format!("Failed to call the tool: {e}");
-                                            dual_error!("{} - request_id: {}", err_msg, request_id);
+                                            dual_error!("{}", err_msg);
                                             ServerError::Operation(err_msg)
                                         })?;
 
@@ -1426,13 +1898,13 @@ async fn call_keyword_search_service(
                                         "Unsupported MCP service: {}",
                                         &peer_info.server_info.name
                                     );
-                                    dual_warn!("{} - request_id: {}", &err_msg, request_id);
+                                    dual_warn!("{}", &err_msg);
                                 }
                             }
                         }
                         None => {
                             let err_msg = "Failed to get MCP service info";
-                            dual_error!("{} - request_id: {}", err_msg, request_id);
+                            dual_error!("{}", err_msg);
                             return Err(ServerError::Operation(err_msg.to_string()));
                         }
                     }
@@ -1443,7 +1915,7 @@ async fn call_keyword_search_service(
         }
         None => {
             let err_msg = "MCP_SERVICES is not initialized";
-            dual_error!("{} - request_id: {}", err_msg, request_id);
+            dual_error!("{}", err_msg);
             Err(ServerError::Operation(err_msg.to_string()))
         }
     }
@@ -1452,20 +1924,13 @@ async fn call_keyword_search_service(
 async fn call_vector_search_service(
     tool_calls: &[ToolCall],
     vector: &[f64],
-    request_id: impl AsRef<str>,
+    headers: &HeaderMap,
 ) -> ServerResult<Vec<RagScoredPoint>> {
-    let request_id = request_id.as_ref();
-
     // get the tool call from the tool calls
     let tool_call = &tool_calls[0];
     let tool_name = tool_call.function.name.as_str();
     let tool_args = &tool_call.function.arguments;
-    dual_debug!(
-        "tool name: {}, tool args: {} - request_id: {}",
-        tool_name,
-        tool_args,
-        request_id
-    );
+    dual_debug!("tool name: {}, tool args: {}", tool_name, tool_args);
 
     // convert the func_args to a json object
     let arguments = Some(serde_json::Map::from_iter([(
@@ -1490,28 +1955,23 @@ async fn call_vector_search_service(
                                 let mcp_tool_result = service
                                     .read()
                                     .await
-                                    .raw
-                                    .call_tool(request_param)
+                                    .call_tool(request_param, handlers::request_id(headers))
                                     .await
                                     .map_err(|e| {
                                         let err_msg = format!("Failed to call the tool: {e}");
-                                        dual_error!("{} - request_id: {}", err_msg, request_id);
+                                        dual_error!("{}", err_msg);
                                         ServerError::Operation(err_msg)
                                     })?;
 
                                 dual_debug!(
-                                    "{} - request_id: {}",
-                                    serde_json::to_string_pretty(&mcp_tool_result).unwrap(),
-                                    request_id
+                                    "{}",
+                                    serde_json::to_string_pretty(&mcp_tool_result).unwrap()
                                 );
 
                                 let search_response = SearchPointsResponse::from(mcp_tool_result);
                                 let scored_points = search_response.result;
 
-                                dual_debug!(
-                                    "Check and remove duplicated vector search results - request_id: {}",
-                                    request_id
-                                );
+                                dual_debug!("Check and remove duplicated vector search results");
 
                                 // remove duplicates, which have the same source
                                 let mut seen = HashSet::new();
@@ -1525,9 +1985,8 @@ async fn call_vector_search_service(
                                     .collect();
 
                                 dual_debug!(
-                                    "Retrieved {} unique vector search results in total - request_id: {}",
-                                    unique_scored_points.len(),
-                                    request_id
+                                    "Retrieved {} unique vector search results in total",
+                                    unique_scored_points.len()
                                 );
 
                                 let mut points: Vec<RagScoredPoint> = vec![];
@@ -1552,11 +2011,7 @@ async fn call_vector_search_service(
                                     if let Some(search) =
                                         point.payload.get("search").and_then(Value::as_str)
                                     {
-                                        dual_info!(
-                                            "search: {} - request_id: {}",
-                                            search,
-                                            request_id
-                                        );
+                                        dual_info!("search: {}", search);
                                     }
                                 }
 
@@ -1565,7 +2020,7 @@ async fn call_vector_search_service(
                         }
                         None => {
                             let err_msg = "Failed to get MCP service info";
-                            dual_error!("{} - request_id: {}", err_msg, request_id);
+                            dual_error!("{}", err_msg);
                             return Err(ServerError::Operation(err_msg.to_string()));
                         }
                     }
@@ -1576,8 +2031,137 @@ async fn call_vector_search_service(
         }
         None => {
             let err_msg = "MCP_SERVICES is not initialized";
-            dual_error!("{} - request_id: {}", err_msg, request_id);
+            dual_error!("{}", err_msg);
             Err(ServerError::Operation(err_msg.to_string()))
         }
     }
 }
+
+/// A hit of the keyword search by the `cardea-kwsearch-mcp-server`
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+struct KwSearchHit {
+    title: String,
+    content: String,
+    score: f64,
+}
+
+/// The response of the `cardea-kwsearch-mcp-server`
+#[derive(Debug, Default, serde::Deserialize)]
+struct SearchDocumentsResponse {
+    #[serde(default)]
+    hits: Vec<KwSearchHit>,
+}
+
+/// The response of the `cardea-tidb-mcp-server`
+#[derive(Debug, Default, serde::Deserialize)]
+struct TidbSearchResponse {
+    #[serde(default)]
+    hits: Vec<TidbSearchHit>,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+struct TidbSearchHit {
+    title: String,
+    content: String,
+}
+
+/// The response of the `cardea-elastic-mcp-server`, i.e. the response of the Elasticsearch search
+/// API
+#[derive(Debug, Default, serde::Deserialize)]
+struct SearchResponse {
+    #[serde(default)]
+    hits: ElasticHits,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+struct ElasticHits {
+    #[serde(default)]
+    hits: Vec<ElasticHit>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ElasticHit {
+    #[serde(rename = "_score", default)]
+    score: f64,
+    #[serde(rename = "_source", default)]
+    source: serde_json::Map<String, Value>,
+}
+
+/// The response of the `gaia-qdrant-mcp-server`, i.e. the response of the Qdrant search API
+#[derive(Debug, Default, serde::Deserialize)]
+struct SearchPointsResponse {
+    #[serde(default)]
+    result: Vec<ScoredPoint>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ScoredPoint {
+    score: f64,
+    #[serde(default)]
+    payload: serde_json::Map<String, Value>,
+}
+
+/// Parse the text content of the result of a search tool. A result which cannot be parsed, e.g.
+/// the error message of a failed call, is taken as no hits.
+fn parse_search_tool_result<T: serde::de::DeserializeOwned + Default>(result: CallToolResult) -> T {
+    let text = result
+        .content
+        .iter()
+        .flatten()
+        .filter_map(|content| content.as_text())
+        .map(|text| text.text.as_str())
+        .collect::<String>();
+    serde_json::from_str(&text).unwrap_or_else(|e| {
+        dual_warn!("Failed to parse the result of the search tool: {e}");
+        T::default()
+    })
+}
+
+impl From<CallToolResult> for SearchDocumentsResponse {
+    fn from(result: CallToolResult) -> Self {
+        parse_search_tool_result(result)
+    }
+}
+
+impl From<CallToolResult> for TidbSearchResponse {
+    fn from(result: CallToolResult) -> Self {
+        parse_search_tool_result(result)
+    }
+}
+
+impl From<CallToolResult> for SearchResponse {
+    fn from(result: CallToolResult) -> Self {
+        parse_search_tool_result(result)
+    }
+}
+
+impl From<CallToolResult> for SearchPointsResponse {
+    fn from(result: CallToolResult) -> Self {
+        parse_search_tool_result(result)
+    }
+}
+
+#[test]
+fn test_parse_search_tool_result() {
+    let result = CallToolResult::success(vec![rmcp::model::Content::text(
+        r#"{"result": [{"id": 1, "score": 0.9, "payload": {"source": "LlamaEdge is a runtime"}}]}"#,
+    )]);
+    let response = SearchPointsResponse::from(result);
+    assert_eq!(response.result.len(), 1);
+    assert_eq!(response.result[0].score, 0.9);
+    assert_eq!(
+        response.result[0].payload["source"],
+        "LlamaEdge is a runtime"
+    );
+
+    let result = CallToolResult::success(vec![rmcp::model::Content::text(
+        r#"{"hits": {"hits": [{"_score": 1.5, "_source": {"title": "t", "content": "c"}}]}}"#,
+    )]);
+    let response = SearchResponse::from(result);
+    assert_eq!(response.hits.hits[0].score, 1.5);
+    assert_eq!(response.hits.hits[0].source["content"], "c");
+
+    // an error message is no hits
+    let result = CallToolResult::error(vec![rmcp::model::Content::text("timed out")]);
+    assert!(SearchDocumentsResponse::from(result).hits.is_empty());
+}
@@ -0,0 +1,119 @@
+use regex::Regex;
+
+use crate::{config::RedactionConfig, dual_error};
+
+/// Redacts the configured patterns from the content of the non-streaming responses
+#[derive(Debug)]
+pub(crate) struct ResponseRedactor {
+    /// The patterns and their replacements, applied in order
+    rules: Vec<(Regex, String)>,
+}
+impl ResponseRedactor {
+    pub(crate) fn new(redaction_config: &RedactionConfig) -> Self {
+        let rules = redaction_config
+            .patterns
+            .iter()
+            .filter_map(|pattern| match Regex::new(&pattern.pattern) {
+                Ok(regex) => Some((
+                    regex,
+                    pattern
+                        .replacement
+                        .clone()
+                        .unwrap_or_else(|| redaction_config.replacement.clone()),
+                )),
+                // the patterns are validated when the config is loaded
+                Err(e) => {
+                    dual_error!(
+                        "Skipped the invalid redaction pattern `{}`: {e}",
+                        pattern.pattern
+                    );
+                    None
+                }
+            })
+            .collect();
+
+        Self { rules }
+    }
+
+    /// Redact the content of each choice of a chat or completions response. Returns whether
+    /// anything was redacted.
+    pub(crate) fn redact_response(&self, response: &mut serde_json::Value) -> bool {
+        let mut redacted = false;
+        for choice in response["choices"].as_array_mut().into_iter().flatten() {
+            let content = match choice.get_mut("message") {
+                Some(message) => message.get_mut("content"),
+                // the legacy completions
+                None => choice.get_mut("text"),
+            };
+            if let Some(content) = content
+                && let Some(text) = content.as_str()
+                && let Some(text) = self.redact(text)
+            {
+                *content = text.into();
+                redacted = true;
+            }
+        }
+
+        redacted
+    }
+
+    /// Redact the text. Returns `None` if no pattern matches.
+    fn redact(&self, text: &str) -> Option<String> {
+        let mut redacted = None;
+        for (regex, replacement) in &self.rules {
+            let current = redacted.as_deref().unwrap_or(text);
+            if regex.is_match(current) {
+                redacted = Some(
+                    regex
+                        .replace_all(current, replacement.as_str())
+                        .into_owned(),
+                );
+            }
+        }
+
+        redacted
+    }
+}
+
+#[test]
+fn test_redact_response() {
+    let redactor = ResponseRedactor::new(&RedactionConfig {
+        patterns: vec![
+            crate::config::RedactionPattern {
+                pattern: r"sk-[A-Za-z0-9]{8,}".to_string(),
+                replacement: None,
+            },
+            crate::config::RedactionPattern {
+                pattern: r"(\w+)@example\.com".to_string(),
+                replacement: Some("$1@***".to_string()),
+            },
+        ],
+        replacement: "[REDACTED]".to_string(),
+    });
+
+    let mut response = serde_json::json!({
+        "choices": [
+            {"index": 0, "message": {"role": "assistant", "content": "The key is sk-abcdef123456, ask alice@example.com"}},
+            {"index": 1, "message": {"role": "assistant", "content": "Nothing to hide"}},
+            {"index": 2, "message": {"role": "assistant", "content": null, "tool_calls": []}},
+        ]
+    });
+    assert!(redactor.redact_response(&mut response));
+    assert_eq!(
+        response["choices"][0]["message"]["content"],
+        "The key is [REDACTED], ask alice@***"
+    );
+    assert_eq!(
+        response["choices"][1]["message"]["content"],
+        "Nothing to hide"
+    );
+    assert!(response["choices"][2]["message"]["content"].is_null());
+
+    // the legacy completions
+    let mut response = serde_json::json!({"choices": [{"index": 0, "text": "sk-abcdef123456"}]});
+    assert!(redactor.redact_response(&mut response));
+    assert_eq!(response["choices"][0]["text"], "[REDACTED]");
+
+    let mut response = serde_json::json!({"choices": [{"index": 0, "text": "Paris"}]});
+    assert!(!redactor.redact_response(&mut response));
+}
@@ -1,22 +1,24 @@
 use std::{
     collections::HashSet,
-    sync::atomic::{AtomicUsize, Ordering},
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
     time::{Duration, SystemTime},
 };
 
 use async_trait::async_trait;
 use bitflags::bitflags;
 use serde::{Deserialize, Serialize};
-use tokio::sync::RwLock;
+use tokio::sync::{OwnedSemaphorePermit, RwLock, Semaphore};
 
 use crate::{
-    HEALTH_CHECK_INTERVAL, dual_error, dual_warn,
+    HEALTH_CHECK_INTERVAL,
+    client::health_check_client,
+    dual_error, dual_warn,
     error::{ServerError, ServerResult},
 };
 
-/// Timeout duration for health checks (in seconds)
-const TIMEOUT: u64 = 10;
-
 pub(crate) type ServerId = String;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -29,6 +31,8 @@ pub(crate) struct ServerIdToRemove {
 pub struct HealthStatus {
     pub is_healthy: bool,
     pub last_check: SystemTime,
+    /// The number of the health checks failed in a row
+    pub failures: u32,
 }
 
 impl Default for HealthStatus {
@@ -36,6 +40,92 @@ impl Default for HealthStatus {
         Self {
             is_healthy: true,
             last_check: SystemTime::now(),
+            failures: 0,
+        }
+    }
+}
+impl HealthStatus {
+    /// The delay before probing an unhealthy server again: `base` after the first failed check,
+    /// doubled after each further one, up to `max`
+    pub(crate) fn probe_backoff(&self, base: Duration, max: Duration) -> Duration {
+        let exponent = self.failures.saturating_sub(1).min(31);
+        base.saturating_mul(1 << exponent).min(max)
+    }
+
+    /// Whether the unhealthy server is due for another probe
+    pub(crate) fn is_probe_due(&self, base: Duration, max: Duration) -> bool {
+        SystemTime::now()
+            .duration_since(self.last_check)
+            .is_ok_and(|elapsed| elapsed >= self.probe_backoff(base, max))
+    }
+
+    /// The status after a health check with the given result
+    pub(crate) fn after_check(&self, is_healthy: bool) -> Self {
+        Self {
+            is_healthy,
+            last_check: SystemTime::now(),
+            failures: match is_healthy {
+                true => 0,
+                false => self.failures.saturating_add(1),
+            },
+        }
+    }
+}
+
+#[test]
+fn test_probe_backoff() {
+    let base = Duration::from_secs(5);
+    let max = Duration::from_secs(60);
+    let backoff = |failures| {
+        HealthStatus {
+            is_healthy: false,
+            last_check: SystemTime::now(),
+            failures,
+        }
+        .probe_backoff(base, max)
+    };
+    assert_eq!(backoff(1), Duration::from_secs(5));
+    assert_eq!(backoff(2), Duration::from_secs(10));
+    assert_eq!(backoff(3), Duration::from_secs(20));
+    assert_eq!(backoff(5), max);
+    assert_eq!(backoff(u32::MAX), max);
+
+    let health_status = HealthStatus {
+        is_healthy: false,
+        last_check: SystemTime::now() - Duration::from_secs(15),
+        failures: 2,
+    };
+    assert!(health_status.is_probe_due(base, max));
+    assert!(!health_status.is_probe_due(Duration::from_secs(10), max));
+}
+
+/// The API flavor of a server. The servers are OpenAI-compatible by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    /// The OpenAI API, e.g. `/v1/chat/completions` with the `Authorization` header
+    #[default]
+    #[serde(rename = "openai")]
+    OpenAi,
+    /// The Azure OpenAI API, e.g.
+    /// `/openai/deployments/{deployment}/chat/completions?api-version=...` with the `api-key`
+    /// header
+    Azure,
+    /// The Anthropic Messages API, e.g. `/v1/messages` with the `x-api-key` header. The chat
+    /// requests and responses are translated from and to the OpenAI API.
+    Anthropic,
+}
+impl Provider {
+    fn is_openai(&self) -> bool {
+        *self == Self::OpenAi
+    }
+
+    /// The header carrying the api key of the server
+    pub(crate) fn api_key_header(&self) -> &'static str {
+        match self {
+            Self::OpenAi => "authorization",
+            Self::Azure => "api-key",
+            Self::Anthropic => "x-api-key",
         }
     }
 }
@@ -48,6 +138,36 @@ pub struct Server {
     pub kind: ServerKind,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub api_key: Option<String>,
+    /// Whether new requests can be routed to the server
+    pub enabled: bool,
+    /// The request params the server rejects, which are removed before forwarding
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unsupported_params: Vec<String>,
+    /// The failover tier of the server. The servers of a higher tier, i.e. a greater number, only
+    /// take the requests no server of the lower tiers can take.
+    pub priority: u32,
+    /// The path the API of the server is mounted under, relative to `url`, e.g. `/openai`
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub path_prefix: String,
+    /// The maximum number of requests sent to the server at the same time. Unlimited if not set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_concurrency: Option<usize>,
+    /// The capabilities of the server, e.g. `vision`, which the clients select with the
+    /// `X-Model-Tag` header
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// The API flavor of the server, which decides the urls and the auth header of the requests
+    #[serde(skip_serializing_if = "Provider::is_openai")]
+    pub provider: Provider,
+    /// The `api-version` query param of the requests to an Azure server
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_version: Option<String>,
+    /// The deployments of an Azure server, which are its models
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub deployments: Vec<String>,
+    /// The permits of the concurrent requests, shared by the clones of the server
+    #[serde(skip)]
+    permits: Option<Arc<Semaphore>>,
     #[serde(skip)]
     connections: AtomicUsize,
     #[serde(skip)]
@@ -61,16 +181,70 @@ impl<'de> Deserialize<'de> for Server {
         // Create a helper struct to deserialize into
         #[derive(Deserialize)]
         struct ServerHelper {
+            id: Option<String>,
             url: String,
             kind: ServerKind,
             api_key: Option<String>,
+            #[serde(default)]
+            unsupported_params: Vec<String>,
+            #[serde(default)]
+            priority: u32,
+            #[serde(default)]
+            path_prefix: String,
+            max_concurrency: Option<usize>,
+            #[serde(default)]
+            tags: Vec<String>,
+            #[serde(default)]
+            provider: Provider,
+            api_version: Option<String>,
+            #[serde(default)]
+            deployments: Vec<String>,
         }
 
         // Deserialize into the helper struct
         let helper = ServerHelper::deserialize(deserializer)?;
+        if helper.max_concurrency == Some(0) {
+            return Err(serde::de::Error::custom(
+                "max_concurrency should be greater than 0",
+            ));
+        }
+
+        if helper.provider == Provider::Azure {
+            if helper
+                .api_version
+                .as_ref()
+                .is_none_or(|api_version| api_version.is_empty())
+            {
+                return Err(serde::de::Error::custom(
+                    "an azure server should have an `api_version`",
+                ));
+            }
+            if helper.deployments.is_empty() {
+                return Err(serde::de::Error::custom(
+                    "an azure server should have at least one of `deployments`",
+                ));
+            }
+        }
 
-        let kind = helper.kind.to_string().trim().replace(',', "-");
-        let id = format!("{}-server-{}", kind, uuid::Uuid::new_v4());
+        // the id is part of the paths of the admin endpoints, e.g. `/admin/servers/{id}/disable`
+        let id = match helper.id {
+            Some(id) => {
+                if id.is_empty()
+                    || !id
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+                {
+                    return Err(serde::de::Error::custom(format!(
+                        "invalid server id `{id}`: it should only contain ASCII letters, digits, `-`, `_` and `.`"
+                    )));
+                }
+                id
+            }
+            None => {
+                let kind = helper.kind.to_string().trim().replace(',', "-");
+                format!("{}-server-{}", kind, uuid::Uuid::new_v4())
+            }
+        };
 
         // Create the actual Server instance
         Ok(Server {
@@ -78,6 +252,18 @@ impl<'de> Deserialize<'de> for Server {
             url: helper.url,
             kind: helper.kind,
             api_key: helper.api_key,
+            enabled: true,
+            unsupported_params: helper.unsupported_params,
+            priority: helper.priority,
+            path_prefix: helper.path_prefix,
+            max_concurrency: helper.max_concurrency,
+            tags: helper.tags,
+            provider: helper.provider,
+            api_version: helper.api_version,
+            deployments: helper.deployments,
+            permits: helper
+                .max_concurrency
+                .map(|max_concurrency| Arc::new(Semaphore::new(max_concurrency))),
             connections: AtomicUsize::new(0),
             health_status: HealthStatus::default(),
         })
@@ -90,55 +276,80 @@ impl Clone for Server {
             url: self.url.clone(),
             kind: self.kind,
             api_key: self.api_key.clone(),
+            enabled: self.enabled,
+            unsupported_params: self.unsupported_params.clone(),
+            priority: self.priority,
+            path_prefix: self.path_prefix.clone(),
+            max_concurrency: self.max_concurrency,
+            tags: self.tags.clone(),
+            provider: self.provider,
+            api_version: self.api_version.clone(),
+            deployments: self.deployments.clone(),
+            permits: self.permits.clone(),
             connections: AtomicUsize::new(self.connections.load(Ordering::Relaxed)),
             health_status: self.health_status.clone(),
         }
     }
 }
 impl Server {
-    pub(crate) async fn check_health(&mut self) -> bool {
-        // If the server is currently healthy, check if a new health check is needed
-        if self.health_status.is_healthy {
-            let now = SystemTime::now();
-            if let Ok(duration) = now.duration_since(self.health_status.last_check) {
-                let check_interval =
-                    Duration::from_secs(*HEALTH_CHECK_INTERVAL.get().unwrap_or(&60));
-                if duration < check_interval {
-                    // If the time since last check is less than the interval, return current status
-                    return true;
-                }
-            }
-        }
+    /// The url of an endpoint of the server, e.g. `chat/completions`
+    pub(crate) fn endpoint(&self, path: &str) -> String {
+        endpoint_url(&self.url, &self.path_prefix, path)
+    }
 
-        // Perform new health check
-        let client = reqwest::Client::new();
-        let health_url = format!("{}/info", self.url);
-
-        // Use configured timeout duration
-        let timeout = Duration::from_secs(TIMEOUT);
-        let is_healthy = match client.get(&health_url).timeout(timeout).send().await {
-            Ok(response) => {
-                // Consider server healthy if response is timeout (408)
-                if response.status() == reqwest::StatusCode::REQUEST_TIMEOUT {
-                    dual_warn!("Health check: {} server {} is in use", self.kind, self.id);
-                    true
-                } else {
-                    response.status().is_success()
-                }
-            }
-            Err(e) => {
-                // Consider server healthy if error is timeout
-                dual_warn!("Health check: {} server {} is in use", self.kind, self.id);
-                e.is_timeout()
-            }
-        };
+    /// The url the API of the server is mounted under, identifying the backend of the server
+    pub(crate) fn base_url(&self) -> String {
+        self.endpoint("").trim_end_matches('/').to_string()
+    }
 
-        self.health_status = HealthStatus {
-            is_healthy,
-            last_check: SystemTime::now(),
-        };
+    /// Whether the server has the tag
+    pub(crate) fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+
+    /// Whether the server already takes as many requests as it is allowed to
+    fn is_saturated(&self) -> bool {
+        self.permits
+            .as_ref()
+            .is_some_and(|permits| permits.available_permits() == 0)
+    }
+
+    /// Whether the health of the server is to be checked. A healthy server is only checked again
+    /// once the health check interval has passed since its last check, unless `force` is set.
+    pub(crate) fn is_health_check_due(&self, force: bool) -> bool {
+        if !self.health_status.is_healthy || force {
+            return true;
+        }
 
-        is_healthy
+        let check_interval = Duration::from_secs(*HEALTH_CHECK_INTERVAL.get().unwrap_or(&60));
+        SystemTime::now()
+            .duration_since(self.health_status.last_check)
+            .map_or(true, |duration| duration >= check_interval)
+    }
+}
+
+/// Check the health of a server at its `info` endpoint with the timeouts of the health check
+/// client. The server is not locked meanwhile.
+pub(crate) async fn probe_health(kind: ServerKind, id: &str, health_url: &str) -> bool {
+    match health_check_client().get(health_url).send().await {
+        Ok(response) => {
+            // Consider server healthy if response is timeout (408)
+            if response.status() == reqwest::StatusCode::REQUEST_TIMEOUT {
+                dual_warn!("Health check: {} server {} is in use", kind, id);
+                true
+            } else {
+                response.status().is_success()
+            }
+        }
+        // Consider server healthy if the response times out, but not if the connection does
+        Err(e) if e.is_timeout() && !e.is_connect() => {
+            dual_warn!("Health check: {} server {} is in use", kind, id);
+            true
+        }
+        Err(e) => {
+            dual_warn!("Health check: {} server {} is unreachable: {}", kind, id, e);
+            false
+        }
     }
 }
 
@@ -149,12 +360,37 @@ fn test_deserialize_server() {
     println!("id: {}", server.id);
     assert_eq!(server.url, "http://localhost:8000");
     assert_eq!(server.kind, ServerKind::chat | ServerKind::tts);
+    assert!(server.enabled);
 
     let serialized = r#"{"url": "http://localhost:8000", "kind": "chat"}"#;
     let server: Server = serde_json::from_str(serialized).unwrap();
     println!("id: {}", server.id);
     assert_eq!(server.url, "http://localhost:8000");
     assert_eq!(server.kind, ServerKind::chat);
+    assert!(server.id.starts_with("chat-server-"));
+
+    // the explicit id is used verbatim
+    let serialized = r#"{"id": "chat-prod-1", "url": "http://localhost:8000", "kind": "chat"}"#;
+    let server: Server = serde_json::from_str(serialized).unwrap();
+    assert_eq!(server.id, "chat-prod-1");
+
+    for id in ["", "chat/prod", "chat prod"] {
+        let serialized =
+            format!(r#"{{"id": "{id}", "url": "http://localhost:8000", "kind": "chat"}}"#);
+        assert!(serde_json::from_str::<Server>(&serialized).is_err());
+    }
+
+    // an azure server needs the api version and its deployments
+    let serialized = r#"{"url": "https://example.openai.azure.com", "kind": "chat", "provider": "azure", "api_version": "2024-10-21", "deployments": ["gpt-4o"]}"#;
+    let server: Server = serde_json::from_str(serialized).unwrap();
+    assert_eq!(server.provider, Provider::Azure);
+    assert_eq!(server.deployments, ["gpt-4o"]);
+    for serialized in [
+        r#"{"url": "https://example.openai.azure.com", "kind": "chat", "provider": "azure", "deployments": ["gpt-4o"]}"#,
+        r#"{"url": "https://example.openai.azure.com", "kind": "chat", "provider": "azure", "api_version": "2024-10-21"}"#,
+    ] {
+        assert!(serde_json::from_str::<Server>(serialized).is_err());
+    }
 }
 
 #[test]
@@ -165,13 +401,23 @@ fn test_serialize_server() {
         url: "http://localhost:8000".to_string(),
         kind: ServerKind::chat | ServerKind::tts,
         api_key: None,
+        enabled: true,
+        unsupported_params: Vec::new(),
+        priority: 0,
+        path_prefix: String::new(),
+        max_concurrency: None,
+        tags: Vec::new(),
+        provider: Provider::default(),
+        api_version: None,
+        deployments: Vec::new(),
+        permits: None,
         connections: AtomicUsize::new(0),
         health_status: HealthStatus::default(),
     };
     let serialized = serde_json::to_string(&server).unwrap();
     assert_eq!(
         serialized,
-        r#"{"id":"chat-tts-29b6c973-d45a-4487-a3da-2e9b1f704fd9","url":"http://localhost:8000","kind":"chat,tts"}"#
+        r#"{"id":"chat-tts-29b6c973-d45a-4487-a3da-2e9b1f704fd9","url":"http://localhost:8000","kind":"chat,tts","enabled":true,"priority":0}"#
     );
 
     let id = "chat-2424f42e-fcfb-458e-9a6a-ad419e24b5f5".to_string();
@@ -180,13 +426,23 @@ fn test_serialize_server() {
         url: "http://localhost:8000".to_string(),
         kind: ServerKind::chat,
         api_key: Some("test-api-key".to_string()),
+        enabled: false,
+        unsupported_params: Vec::new(),
+        priority: 0,
+        path_prefix: String::new(),
+        max_concurrency: None,
+        tags: Vec::new(),
+        provider: Provider::default(),
+        api_version: None,
+        deployments: Vec::new(),
+        permits: None,
         connections: AtomicUsize::new(0),
         health_status: HealthStatus::default(),
     };
     let serialized = serde_json::to_string(&server).unwrap();
     assert_eq!(
         serialized,
-        r#"{"id":"chat-2424f42e-fcfb-458e-9a6a-ad419e24b5f5","url":"http://localhost:8000","kind":"chat","api_key":"test-api-key"}"#
+        r#"{"id":"chat-2424f42e-fcfb-458e-9a6a-ad419e24b5f5","url":"http://localhost:8000","kind":"chat","api_key":"test-api-key","enabled":false,"priority":0}"#
     );
 }
 
@@ -200,6 +456,7 @@ bitflags! {
         const tts = 1 << 3;
         const translate = 1 << 4;
         const transcribe = 1 << 5;
+        const rerank = 1 << 6;
     }
 }
 impl std::fmt::Display for ServerKind {
@@ -223,6 +480,9 @@ impl std::fmt::Display for ServerKind {
         if self.contains(ServerKind::transcribe) {
             kind_str.push_str("transcribe,");
         }
+        if self.contains(ServerKind::rerank) {
+            kind_str.push_str("rerank,");
+        }
 
         if !kind_str.is_empty() {
             kind_str = kind_str.trim_end_matches(',').to_string();
@@ -235,6 +495,11 @@ impl std::str::FromStr for ServerKind {
     type Err = ServerError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // an empty kind is serialized as an empty string
+        if s.trim().is_empty() {
+            return Ok(Self::empty());
+        }
+
         let ss = s.to_lowercase();
         let values = ss.split(',').collect::<Vec<&str>>();
         let mut kind = Self::empty();
@@ -246,6 +511,7 @@ impl std::str::FromStr for ServerKind {
                 "tts" => kind.set(Self::tts, true),
                 "translate" => kind.set(Self::translate, true),
                 "transcribe" => kind.set(Self::transcribe, true),
+                "rerank" => kind.set(Self::rerank, true),
                 _ => return Err(ServerError::InvalidServerKind(s.to_string())),
             }
         }
@@ -277,6 +543,9 @@ impl Serialize for ServerKind {
         if self.contains(ServerKind::transcribe) {
             kind_str.push_str("transcribe,");
         }
+        if self.contains(ServerKind::rerank) {
+            kind_str.push_str("rerank,");
+        }
 
         // Remove trailing comma if present
         if !kind_str.is_empty() {
@@ -336,6 +605,23 @@ fn test_deserialize_server_kind() {
     // assert_eq!(kind, ServerKind::vdb);
 }
 
+#[test]
+fn test_server_kind_round_trip() {
+    for kind in [ServerKind::empty(), ServerKind::all()] {
+        let serialized = serde_json::to_string(&kind).unwrap();
+        let deserialized: ServerKind = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized, kind);
+        assert_eq!(kind.to_string().parse::<ServerKind>().unwrap(), kind);
+    }
+
+    assert_eq!(serde_json::to_string(&ServerKind::empty()).unwrap(), "\"\"");
+    assert_eq!(
+        serde_json::to_string(&ServerKind::all()).unwrap(),
+        "\"chat,embeddings,image,tts,translate,transcribe,rerank\""
+    );
+    assert!("chat,".parse::<ServerKind>().is_err());
+}
+
 #[derive(Debug)]
 pub(crate) struct ServerGroup {
     pub(crate) servers: RwLock<Vec<RwLock<Server>>>,
@@ -401,6 +687,20 @@ impl ServerGroup {
         Ok(())
     }
 
+    /// Enable or disable routing to the server. Returns `false` if the server is not in the group.
+    pub(crate) async fn set_enabled(&self, server_id: impl AsRef<str>, enabled: bool) -> bool {
+        let servers = self.servers.read().await;
+        for server_lock in servers.iter() {
+            let mut server = server_lock.write().await;
+            if server.id == server_id.as_ref() {
+                server.enabled = enabled;
+                return true;
+            }
+        }
+
+        false
+    }
+
     #[allow(dead_code)]
     pub(crate) async fn ty(&self) -> ServerKind {
         self.ty
@@ -409,10 +709,14 @@ impl ServerGroup {
     pub(crate) async fn is_empty(&self) -> bool {
         self.healthy_servers.read().await.is_empty()
     }
-}
-#[async_trait]
-impl RoutingPolicy for ServerGroup {
-    async fn next(&self) -> Result<TargetServerInfo, ServerError> {
+
+    /// Pick the enabled and healthy server with the least connections in the lowest priority tier
+    /// among the servers matching the filter. The servers taking as many requests as they are allowed to are
+    /// only picked if all the matching servers do.
+    pub(crate) async fn next_matching(
+        &self,
+        filter: impl Fn(&Server) -> bool + Send + Sync,
+    ) -> ServerResult<TargetServerInfo> {
         let servers = self.servers.read().await;
         if servers.is_empty() {
             let err_msg = format!("No {} server found", self.ty);
@@ -420,22 +724,34 @@ impl RoutingPolicy for ServerGroup {
             return Err(ServerError::NotFoundServer(self.ty.to_string()));
         }
 
-        let server_lock = if servers.len() == 1 {
-            servers.first().unwrap()
-        } else {
-            // Find server with minimum connections - need to read each server
-            let mut min_connections = usize::MAX;
-            let mut min_server = &servers[0];
+        // Find the enabled server with minimum connections in the lowest tier - need to read each
+        // server
+        let mut min_load = (true, u32::MAX, usize::MAX);
+        let mut min_server = None;
+        for server in servers.iter() {
+            let guard = server.read().await;
+            if !guard.enabled || !guard.health_status.is_healthy || !filter(&guard) {
+                continue;
+            }
 
-            for server in servers.iter() {
-                let guard = server.read().await;
-                let connections = guard.connections.load(Ordering::Relaxed);
-                if connections < min_connections {
-                    min_connections = connections;
-                    min_server = server;
-                }
+            let load = (
+                guard.is_saturated(),
+                guard.priority,
+                guard.connections.load(Ordering::Relaxed),
+            );
+            if min_server.is_none() || load < min_load {
+                min_load = load;
+                min_server = Some(server);
+            }
+        }
+
+        let server_lock = match min_server {
+            Some(server_lock) => server_lock,
+            None => {
+                let err_msg = format!("No enabled and healthy {} server found", self.ty);
+                dual_error!("{}", &err_msg);
+                return Err(ServerError::NotFoundServer(self.ty.to_string()));
             }
-            min_server
         };
 
         // Access the chosen server
@@ -446,22 +762,320 @@ impl RoutingPolicy for ServerGroup {
                 id: server.id.clone(),
                 url: server.url.clone(),
                 api_key: server.api_key.clone(),
+                unsupported_params: server.unsupported_params.clone(),
+                path_prefix: server.path_prefix.clone(),
+                provider: server.provider,
+                api_version: server.api_version.clone(),
+                permits: server.permits.clone(),
             }
         };
 
         Ok(target_server_info)
     }
 }
+#[async_trait]
+impl RoutingPolicy for ServerGroup {
+    async fn next(&self) -> Result<TargetServerInfo, ServerError> {
+        self.next_matching(|_| true).await
+    }
+}
+
+/// Why a registered server is not a routing candidate of a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ExclusionReason {
+    /// The server does not provide the requested kind of service
+    WrongKind,
+    /// The server does not serve the requested model
+    ModelNotServed,
+    /// The server does not have the requested tag
+    TagNotMatched,
+    /// The server failed the last health check
+    Unhealthy,
+    /// The server is disabled and only finishes its in-flight requests
+    Draining,
+}
+
+/// A registered server considered when routing a request
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct RoutingCandidate {
+    pub id: ServerId,
+    pub kind: ServerKind,
+    /// The reasons the server is excluded. Empty if the server can take the request.
+    pub excluded_because: Vec<ExclusionReason>,
+}
 
 #[allow(dead_code)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct TargetServerInfo {
     pub id: ServerId,
     pub url: String,
     pub api_key: Option<String>,
+    pub unsupported_params: Vec<String>,
+    pub path_prefix: String,
+    pub provider: Provider,
+    pub api_version: Option<String>,
+    /// The permits of the concurrent requests to the server, if limited
+    pub permits: Option<Arc<Semaphore>>,
+}
+impl TargetServerInfo {
+    /// The url of an endpoint of the server, e.g. `chat/completions`
+    pub(crate) fn endpoint(&self, path: &str) -> String {
+        endpoint_url(&self.url, &self.path_prefix, path)
+    }
+
+    /// The url of the chat completions endpoint of the server for the model, shared by the chat
+    /// forwarding and the RAG helper calls so that both hit the same path. An Azure server serves
+    /// the model as the deployment of the same name, and an Anthropic server serves the chat
+    /// completions as messages.
+    pub(crate) fn chat_completions_url(&self, model: Option<&str>) -> ServerResult<String> {
+        match self.provider {
+            Provider::OpenAi => Ok(self.endpoint("chat/completions")),
+            Provider::Anthropic => Ok(self.endpoint("messages")),
+            Provider::Azure => {
+                let Some(deployment) = model.filter(|model| !model.is_empty()) else {
+                    let err_msg = format!(
+                        "The model is required by the azure server {}, as the name of its deployment",
+                        self.id
+                    );
+                    dual_error!("{}", err_msg);
+                    return Err(ServerError::BadRequest(err_msg));
+                };
+                // the deployment is a path segment of the url, so the names that could change the
+                // path or the query, e.g. `../models` or `a?b`, are rejected. The names of the
+                // azure deployments only have alphanumerics, `-`, `_` and `.`.
+                if deployment.chars().all(|c| c == '.')
+                    || !deployment
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+                {
+                    let err_msg = format!(
+                        "Invalid model for the azure server {}, which is not a deployment name: {deployment}",
+                        self.id
+                    );
+                    dual_error!("{}", err_msg);
+                    return Err(ServerError::BadRequest(err_msg));
+                }
+
+                Ok(format!(
+                    "{}?api-version={}",
+                    self.endpoint(&format!("openai/deployments/{deployment}/chat/completions")),
+                    self.api_version.as_deref().unwrap_or_default()
+                ))
+            }
+        }
+    }
+
+    /// The header carrying the api key of the server
+    pub(crate) fn api_key_header(&self) -> &'static str {
+        self.provider.api_key_header()
+    }
+
+    /// Acquire a permit of the concurrent requests to the server, waiting at most `wait` for a
+    /// request to finish. The permit is released once dropped. Returns `None` if the concurrent
+    /// requests are not limited.
+    pub(crate) async fn acquire_permit(
+        &self,
+        wait: Duration,
+    ) -> ServerResult<Option<OwnedSemaphorePermit>> {
+        let Some(permits) = self.permits.clone() else {
+            return Ok(None);
+        };
+
+        match tokio::time::timeout(wait, permits.acquire_owned()).await {
+            Ok(Ok(permit)) => Ok(Some(permit)),
+            _ => {
+                let err = ServerError::ServerBusy(self.id.clone());
+                dual_warn!("{}", err);
+                Err(err)
+            }
+        }
+    }
+}
+
+/// Join the base url, the path prefix and the path of an endpoint with single slashes
+fn endpoint_url(url: &str, path_prefix: &str, path: &str) -> String {
+    let url = url.trim_end_matches('/');
+    let path = path.trim_start_matches('/');
+    match path_prefix.trim_matches('/') {
+        "" => format!("{url}/{path}"),
+        path_prefix => format!("{url}/{path_prefix}/{path}"),
+    }
+}
+
+#[test]
+fn test_endpoint_url() {
+    assert_eq!(
+        endpoint_url("http://localhost:8000/v1", "", "chat/completions"),
+        "http://localhost:8000/v1/chat/completions"
+    );
+    assert_eq!(
+        endpoint_url("http://localhost:8000/v1/", "", "/info"),
+        "http://localhost:8000/v1/info"
+    );
+    assert_eq!(
+        endpoint_url("http://localhost:8000", "/openai/", "chat/completions"),
+        "http://localhost:8000/openai/chat/completions"
+    );
+    assert_eq!(
+        endpoint_url("http://localhost:8000", "openai/v1", "embeddings"),
+        "http://localhost:8000/openai/v1/embeddings"
+    );
+}
+
+#[test]
+fn test_chat_completions_url() {
+    let mut target_server = TargetServerInfo {
+        id: "chat-server-1".to_string(),
+        url: "http://localhost:8000/v1/".to_string(),
+        ..Default::default()
+    };
+    assert_eq!(
+        target_server
+            .chat_completions_url(Some("Llama-3.2-3b"))
+            .unwrap(),
+        "http://localhost:8000/v1/chat/completions"
+    );
+    assert_eq!(target_server.api_key_header(), "authorization");
+
+    target_server.url = "http://localhost:8000".to_string();
+    target_server.path_prefix = "/openai".to_string();
+    assert_eq!(
+        target_server.chat_completions_url(None).unwrap(),
+        "http://localhost:8000/openai/chat/completions"
+    );
+
+    // the model is the deployment of an azure server
+    target_server.url = "https://example.openai.azure.com/".to_string();
+    target_server.path_prefix = String::new();
+    target_server.provider = Provider::Azure;
+    target_server.api_version = Some("2024-10-21".to_string());
+    assert_eq!(
+        target_server.chat_completions_url(Some("gpt-4o")).unwrap(),
+        "https://example.openai.azure.com/openai/deployments/gpt-4o/chat/completions?api-version=2024-10-21"
+    );
+    assert!(target_server.chat_completions_url(None).is_err());
+    assert_eq!(
+        target_server
+            .chat_completions_url(Some("gpt-4o.mini_2"))
+            .unwrap(),
+        "https://example.openai.azure.com/openai/deployments/gpt-4o.mini_2/chat/completions?api-version=2024-10-21"
+    );
+    // the models changing the path or the query of the url are rejected
+    for model in [
+        "..",
+        "../models",
+        "gpt-4o/embeddings",
+        "gpt-4o?api-version=1",
+        "a#b",
+        "a%2F",
+    ] {
+        assert!(matches!(
+            target_server.chat_completions_url(Some(model)),
+            Err(ServerError::BadRequest(_))
+        ));
+    }
+    assert_eq!(target_server.api_key_header(), "api-key");
+
+    // an anthropic server serves the chat completions as messages
+    target_server.url = "https://api.anthropic.com/v1".to_string();
+    target_server.provider = Provider::Anthropic;
+    target_server.api_version = None;
+    assert_eq!(
+        target_server.chat_completions_url(None).unwrap(),
+        "https://api.anthropic.com/v1/messages"
+    );
+    assert_eq!(target_server.api_key_header(), "x-api-key");
 }
 
 #[async_trait]
 pub(crate) trait RoutingPolicy: Sync + Send {
     async fn next(&self) -> Result<TargetServerInfo, ServerError>;
 }
+
+#[tokio::test]
+async fn test_next_skips_disabled_servers() {
+    let group = ServerGroup::new(ServerKind::chat);
+    let server1: Server =
+        serde_json::from_str(r#"{"url": "http://localhost:8000", "kind": "chat"}"#).unwrap();
+    let server2: Server =
+        serde_json::from_str(r#"{"url": "http://localhost:8001", "kind": "chat"}"#).unwrap();
+    let server1_id = server1.id.clone();
+    let server2_id = server2.id.clone();
+    group.register(server1).await.unwrap();
+    group.register(server2).await.unwrap();
+
+    assert!(group.set_enabled(&server1_id, false).await);
+    for _ in 0..3 {
+        assert_eq!(group.next().await.unwrap().id, server2_id);
+    }
+
+    assert!(group.set_enabled(&server2_id, false).await);
+    assert!(group.next().await.is_err());
+
+    assert!(group.set_enabled(&server1_id, true).await);
+    assert_eq!(group.next().await.unwrap().id, server1_id);
+    assert!(!group.set_enabled("unknown-server", true).await);
+}
+
+#[tokio::test]
+async fn test_next_prefers_lower_priority_tier() {
+    let group = ServerGroup::new(ServerKind::chat);
+    let primary: Server =
+        serde_json::from_str(r#"{"url": "http://localhost:8000", "kind": "chat"}"#).unwrap();
+    let secondary: Server = serde_json::from_str(
+        r#"{"url": "https://api.example.com/v1", "kind": "chat", "priority": 1}"#,
+    )
+    .unwrap();
+    let primary_id = primary.id.clone();
+    let secondary_id = secondary.id.clone();
+    group.register(secondary).await.unwrap();
+    group.register(primary).await.unwrap();
+
+    // the busy primary server still takes the requests
+    for _ in 0..3 {
+        assert_eq!(group.next().await.unwrap().id, primary_id);
+    }
+
+    // the secondary server takes over only when the primary tier is empty
+    assert!(group.set_enabled(&primary_id, false).await);
+    assert_eq!(group.next().await.unwrap().id, secondary_id);
+    group.unregister(&primary_id).await.unwrap();
+    assert_eq!(group.next().await.unwrap().id, secondary_id);
+}
+
+#[tokio::test]
+async fn test_next_skips_saturated_servers() {
+    let group = ServerGroup::new(ServerKind::chat);
+    let small: Server = serde_json::from_str(
+        r#"{"url": "http://localhost:8000", "kind": "chat", "max_concurrency": 1}"#,
+    )
+    .unwrap();
+    let fallback: Server = serde_json::from_str(
+        r#"{"url": "https://api.example.com/v1", "kind": "chat", "priority": 1}"#,
+    )
+    .unwrap();
+    let small_id = small.id.clone();
+    let fallback_id = fallback.id.clone();
+    group.register(small).await.unwrap();
+    group.register(fallback).await.unwrap();
+
+    // the saturated server is skipped, even by a higher tier
+    let target = group.next().await.unwrap();
+    assert_eq!(target.id, small_id);
+    let permit = target.acquire_permit(Duration::ZERO).await.unwrap();
+    assert!(permit.is_some());
+    assert_eq!(group.next().await.unwrap().id, fallback_id);
+    assert!(target.acquire_permit(Duration::ZERO).await.is_err());
+
+    // the permit is released once dropped
+    drop(permit);
+    assert_eq!(group.next().await.unwrap().id, small_id);
+
+    assert!(
+        serde_json::from_str::<Server>(
+            r#"{"url": "http://localhost:8000", "kind": "chat", "max_concurrency": 0}"#
+        )
+        .is_err()
+    );
+}
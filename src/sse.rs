@@ -0,0 +1,158 @@
+/// Splits a stream of server-sent events into the data of its events
+///
+/// The stream may be fed in chunks split anywhere, e.g. in the middle of a line, of a JSON payload
+/// or of a UTF-8 char. The partial line at the end of a chunk is kept until the next one completes
+/// it. The data lines of an event are joined with `\n`, and the comments and the other fields are
+/// skipped.
+#[derive(Debug, Default)]
+pub(crate) struct SseParser {
+    /// The bytes of the line not terminated yet
+    buffer: Vec<u8>,
+    /// The data lines of the event not terminated yet
+    data: Vec<String>,
+}
+impl SseParser {
+    /// Feed the next chunk of the stream. Returns the data of the events completed by the chunk.
+    pub(crate) fn feed(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(chunk);
+
+        let mut events = Vec::new();
+        let mut start = 0;
+        while let Some(len) = self.buffer[start..].iter().position(|&b| b == b'\n') {
+            let line = String::from_utf8_lossy(&self.buffer[start..start + len]).into_owned();
+            start += len + 1;
+
+            if let Some(event) = self.process_line(line.trim_end_matches('\r')) {
+                events.push(event);
+            }
+        }
+        self.buffer.drain(..start);
+
+        events
+    }
+
+    /// End the stream. Returns the data of the last event if the stream ends without the empty
+    /// line terminating it.
+    pub(crate) fn finish(&mut self) -> Option<String> {
+        let line = String::from_utf8_lossy(&std::mem::take(&mut self.buffer)).into_owned();
+        self.process_line(line.trim_end_matches('\r'));
+
+        self.dispatch()
+    }
+
+    fn process_line(&mut self, line: &str) -> Option<String> {
+        // an empty line terminates the event
+        if line.is_empty() {
+            return self.dispatch();
+        }
+
+        // the lines starting with a colon are comments, e.g. `: keep-alive`
+        if let Some((field, value)) = line.split_once(':')
+            && field == "data"
+        {
+            self.data
+                .push(value.strip_prefix(' ').unwrap_or(value).to_string());
+        } else if line == "data" {
+            self.data.push(String::new());
+        }
+
+        None
+    }
+
+    fn dispatch(&mut self) -> Option<String> {
+        if self.data.is_empty() {
+            return None;
+        }
+
+        Some(std::mem::take(&mut self.data).join("\n"))
+    }
+}
+
+#[test]
+fn test_sse_parser() {
+    let stream = "data: {\"a\": 1}\n\n: keep-alive\n\nevent: message\ndata: {\"b\":\ndata: 2}\r\n\r\ndata: [DONE]\n\n";
+
+    // the events are the same however the stream is split
+    for chunk_size in [1, 2, 7, stream.len()] {
+        let mut parser = SseParser::default();
+        let mut events = Vec::new();
+        for chunk in stream.as_bytes().chunks(chunk_size) {
+            events.extend(parser.feed(chunk));
+        }
+        events.extend(parser.finish());
+
+        assert_eq!(events, ["{\"a\": 1}", "{\"b\":\n2}", "[DONE]"]);
+    }
+
+    // the last event is returned even if the stream ends without an empty line
+    let mut parser = SseParser::default();
+    assert_eq!(parser.feed("data: é".as_bytes()), Vec::<String>::new());
+    assert_eq!(parser.finish(), Some("é".to_string()));
+    assert_eq!(parser.finish(), None);
+}
+
+/// Splits a stream of server-sent events into the raw bytes of its events, each with the empty
+/// line terminating it, so that some events can be dropped without re-encoding the others
+#[derive(Debug, Default)]
+pub(crate) struct SseEventSplitter {
+    /// The bytes of the event not terminated yet
+    buffer: Vec<u8>,
+}
+impl SseEventSplitter {
+    /// Feed the next chunk of the stream. Returns the events completed by the chunk.
+    pub(crate) fn feed(&mut self, chunk: &[u8]) -> Vec<Vec<u8>> {
+        self.buffer.extend_from_slice(chunk);
+
+        let mut events = Vec::new();
+        let mut start = 0;
+        let mut line_start = 0;
+        while let Some(len) = self.buffer[line_start..].iter().position(|&b| b == b'\n') {
+            let line = &self.buffer[line_start..line_start + len];
+            line_start += len + 1;
+
+            // an empty line terminates the event
+            if line.is_empty() || line == b"\r" {
+                events.push(self.buffer[start..line_start].to_vec());
+                start = line_start;
+            }
+        }
+        self.buffer.drain(..start);
+
+        events
+    }
+
+    /// End the stream. Returns the bytes of the last event not terminated by an empty line.
+    pub(crate) fn finish(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buffer)
+    }
+}
+
+#[test]
+fn test_sse_event_splitter() {
+    let stream =
+        "data: {\"a\": 1}\n\n: keep-alive\n\ndata: {\"b\":\r\ndata: 2}\r\n\r\ndata: [DONE]";
+
+    // the events are the same however the stream is split
+    for chunk_size in [1, 2, 7, stream.len()] {
+        let mut splitter = SseEventSplitter::default();
+        let mut events = Vec::new();
+        for chunk in stream.as_bytes().chunks(chunk_size) {
+            events.extend(splitter.feed(chunk));
+        }
+        events.push(splitter.finish());
+
+        let events: Vec<String> = events
+            .into_iter()
+            .map(|event| String::from_utf8(event).unwrap())
+            .collect();
+        assert_eq!(
+            events,
+            [
+                "data: {\"a\": 1}\n\n",
+                ": keep-alive\n\n",
+                "data: {\"b\":\r\ndata: 2}\r\n\r\n",
+                "data: [DONE]",
+            ]
+        );
+    }
+}
@@ -0,0 +1,81 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use serde::Serialize;
+
+/// The request stats of the API endpoints, keyed by route
+#[derive(Debug, Default)]
+pub(crate) struct RequestStats {
+    endpoints: RwLock<HashMap<String, Arc<EndpointCounters>>>,
+}
+impl RequestStats {
+    /// Count a served request of the route
+    pub(crate) fn record(&self, route: &str, is_error: bool, latency: Duration) {
+        let counters = self.endpoints.read().unwrap().get(route).cloned();
+        let counters = match counters {
+            Some(counters) => counters,
+            // the route is counted for the first time
+            None => self
+                .endpoints
+                .write()
+                .unwrap()
+                .entry(route.to_string())
+                .or_default()
+                .clone(),
+        };
+
+        counters.requests.fetch_add(1, Ordering::Relaxed);
+        if is_error {
+            counters.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        counters
+            .total_latency_us
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Take a snapshot of the stats of every route served so far
+    pub(crate) fn snapshot(&self) -> HashMap<String, EndpointStats> {
+        self.endpoints
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(route, counters)| (route.clone(), counters.stats()))
+            .collect()
+    }
+}
+
+#[derive(Debug, Default)]
+struct EndpointCounters {
+    requests: AtomicU64,
+    errors: AtomicU64,
+    total_latency_us: AtomicU64,
+}
+impl EndpointCounters {
+    fn stats(&self) -> EndpointStats {
+        let requests = self.requests.load(Ordering::Relaxed);
+        let total_latency_us = self.total_latency_us.load(Ordering::Relaxed);
+
+        EndpointStats {
+            requests,
+            errors: self.errors.load(Ordering::Relaxed),
+            avg_latency_ms: match requests {
+                0 => 0.0,
+                _ => total_latency_us as f64 / requests as f64 / 1000.0,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct EndpointStats {
+    pub requests: u64,
+    /// The requests answered with a 4xx or 5xx status
+    pub errors: u64,
+    pub avg_latency_ms: f64,
+}
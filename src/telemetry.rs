@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+
+use once_cell::sync::OnceCell;
+use opentelemetry::{global, trace::TracerProvider};
+use opentelemetry_http::HeaderInjector;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    Resource,
+    propagation::TraceContextPropagator,
+    trace::{SdkTracerProvider, Tracer},
+};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::{
+    config::TracingConfig,
+    error::{ServerError, ServerResult},
+};
+
+// The provider exporting the spans, which is flushed at shutdown
+static TRACER_PROVIDER: OnceCell<SdkTracerProvider> = OnceCell::new();
+
+/// Create the tracer exporting the spans to the OTLP endpoint, and propagate the trace context
+/// to the downstream servers in the `traceparent` header
+pub(crate) fn init_tracer(tracing_config: &TracingConfig) -> ServerResult<Tracer> {
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(&tracing_config.otlp_endpoint)
+        .build()
+        .map_err(|e| {
+            let err_msg = format!("Failed to create the OTLP span exporter: {e}");
+            eprintln!("{err_msg}");
+            ServerError::Operation(err_msg)
+        })?;
+
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(tracing_config.service_name.clone())
+                .build(),
+        )
+        .build();
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    global::set_tracer_provider(provider.clone());
+    TRACER_PROVIDER.set(provider).map_err(|_| {
+        let err_msg = "Failed to set the tracer provider".to_string();
+        eprintln!("{err_msg}");
+        ServerError::Operation(err_msg)
+    })?;
+
+    Ok(tracer)
+}
+
+/// Export the spans not exported yet
+pub(crate) fn shutdown_tracer() {
+    if let Some(provider) = TRACER_PROVIDER.get()
+        && let Err(e) = provider.shutdown()
+    {
+        eprintln!("Failed to shut down the tracer provider: {e}");
+    }
+}
+
+/// Inject the trace context of the current span into the headers of a downstream request. Nothing
+/// is injected if the tracing is not enabled.
+pub(crate) fn inject_trace_context(headers: &mut http::HeaderMap) {
+    let context = tracing::Span::current().context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(headers))
+    });
+}
+
+/// Get the trace context of the current span as key-value pairs, e.g. `traceparent`
+pub(crate) fn trace_context() -> HashMap<String, String> {
+    let context = tracing::Span::current().context();
+    let mut fields = HashMap::new();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(&context, &mut fields));
+    fields
+}
+
+#[test]
+fn test_inject_trace_context() {
+    use tracing_subscriber::prelude::*;
+
+    // no trace context without the tracing
+    let mut headers = http::HeaderMap::new();
+    inject_trace_context(&mut headers);
+    assert!(headers.get("traceparent").is_none());
+
+    let provider = SdkTracerProvider::builder().build();
+    let subscriber = tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    tracing::subscriber::with_default(subscriber, || {
+        let _span = tracing::info_span!("request").entered();
+        inject_trace_context(&mut headers);
+    });
+
+    // e.g. 00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01
+    let traceparent = headers["traceparent"].to_str().unwrap();
+    assert_eq!(traceparent.split('-').count(), 4);
+    assert!(traceparent.starts_with("00-"));
+}
@@ -0,0 +1,220 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::config::UsageConfig;
+
+/// The user of the requests not telling theirs in the `user` field
+pub(crate) const ANONYMOUS_USER: &str = "anonymous";
+
+/// The in-memory ledger of the tokens used by the requests, kept for the retention period
+#[derive(Debug)]
+pub(crate) struct UsageLedger {
+    retention_secs: u64,
+    max_users: usize,
+    records: Mutex<UsageRecords>,
+}
+impl UsageLedger {
+    pub(crate) fn new(usage_config: &UsageConfig) -> Self {
+        Self {
+            retention_secs: usage_config.retention_secs,
+            max_users: usage_config.max_users,
+            records: Mutex::new(UsageRecords::default()),
+        }
+    }
+
+    /// Record the tokens used by a request of the user to the model
+    pub(crate) fn record(
+        &self,
+        user: &str,
+        model: &str,
+        prompt_tokens: u64,
+        completion_tokens: u64,
+    ) {
+        self.record_at(unix_now(), user, model, prompt_tokens, completion_tokens);
+    }
+
+    fn record_at(
+        &self,
+        timestamp: u64,
+        user: &str,
+        model: &str,
+        prompt_tokens: u64,
+        completion_tokens: u64,
+    ) {
+        let mut records = self.records.lock().unwrap();
+
+        // drop the records past the retention period, the oldest ones being at the front, and
+        // forget the users left without records
+        let expired_before = timestamp.saturating_sub(self.retention_secs);
+        while records
+            .records
+            .front()
+            .is_some_and(|record| record.timestamp < expired_before)
+        {
+            let record = records.records.pop_front().unwrap();
+            if let Some(user) = record.user {
+                let count = records.users.get_mut(&user).unwrap();
+                *count -= 1;
+                if *count == 0 {
+                    records.users.remove(&user);
+                }
+            }
+        }
+
+        // the users tallied together do not take the room of a user
+        let user = if records.users.contains_key(user) || records.users.len() < self.max_users {
+            *records.users.entry(user.to_string()).or_default() += 1;
+            Some(user.to_string())
+        } else {
+            None
+        };
+        records.records.push_back(UsageRecord {
+            timestamp,
+            user,
+            model: model.to_string(),
+            prompt_tokens,
+            completion_tokens,
+        });
+    }
+
+    /// Sum up the tokens used in the time window, in unix seconds. The window is open-ended if a
+    /// bound is not set.
+    pub(crate) fn report(&self, since: Option<u64>, until: Option<u64>) -> UsageReport {
+        let mut report = UsageReport::default();
+        for record in self
+            .records
+            .lock()
+            .unwrap()
+            .records
+            .iter()
+            .filter(|record| {
+                since.is_none_or(|since| record.timestamp >= since)
+                    && until.is_none_or(|until| record.timestamp <= until)
+            })
+        {
+            report.total.add(record);
+            match &record.user {
+                Some(user) => report.users.entry(user.clone()).or_default().add(record),
+                None => report.other_users.add(record),
+            }
+            report
+                .models
+                .entry(record.model.clone())
+                .or_default()
+                .add(record);
+        }
+
+        report
+    }
+}
+
+/// The records of the retention period, along with the number of records of each user tallied on
+/// their own
+#[derive(Debug, Default)]
+struct UsageRecords {
+    records: VecDeque<UsageRecord>,
+    users: HashMap<String, usize>,
+}
+
+#[derive(Debug)]
+struct UsageRecord {
+    /// When the request was served, in unix seconds
+    timestamp: u64,
+    /// The user, none if tallied together with the users past `max_users`
+    user: Option<String>,
+    model: String,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+}
+
+/// The tokens used in a time window, in total, per user and per model. The users past
+/// `max_users` are tallied together in `other_users`.
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct UsageReport {
+    pub total: TokenUsage,
+    pub users: HashMap<String, TokenUsage>,
+    pub other_users: TokenUsage,
+    pub models: HashMap<String, TokenUsage>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub(crate) struct TokenUsage {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+impl TokenUsage {
+    fn add(&mut self, record: &UsageRecord) {
+        self.requests += 1;
+        self.prompt_tokens += record.prompt_tokens;
+        self.completion_tokens += record.completion_tokens;
+        self.total_tokens += record.prompt_tokens + record.completion_tokens;
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[test]
+fn test_usage_ledger() {
+    let ledger = UsageLedger::new(&UsageConfig {
+        retention_secs: 100,
+        max_users: 2,
+    });
+    ledger.record_at(1000, "alice", "llama", 10, 5);
+    ledger.record_at(1050, "bob", "llama", 20, 10);
+    ledger.record_at(1060, "alice", "qwen", 1, 2);
+
+    let report = ledger.report(None, None);
+    assert_eq!(
+        report.total,
+        TokenUsage {
+            requests: 3,
+            prompt_tokens: 31,
+            completion_tokens: 17,
+            total_tokens: 48,
+        }
+    );
+    assert_eq!(report.users["alice"].total_tokens, 18);
+    assert_eq!(report.users["bob"].total_tokens, 30);
+    assert_eq!(report.models["llama"].requests, 2);
+    assert_eq!(report.models["qwen"].total_tokens, 3);
+
+    // only the records in the window are summed up
+    let report = ledger.report(Some(1050), Some(1059));
+    assert_eq!(report.total.requests, 1);
+    assert_eq!(report.users.keys().collect::<Vec<_>>(), ["bob"]);
+
+    // the records past the retention period are dropped
+    ledger.record_at(1120, "bob", "qwen", 1, 1);
+    let report = ledger.report(None, None);
+    assert_eq!(report.total.requests, 3);
+    assert_eq!(report.users["alice"].requests, 1);
+
+    // the users past `max_users` are tallied together
+    ledger.record_at(1120, "carol", "qwen", 1, 1);
+    let report = ledger.report(None, None);
+    assert_eq!(report.other_users.requests, 1);
+    assert!(!report.users.contains_key("carol"));
+
+    // a user named like the bucket of the users tallied together is a user of its own
+    ledger.record_at(1161, "other", "qwen", 1, 1);
+    let report = ledger.report(None, None);
+    assert_eq!(report.users["other"].requests, 1);
+    assert_eq!(report.other_users.requests, 1);
+
+    // a user is forgotten once its records are dropped, which makes room for another one
+    ledger.record_at(1221, "carol", "qwen", 1, 1);
+    let report = ledger.report(None, None);
+    assert_eq!(report.users["carol"].requests, 1);
+    assert!(!report.users.contains_key("bob"));
+}
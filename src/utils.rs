@@ -3,6 +3,18 @@ use once_cell::sync::OnceCell;
 // Global log configuration
 pub(crate) static LOG_DESTINATION: OnceCell<String> = OnceCell::new();
 
+/// Compare a key sent by a client with the expected one in a time independent of where they
+/// differ, so that the timing of the rejections does not reveal the expected key
+pub(crate) fn keys_match(key: &str, expected: &str) -> bool {
+    let (key, expected) = (key.as_bytes(), expected.as_bytes());
+    key.len() == expected.len()
+        && key
+            .iter()
+            .zip(expected)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
 // Helper macro for dual logging (to both stdout and log file)
 #[macro_export]
 macro_rules! dual_log {